@@ -1,18 +1,75 @@
-use brightidea_test::{ChatRoom, ChatRooms, Users};
-use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Weak,
+};
+
+use brightidea_test::{
+    config::RoomConfig, rooms::DEFAULT_SHARDS, sink::DiscardSink, ChatRoom, ChatRooms, Users,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// Concurrent connects per iteration of the room creation benchmark, each to its own room.
+const CONCURRENT_CONNECTS: usize = 64;
+
+/// Finds or creates a room the way `get_room` does, but with a transcript that is thrown away.
+async fn connect(rooms: &ChatRooms, room_name: String) -> Arc<ChatRoom> {
+    let mut shard = rooms.write_shard(&room_name).await;
+    shard.retain(|_, room_ptr| room_ptr.strong_count() > 0);
+    if let Some(room) = shard.get(&room_name).and_then(Weak::upgrade) {
+        return room;
+    }
+    let room = Arc::new(
+        ChatRoom::with_sink(
+            room_name.clone(),
+            Users::default(),
+            RoomConfig::default(),
+            Box::new(DiscardSink),
+        )
+        .await,
+    );
+    shard.insert(room_name, Arc::downgrade(&room));
+    room
+}
 
 pub fn criterion_benchmark(c: &mut Criterion) {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(async {
-            let chatroom = ChatRoom::new("benchmark_test".to_owned(), Users::default()).await;
+        .unwrap();
+
+    // Discard the transcript so benchmark runs don't leave log files behind.
+    let chatroom = runtime.block_on(ChatRoom::with_sink(
+        "benchmark_test".to_owned(),
+        Users::default(),
+        RoomConfig::default(),
+        Box::new(DiscardSink),
+    ));
+    c.bench_function("log hello, world", |b| {
+        b.iter(|| chatroom.log_message("hello_world", 0))
+    });
 
-            c.bench_function("log hello, world", |b| {
-                b.iter(|| chatroom.log_message(&"hello_world".to_string(), 0))
-            });
+    // One shard is the old single global lock.
+    let mut group = c.benchmark_group("concurrent distinct-room connects");
+    let next_room = Arc::new(AtomicUsize::new(0));
+    for shards in [1, DEFAULT_SHARDS] {
+        let rooms = ChatRooms::with_shards(shards);
+        group.bench_with_input(BenchmarkId::new("shards", shards), &rooms, |b, rooms| {
+            b.to_async(&runtime).iter(|| async {
+                let connects: Vec<_> = (0..CONCURRENT_CONNECTS)
+                    .map(|_| {
+                        let rooms = rooms.clone();
+                        let room_name =
+                            format!("room_{}", next_room.fetch_add(1, Ordering::Relaxed));
+                        tokio::spawn(async move { connect(&rooms, room_name).await })
+                    })
+                    .collect();
+                for connect in connects {
+                    connect.await.unwrap();
+                }
+            })
         });
+    }
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
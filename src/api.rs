@@ -1,8 +1,45 @@
-use std::convert::Infallible;
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use warp::Filter;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use warp::{
+    body::BodyDeserializeError,
+    http::{
+        header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER, SEC_WEBSOCKET_PROTOCOL},
+        Method, StatusCode,
+    },
+    hyper::Body,
+    path::FullPath,
+    reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
 
-use crate::{ChatRooms, get_room, user_connected};
+use crate::{
+    close_room,
+    config::{RoomConfig, RoomLimits, DEFAULT_BIND_ADDR, DEFAULT_DRAIN_GRACE},
+    drain_room, find_room, get_room,
+    membership::{self, Connected},
+    metrics,
+    protocol::{ChatEvent, Protocol},
+    ratelimit::ConcurrencyLimit,
+    reap_rooms, refuse_busy,
+    reload::SharedConfig,
+    replay::{replay, ReplayOptions, ReplaySpeed},
+    rooms::{spawn_idle_reaper, validate_room_name, CreationRoute, RoomNameError, RoomOrigin},
+    shutdown::{retry_secs, Unavailable},
+    transcript::{self, ExportFormat},
+    user_connected, ChatRoom, ChatRooms,
+};
 
 static INDEX_HTML: &str = r#"<!DOCTYPE html>
 <html lang="en">
@@ -21,7 +58,8 @@ static INDEX_HTML: &str = r#"<!DOCTYPE html>
         const text = document.getElementById('text');
         const room_name = location.pathname.split('/');
         console.log(room_name);
-        const uri = 'ws://' + location.host + '/chat/' + room_name[1]; 
+        const scheme = location.protocol === 'https:' ? 'wss://' : 'ws://';
+        const uri = scheme + location.host + '/chat/' + room_name[1];
         const ws = new WebSocket(uri);
         function message(data) {
             const line = document.createElement('p');
@@ -48,9 +86,97 @@ static INDEX_HTML: &str = r#"<!DOCTYPE html>
 </html>
 "#;
 
+/// Turns a room name path segment into the room's name, or `None` if it isn't validly encoded.
+fn room_name(segment: String, config: &RoomConfig) -> Option<String> {
+    if !config.decode_room_names {
+        return Some(segment);
+    }
+    let bytes = segment.as_bytes();
+    let well_formed = bytes.iter().enumerate().all(|(i, &b)| {
+        b != b'%'
+            || (bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit)
+                && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit))
+    });
+    if !well_formed {
+        return None;
+    }
+    let decoded = percent_decode_str(&segment).decode_utf8().ok()?;
+    Some(decoded.into_owned())
+}
+
+fn invalid_room_name() -> Response {
+    warp::reply::with_status("invalid room name encoding", StatusCode::BAD_REQUEST).into_response()
+}
+
+fn refused_room_name(e: RoomNameError) -> Response {
+    warp::reply::with_status(format!("invalid room name: {}", e), StatusCode::BAD_REQUEST)
+        .into_response()
+}
+
+/// Refuses an admin operation because `max_admin_operations` are already running.
+fn admin_busy() -> Response {
+    let mut response = warp::reply::with_status(
+        "too many admin operations in progress",
+        StatusCode::TOO_MANY_REQUESTS,
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(1));
+    response
+}
+
+fn reserved_room_name() -> Response {
+    warp::reply::with_status("reserved room name", StatusCode::BAD_REQUEST).into_response()
+}
+
+/// Single-segment routes matched before `room()`, which would otherwise serve the chat page of a
+/// room with the same name. A route added here must be added to `build_filters` before `room()`.
+/// `/metrics.json` isn't listed, since `validate_room_name` refuses the `.` in it.
+pub const ROUTE_NAMES: &[&str] = &["metrics", "stats", "rooms"];
+
+/// A room name the config allows that one of `ROUTE_NAMES` would shadow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteCollision(pub &'static str);
+
+impl fmt::Display for RouteCollision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "room name {:?} collides with the /{} route and must be in reserved_room_names",
+            self.0, self.0
+        )
+    }
+}
+
+impl Error for RouteCollision {}
+
+/// Checks that `config` reserves every room name a route would shadow. Meant to be called before
+/// serving, so a new route can't quietly take over an existing room's page.
+pub fn check_reserved_names(config: &RoomConfig) -> Result<(), RouteCollision> {
+    match ROUTE_NAMES
+        .iter()
+        .find(|name| !config.reserved_room_names.contains(**name))
+    {
+        Some(name) => Err(RouteCollision(name)),
+        None => Ok(()),
+    }
+}
+
 // GET /{room: str} -> index html to join room
-fn room() -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!(String).map(|_| warp::reply::html(INDEX_HTML))
+fn room(
+    config: RoomConfig,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String)
+        .and(warp::get())
+        .map(move |segment| match room_name(segment, &config) {
+            Some(name) if config.reserved_room_names.contains(&name) => reserved_room_name(),
+            Some(name) => match validate_room_name(&name) {
+                Ok(()) => warp::reply::html(INDEX_HTML).into_response(),
+                Err(e) => refused_room_name(e),
+            },
+            None => invalid_room_name(),
+        })
 }
 
 fn with_rooms(
@@ -59,38 +185,1055 @@ fn with_rooms(
     warp::any().map(move || rooms.clone())
 }
 
+/// An admin route was called without the configured bearer token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("missing or wrong admin token")
+    }
+}
+
+impl Error for Unauthorized {}
+
+impl reject::Reject for Unauthorized {}
+
+/// An admin route was called on a server with no admin token configured.
+#[derive(Debug)]
+struct AdminDisabled;
+
+impl fmt::Display for AdminDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("admin routes are disabled: no admin token is configured")
+    }
+}
+
+impl Error for AdminDisabled {}
+
+impl reject::Reject for AdminDisabled {}
+
+/// Passes requests with an `Authorization: Bearer <token>` header, rejecting others as
+/// `Unauthorized`, or every request as `AdminDisabled` when there is no token. Goes after an admin
+/// route's path and method, so requests for other routes aren't refused.
+fn admin_auth(
+    token: Option<String>,
+) -> impl warp::Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let expected: Option<Arc<str>> = token.map(|token| format!("Bearer {}", token).into());
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let result = match (&expected, authorization) {
+                (None, _) => Err(reject::custom(AdminDisabled)),
+                (Some(expected), Some(given)) if constant_time_eq(expected, &given) => Ok(()),
+                (Some(_), _) => Err(reject::custom(Unauthorized)),
+            };
+            async move { result }
+        })
+        .untuple_one()
+}
+
+/// Compares `a` and `b` in time that depends only on their lengths, so a token can't be guessed
+/// a byte at a time.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Names the authenticated account of a websocket upgrade. Expected to be set by an
+/// authenticating proxy in front of the server, which must strip it from client requests.
+pub const ACCOUNT_HEADER: &str = "x-authenticated-user";
+
+/// Reads the shared config afresh for each request, so reloads apply to new connections.
+fn with_config(
+    config: SharedConfig,
+) -> impl warp::Filter<Extract = (RoomConfig,), Error = Infallible> + Clone {
+    warp::any().map(move || RoomConfig::clone(&config.load()))
+}
+
 async fn upgrade_connection(
-    room_name: String,
+    segment: String,
     ws: warp::ws::Ws,
+    requested_protocols: Option<String>,
+    account: Option<String>,
+    addr: Option<SocketAddr>,
     rooms: ChatRooms,
+    config: RoomConfig,
 ) -> Result<impl warp::Reply, Infallible> {
+    let room_name = match room_name(segment, &config) {
+        Some(room_name) => room_name,
+        None => return Ok(invalid_room_name()),
+    };
+    if config.reserved_room_names.contains(&room_name) {
+        return Ok(reserved_room_name());
+    }
+    if let Err(e) = validate_room_name(&room_name) {
+        return Ok(refused_room_name(e));
+    }
+    let protocol = Protocol::negotiate(requested_protocols.as_deref());
     // This will call our function if the handshake succeeds.
-    let channel = get_room(&room_name, rooms).await;
-    Ok(ws.on_upgrade(move |socket| user_connected(socket, channel)))
+    let origin = RoomOrigin::new(CreationRoute::Websocket, addr, account.clone());
+    let channel = match get_room(&room_name, rooms.clone(), &config, Some(origin)).await {
+        Ok(channel) => channel,
+        Err(Unavailable::Throttled(wait)) if config.busy_retry_after.is_some() => {
+            let app_codes = config.app_close_codes;
+            return Ok(ws
+                .on_upgrade(move |socket| refuse_busy(socket, protocol, wait, app_codes))
+                .into_response());
+        }
+        Err(e @ Unavailable::Throttled(wait)) => {
+            let mut response =
+                warp::reply::with_status(e.to_string(), StatusCode::TOO_MANY_REQUESTS)
+                    .into_response();
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_secs(wait)));
+            return Ok(response);
+        }
+        Err(e @ Unavailable::NoTranscript) => {
+            return Ok(
+                warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_response(),
+            )
+        }
+        Err(e) => {
+            return Ok(
+                warp::reply::with_status(e.to_string(), StatusCode::SERVICE_UNAVAILABLE)
+                    .into_response(),
+            )
+        }
+    };
+    let mut response = ws
+        .on_upgrade(move |socket| user_connected(socket, channel, rooms, protocol, addr, account))
+        .into_response();
+    if let Some(subprotocol) = protocol.subprotocol() {
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(subprotocol),
+        );
+    }
+    Ok(response)
 }
 
 // GET /chat/{room: str}-> websocket upgrade
 fn ws_upgrade(
     rooms: ChatRooms,
+    config: impl Into<SharedConfig>,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("chat" / String)
         // The `ws()` filter will prepare Websocket handshake...
         .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::header::optional::<String>(ACCOUNT_HEADER))
+        .and(warp::addr::remote())
         .and(with_rooms(rooms))
+        .and(with_config(config.into()))
         .and_then(upgrade_connection)
 }
 
-pub fn build_filters(rooms: ChatRooms) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    room().or(ws_upgrade(rooms))
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+async fn export_transcript(
+    room_name: String,
+    query: ExportQuery,
+    rooms: ChatRooms,
+) -> Result<Response, Infallible> {
+    let format = match query.format.as_deref() {
+        None | Some("json") => ExportFormat::Json,
+        Some("csv") => ExportFormat::Csv,
+        Some(other) => {
+            return Ok(warp::reply::with_status(
+                format!("unsupported export format: {}", other),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response())
+        }
+    };
+
+    let room = match find_room(&room_name, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let log_path = match &room.log_path {
+        Some(log_path) => log_path,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    room.flush_log().await;
+    let file = match File::open(log_path).await {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::error!(room = %room_name, error = %e, "failed to open transcript");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+
+    let mut response = Response::new(Body::wrap_stream(transcript::export(
+        file,
+        room.config.transcript_format,
+        format,
+    )));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    Ok(response)
+}
+
+// GET /chat/{room: str}/export?format=csv|json -> room transcript
+fn export(
+    rooms: ChatRooms,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("chat" / String / "export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+        .and(with_rooms(rooms))
+        .and_then(export_transcript)
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesQuery {
+    /// Only return messages containing this, ignoring case.
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Messages `/messages` returns when the request doesn't say how many.
+const DEFAULT_MESSAGES_LIMIT: usize = 50;
+
+async fn get_messages(
+    room_name: String,
+    query: MessagesQuery,
+    rooms: ChatRooms,
+) -> Result<Response, Infallible> {
+    let room = match find_room(&room_name, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let store = match room.message_store() {
+        Some(store) => store,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    room.flush_log().await;
+    let limit = query.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT);
+    let records = match &query.q {
+        Some(q) => store.search(&room.name, q, limit).await,
+        None => store.query_recent(&room.name, limit).await,
+    };
+    Ok(match records {
+        Ok(records) => warp::reply::json(&records).into_response(),
+        Err(e) => {
+            tracing::error!(room = %room_name, error = %e, "failed to read messages");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    })
+}
+
+// GET /chat/{room: str}/messages?q=&limit= -> the room's latest messages, oldest first
+fn messages(
+    rooms: ChatRooms,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("chat" / String / "messages")
+        .and(warp::get())
+        .and(warp::query::<MessagesQuery>())
+        .and(with_rooms(rooms))
+        .and_then(get_messages)
+}
+
+async fn get_room_limits(room_name: String, rooms: ChatRooms) -> Result<Response, Infallible> {
+    Ok(match find_room(&room_name, &rooms).await {
+        Some(room) => warp::reply::json(&room.limits()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+async fn put_room_limits(
+    room_name: String,
+    limits: RoomLimits,
+    rooms: ChatRooms,
+) -> Result<Response, Infallible> {
+    let room = match find_room(&room_name, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    Ok(match room.set_limits(limits) {
+        Ok(()) => {
+            tracing::info!(room = %room_name, limits = ?room.limits(), "limits updated");
+            warp::reply::json(&room.limits()).into_response()
+        }
+        Err(e) => warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response(),
+    })
+}
+
+// GET /chat/{room: str}/config -> current room limits
+// PUT /chat/{room: str}/config -> replace room limits
+fn room_config(
+    rooms: ChatRooms,
+    token: Option<String>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let get = warp::path!("chat" / String / "config")
+        .and(warp::get())
+        .and(with_rooms(rooms.clone()))
+        .and_then(get_room_limits);
+    let put = warp::path!("chat" / String / "config")
+        .and(warp::put())
+        .and(admin_auth(token))
+        .and(warp::body::json())
+        .and(with_rooms(rooms))
+        .and_then(put_room_limits);
+    get.or(put)
+}
+
+#[derive(Debug, Serialize)]
+struct DrainSummary {
+    /// Seconds until the room's remaining users are disconnected.
+    grace_secs: u64,
+}
+
+async fn start_drain(room_name: String, rooms: ChatRooms) -> Result<Response, Infallible> {
+    let room = match find_room(&room_name, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let grace = room.config.drain_grace.unwrap_or(DEFAULT_DRAIN_GRACE);
+    if !drain_room(room, rooms).await {
+        return Ok(
+            warp::reply::with_status("room is already draining", StatusCode::CONFLICT)
+                .into_response(),
+        );
+    }
+    let summary = DrainSummary {
+        grace_secs: grace.as_secs(),
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&summary), StatusCode::ACCEPTED).into_response())
+}
+
+// POST /chat/{room: str}/drain -> close a room for maintenance after its drain grace
+fn room_drain(
+    rooms: ChatRooms,
+    token: Option<String>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("chat" / String / "drain")
+        .and(warp::post())
+        .and(admin_auth(token))
+        .and(with_rooms(rooms))
+        .and_then(start_drain)
+}
+
+#[derive(Debug, Serialize)]
+struct CloseSummary {
+    /// Users who were in the room when it closed.
+    disconnected: usize,
+}
+
+async fn delete_room(room_name: String, rooms: ChatRooms) -> Result<Response, Infallible> {
+    let room = match find_room(&room_name, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    Ok(match close_room(room, rooms).await {
+        Some(disconnected) => warp::reply::json(&CloseSummary { disconnected }).into_response(),
+        // Closed by someone else since it was found.
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+// DELETE /rooms/{room: str} -> close a room at once, disconnecting everyone in it
+fn room_close(
+    rooms: ChatRooms,
+    token: Option<String>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rooms" / String)
+        .and(warp::delete())
+        .and(admin_auth(token))
+        .and(with_rooms(rooms))
+        .and_then(delete_room)
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayRequest {
+    /// Transcript file to replay.
+    path: String,
+    /// Room to replay into, which must currently be open.
+    room: String,
+    #[serde(flatten)]
+    options: ReplayOptions,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplaySummary {
+    messages: usize,
+    dry_run: bool,
+}
+
+/// Opens `path`, resolved against `dir`, if it names a file inside `dir` once symlinks and `..`
+/// are resolved.
+async fn open_within(dir: &Path, path: &str) -> io::Result<Option<File>> {
+    let dir = tokio::fs::canonicalize(dir).await?;
+    let path = tokio::fs::canonicalize(dir.join(path)).await?;
+    if !path.starts_with(&dir) {
+        return Ok(None);
+    }
+    File::open(path).await.map(Some)
+}
+
+async fn run_replay(
+    request: ReplayRequest,
+    rooms: ChatRooms,
+    log_dir: PathBuf,
+    admin_ops: ConcurrencyLimit,
+) -> Result<Response, Infallible> {
+    let _running = match admin_ops.try_start() {
+        Some(running) => running,
+        None => return Ok(admin_busy()),
+    };
+    let room = match find_room(&request.room, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    // Only transcripts in the log directory can be replayed, and why others can't isn't said, so
+    // the route can't be used to probe the rest of the filesystem.
+    let file = match open_within(&log_dir, &request.path).await {
+        Ok(Some(file)) => file,
+        opened => {
+            if let Err(e) = opened {
+                tracing::warn!(transcript = %request.path, error = %e, "cannot open transcript for replay");
+            }
+            return Ok(warp::reply::with_status(
+                "no such transcript in the log directory",
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response());
+        }
+    };
+
+    let options = request.options;
+    if options.speed == ReplaySpeed::RealTime && !options.dry_run {
+        // A real-time replay takes as long as the original conversation, so don't hold the
+        // request open for it.
+        tokio::task::spawn(async move {
+            match replay(file, &room, options).await {
+                Ok(n) => tracing::info!(room = %room.name, messages = n, "replayed transcript"),
+                Err(e) => tracing::error!(room = %room.name, error = %e, "replay failed"),
+            }
+        });
+        return Ok(StatusCode::ACCEPTED.into_response());
+    }
+
+    Ok(match replay(file, &room, options).await {
+        Ok(messages) => warp::reply::json(&ReplaySummary {
+            messages,
+            dry_run: options.dry_run,
+        })
+        .into_response(),
+        Err(e) => {
+            tracing::error!(room = %room.name, error = %e, "replay failed");
+            warp::reply::with_status("replay failed", StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response()
+        }
+    })
+}
+
+// POST /admin/replay -> re-post a transcript's messages into a room
+fn admin_replay(
+    rooms: ChatRooms,
+    log_dir: Option<PathBuf>,
+    token: Option<String>,
+    admin_ops: ConcurrencyLimit,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // Transcripts go in the working directory without a log directory.
+    let log_dir = log_dir.unwrap_or_else(|| PathBuf::from("."));
+    warp::path!("admin" / "replay")
+        .and(warp::post())
+        .and(admin_auth(token))
+        .and(warp::body::json())
+        .and(with_rooms(rooms))
+        .and(warp::any().map(move || log_dir.clone()))
+        .and(warp::any().map(move || admin_ops.clone()))
+        .and_then(run_replay)
+}
+
+#[derive(Debug, Serialize)]
+struct GcSummary {
+    reaped: usize,
+    remaining: usize,
+}
+
+async fn run_gc(rooms: ChatRooms, admin_ops: ConcurrencyLimit) -> Result<Response, Infallible> {
+    let _running = match admin_ops.try_start() {
+        Some(running) => running,
+        None => return Ok(admin_busy()),
+    };
+    let reaped = reap_rooms(&rooms).await;
+    let remaining = rooms.len().await;
+    tracing::info!(reaped, remaining, "admin gc reaped rooms");
+    Ok(warp::reply::json(&GcSummary { reaped, remaining }).into_response())
+}
+
+// POST /admin/gc -> reap empty rooms immediately
+fn admin_gc(
+    rooms: ChatRooms,
+    token: Option<String>,
+    admin_ops: ConcurrencyLimit,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "gc")
+        .and(warp::post())
+        .and(admin_auth(token))
+        .and(with_rooms(rooms))
+        .and(warp::any().map(move || admin_ops.clone()))
+        .and_then(run_gc)
+}
+
+async fn get_metrics(max_labeled: Option<usize>, rooms: ChatRooms) -> Result<Response, Infallible> {
+    let snapshot = metrics::snapshot(&rooms).await;
+    let text = metrics::render_prometheus(&snapshot, max_labeled);
+    let mut response = Response::new(Body::from(text));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(response)
+}
+
+async fn get_metrics_json(rooms: ChatRooms) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&metrics::snapshot(&rooms).await))
+}
+
+// GET /metrics -> per-room gauges in Prometheus text format
+// GET /metrics.json -> the same gauges as JSON
+fn metrics(
+    rooms: ChatRooms,
+    max_labeled: Option<usize>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let text = warp::path!("metrics")
+        .and(warp::get())
+        .map(move || max_labeled)
+        .and(with_rooms(rooms.clone()))
+        .and_then(get_metrics);
+    let json = warp::path!("metrics.json")
+        .and(warp::get())
+        .and(with_rooms(rooms))
+        .and_then(get_metrics_json);
+    text.or(json)
+}
+
+/// Most items a paginated endpoint returns at once.
+const MAX_PAGE: usize = 1000;
+
+/// `?offset=&limit=` for endpoints listing rooms or users.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// One page of a listing, with the total so clients know when to stop.
+#[derive(Debug, Serialize)]
+struct Paged<T> {
+    total: usize,
+    offset: usize,
+    items: Vec<T>,
+}
+
+impl Page {
+    fn apply<T>(&self, items: Vec<T>) -> Paged<T> {
+        let total = items.len();
+        let limit = self.limit.unwrap_or(MAX_PAGE).min(MAX_PAGE);
+        Paged {
+            total,
+            offset: self.offset,
+            items: items.into_iter().skip(self.offset).take(limit).collect(),
+        }
+    }
+}
+
+async fn list_rooms(page: Page, rooms: ChatRooms) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(
+        &page.apply(metrics::room_users(&rooms).await),
+    ))
+}
+
+// GET /rooms?offset=&limit= -> live rooms and their user counts, by name
+fn rooms_list(
+    rooms: ChatRooms,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rooms")
+        .and(warp::get())
+        .and(warp::query::<Page>())
+        .and(with_rooms(rooms))
+        .and_then(list_rooms)
+}
+
+async fn list_users(
+    room_name: String,
+    page: Page,
+    rooms: ChatRooms,
+) -> Result<Response, Infallible> {
+    Ok(match find_room(&room_name, &rooms).await {
+        Some(room) => {
+            warp::reply::json(&page.apply(membership::members(&room.users).await)).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+// GET /chat/{room: str}/users?offset=&limit= -> the room's users, by id
+fn room_users(
+    rooms: ChatRooms,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("chat" / String / "users")
+        .and(warp::get())
+        .and(warp::query::<Page>())
+        .and(with_rooms(rooms))
+        .and_then(list_users)
+}
+
+/// One room's group on the admin connections view.
+#[derive(Debug, Serialize)]
+struct RoomConnections {
+    room: String,
+    users: Vec<Connected>,
+}
+
+async fn list_connections(
+    page: Page,
+    rooms: ChatRooms,
+    admin_ops: ConcurrencyLimit,
+) -> Result<Response, Infallible> {
+    let _running = match admin_ops.try_start() {
+        Some(running) => running,
+        None => return Ok(admin_busy()),
+    };
+    let mut live = rooms.live_rooms().await;
+    live.sort_by(|a, b| a.name.cmp(&b.name));
+    // Only the rooms on the requested page have their user maps read.
+    let page = page.apply(live);
+    let mut items = Vec::with_capacity(page.items.len());
+    for room in page.items {
+        items.push(RoomConnections {
+            room: room.name.clone(),
+            users: membership::connections(&room.users).await,
+        });
+    }
+    Ok(warp::reply::json(&Paged {
+        total: page.total,
+        offset: page.offset,
+        items,
+    })
+    .into_response())
+}
+
+// GET /admin/connections?offset=&limit= -> every connected user, grouped by room and paginated
+// by room
+fn admin_connections(
+    rooms: ChatRooms,
+    token: Option<String>,
+    admin_ops: ConcurrencyLimit,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "connections")
+        .and(warp::get())
+        .and(admin_auth(token))
+        .and(warp::query::<Page>())
+        .and(with_rooms(rooms))
+        .and(warp::any().map(move || admin_ops.clone()))
+        .and_then(list_connections)
+}
+
+/// How many of a room's latest messages its snapshot includes.
+const SNAPSHOT_RECENT_MESSAGES: usize = 50;
+
+/// Everything the admin room view shows, read in one request.
+#[derive(Debug, Serialize)]
+struct RoomSnapshot {
+    room: String,
+    /// The room's adjustable config, as served by `/chat/{room}/config`.
+    config: RoomLimits,
+    stats: SnapshotStats,
+    users: Vec<Connected>,
+    pinned: Option<ChatEvent>,
+    /// The latest messages, oldest first.
+    recent: Vec<SnapshotMessage>,
+    degraded: bool,
+    draining: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotStats {
+    users: usize,
+    /// Messages queued for delivery, summed over every user in the room.
+    outbound_queue_depth: usize,
+    log_queue_depth: usize,
+    idle_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotMessage {
+    seq: u64,
+    from: usize,
+    body: String,
+    /// RFC 3339 time the message was sent.
+    sent_at: String,
+}
+
+impl RoomSnapshot {
+    /// Reads `room`'s state, holding its user map only while connection handles are copied out
+    /// so the users and their queue depths agree. Everything is formatted after it is released.
+    async fn take(room: &ChatRoom) -> RoomSnapshot {
+        let (handles, outbound_queue_depth) = {
+            let users = room.users.read().await;
+            let handles: Vec<_> = users
+                .values()
+                .map(|user| (user.identity.clone(), user.info.clone()))
+                .collect();
+            (handles, users.values().map(|user| user.depth.get()).sum())
+        };
+        let mut users: Vec<Connected> = handles
+            .iter()
+            .map(|(identity, info)| Connected::new(identity, info))
+            .collect();
+        users.sort_by_key(|user| user.id);
+        let recent = room
+            .recent_messages(SNAPSHOT_RECENT_MESSAGES)
+            .into_iter()
+            .map(|message| SnapshotMessage {
+                seq: message.seq,
+                from: message.from,
+                body: message.body,
+                sent_at: humantime::format_rfc3339_seconds(message.sent_at).to_string(),
+            })
+            .collect();
+        RoomSnapshot {
+            room: room.name.clone(),
+            config: room.limits(),
+            stats: SnapshotStats {
+                users: users.len(),
+                outbound_queue_depth,
+                log_queue_depth: room.log_queue_depth(),
+                idle_secs: room.idle_for().as_secs(),
+            },
+            users,
+            pinned: room.pinned(),
+            recent,
+            degraded: room.is_degraded(),
+            draining: room.is_draining(),
+        }
+    }
+}
+
+async fn get_room_snapshot(
+    room_name: String,
+    rooms: ChatRooms,
+    admin_ops: ConcurrencyLimit,
+) -> Result<Response, Infallible> {
+    let _running = match admin_ops.try_start() {
+        Some(running) => running,
+        None => return Ok(admin_busy()),
+    };
+    Ok(match find_room(&room_name, &rooms).await {
+        Some(room) => warp::reply::json(&RoomSnapshot::take(&room).await).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+// GET /admin/chat/{room: str}/snapshot -> the room's config, stats, users, pin and recent messages
+fn room_snapshot(
+    rooms: ChatRooms,
+    token: Option<String>,
+    admin_ops: ConcurrencyLimit,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "chat" / String / "snapshot")
+        .and(warp::get())
+        .and(admin_auth(token))
+        .and(with_rooms(rooms))
+        .and(warp::any().map(move || admin_ops.clone()))
+        .and_then(get_room_snapshot)
+}
+
+async fn get_delivery_failures(
+    room_name: String,
+    rooms: ChatRooms,
+) -> Result<Response, Infallible> {
+    let room = match find_room(&room_name, &rooms).await {
+        Some(room) => room,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    Ok(match room.delivery_failures() {
+        Some(failures) => warp::reply::json(&failures.recent()).into_response(),
+        None => warp::reply::with_status(
+            "this room doesn't record delivery failures",
+            StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    })
+}
+
+// GET /chat/{room: str}/delivery-failures -> the room's latest failed deliveries, oldest first
+fn delivery_failures(
+    rooms: ChatRooms,
+    token: Option<String>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("chat" / String / "delivery-failures")
+        .and(warp::get())
+        .and(admin_auth(token))
+        .and(with_rooms(rooms))
+        .and_then(get_delivery_failures)
+}
+
+async fn get_stats(rooms: ChatRooms) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&metrics::server_stats(&rooms).await))
+}
+
+// GET /stats -> summary of the whole server
+fn stats(
+    rooms: ChatRooms,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("stats")
+        .and(warp::get())
+        .and(with_rooms(rooms))
+        .and_then(get_stats)
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: u16,
+    message: String,
+}
+
+/// The status and message warp would pick for `rejection`, if it is one of warp's own or an admin
+/// auth failure. Of several causes the one with the highest status wins, except that 405 only
+/// beats 404.
+fn rejection_status(rejection: &Rejection) -> Option<(StatusCode, String)> {
+    fn found<T: std::error::Error + 'static>(
+        rejection: &Rejection,
+        status: StatusCode,
+    ) -> Option<(StatusCode, String)> {
+        rejection.find::<T>().map(|e| (status, e.to_string()))
+    }
+    let causes = [
+        found::<reject::PayloadTooLarge>(rejection, StatusCode::PAYLOAD_TOO_LARGE),
+        found::<reject::UnsupportedMediaType>(rejection, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        found::<reject::LengthRequired>(rejection, StatusCode::LENGTH_REQUIRED),
+        found::<reject::InvalidQuery>(rejection, StatusCode::BAD_REQUEST),
+        found::<reject::MissingHeader>(rejection, StatusCode::BAD_REQUEST),
+        found::<reject::InvalidHeader>(rejection, StatusCode::BAD_REQUEST),
+        found::<BodyDeserializeError>(rejection, StatusCode::BAD_REQUEST),
+        found::<Unauthorized>(rejection, StatusCode::UNAUTHORIZED),
+        found::<AdminDisabled>(rejection, StatusCode::FORBIDDEN),
+    ];
+    causes
+        .iter()
+        .flatten()
+        .max_by_key(|(status, _)| *status)
+        .cloned()
+        .or_else(|| found::<reject::MethodNotAllowed>(rejection, StatusCode::METHOD_NOT_ALLOWED))
+        .or_else(|| {
+            rejection
+                .is_not_found()
+                .then(|| (StatusCode::NOT_FOUND, "not found".to_owned()))
+        })
+}
+
+/// Records a tracing event at `level`, which unlike `tracing::event!` needn't be a constant.
+macro_rules! event_at {
+    ($level:expr, $($event:tt)+) => {
+        match $level {
+            tracing::Level::ERROR => tracing::error!($($event)+),
+            tracing::Level::WARN => tracing::warn!($($event)+),
+            tracing::Level::INFO => tracing::info!($($event)+),
+            tracing::Level::DEBUG => tracing::debug!($($event)+),
+            _ => tracing::trace!($($event)+),
+        }
+    };
+}
+
+/// Answers requests `routes` rejects with a JSON error body, first recording the method, path and
+/// rejection in a tracing event at `level` (not at all when `None`). Rejections warp doesn't define, such as a
+/// missing websocket upgrade, keep warp's own response.
+fn recover_logged<F, R>(
+    routes: F,
+    level: Option<tracing::Level>,
+) -> impl warp::Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: warp::Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let attempt = routes
+        .map(|reply: R| Ok(reply.into_response()))
+        .or_else(|rejection| async move { Ok::<_, Rejection>((Err(rejection),)) });
+    warp::method()
+        .and(warp::path::full())
+        .and(attempt)
+        .and_then(
+            move |method: Method, path: FullPath, attempt: Result<Response, Rejection>| async move {
+                let rejection = match attempt {
+                    Ok(response) => return Ok(response),
+                    Err(rejection) => rejection,
+                };
+                if let Some(level) = level {
+                    event_at!(
+                        level,
+                        method = %method,
+                        path = path.as_str(),
+                        rejection = ?rejection,
+                        "rejected request"
+                    );
+                }
+                match rejection_status(&rejection) {
+                    Some((status, message)) => {
+                        let body = ErrorBody {
+                            code: status.as_u16(),
+                            message,
+                        };
+                        Ok(warp::reply::with_status(warp::reply::json(&body), status)
+                            .into_response())
+                    }
+                    None => Err(rejection),
+                }
+            },
+        )
+}
+
+/// Records each request a wrapped filter answers as a `chat::access` event at `level`, if there
+/// is one. Successful websocket upgrades are logged with status 101 and refused handshakes with
+/// their error status; only rejections warp answers itself go unlogged.
+fn access_logged(
+    level: Option<tracing::Level>,
+) -> warp::log::Log<impl Fn(warp::log::Info<'_>) + Clone + Send> {
+    warp::log::custom(move |info| {
+        let level = match level {
+            Some(level) => level,
+            None => return,
+        };
+        let remote = info
+            .remote_addr()
+            .map_or_else(|| "-".to_owned(), |addr| addr.to_string());
+        event_at!(
+            level,
+            target: "chat::access",
+            method = %info.method(),
+            path = info.path(),
+            status = info.status().as_u16(),
+            latency_us = info.elapsed().as_micros() as u64,
+            remote = %remote,
+            "request"
+        );
+    })
+}
+
+/// What `build_filters` serves: the config rooms are created with, plus settings for the
+/// server as a whole.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub rooms: SharedConfig,
+    /// Remove rooms that nobody is in and nothing has happened in for this long, even if they are
+    /// still held open. Rooms are only removed once dropped when `None`.
+    pub idle_room_timeout: Option<Duration>,
+    /// Where the server is bound. The filters themselves don't depend on it.
+    pub addr: SocketAddr,
+}
+
+impl From<SharedConfig> for ServerOptions {
+    fn from(rooms: SharedConfig) -> ServerOptions {
+        ServerOptions {
+            rooms,
+            idle_room_timeout: None,
+            addr: DEFAULT_BIND_ADDR,
+        }
+    }
+}
+
+impl From<RoomConfig> for ServerOptions {
+    fn from(config: RoomConfig) -> ServerOptions {
+        SharedConfig::new(config).into()
+    }
+}
+
+/// All routes. Only websocket upgrades follow reloads of the room config; the rest keep the
+/// config current when the filters are built.
+///
+/// Starts the idle room reaper if `options` sets a timeout, so must be called on a runtime.
+pub fn build_filters(
+    rooms: ChatRooms,
+    options: impl Into<ServerOptions>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    metrics::mark_started();
+    let options = options.into();
+    if let Some(timeout) = options.idle_room_timeout {
+        spawn_idle_reaper(rooms.clone(), timeout);
+    }
+    let shared = options.rooms;
+    let config = RoomConfig::clone(&shared.load());
+    let admin_token = config.admin_token.clone();
+    let admin_ops = ConcurrencyLimit::new(config.max_admin_operations);
+    let (log_rejections, access_log) = (config.log_rejections, config.access_log);
+    // Matched before `room()`, which would otherwise serve the chat page for `ROUTE_NAMES`.
+    let routes = metrics(rooms.clone(), config.metrics_room_labels)
+        .or(stats(rooms.clone()))
+        .or(rooms_list(rooms.clone()))
+        .or(room(config.clone()))
+        .or(ws_upgrade(rooms.clone(), shared))
+        .or(export(rooms.clone()))
+        .or(messages(rooms.clone()))
+        .or(room_config(rooms.clone(), admin_token.clone()))
+        .or(room_drain(rooms.clone(), admin_token.clone()))
+        .or(room_close(rooms.clone(), admin_token.clone()))
+        .or(room_users(rooms.clone()))
+        .or(admin_connections(
+            rooms.clone(),
+            admin_token.clone(),
+            admin_ops.clone(),
+        ))
+        .or(room_snapshot(
+            rooms.clone(),
+            admin_token.clone(),
+            admin_ops.clone(),
+        ))
+        .or(delivery_failures(rooms.clone(), admin_token.clone()))
+        .or(admin_replay(
+            rooms.clone(),
+            config.log_dir.clone(),
+            admin_token.clone(),
+            admin_ops.clone(),
+        ))
+        .or(admin_gc(rooms, admin_token, admin_ops));
+    recover_logged(routes, log_rejections).with(access_logged(access_log))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{ChatRooms, api::{INDEX_HTML, room, ws_upgrade}};
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        api::{
+            admin_connections, admin_gc, build_filters, check_reserved_names, export, messages,
+            metrics, room, room_drain, room_snapshot, room_users, ws_upgrade, RouteCollision,
+            ACCOUNT_HEADER, INDEX_HTML, ROUTE_NAMES,
+        },
+        close_room,
+        config::{MessagePolicy, PreJoinPolicy, RoomConfig, DEFAULT_MAX_QUEUED_PER_USER},
+        find_room,
+        protocol::{MessageKind, Protocol},
+        ratelimit::{ConcurrencyLimit, TokenBucket},
+        rooms::{validate_room_name, CreationRoute, RoomNameError, MAX_ROOM_NAME_CHARS},
+        sink::{DiscardSink, MemorySink},
+        store::{MemoryStore, MessageStore},
+        tests::test_config,
+        transcript::Record,
+        ChatRoom, ChatRooms, Identity, User, Users,
+    };
 
     #[tokio::test]
     async fn chat_endpoint() {
-        let filter = room();
+        let filter = room(test_config());
         let ok_reply = warp::test::request()
             .path("/test_room")
             .reply(&filter)
@@ -107,12 +1250,19 @@ mod tests {
             .reply(&filter)
             .await;
         assert_eq!(too_many_rooms.status(), 404);
+
+        let wrong_method = warp::test::request()
+            .method("POST")
+            .path("/test_room")
+            .reply(&filter)
+            .await;
+        assert_eq!(wrong_method.status(), 405);
     }
 
     #[tokio::test]
     async fn chat_upgrade_endpoint() {
         let channels = ChatRooms::default();
-        let filter = ws_upgrade(channels.clone());
+        let filter = ws_upgrade(channels.clone(), test_config());
 
         let ok_reply = warp::test::ws()
             .path("/chat/test_room")
@@ -120,10 +1270,9 @@ mod tests {
             .await;
         assert!(ok_reply.is_ok());
 
-        let channel_read_lock = channels.read().await;
-        assert_eq!(channel_read_lock.len(), 1);
+        assert_eq!(channels.len().await, 1);
 
-        let test_room_channel = channel_read_lock.get("test_room");
+        let test_room_channel = channels.get("test_room").await;
         assert!(test_room_channel.is_some());
 
         let test_room_channel = test_room_channel.unwrap().upgrade().unwrap();
@@ -131,11 +1280,1646 @@ mod tests {
         assert_eq!(test_room_channel.users.read().await.len(), 1);
 
         // Fail test
-        let filter = ws_upgrade(channels.clone());
-        let no_room = warp::test::ws()
-            .path("/chat")
-            .handshake(filter)
-            .await;
-        assert!(!no_room.is_ok());
+        let filter = ws_upgrade(channels.clone(), test_config());
+        let no_room = warp::test::ws().path("/chat").handshake(filter).await;
+        assert!(no_room.is_err());
+    }
+
+    #[tokio::test]
+    async fn each_room_numbers_its_users_from_one() {
+        let rooms = ChatRooms::default();
+        let mut clients = Vec::new();
+        for path in ["/chat/numbered", "/chat/numbered", "/chat/numbered_too"].iter() {
+            let client = warp::test::ws()
+                .path(path)
+                .handshake(ws_upgrade(rooms.clone(), test_config()))
+                .await
+                .unwrap();
+            clients.push(client);
+        }
+        let ids = |name: &'static str| {
+            let rooms = rooms.clone();
+            async move {
+                let room = find_room(name, &rooms).await.unwrap();
+                let mut ids: Vec<usize> = room.users.read().await.keys().copied().collect();
+                ids.sort_unstable();
+                ids
+            }
+        };
+        assert_eq!(ids("numbered").await, [1, 2]);
+        assert_eq!(ids("numbered_too").await, [1]);
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let rooms = ChatRooms::default();
+        let room = Arc::new(ChatRoom::unlogged("metrics_room".to_owned(), Users::default()).await);
+        rooms
+            .insert("metrics_room".to_owned(), Arc::downgrade(&room))
+            .await;
+
+        let text = warp::test::request()
+            .path("/metrics")
+            .reply(&metrics(rooms.clone(), None))
+            .await;
+        assert_eq!(text.status(), 200);
+        let body = std::str::from_utf8(text.body()).unwrap();
+        assert!(body.contains("chat_room_users{room=\"metrics_room\"} 0\n"));
+
+        let json = warp::test::request()
+            .path("/metrics.json")
+            .reply(&metrics(rooms.clone(), None))
+            .await;
+        assert_eq!(
+            json.body(),
+            r#"[{"room":"metrics_room","users":0,"outbound_queue_depth":0,"log_queue_depth":0,"log_degraded":0}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_counts_an_open_connection() {
+        let rooms = ChatRooms::default();
+        let client = warp::test::ws()
+            .path("/chat/counted_room")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        // Other tests open and close connections concurrently, so only ours is certain.
+        assert!(crate::metrics::open_connections() >= 1);
+
+        let text = warp::test::request()
+            .path("/metrics")
+            .reply(&metrics(rooms.clone(), None))
+            .await;
+        let body = std::str::from_utf8(text.body()).unwrap();
+        let open: usize = body
+            .lines()
+            .find_map(|line| line.strip_prefix("chat_connections_open "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(open >= 1);
+        assert!(body.contains("# TYPE chat_connections_total counter\n"));
+        assert!(body.contains("# TYPE chat_rooms_created_total counter\n"));
+        drop(client);
+    }
+
+    /// An event's or span's fields as `(name, value)` pairs, values formatted with `Debug`.
+    type Fields = Vec<(String, String)>;
+
+    /// Each event's fields, with those of the span it was emitted in.
+    type CapturedEvents = Vec<(Fields, Option<Fields>)>;
+
+    /// A captured field, its value formatted with `Debug`.
+    fn field(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[derive(Default)]
+    struct FieldVisitor(Fields);
+
+    impl tracing::field::Visit for FieldVisitor {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0
+                .push((field.name().to_owned(), format!("{:?}", value)));
+        }
+    }
+
+    /// Keeps every event along with the fields of the span it was emitted in, if any.
+    #[derive(Default)]
+    struct CapturingSubscriber {
+        spans: Mutex<Vec<Fields>>,
+        entered: Mutex<Vec<u64>>,
+        events: Arc<Mutex<CapturedEvents>>,
+    }
+
+    impl tracing::Subscriber for CapturingSubscriber {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = FieldVisitor::default();
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields.0);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = FieldVisitor::default();
+            event.record(&mut fields);
+            let span = self.entered.lock().unwrap().last().map(|id| {
+                let spans = self.spans.lock().unwrap();
+                spans[*id as usize - 1].clone()
+            });
+            self.events.lock().unwrap().push((fields.0, span));
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_events_carry_their_user_and_room() {
+        let subscriber = CapturingSubscriber::default();
+        let events = subscriber.events.clone();
+        // Scoped to this test's thread, which also runs the connection's tasks.
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let rooms = ChatRooms::default();
+        let client = warp::test::ws()
+            .path("/chat/traced_room")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        let users = rooms
+            .get("traced_room")
+            .await
+            .unwrap()
+            .upgrade()
+            .unwrap()
+            .users
+            .clone();
+        let user_id = *users.read().await.keys().next().unwrap();
+        drop(client);
+
+        let events = events.lock().unwrap();
+        let (fields, span) = events
+            .iter()
+            .find(|(fields, _)| fields.contains(&field("message", "new chat user")))
+            .expect("no connection event");
+        let expected = [
+            field("user_id", &user_id.to_string()),
+            field("room", "traced_room"),
+        ];
+        for expected in expected.iter() {
+            assert!(fields.contains(expected), "{:?}", fields);
+            assert!(span.as_ref().unwrap().contains(expected), "{:?}", span);
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_endpoint() {
+        let rooms = ChatRooms::default();
+        let mut open = Vec::new();
+        for (name, users) in [("quiet_room", 1), ("busy_room", 3)] {
+            let room = Arc::new(ChatRoom::unlogged(name.to_owned(), Users::default()).await);
+            for id in 0..users {
+                let (tx, _rx) = tokio::sync::mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+                room.users
+                    .write()
+                    .await
+                    .insert(id, User::new(tx, Protocol::LegacyText));
+            }
+            rooms.insert(name.to_owned(), Arc::downgrade(&room)).await;
+            open.push(room);
+        }
+        open[1].post_message(0, "hello", None, None).await;
+
+        let reply = warp::test::request()
+            .path("/stats")
+            .reply(&build_filters(rooms, test_config()))
+            .await;
+        assert_eq!(reply.status(), 200);
+        let stats: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(stats["rooms"], 2);
+        assert_eq!(stats["users"], 4);
+        assert!(stats["messages"].as_u64().unwrap() >= 1);
+        assert_eq!(stats["busiest_rooms"][0]["room"], "busy_room");
+        assert_eq!(stats["busiest_rooms"][0]["users"], 3);
+        assert_eq!(stats["busiest_rooms"][1]["room"], "quiet_room");
+    }
+
+    #[tokio::test]
+    async fn stats_show_room_origins() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            record_origins: true,
+            ..test_config()
+        };
+        let filters = build_filters(rooms.clone(), config);
+        let _client = warp::test::ws()
+            .path("/chat/origin_room")
+            .header(ACCOUNT_HEADER, "alice")
+            .handshake(filters.clone())
+            .await
+            .unwrap();
+
+        let room = find_room("origin_room", &rooms).await.unwrap();
+        let origin = room.origin().unwrap();
+        assert_eq!(origin.route, CreationRoute::Websocket);
+        assert_eq!(origin.account.as_deref(), Some("alice"));
+
+        let reply = warp::test::request().path("/stats").reply(&filters).await;
+        let stats: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        let exposed = &stats["busiest_rooms"][0]["origin"];
+        assert_eq!(exposed["route"], "websocket");
+        assert_eq!(exposed["account"], "alice");
+        assert_eq!(exposed["created_at"], origin.created_at.as_str());
+    }
+
+    #[tokio::test]
+    async fn users_and_rooms_are_paginated() {
+        let rooms = ChatRooms::default();
+        let room = Arc::new(ChatRoom::unlogged("huge_room".to_owned(), Users::default()).await);
+        let mut receivers = Vec::new();
+        for id in 1..=250 {
+            let (tx, rx) = tokio::sync::mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+            let user = User {
+                identity: Identity::new(id),
+                ..User::new(tx, Protocol::LegacyText)
+            };
+            room.users.write().await.insert(id, user);
+            receivers.push(rx);
+        }
+        rooms
+            .insert("huge_room".to_owned(), Arc::downgrade(&room))
+            .await;
+
+        let reply = warp::test::request()
+            .path("/chat/huge_room/users?offset=100&limit=3")
+            .reply(&room_users(rooms.clone()))
+            .await;
+        assert_eq!(reply.status(), 200);
+        let page: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(page["total"], 250);
+        let ids: Vec<u64> = page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, [101, 102, 103]);
+
+        let past_end = warp::test::request()
+            .path("/chat/huge_room/users?offset=249&limit=10")
+            .reply(&room_users(rooms.clone()))
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(past_end.body()).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+
+        let listed = warp::test::request()
+            .path("/rooms?limit=10")
+            .reply(&build_filters(rooms, test_config()))
+            .await;
+        assert_eq!(
+            listed.body(),
+            r#"{"total":1,"offset":0,"items":[{"room":"huge_room","users":250}]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn rooms_list_counts_websocket_users() {
+        let rooms = ChatRooms::default();
+        let filters = build_filters(rooms.clone(), test_config());
+        let mut clients = Vec::new();
+        for path in ["/chat/listed_a", "/chat/listed_a", "/chat/listed_b"].iter() {
+            let client = warp::test::ws()
+                .path(path)
+                .handshake(filters.clone())
+                .await
+                .unwrap();
+            clients.push(client);
+        }
+
+        let listed = warp::test::request().path("/rooms").reply(&filters).await;
+        assert_eq!(listed.status(), 200);
+        assert_eq!(
+            listed.body(),
+            r#"{"total":2,"offset":0,"items":[{"room":"listed_a","users":2},{"room":"listed_b","users":1}]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn rejections_are_logged_with_json_body() {
+        let subscriber = CapturingSubscriber::default();
+        let events = subscriber.events.clone();
+        let _default = tracing::subscriber::set_default(subscriber);
+        let config = RoomConfig {
+            log_rejections: Some(tracing::Level::WARN),
+            admin_token: Some("s3cret".to_owned()),
+            ..test_config()
+        };
+        let filters = build_filters(ChatRooms::default(), config);
+
+        let reply = warp::test::request()
+            .method("PUT")
+            .path("/chat/lobby/config")
+            .header("authorization", "Bearer s3cret")
+            .body("not json")
+            .reply(&filters)
+            .await;
+        assert_eq!(reply.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(body["code"], 400);
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Request body deserialize error"));
+
+        let events = events.lock().unwrap();
+        let (fields, _) = events
+            .iter()
+            .find(|(fields, _)| fields.contains(&field("message", "rejected request")))
+            .expect("rejection wasn't logged");
+        assert!(fields.contains(&field("method", "PUT")));
+        assert!(fields.contains(&field("path", "\"/chat/lobby/config\"")));
+        assert!(fields
+            .iter()
+            .any(|(name, value)| name == "rejection" && value.contains("BodyDeserializeError")));
+    }
+
+    #[tokio::test]
+    async fn requests_are_access_logged() {
+        let subscriber = CapturingSubscriber::default();
+        let events = subscriber.events.clone();
+        let _default = tracing::subscriber::set_default(subscriber);
+        let config = RoomConfig {
+            access_log: Some(tracing::Level::INFO),
+            ..test_config()
+        };
+        let filters = build_filters(ChatRooms::default(), config);
+
+        let reply = warp::test::request()
+            .path("/stats")
+            .remote_addr(([10, 0, 0, 7], 52000).into())
+            .reply(&filters)
+            .await;
+        assert_eq!(reply.status(), 200);
+
+        let events = events.lock().unwrap();
+        let (fields, _) = events
+            .iter()
+            .find(|(fields, _)| fields.contains(&field("remote", "10.0.0.7:52000")))
+            .expect("request wasn't access logged");
+        assert!(fields.contains(&field("message", "request")));
+        assert!(fields.contains(&field("method", "GET")));
+        assert!(fields.contains(&field("path", "\"/stats\"")));
+        assert!(fields.contains(&field("status", "200")));
+        assert!(fields.iter().any(|(name, _)| name == "latency_us"));
+    }
+
+    #[tokio::test]
+    async fn room_snapshot_has_every_section() {
+        let rooms = ChatRooms::default();
+        let filter = room_snapshot(
+            rooms.clone(),
+            Some("s3cret".to_owned()),
+            ConcurrencyLimit::default(),
+        );
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client = warp::test::ws()
+                .path("/chat/detail")
+                .handshake(ws_upgrade(rooms.clone(), test_config()))
+                .await
+                .unwrap();
+            clients.push(client);
+        }
+        clients[0].send_text("pin me").await;
+        clients[1].recv().await.unwrap();
+        let room = find_room("detail", &rooms).await.unwrap();
+        assert!(room.pin(1).await);
+
+        let reply = warp::test::request()
+            .path("/admin/chat/detail/snapshot")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), 200);
+        let snapshot: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(snapshot["room"], "detail");
+        assert!(snapshot["config"].is_object());
+        assert_eq!(snapshot["stats"]["users"], 2);
+        assert_eq!(snapshot["users"].as_array().unwrap().len(), 2);
+        assert_eq!(snapshot["users"][0]["messages"], 1);
+        assert_eq!(snapshot["pinned"]["body"], "pin me");
+        assert_eq!(snapshot["recent"][0]["seq"], 1);
+        assert_eq!(snapshot["recent"][0]["body"], "pin me");
+        assert_eq!(snapshot["degraded"], false);
+        assert_eq!(snapshot["draining"], false);
+
+        let unknown = warp::test::request()
+            .path("/admin/chat/nowhere/snapshot")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(unknown.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_listed() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            delivery_failure_log: Some(8),
+            ..test_config()
+        };
+        let _client = warp::test::ws()
+            .path("/chat/lossy")
+            .handshake(ws_upgrade(rooms.clone(), config))
+            .await
+            .unwrap();
+        let room = find_room("lossy", &rooms).await.unwrap();
+        // A user whose queue is already full, so nothing more can be delivered to them.
+        let uid = room.next_user_id();
+        let (tx, _stalled_rx) = tokio::sync::mpsc::channel(1);
+        tx.try_send(warp::ws::Message::text("unread")).unwrap();
+        let stalled = User {
+            identity: Identity::new(uid),
+            delivery_failures: room.delivery_failures.clone(),
+            ..User::new(tx, Protocol::LegacyText)
+        };
+        room.users.write().await.insert(uid, stalled);
+        room.post_message(0, "anyone?", None, None).await;
+
+        let filter = build_filters(
+            rooms.clone(),
+            RoomConfig {
+                admin_token: Some("s3cret".to_owned()),
+                ..test_config()
+            },
+        );
+        let unauthorized = warp::test::request()
+            .path("/chat/lossy/delivery-failures")
+            .reply(&filter)
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+
+        let reply = warp::test::request()
+            .path("/chat/lossy/delivery-failures")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), 200);
+        let failures: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        let failures = failures.as_array().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0]["uid"], uid);
+        assert_eq!(failures[0]["seq"], 1);
+        assert_eq!(failures[0]["reason"], "queue_full");
+        assert!(humantime::parse_rfc3339(failures[0]["at"].as_str().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_refused_without_a_configured_token() {
+        let rooms = ChatRooms::default();
+        let _client = warp::test::ws()
+            .path("/chat/unguarded")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        let filter = build_filters(rooms.clone(), test_config());
+
+        for (method, path) in [
+            ("DELETE", "/rooms/unguarded"),
+            ("POST", "/chat/unguarded/drain"),
+            ("PUT", "/chat/unguarded/config"),
+            ("POST", "/admin/replay"),
+            ("POST", "/admin/gc"),
+            ("GET", "/admin/connections"),
+            ("GET", "/admin/chat/unguarded/snapshot"),
+            ("GET", "/chat/unguarded/delivery-failures"),
+        ] {
+            let reply = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", "Bearer anything")
+                .reply(&filter)
+                .await;
+            assert_eq!(reply.status(), 403, "{} {}", method, path);
+        }
+        let room = find_room("unguarded", &rooms).await.unwrap();
+        assert!(!room.is_draining());
+    }
+
+    #[tokio::test]
+    async fn deleted_room_is_closed_and_dropped() {
+        let rooms = ChatRooms::default();
+        let mut client = warp::test::ws()
+            .path("/chat/doomed")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        let room = rooms.get("doomed").await.unwrap();
+
+        let filter = build_filters(
+            rooms.clone(),
+            RoomConfig {
+                admin_token: Some("s3cret".to_owned()),
+                ..test_config()
+            },
+        );
+        let unauthorized = warp::test::request()
+            .method("DELETE")
+            .path("/rooms/doomed")
+            .reply(&filter)
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+        let wrong_token = warp::test::request()
+            .method("DELETE")
+            .path("/rooms/doomed")
+            .header("authorization", "Bearer s3cres")
+            .reply(&filter)
+            .await;
+        assert_eq!(wrong_token.status(), 401);
+        assert!(rooms.get("doomed").await.is_some());
+
+        let reply = warp::test::request()
+            .method("DELETE")
+            .path("/rooms/doomed")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), 200);
+        assert_eq!(reply.body(), r#"{"disconnected":1}"#);
+        assert!(rooms.get("doomed").await.is_none());
+        assert_eq!(
+            client.recv().await.unwrap().to_str(),
+            Ok("*** this room has been closed")
+        );
+        assert!(client.recv_closed().await.is_ok());
+
+        // The connection lets go of the room without the client closing its side.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while room.upgrade().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("closed room was never dropped");
+
+        let gone = warp::test::request()
+            .method("DELETE")
+            .path("/rooms/doomed")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(gone.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn replay_only_reads_the_log_directory() {
+        let base = std::env::temp_dir().join(format!("replay_dir_{}", std::process::id()));
+        let log_dir = base.join("logs");
+        tokio::fs::create_dir_all(&log_dir).await.unwrap();
+        let line = crate::transcript::Record::new(1, "hi").to_line("replayed");
+        tokio::fs::write(log_dir.join("old.log"), format!("{}\n", line))
+            .await
+            .unwrap();
+        tokio::fs::write(base.join("secret.log"), format!("{}\n", line))
+            .await
+            .unwrap();
+
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            log_dir: Some(log_dir),
+            admin_token: Some("s3cret".to_owned()),
+            ..test_config()
+        };
+        let _client = warp::test::ws()
+            .path("/chat/replayed")
+            .handshake(ws_upgrade(rooms.clone(), config.clone()))
+            .await
+            .unwrap();
+        let filter = build_filters(rooms.clone(), config);
+        let replay = |path: String, token: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/replay")
+                .header("authorization", token)
+                .json(&serde_json::json!({ "path": path, "room": "replayed", "dry_run": true }))
+                .reply(&filter)
+        };
+
+        let unauthorized = replay("old.log".to_owned(), "Bearer guess").await;
+        assert_eq!(unauthorized.status(), 401);
+        let outside = base.join("secret.log").to_string_lossy().into_owned();
+        for path in [
+            "../secret.log".to_owned(),
+            outside,
+            "missing.log".to_owned(),
+        ] {
+            let refused = replay(path, "Bearer s3cret").await;
+            assert_eq!(refused.status(), 400);
+            assert_eq!(refused.body(), "no such transcript in the log directory");
+        }
+        let replayed = replay("old.log".to_owned(), "Bearer s3cret").await;
+        let _ = tokio::fs::remove_dir_all(&base).await;
+        assert_eq!(replayed.status(), 200);
+        assert_eq!(replayed.body(), r#"{"messages":1,"dry_run":true}"#);
+    }
+
+    #[tokio::test]
+    async fn admin_operations_past_the_limit_are_refused() {
+        let rooms = ChatRooms::default();
+        let room = Arc::new(ChatRoom::unlogged("admin_busy".to_owned(), Users::default()).await);
+        rooms
+            .insert("admin_busy".to_owned(), Arc::downgrade(&room))
+            .await;
+        let filter = admin_connections(
+            rooms.clone(),
+            Some("s3cret".to_owned()),
+            ConcurrencyLimit::new(Some(2)),
+        );
+
+        // Holding the room's users keeps every admitted request waiting on them.
+        let held = room.users.write().await;
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel();
+        for _ in 0..5 {
+            let (filter, done_tx) = (filter.clone(), done_tx.clone());
+            tokio::spawn(async move {
+                let reply = warp::test::request()
+                    .path("/admin/connections")
+                    .header("authorization", "Bearer s3cret")
+                    .reply(&filter)
+                    .await;
+                let _ = done_tx.send(reply.status().as_u16());
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut statuses = Vec::new();
+        while let Ok(status) = done_rx.try_recv() {
+            statuses.push(status);
+        }
+        assert_eq!(statuses, [429, 429, 429]);
+
+        drop(held);
+        for _ in 0..2 {
+            statuses.push(done_rx.recv().await.unwrap());
+        }
+        assert_eq!(statuses, [429, 429, 429, 200, 200]);
+
+        let reply = warp::test::request()
+            .path("/admin/connections")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn admin_connections_groups_by_room() {
+        let rooms = ChatRooms::default();
+        let filter = build_filters(
+            rooms.clone(),
+            RoomConfig {
+                admin_token: Some("s3cret".to_owned()),
+                ..test_config()
+            },
+        );
+        let mut clients = Vec::new();
+        for path in ["/chat/dash_b", "/chat/dash_a", "/chat/dash_b"] {
+            let client = warp::test::ws()
+                .path(path)
+                .handshake(ws_upgrade(rooms.clone(), test_config()))
+                .await
+                .unwrap();
+            clients.push(client);
+        }
+        clients[0].send_text("counted").await;
+        // The sender's own message isn't echoed, so wait for it to reach the room's other user.
+        clients[2].recv().await.unwrap();
+
+        let unauthorized = warp::test::request()
+            .path("/admin/connections")
+            .reply(&filter)
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+
+        let reply = warp::test::request()
+            .path("/admin/connections")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), 200);
+        let page: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(page["total"], 2);
+        let groups = page["items"].as_array().unwrap();
+        let summary: Vec<(&str, usize)> = groups
+            .iter()
+            .map(|group| {
+                let users = group["users"].as_array().unwrap();
+                (group["room"].as_str().unwrap(), users.len())
+            })
+            .collect();
+        assert_eq!(summary, [("dash_a", 1), ("dash_b", 2)]);
+
+        let dash_b = groups[1]["users"].as_array().unwrap();
+        assert!(dash_b[0]["addr"]
+            .as_str()
+            .unwrap()
+            .starts_with("127.0.0.1:"));
+        assert_eq!(dash_b[0]["messages"], 1);
+        assert_eq!(dash_b[1]["messages"], 0);
+
+        let second_page = warp::test::request()
+            .path("/admin/connections?offset=1&limit=1")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(second_page.body()).unwrap();
+        assert_eq!(page["items"][0]["room"], "dash_b");
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn route_names_must_be_reserved() {
+        let mut config = RoomConfig {
+            reserved_room_names: ["metrics", "rooms"].iter().map(|&n| n.to_owned()).collect(),
+            ..test_config()
+        };
+        assert_eq!(check_reserved_names(&config), Err(RouteCollision("stats")));
+
+        config.reserved_room_names = ROUTE_NAMES.iter().map(|&n| n.to_owned()).collect();
+        assert_eq!(check_reserved_names(&config), Ok(()));
+        let refused = warp::test::ws()
+            .path("/chat/stats")
+            .handshake(ws_upgrade(ChatRooms::default(), config))
+            .await;
+        assert!(refused.is_err());
+    }
+
+    #[tokio::test]
+    async fn room_names_are_validated() {
+        assert_eq!(validate_room_name("team-chat_2"), Ok(()));
+        assert_eq!(validate_room_name("café"), Ok(()));
+        assert_eq!(validate_room_name(""), Err(RoomNameError::Empty));
+        let long = "a".repeat(MAX_ROOM_NAME_CHARS + 1);
+        assert_eq!(validate_room_name(&long), Err(RoomNameError::TooLong));
+        assert_eq!(
+            validate_room_name("../etc"),
+            Err(RoomNameError::InvalidChar('.'))
+        );
+
+        let config = RoomConfig {
+            decode_room_names: true,
+            ..test_config()
+        };
+        let rooms = ChatRooms::default();
+        for path in ["/chat/..%2F..%2Fetc", &format!("/chat/{}", long)].iter() {
+            let refused = warp::test::ws()
+                .path(path)
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+                .await;
+            assert!(refused.is_err(), "{}", path);
+        }
+        assert!(rooms.is_empty().await);
+
+        let page = warp::test::request()
+            .path("/..%2Fetc")
+            .reply(&room(config.clone()))
+            .await;
+        assert_eq!(page.status(), 400);
+        assert_eq!(page.body(), "invalid room name: room name contains '.'");
+
+        let _client = warp::test::ws()
+            .path("/chat/team-chat_2")
+            .handshake(ws_upgrade(rooms.clone(), config))
+            .await
+            .unwrap();
+        assert!(rooms.get("team-chat_2").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn invalid_room_name_encoding() {
+        let config = RoomConfig {
+            decode_room_names: true,
+            ..test_config()
+        };
+        let filter = room(config.clone());
+        for path in ["/%FF%FE", "/room%2"] {
+            let reply = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(reply.status(), 400);
+            assert_eq!(reply.body(), "invalid room name encoding");
+        }
+        let decoded = warp::test::request()
+            .path("/caf%C3%A9")
+            .reply(&filter)
+            .await;
+        assert_eq!(decoded.status(), 200);
+
+        let rooms = ChatRooms::default();
+        let invalid = warp::test::ws()
+            .path("/chat/%FF")
+            .handshake(ws_upgrade(rooms.clone(), config.clone()))
+            .await;
+        assert!(invalid.is_err());
+        let _client = warp::test::ws()
+            .path("/chat/caf%C3%A9")
+            .handshake(ws_upgrade(rooms.clone(), config))
+            .await
+            .unwrap();
+        assert!(rooms.get("café").await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn admin_gc_endpoint() {
+        let rooms = ChatRooms::default();
+        let busy_room =
+            Arc::new(ChatRoom::unlogged("busy_room".to_owned(), Users::default()).await);
+        let (tx, _rx) = tokio::sync::mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        busy_room
+            .users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+        let closed_room =
+            Arc::new(ChatRoom::unlogged("closed_room".to_owned(), Users::default()).await);
+        // Emptied, and still held by something once its linger has run out.
+        let sink = MemorySink::new();
+        let idle_room = Arc::new(
+            ChatRoom::with_sink(
+                "idle_room".to_owned(),
+                Users::default(),
+                RoomConfig {
+                    linger: Some(Duration::from_secs(10)),
+                    ..test_config()
+                },
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        for room in [&busy_room, &closed_room, &idle_room] {
+            rooms.insert(room.name.clone(), Arc::downgrade(room)).await;
+        }
+        drop(closed_room);
+        idle_room.log_message("last words", 1);
+        crate::linger(idle_room.clone(), rooms.clone());
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(rooms.get("idle_room").await.is_some());
+
+        let unauthorized = warp::test::request()
+            .method("POST")
+            .path("/admin/gc")
+            .reply(&build_filters(
+                rooms.clone(),
+                RoomConfig {
+                    admin_token: Some("s3cret".to_owned()),
+                    ..test_config()
+                },
+            ))
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+        assert!(rooms.get("closed_room").await.is_some());
+
+        let filter = admin_gc(
+            rooms.clone(),
+            Some("s3cret".to_owned()),
+            ConcurrencyLimit::default(),
+        );
+        let reply = warp::test::request()
+            .method("POST")
+            .path("/admin/gc")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), 200);
+        assert_eq!(reply.body(), r#"{"reaped":2,"remaining":1}"#);
+
+        assert!(rooms.get("closed_room").await.is_none());
+        assert!(rooms.get("idle_room").await.is_none());
+        assert!(rooms.get("busy_room").await.is_some());
+        // The reaped room's transcript was written out and closed.
+        assert_eq!(sink.lines().len(), 1);
+        assert!(sink.lines()[0].ends_with("last words"));
+        idle_room.log_message("too late", 1);
+        idle_room.flush_log().await;
+        assert_eq!(sink.lines().len(), 1);
+
+        let wrong_method = warp::test::request().path("/admin/gc").reply(&filter).await;
+        assert_eq!(wrong_method.status(), 405);
+    }
+
+    #[tokio::test]
+    async fn export_endpoint() {
+        let rooms = ChatRooms::default();
+        let room = Arc::new(
+            ChatRoom::with_config("export_room".to_owned(), Users::default(), test_config()).await,
+        );
+        rooms
+            .insert("export_room".to_owned(), Arc::downgrade(&room))
+            .await;
+        room.log_message("hello", 7);
+        room.log_message("hi, \"there\"", 8);
+
+        let csv = warp::test::request()
+            .path("/chat/export_room/export?format=csv")
+            .reply(&export(rooms.clone()))
+            .await;
+        assert_eq!(csv.status(), 200);
+        assert_eq!(csv.headers()["content-type"], "text/csv");
+        let body = String::from_utf8(csv.body().to_vec()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "timestamp,user_id,message");
+        assert!(lines[1].ends_with(",7,hello"));
+        assert!(lines[2].ends_with(r#",8,"hi, ""there""""#));
+
+        let json = warp::test::request()
+            .path("/chat/export_room/export")
+            .reply(&export(rooms.clone()))
+            .await;
+        assert_eq!(json.status(), 200);
+        let records: serde_json::Value = serde_json::from_slice(json.body()).unwrap();
+        assert_eq!(records[0]["user_id"], 7);
+        assert_eq!(records[1]["message"], "hi, \"there\"");
+
+        let invalid = warp::test::request()
+            .path("/chat/export_room/export?format=xml")
+            .reply(&export(rooms.clone()))
+            .await;
+        assert_eq!(invalid.status(), 400);
+
+        let unknown_room = warp::test::request()
+            .path("/chat/missing_room/export")
+            .reply(&export(rooms.clone()))
+            .await;
+        assert_eq!(unknown_room.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn export_leaves_out_private_messages() {
+        let rooms = ChatRooms::default();
+        let room = Arc::new(
+            ChatRoom::with_config("whisper_room".to_owned(), Users::default(), test_config()).await,
+        );
+        rooms
+            .insert("whisper_room".to_owned(), Arc::downgrade(&room))
+            .await;
+        room.log_message("hello", 7);
+        room.log_record(Record {
+            to: Some(8),
+            ..Record::new(7, "just between us")
+        });
+
+        let json = warp::test::request()
+            .path("/chat/whisper_room/export")
+            .reply(&export(rooms.clone()))
+            .await;
+        assert_eq!(json.status(), 200);
+        let records: serde_json::Value = serde_json::from_slice(json.body()).unwrap();
+        assert_eq!(records.as_array().unwrap().len(), 1);
+        assert_eq!(records[0]["message"], "hello");
+    }
+
+    #[tokio::test]
+    async fn messages_endpoint_leaves_out_private_messages() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            message_store: Some(Arc::new(MemoryStore::new())),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "whisper_store".to_owned(),
+                Users::default(),
+                config,
+                Box::new(DiscardSink),
+            )
+            .await,
+        );
+        rooms
+            .insert("whisper_store".to_owned(), Arc::downgrade(&room))
+            .await;
+        room.log_message("hello", 7);
+        room.log_record(Record {
+            to: Some(8),
+            ..Record::new(7, "hello, just between us")
+        });
+
+        for path in [
+            "/chat/whisper_store/messages",
+            "/chat/whisper_store/messages?q=hello",
+        ] {
+            let response = warp::test::request()
+                .path(path)
+                .reply(&messages(rooms.clone()))
+                .await;
+            assert_eq!(response.status(), 200);
+            let records: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(records.as_array().unwrap().len(), 1, "{}", path);
+            assert_eq!(records[0]["message"], "hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_endpoint_reads_the_configured_store() {
+        let rooms = ChatRooms::default();
+        let store = Arc::new(MemoryStore::new());
+        let config = RoomConfig {
+            message_store: Some(store.clone()),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "store_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(DiscardSink),
+            )
+            .await,
+        );
+        rooms
+            .insert("store_room".to_owned(), Arc::downgrade(&room))
+            .await;
+        room.log_message("Hello", 1);
+        room.log_message("goodbye", 2);
+        room.log_message("hello again", 3);
+
+        let get = |path: &'static str| {
+            let rooms = rooms.clone();
+            async move {
+                let response = warp::test::request()
+                    .path(path)
+                    .reply(&messages(rooms))
+                    .await;
+                assert_eq!(response.status(), 200);
+                let records: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+                records
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|record| record["message"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            get("/chat/store_room/messages").await,
+            ["Hello", "goodbye", "hello again"]
+        );
+        assert_eq!(
+            get("/chat/store_room/messages?limit=1").await,
+            ["hello again"]
+        );
+        assert_eq!(
+            get("/chat/store_room/messages?q=HELLO").await,
+            ["Hello", "hello again"]
+        );
+        assert_eq!(store.query_recent("store_room", 10).await.unwrap().len(), 3);
+
+        let unknown_room = warp::test::request()
+            .path("/chat/missing_room/messages")
+            .reply(&messages(rooms.clone()))
+            .await;
+        assert_eq!(unknown_room.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn room_config_endpoint() {
+        let rooms = ChatRooms::default();
+        let mut sender = warp::test::ws()
+            .path("/chat/config_room")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        let mut receiver = warp::test::ws()
+            .path("/chat/config_room")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        let filter = build_filters(
+            rooms.clone(),
+            RoomConfig {
+                admin_token: Some("s3cret".to_owned()),
+                ..test_config()
+            },
+        );
+
+        // Reading the limits needs no token.
+        let current = warp::test::request()
+            .path("/chat/config_room/config")
+            .reply(&filter)
+            .await;
+        assert_eq!(current.status(), 200);
+        assert_eq!(
+            current.body(),
+            r#"{"max_message_bytes":null,"max_users":null,"message_rate":null}"#
+        );
+
+        let unauthorized = warp::test::request()
+            .method("PUT")
+            .path("/chat/config_room/config")
+            .json(&serde_json::json!({ "max_message_bytes": 5, "max_users": null }))
+            .reply(&filter)
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+
+        let updated = warp::test::request()
+            .method("PUT")
+            .path("/chat/config_room/config")
+            .header("authorization", "Bearer s3cret")
+            .json(&serde_json::json!({ "max_message_bytes": 5, "max_users": null }))
+            .reply(&filter)
+            .await;
+        assert_eq!(updated.status(), 200);
+
+        let invalid = warp::test::request()
+            .method("PUT")
+            .path("/chat/config_room/config")
+            .header("authorization", "Bearer s3cret")
+            .json(&serde_json::json!({ "max_message_bytes": 0, "max_users": null }))
+            .reply(&filter)
+            .await;
+        assert_eq!(invalid.status(), 400);
+
+        sender.send_text("too long").await;
+        let notice = sender.recv().await.unwrap();
+        assert_eq!(notice.to_str(), Ok("*** message too long (max 5 bytes)"));
+
+        // Only the short message makes it through to the other user.
+        sender.send_text("short").await;
+        let relayed = receiver.recv().await.unwrap();
+        assert!(relayed.to_str().unwrap().ends_with(": short"));
+
+        let zero_rate = warp::test::request()
+            .method("PUT")
+            .path("/chat/config_room/config")
+            .header("authorization", "Bearer s3cret")
+            .json(&serde_json::json!({
+                "message_rate": { "max_messages_per_window": 0, "window_secs": 60 }
+            }))
+            .reply(&filter)
+            .await;
+        assert_eq!(zero_rate.status(), 400);
+
+        let rate = warp::test::request()
+            .method("PUT")
+            .path("/chat/config_room/config")
+            .header("authorization", "Bearer s3cret")
+            .json(&serde_json::json!({
+                "message_rate": { "max_messages_per_window": 1, "window_secs": 60 }
+            }))
+            .reply(&filter)
+            .await;
+        assert_eq!(rate.status(), 200);
+
+        // The lowered rate applies to users already in the room.
+        sender.send_text("first").await;
+        let relayed = receiver.recv().await.unwrap();
+        assert!(relayed.to_str().unwrap().ends_with(": first"));
+        sender.send_text("second").await;
+        let notice = sender.recv().await.unwrap();
+        assert!(notice
+            .to_str()
+            .unwrap()
+            .starts_with("*** too many messages"));
+    }
+
+    #[tokio::test]
+    async fn messages_before_join() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            explicit_join: Some(PreJoinPolicy::Drop),
+            ..test_config()
+        };
+        let connect = || {
+            warp::test::ws()
+                .path("/chat/join_room")
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let mut member = connect().await.unwrap();
+        let mut newcomer = connect().await.unwrap();
+
+        member.send_text("/join").await;
+        assert_eq!(
+            member.recv().await.unwrap().to_str(),
+            Ok("*** joined join_room")
+        );
+
+        newcomer.send_text("sneaky").await;
+        assert_eq!(
+            newcomer.recv().await.unwrap().to_str(),
+            Ok("*** not joined yet, send /join first")
+        );
+
+        newcomer.send_text("/join").await;
+        assert_eq!(
+            newcomer.recv().await.unwrap().to_str(),
+            Ok("*** joined join_room")
+        );
+        newcomer.send_text("hello").await;
+        let relayed = member.recv().await.unwrap();
+        assert!(relayed.to_str().unwrap().ends_with(": hello"));
+    }
+
+    #[tokio::test]
+    async fn messages_buffered_until_join() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            explicit_join: Some(PreJoinPolicy::Buffer),
+            ..test_config()
+        };
+        let connect = || {
+            warp::test::ws()
+                .path("/chat/buffer_room")
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let mut member = connect().await.unwrap();
+        let mut newcomer = connect().await.unwrap();
+        member.send_text("/join").await;
+        member.recv().await.unwrap();
+
+        newcomer.send_text("early").await;
+        newcomer.send_text("/join").await;
+        assert!(member
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(": early"));
+    }
+
+    #[tokio::test]
+    async fn welcome_arrives_before_room_traffic() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            motd: Some("be nice".to_owned()),
+            ..test_config()
+        };
+        let connect = || {
+            warp::test::ws()
+                .path("/chat/welcome_room")
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let mut member = connect().await.unwrap();
+        member.recv().await.unwrap();
+
+        // Both sides talk the moment the newcomer is connected.
+        let mut newcomer = connect().await.unwrap();
+        newcomer.send_text("hi all").await;
+        member.send_text("welcome!").await;
+
+        assert_eq!(newcomer.recv().await.unwrap().to_str(), Ok("*** be nice"));
+        assert!(newcomer
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(": welcome!"));
+        assert!(member
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(": hi all"));
+    }
+
+    #[tokio::test]
+    async fn envelope_names_room() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            room_in_envelope: true,
+            ..test_config()
+        };
+        let connect = |protocol: &'static str| {
+            warp::test::ws()
+                .path("/chat/envelope_room")
+                .header("sec-websocket-protocol", protocol)
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let mut json = connect("chat.v1.json").await.unwrap();
+        let mut legacy = connect("none").await.unwrap();
+
+        legacy.send_text("hi").await;
+        let received = json.recv().await.unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(received.to_str().unwrap()).unwrap();
+        assert_eq!(envelope["room"], "envelope_room");
+        assert_eq!(envelope["body"], "hi");
+
+        json.send_text(r#"{"type":"message","body":"hello"}"#).await;
+        assert!(legacy
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(">: hello"));
+    }
+
+    #[tokio::test]
+    async fn json_subprotocol_sends_structured_messages() {
+        let rooms = ChatRooms::default();
+        let connect = |protocol: &'static str| {
+            warp::test::ws()
+                .path("/chat/json_room")
+                .header("sec-websocket-protocol", protocol)
+                .handshake(ws_upgrade(rooms.clone(), test_config()))
+        };
+        let mut json = connect("chat.v1.json").await.unwrap();
+        let mut legacy = connect("none").await.unwrap();
+
+        legacy.send_text("hi").await;
+        let received = json.recv().await.unwrap();
+        let frame: serde_json::Value = serde_json::from_str(received.to_str().unwrap()).unwrap();
+        assert_eq!(frame["type"], "message");
+        assert_eq!(frame["from"], 2);
+        assert_eq!(frame["body"], "hi");
+        assert!(humantime::parse_rfc3339(frame["ts"].as_str().unwrap()).is_ok());
+
+        // Whatever the client says about itself is ignored.
+        json.send_text(r#"{"type":"message","from":7,"body":"hello","ts":"now"}"#)
+            .await;
+        assert_eq!(legacy.recv().await.unwrap().to_str(), Ok("<User#1>: hello"));
+
+        // Frames that aren't JSON messages are refused, and the client told why.
+        for malformed in [
+            "hello",
+            r#"{"type":"message"}"#,
+            r#"{"type":"shout","body":"hi"}"#,
+        ] {
+            json.send_text(malformed).await;
+            let reply = json.recv().await.unwrap();
+            let frame: serde_json::Value = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+            assert_eq!(frame["type"], "notice");
+            assert!(frame["body"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid frame: "));
+        }
+        json.send_text(r#"{"type":"message","body":"still here"}"#)
+            .await;
+        assert_eq!(
+            legacy.recv().await.unwrap().to_str(),
+            Ok("<User#1>: still here")
+        );
+    }
+
+    #[tokio::test]
+    async fn multiplexed_rooms_are_isolated() {
+        let rooms = ChatRooms::default();
+        let connect = |room: &str, protocol: &'static str| {
+            warp::test::ws()
+                .path(&format!("/chat/{}", room))
+                .header("sec-websocket-protocol", protocol)
+                .handshake(ws_upgrade(rooms.clone(), test_config()))
+        };
+        let recv_json = |msg: warp::ws::Message| -> serde_json::Value {
+            serde_json::from_str(msg.to_str().unwrap()).unwrap()
+        };
+
+        let mut mux = connect("mux_a", "chat.v1.mux").await.unwrap();
+        assert_eq!(recv_json(mux.recv().await.unwrap())["body"], "joined mux_a");
+        let mut in_a = connect("mux_a", "none").await.unwrap();
+        let mut in_b = connect("mux_b", "none").await.unwrap();
+
+        mux.send_text(r#"{"op":"join","room":"mux_b"}"#).await;
+        let joined = recv_json(mux.recv().await.unwrap());
+        assert_eq!(joined["room"], "mux_b");
+        assert_eq!(joined["body"], "joined mux_b");
+
+        in_b.send_text("from b").await;
+        let received = recv_json(mux.recv().await.unwrap());
+        assert_eq!(received["room"], "mux_b");
+        assert_eq!(received["body"], "from b");
+
+        mux.send_text(r#"{"op":"send","room":"mux_a","body":"to a"}"#)
+            .await;
+        mux.send_text(r#"{"op":"send","room":"mux_b","body":"to b"}"#)
+            .await;
+        assert!(in_a
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(">: to a"));
+        // mux_b's user never sees what was sent to mux_a.
+        assert!(in_b
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(">: to b"));
+
+        mux.send_text(r#"{"op":"leave","room":"mux_b"}"#).await;
+        assert_eq!(recv_json(mux.recv().await.unwrap())["body"], "left mux_b");
+        in_b.send_text("anyone?").await;
+        in_a.send_text("still here").await;
+        let received = recv_json(mux.recv().await.unwrap());
+        assert_eq!(received["room"], "mux_a");
+        assert_eq!(received["body"], "still here");
+    }
+
+    #[tokio::test]
+    async fn closed_rooms_are_released_by_multiplexed_members() {
+        let rooms = ChatRooms::default();
+        let mut mux = warp::test::ws()
+            .path("/chat/mux_closed")
+            .header("sec-websocket-protocol", "chat.v1.mux")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        mux.recv().await.unwrap();
+        let room = rooms.get("mux_closed").await.unwrap();
+
+        assert_eq!(
+            close_room(room.upgrade().unwrap(), rooms.clone()).await,
+            Some(1)
+        );
+        // The mux connection gives up the room without sending another frame.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while room.upgrade().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("closed room was never dropped");
+    }
+
+    #[tokio::test]
+    async fn drain_endpoint() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            drain_grace: Some(Duration::from_secs(60)),
+            ..test_config()
+        };
+        let connect = || {
+            warp::test::ws()
+                .path("/chat/drain_room")
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let mut member = connect().await.unwrap();
+        let unauthorized = warp::test::request()
+            .method("POST")
+            .path("/chat/drain_room/drain")
+            .reply(&build_filters(
+                rooms.clone(),
+                RoomConfig {
+                    admin_token: Some("s3cret".to_owned()),
+                    ..test_config()
+                },
+            ))
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+        assert!(!rooms
+            .get("drain_room")
+            .await
+            .unwrap()
+            .upgrade()
+            .unwrap()
+            .is_draining());
+        let filter = room_drain(rooms.clone(), Some("s3cret".to_owned()));
+        let drain = || {
+            warp::test::request()
+                .method("POST")
+                .path("/chat/drain_room/drain")
+                .header("authorization", "Bearer s3cret")
+                .reply(&filter)
+        };
+
+        let reply = drain().await;
+        assert_eq!(reply.status(), 202);
+        assert_eq!(reply.body(), r#"{"grace_secs":60}"#);
+        assert_eq!(
+            member.recv().await.unwrap().to_str(),
+            Ok("*** room is closing for maintenance in 1m")
+        );
+        assert_eq!(drain().await.status(), 409);
+        assert!(connect().await.is_err());
+
+        let unknown = warp::test::request()
+            .method("POST")
+            .path("/chat/no_such_room/drain")
+            .header("authorization", "Bearer s3cret")
+            .reply(&filter)
+            .await;
+        assert_eq!(unknown.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn throttled_upgrade_is_told_to_retry() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            room_creation_rate: Some(Arc::new(TokenBucket::new(0.1, 1))),
+            busy_retry_after: Some(Duration::from_secs(30)),
+            ..test_config()
+        };
+        let connect = |path| {
+            warp::test::ws()
+                .path(path)
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let _first = connect("/chat/busy_a").await.unwrap();
+
+        // The handshake completes, so the client can read why it is being turned away.
+        let mut refused = connect("/chat/busy_b").await.unwrap();
+        assert_eq!(
+            refused.recv().await.unwrap().to_str(),
+            Ok("*** server busy, retry in 10s")
+        );
+        assert!(refused.recv_closed().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn frame_flood_disconnects() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            max_frames_per_sec: Some(5),
+            ..test_config()
+        };
+        let mut flooder = warp::test::ws()
+            .path("/chat/flood_room")
+            .handshake(ws_upgrade(rooms.clone(), config))
+            .await
+            .unwrap();
+
+        for _ in 0..10 {
+            flooder.send(warp::ws::Message::ping(Vec::new())).await;
+        }
+        // Pongs for the allowed pings may arrive first, then the connection is closed.
+        loop {
+            match flooder.recv().await {
+                Ok(msg) if msg.is_pong() => continue,
+                Ok(msg) => panic!("unexpected frame: {:?}", msg),
+                Err(_) => break,
+            }
+        }
+
+        // The flooder was the only user, so the room goes away once it has left.
+        for _ in 0..100 {
+            if rooms.live_rooms().await.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("flooder was never removed from the room");
+    }
+
+    #[tokio::test]
+    async fn disallowed_message_kinds() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            message_policy: MessagePolicy::allowing(&[MessageKind::Text, MessageKind::Typing]),
+            ..test_config()
+        };
+        let connect = || {
+            warp::test::ws()
+                .path("/chat/policy_room")
+                .handshake(ws_upgrade(rooms.clone(), config.clone()))
+        };
+        let mut sender = connect().await.unwrap();
+        let mut receiver = connect().await.unwrap();
+
+        sender.send_text("/react 1 +1").await;
+        assert_eq!(
+            sender.recv().await.unwrap().to_str(),
+            Ok("*** react messages are not allowed in this room")
+        );
+
+        sender.send_text("/typing").await;
+        let typing = receiver.recv().await.unwrap();
+        assert!(typing.to_str().unwrap().ends_with(" is typing..."));
+
+        sender.send_text("plain text").await;
+        let text = receiver.recv().await.unwrap();
+        assert!(text.to_str().unwrap().ends_with(": plain text"));
+    }
+
+    #[tokio::test]
+    async fn binary_frames_are_relayed_when_allowed() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            message_policy: MessagePolicy::allowing(&[MessageKind::Text, MessageKind::Binary]),
+            ..test_config()
+        };
+        let connect = |config: RoomConfig| {
+            warp::test::ws()
+                .path("/chat/binary_room")
+                .handshake(ws_upgrade(rooms.clone(), config))
+        };
+        let mut sender = connect(config.clone()).await.unwrap();
+        let mut receiver = connect(config).await.unwrap();
+
+        sender
+            .send(warp::ws::Message::binary(vec![0, 159, 146, 150]))
+            .await;
+        let frame = receiver.recv().await.unwrap();
+        assert!(frame.is_binary());
+        assert_eq!(frame.as_bytes(), [0, 159, 146, 150]);
+
+        let mut refused = warp::test::ws()
+            .path("/chat/text_room")
+            .handshake(ws_upgrade(rooms.clone(), test_config()))
+            .await
+            .unwrap();
+        refused.send(warp::ws::Message::binary(vec![1, 2, 3])).await;
+        assert_eq!(
+            refused.recv().await.unwrap().to_str(),
+            Ok("*** binary messages are not allowed in this room")
+        );
     }
 }
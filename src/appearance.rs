@@ -0,0 +1,55 @@
+use serde::Serialize;
+
+/// Colors handed out to users, chosen to be distinguishable on light and dark backgrounds.
+const PALETTE: [&str; 12] = [
+    "#e6194b", "#3cb44b", "#ffe119", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6",
+    "#bfef45", "#469990", "#9a6324", "#800000",
+];
+
+/// How clients should render a participant, derived deterministically from their name so every
+/// client (and every reconnect) shows them the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Appearance {
+    pub color: &'static str,
+    /// Seed for clients that generate avatars, e.g. identicons.
+    pub avatar_seed: u32,
+}
+
+impl Appearance {
+    pub fn for_name(name: &str) -> Appearance {
+        let hash = fnv1a(name.as_bytes());
+        Appearance {
+            color: PALETTE[(hash % PALETTE.len() as u64) as usize],
+            avatar_seed: (hash >> 32) as u32,
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is fixed, so colors stay stable across
+/// restarts and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::appearance::Appearance;
+
+    #[test]
+    fn appearance_is_stable_per_name() {
+        assert_eq!(Appearance::for_name("alice"), Appearance::for_name("alice"));
+
+        let colors: HashSet<&str> = (1..=20)
+            .map(|id| Appearance::for_name(&format!("User#{}", id)).color)
+            .collect();
+        assert!(colors.len() > 1);
+        assert_ne!(
+            Appearance::for_name("alice").avatar_seed,
+            Appearance::for_name("bob").avatar_seed
+        );
+    }
+}
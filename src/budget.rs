@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use crate::RecentMessage;
+
+/// A room's buffer of recent messages, oldest first.
+pub(crate) type MessageBuffer = Mutex<VecDeque<RecentMessage>>;
+
+/// A cap on the number of messages queued for delivery across every connection sharing it.
+///
+/// Each enqueue takes a slot which the connection's forwarding task gives back once the message
+/// has been written to its websocket. When no slot is free the message is shed rather than
+/// queued, so aggregate memory stays bounded however many clients fall behind.
+#[derive(Debug)]
+pub struct SendBudget {
+    limit: usize,
+    queued: AtomicUsize,
+    shed: AtomicUsize,
+}
+
+impl SendBudget {
+    pub fn new(limit: usize) -> SendBudget {
+        SendBudget {
+            limit,
+            queued: AtomicUsize::new(0),
+            shed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a slot for one message, or counts it as shed if the budget is exhausted.
+    pub fn try_acquire(&self) -> bool {
+        let acquired = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                if queued < self.limit {
+                    Some(queued + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !acquired {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        acquired
+    }
+
+    /// Takes a slot for one message even if none is free, for messages that must never be shed.
+    /// The count can go past the limit this way, so later messages are shed until it is back
+    /// under.
+    pub fn acquire_past_limit(&self) {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Gives back the slot taken for a message that has left its queue.
+    pub fn release(&self) {
+        let _ = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                queued.checked_sub(1)
+            });
+    }
+
+    /// Messages currently queued against this budget.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    /// Messages dropped so far because the budget was exhausted.
+    pub fn shed(&self) -> usize {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+/// Approximate memory held by `message` while it is buffered.
+pub fn buffered_bytes(message: &RecentMessage) -> usize {
+    mem::size_of::<RecentMessage>() + message.body.len()
+}
+
+/// A cap on the approximate bytes held in the recent message buffers of every room sharing it.
+///
+/// Buffering a message that takes usage past the cap evicts the oldest buffered messages, from
+/// whichever rooms hold them, until usage is back within it. Only buffers are affected: evicted
+/// messages stay in their transcripts.
+#[derive(Debug)]
+pub struct BufferBudget {
+    limit: usize,
+    used: AtomicUsize,
+    evicted: AtomicUsize,
+    buffers: Mutex<Vec<Weak<MessageBuffer>>>,
+}
+
+impl BufferBudget {
+    pub fn new(limit_bytes: usize) -> BufferBudget {
+        BufferBudget {
+            limit: limit_bytes,
+            used: AtomicUsize::new(0),
+            evicted: AtomicUsize::new(0),
+            buffers: Mutex::default(),
+        }
+    }
+
+    /// Bytes currently buffered against this budget.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// Messages evicted so far to stay within the budget.
+    pub fn evicted(&self) -> usize {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Makes `buffer` a candidate for eviction, forgetting buffers of rooms that have closed.
+    pub(crate) fn register(&self, buffer: &Arc<MessageBuffer>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.retain(|buffer| buffer.strong_count() > 0);
+        buffers.push(Arc::downgrade(buffer));
+    }
+
+    /// Counts `bytes` newly buffered, then evicts until usage is back within the limit. The
+    /// caller must not hold any buffer's lock.
+    pub(crate) fn charge(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::AcqRel);
+        while self.used() > self.limit && self.evict_oldest() {}
+    }
+
+    /// Gives back `bytes` of messages that have left their buffer.
+    pub(crate) fn release(&self, bytes: usize) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+
+    /// Drops the oldest message buffered in any room, returning `false` if every buffer is empty.
+    fn evict_oldest(&self) -> bool {
+        let buffers: Vec<Arc<MessageBuffer>> = self
+            .buffers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let oldest = buffers
+            .iter()
+            .filter_map(|buffer| {
+                let sent_at = buffer.lock().unwrap().front()?.sent_at;
+                Some((sent_at, buffer))
+            })
+            .min_by_key(|(sent_at, _)| *sent_at);
+        let buffer = match oldest {
+            Some((_, buffer)) => buffer,
+            None => return false,
+        };
+        // Another message may have been evicted from it meanwhile; its new front is then the
+        // oldest left there, which is close enough.
+        let evicted = buffer.lock().unwrap().pop_front();
+        if let Some(message) = evicted {
+            self.release(buffered_bytes(&message));
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        budget::{buffered_bytes, BufferBudget, SendBudget},
+        RecentMessage,
+    };
+
+    #[test]
+    fn acquire_up_to_limit() {
+        let budget = SendBudget::new(2);
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+        assert_eq!(budget.queued(), 2);
+        assert_eq!(budget.shed(), 1);
+
+        budget.release();
+        assert!(budget.try_acquire());
+
+        budget.acquire_past_limit();
+        assert_eq!(budget.queued(), 3);
+        budget.release();
+        assert!(!budget.try_acquire());
+    }
+
+    #[test]
+    fn oldest_buffered_messages_are_evicted_across_rooms() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let message = |seq: u64| RecentMessage {
+            seq,
+            from: 1,
+            body: "x".repeat(100),
+            sent_at: start + Duration::from_secs(seq),
+        };
+        let size = buffered_bytes(&message(0));
+        let budget = BufferBudget::new(size * 3);
+        let rooms = [
+            Arc::new(Mutex::new(VecDeque::new())),
+            Arc::new(Mutex::new(VecDeque::new())),
+        ];
+        for room in &rooms {
+            budget.register(room);
+        }
+
+        // Interleave the rooms so the oldest messages are split between them.
+        for seq in 1..=5 {
+            let room = &rooms[seq as usize % 2];
+            room.lock().unwrap().push_back(message(seq));
+            budget.charge(size);
+        }
+        assert!(budget.used() <= size * 3);
+        assert_eq!(budget.evicted(), 2);
+        let seqs = |room: &Arc<Mutex<VecDeque<RecentMessage>>>| -> Vec<u64> {
+            room.lock()
+                .unwrap()
+                .iter()
+                .map(|message| message.seq)
+                .collect()
+        };
+        assert_eq!(seqs(&rooms[0]), [4]);
+        assert_eq!(seqs(&rooms[1]), [3, 5]);
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::transcript::{Record, SERVER_USER_ID};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How sensitive a room's conversation is. Recorded at the top of its transcript and used to pick
+/// how long the transcript is kept and whether it must be encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Classification {
+    Public,
+    Internal,
+    Confidential,
+}
+
+/// What a classification requires of a room's transcripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// How long transcripts are kept, forever when `None`.
+    pub max_age: Option<Duration>,
+    /// Whether transcripts must be encrypted at rest.
+    pub encrypt: bool,
+}
+
+impl Classification {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Classification::Public => "public",
+            Classification::Internal => "internal",
+            Classification::Confidential => "confidential",
+        }
+    }
+
+    pub fn retention(&self) -> RetentionPolicy {
+        match self {
+            Classification::Public => RetentionPolicy {
+                max_age: None,
+                encrypt: false,
+            },
+            Classification::Internal => RetentionPolicy {
+                max_age: Some(365 * DAY),
+                encrypt: false,
+            },
+            Classification::Confidential => RetentionPolicy {
+                max_age: Some(90 * DAY),
+                encrypt: true,
+            },
+        }
+    }
+
+    /// The record a classified room's transcript starts with, e.g. `*** classification:
+    /// internal ***` from the server.
+    pub fn header(&self) -> Record {
+        Record::new(
+            SERVER_USER_ID,
+            &format!("*** classification: {} ***", self.label()),
+        )
+    }
+}
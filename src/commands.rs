@@ -0,0 +1,216 @@
+/// A line of text from a user, read as the command it names.
+///
+/// Only a known `/word` followed by whitespace or the end of the line is a command; anything
+/// else, including unknown commands, is `Say` with the text exactly as it was sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Say(String),
+    /// `/nick <name>`, which only picks a nickname as the first frame of a connection.
+    Nick(String),
+    /// `/me <action>`.
+    Emote(String),
+    /// `/msg <nick_or_id> <message>`.
+    DirectMessage {
+        target: String,
+        body: String,
+    },
+    /// `/topic <topic> <message>`.
+    Topic {
+        topic: String,
+        body: String,
+    },
+    /// `/join <topic>...`, which only means anything before a user has joined.
+    Join(Vec<String>),
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    Pin(u64),
+    Unpin,
+    Kick(usize),
+    Edit {
+        seq: u64,
+        body: String,
+    },
+    Delete(u64),
+    React {
+        seq: u64,
+        emoji: String,
+    },
+    Typing,
+    /// `/who`, listing everyone in the room.
+    Who,
+    /// A known command missing its arguments or given ones it can't use, with its usage line.
+    Malformed(&'static str),
+}
+
+/// Reads `input` as a command.
+pub fn parse(input: &str) -> Command {
+    let trimmed = input.trim_start();
+    let name = match trimmed.split_whitespace().next() {
+        Some(name) if name.starts_with('/') => name,
+        _ => return Command::Say(input.to_owned()),
+    };
+    let rest = trimmed[name.len()..].trim_start();
+    let mut args = rest.split_whitespace();
+    match name {
+        "/nick" => Command::Nick(args.collect::<Vec<_>>().join(" ")),
+        "/me" if rest.trim().is_empty() => Command::Malformed("usage: /me <action>"),
+        "/me" => Command::Emote(rest.trim_end().to_owned()),
+        "/msg" => match rest.split_once(' ') {
+            Some((target, body)) if !body.trim().is_empty() => Command::DirectMessage {
+                target: target.to_owned(),
+                body: body.to_owned(),
+            },
+            _ => Command::Malformed("usage: /msg <nick_or_id> <message>"),
+        },
+        "/topic" => match rest.split_once(' ') {
+            Some((topic, body)) if !body.trim().is_empty() => Command::Topic {
+                topic: topic.to_owned(),
+                body: body.to_owned(),
+            },
+            _ => Command::Malformed("usage: /topic <topic> <message>"),
+        },
+        "/join" => Command::Join(owned(args)),
+        "/subscribe" => Command::Subscribe(owned(args)),
+        "/unsubscribe" => Command::Unsubscribe(owned(args)),
+        "/pin" => match args.next().map(str::parse) {
+            Some(Ok(seq)) => Command::Pin(seq),
+            _ => Command::Malformed("usage: /pin <seq> or /unpin"),
+        },
+        "/unpin" if rest.is_empty() => Command::Unpin,
+        "/unpin" => Command::Malformed("usage: /pin <seq> or /unpin"),
+        "/kick" => match args.next().map(str::parse) {
+            Some(Ok(id)) => Command::Kick(id),
+            _ => Command::Malformed("usage: /kick <id>"),
+        },
+        "/edit" => match rest.split_once(' ') {
+            Some((seq, body)) if !body.trim().is_empty() => match seq.parse() {
+                Ok(seq) => Command::Edit {
+                    seq,
+                    body: body.to_owned(),
+                },
+                Err(_) => Command::Malformed("usage: /edit <seq> <message>"),
+            },
+            _ => Command::Malformed("usage: /edit <seq> <message>"),
+        },
+        "/delete" => match args.next().map(str::parse) {
+            Some(Ok(seq)) => Command::Delete(seq),
+            _ => Command::Malformed("usage: /delete <seq>"),
+        },
+        "/react" => match (args.next().map(str::parse), args.next(), args.next()) {
+            (Some(Ok(seq)), Some(emoji), None) => Command::React {
+                seq,
+                emoji: emoji.to_owned(),
+            },
+            _ => Command::Malformed("usage: /react <seq> <emoji>"),
+        },
+        "/typing" => Command::Typing,
+        "/who" if rest.is_empty() => Command::Who,
+        "/who" => Command::Malformed("usage: /who"),
+        _ => Command::Say(input.to_owned()),
+    }
+}
+
+fn owned<'a>(args: impl Iterator<Item = &'a str>) -> Vec<String> {
+    args.map(str::to_owned).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{parse, Command};
+
+    #[test]
+    fn known_commands_parse_their_arguments() {
+        assert_eq!(
+            parse("/nick  ada  lovelace"),
+            Command::Nick("ada lovelace".to_owned())
+        );
+        assert_eq!(parse("/me waves"), Command::Emote("waves".to_owned()));
+        assert_eq!(
+            parse("/msg bob just between us"),
+            Command::DirectMessage {
+                target: "bob".to_owned(),
+                body: "just between us".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse("/topic rust hello"),
+            Command::Topic {
+                topic: "rust".to_owned(),
+                body: "hello".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse("/subscribe a b"),
+            Command::Subscribe(vec!["a".to_owned(), "b".to_owned()])
+        );
+        assert_eq!(parse("/pin 3"), Command::Pin(3));
+        assert_eq!(parse("/unpin"), Command::Unpin);
+        assert_eq!(parse("/kick 2"), Command::Kick(2));
+        assert_eq!(
+            parse("/edit 4 fixed it"),
+            Command::Edit {
+                seq: 4,
+                body: "fixed it".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse("/react 1 👍"),
+            Command::React {
+                seq: 1,
+                emoji: "👍".to_owned(),
+            }
+        );
+        assert_eq!(parse("/typing"), Command::Typing);
+        assert_eq!(parse("/who"), Command::Who);
+    }
+
+    #[test]
+    fn leading_whitespace_is_ignored() {
+        assert_eq!(parse("  /pin 7"), Command::Pin(7));
+        assert_eq!(parse("\t/delete 5"), Command::Delete(5));
+        assert_eq!(parse("  hello"), Command::Say("  hello".to_owned()));
+    }
+
+    #[test]
+    fn malformed_commands_carry_their_usage() {
+        assert_eq!(parse("/me"), Command::Malformed("usage: /me <action>"));
+        assert_eq!(
+            parse("/msg bob"),
+            Command::Malformed("usage: /msg <nick_or_id> <message>")
+        );
+        assert_eq!(
+            parse("/pin x"),
+            Command::Malformed("usage: /pin <seq> or /unpin")
+        );
+        assert_eq!(
+            parse("/unpin 3"),
+            Command::Malformed("usage: /pin <seq> or /unpin")
+        );
+        assert_eq!(parse("/kick"), Command::Malformed("usage: /kick <id>"));
+        assert_eq!(parse("/who is here"), Command::Malformed("usage: /who"));
+        assert_eq!(
+            parse("/edit x text"),
+            Command::Malformed("usage: /edit <seq> <message>")
+        );
+        assert_eq!(
+            parse("/react 1 a b"),
+            Command::Malformed("usage: /react <seq> <emoji>")
+        );
+    }
+
+    #[test]
+    fn text_that_only_looks_like_a_command_is_said() {
+        for text in [
+            "/msgbob hi",
+            "/nickname",
+            "/shrug",
+            "/",
+            "// not a command",
+            "hello /msg bob",
+        ]
+        .iter()
+        {
+            assert_eq!(parse(text), Command::Say((*text).to_owned()), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    budget::{BufferBudget, SendBudget},
+    classification::Classification,
+    decoration::Decoration,
+    history::HistoryConfig,
+    membership::SnapshotConfig,
+    protocol::MessageKind,
+    ratelimit::TokenBucket,
+    shutdown::ShutdownFlag,
+    store::MessageStore,
+    syslog::SyslogConfig,
+    transcript::TranscriptFormat,
+    transform::Pipeline,
+};
+
+/// The certificate and private key, both PEM files, to serve HTTPS and WSS with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// Settings for serving TLS when both paths are given, or `None` to serve plaintext when
+    /// neither is. Giving only one is a mistake rather than a request for plaintext.
+    pub fn from_paths(
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+    ) -> Result<Option<TlsConfig>, IncompleteTls> {
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Ok(Some(TlsConfig {
+                cert_path,
+                key_path,
+            })),
+            (None, None) => Ok(None),
+            _ => Err(IncompleteTls),
+        }
+    }
+}
+
+/// Returned when only one of a TLS certificate and key is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncompleteTls;
+
+impl fmt::Display for IncompleteTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TLS needs both a certificate and a private key")
+    }
+}
+
+impl Error for IncompleteTls {}
+
+/// Where the server listens when nothing else is configured.
+pub const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3030);
+
+/// The address the server listens on, from an IP address and a port that each fall back to
+/// `DEFAULT_BIND_ADDR`'s when unset.
+pub fn bind_addr(ip: Option<&str>, port: Option<&str>) -> Result<SocketAddr, InvalidBindAddr> {
+    let ip = match ip {
+        Some(ip) => ip.parse().map_err(|_| InvalidBindAddr::Ip(ip.to_owned()))?,
+        None => DEFAULT_BIND_ADDR.ip(),
+    };
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| InvalidBindAddr::Port(port.to_owned()))?,
+        None => DEFAULT_BIND_ADDR.port(),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// A bind address part that doesn't parse, as it was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidBindAddr {
+    Ip(String),
+    Port(String),
+}
+
+impl fmt::Display for InvalidBindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidBindAddr::Ip(ip) => write!(f, "invalid IP address to listen on: {:?}", ip),
+            InvalidBindAddr::Port(port) => write!(f, "invalid port to listen on: {:?}", port),
+        }
+    }
+}
+
+impl Error for InvalidBindAddr {}
+
+/// Limits on a room's traffic, which can be changed while the room is running.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomLimits {
+    /// Largest accepted message in bytes, unlimited when `None`.
+    pub max_message_bytes: Option<usize>,
+    /// Most users allowed in the room at once, unlimited when `None`.
+    pub max_users: Option<usize>,
+    /// Most chat messages each connection may send in a window, unlimited when `None`. Messages
+    /// over it are dropped with a notice to the sender alone. Connections already in the room
+    /// switch to a changed rate with a full allowance.
+    #[serde(default)]
+    pub message_rate: Option<MessageRate>,
+}
+
+impl RoomLimits {
+    pub fn validate(&self) -> Result<(), InvalidLimits> {
+        if self.max_message_bytes == Some(0) {
+            return Err(InvalidLimits("max_message_bytes must be at least 1"));
+        }
+        if self.max_users == Some(0) {
+            return Err(InvalidLimits("max_users must be at least 1"));
+        }
+        if let Some(rate) = &self.message_rate {
+            if rate.max_messages_per_window == 0 {
+                return Err(InvalidLimits("max_messages_per_window must be at least 1"));
+            }
+            if rate.window.is_zero() {
+                return Err(InvalidLimits(
+                    "message rate window must be longer than zero",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returned when a set of `RoomLimits` would make a room unusable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLimits(pub &'static str);
+
+impl fmt::Display for InvalidLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid room limits: {}", self.0)
+    }
+}
+
+impl Error for InvalidLimits {}
+
+/// What happens to chat messages a client sends before joining a room that requires an explicit
+/// `/join`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreJoinPolicy {
+    /// Reject them, telling the client it has to join first.
+    Drop,
+    /// Hold a bounded number of them and send them once the client joins.
+    Buffer,
+}
+
+/// What happens when a user connects to a room their account is already connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Let both connections stay.
+    #[default]
+    Allow,
+    /// Refuse the new connection.
+    Reject,
+    /// Disconnect the earlier connection in favor of the new one.
+    Replace,
+}
+
+/// How long a room's logging task lets written transcript lines sit unflushed before flushing them
+/// on its own, bounding what a crash can lose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushInterval {
+    Every(Duration),
+    /// Only flush when asked to or when the room closes, for the most throughput.
+    Never,
+}
+
+/// Flush interval a room uses when the config doesn't change it.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Default for FlushInterval {
+    fn default() -> FlushInterval {
+        FlushInterval::Every(DEFAULT_FLUSH_INTERVAL)
+    }
+}
+
+/// Which rooms can still be joined once shutdown has begun. New rooms are never created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownPolicy {
+    /// Let clients keep joining rooms that are still open.
+    #[default]
+    ReuseExisting,
+    /// Refuse every join.
+    RefuseAll,
+}
+
+/// Which kinds of inbound message a room accepts. By default only text and commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessagePolicy {
+    allowed: HashSet<MessageKind>,
+}
+
+impl MessagePolicy {
+    pub fn allowing(kinds: &[MessageKind]) -> MessagePolicy {
+        MessagePolicy {
+            allowed: kinds.iter().copied().collect(),
+        }
+    }
+
+    pub fn allows(&self, kind: MessageKind) -> bool {
+        self.allowed.contains(&kind)
+    }
+}
+
+impl Default for MessagePolicy {
+    fn default() -> MessagePolicy {
+        MessagePolicy::allowing(&[MessageKind::Text, MessageKind::Command])
+    }
+}
+
+/// How many chat messages each connection may send, as a token bucket that holds
+/// `max_messages_per_window` and refills over `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageRate {
+    pub max_messages_per_window: u32,
+    /// Written in seconds, as `window_secs`.
+    #[serde(rename = "window_secs", with = "secs")]
+    pub window: Duration,
+    /// Disconnect a user once this many of their messages in a row have been dropped. They are
+    /// only ever warned when `None`.
+    #[serde(default)]
+    pub disconnect_after: Option<u32>,
+}
+
+/// (De)serializes a `Duration` as a number of seconds, which may be fractional.
+mod secs {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(de::Error::custom)
+    }
+}
+
+impl MessageRate {
+    /// A full bucket for a new connection.
+    pub fn bucket(&self) -> TokenBucket {
+        let per_window = self.max_messages_per_window.max(1);
+        let per_sec = f64::from(per_window) / self.window.as_secs_f64().max(f64::MIN_POSITIVE);
+        TokenBucket::new(per_sec, per_window)
+    }
+}
+
+/// Pings every connection each `interval`, disconnecting it if a ping goes unanswered for
+/// `timeout`, so half-open connections don't linger in their rooms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// The per-user queue limit when `max_queued_per_user` is `None`. It rides out bursts without
+/// letting a stalled client pin much memory: about a megabyte per user at a kilobyte a message.
+pub const DEFAULT_MAX_QUEUED_PER_USER: usize = 1024;
+
+/// The logging queue's capacity when `max_queued_log_lines` is `None`, giving a stalled disk a
+/// few seconds' slack at thousands of messages a second.
+pub const DEFAULT_MAX_QUEUED_LOG_LINES: usize = 16 * 1024;
+
+/// A `log_burst_threshold` well short of `DEFAULT_MAX_QUEUED_LOG_LINES`, so a slow disk is
+/// caught up on long before lines start being dropped.
+pub const DEFAULT_LOG_BURST_THRESHOLD: usize = 256;
+
+/// A `metrics_room_labels` that keeps each per-room gauge to a hundred series or so.
+pub const DEFAULT_METRICS_ROOM_LABELS: usize = 100;
+
+/// A `join_history` of a screenful of messages.
+pub const DEFAULT_JOIN_HISTORY: usize = 50;
+
+/// Grace a drained room gives its users when the config doesn't set one.
+pub const DEFAULT_DRAIN_GRACE: Duration = Duration::from_secs(30);
+
+/// Settings applied to every room created by the server.
+#[derive(Debug, Clone, Default)]
+pub struct RoomConfig {
+    /// Limits each room starts with.
+    pub limits: RoomLimits,
+    /// Percent-decode room names taken from URLs, refusing names that don't decode to UTF-8.
+    /// Names are used exactly as they appear in the path when unset.
+    pub decode_room_names: bool,
+    /// Names no room may have, checked after decoding. Must include every name in
+    /// `api::ROUTE_NAMES`, which `api::check_reserved_names` verifies at startup.
+    pub reserved_room_names: HashSet<String>,
+    /// Transforms applied to each inbound message before it is logged and broadcast.
+    pub transforms: Pipeline,
+    /// Cap on outbound messages queued across every connection, unlimited when `None`.
+    ///
+    /// The budget is shared by every room built from this config.
+    pub send_budget: Option<Arc<SendBudget>>,
+    /// Cap on the approximate bytes held in recent message buffers across every room, unlimited
+    /// when `None`. The oldest buffered messages are evicted first.
+    ///
+    /// The budget is shared by every room built from this config.
+    pub buffer_budget: Option<Arc<BufferBudget>>,
+    /// Keep messages that leave the recent buffer in compressed blocks, so `recent_messages` can
+    /// reach further back. They are discarded as they leave it when `None`.
+    pub compressed_history: Option<HistoryConfig>,
+    /// Send each user up to this many of the room's latest messages as they join, before any live
+    /// traffic. The recent buffer grows to hold at least this many. Nothing is replayed when
+    /// `None`.
+    pub join_history: Option<usize>,
+    /// Seed a new room's buffer with up to this many messages from the newest earlier transcript
+    /// of a room with the same name in `log_dir`, so a room recreated after being reaped keeps its
+    /// history. A missing or unreadable transcript is skipped. Nothing is recovered when `None`.
+    pub recover_history: Option<usize>,
+    /// Limits how quickly new rooms are created; creating one past the rate is refused with a
+    /// retry hint, while joining an existing room is unaffected.
+    ///
+    /// The bucket is shared by every room built from this config.
+    pub room_creation_rate: Option<Arc<TokenBucket>>,
+    /// Accept websocket connections refused for load, because room creation is throttled or the
+    /// room is full, just long enough to send a notice suggesting a retry after this long (or the
+    /// throttle's own wait) and close with `DisconnectReason::Busy`. Throttled handshakes fail
+    /// with a 429 when `None`.
+    pub busy_retry_after: Option<Duration>,
+    /// Also send transcripts to syslog, or only there if `replace_file` is set.
+    pub syslog: Option<SyslogConfig>,
+    /// Also append every logged message to this store, which then answers `/messages` queries
+    /// in place of the room's transcript file.
+    ///
+    /// The store is shared by every room built from this config.
+    pub message_store: Option<Arc<dyn MessageStore>>,
+    /// Most topics with subscribers a room tracks at once, unlimited when `None`. Subscribing to,
+    /// or posting in, a topic beyond the cap is refused.
+    pub max_topics: Option<usize>,
+    /// Most messages queued for any one user before further messages to them are dropped,
+    /// `DEFAULT_MAX_QUEUED_PER_USER` when `None`. It sizes the outbound channel of each new
+    /// connection, at least one message. Fan-out never waits on a full queue, so one stalled
+    /// consumer can't delay delivery to the rest of the room.
+    pub max_queued_per_user: Option<usize>,
+    /// Remove a user whose queue is full from the room and close their connection as
+    /// `DisconnectReason::TooSlow`, rather than dropping the messages that don't fit.
+    pub disconnect_slow_consumers: bool,
+    /// Keep this many of the room's latest failed deliveries, served at
+    /// `/chat/{room}/delivery-failures`; none are kept when `None`.
+    pub delivery_failure_log: Option<usize>,
+    /// Most transcript lines waiting for the room's logging task before further lines are
+    /// dropped, `DEFAULT_MAX_QUEUED_LOG_LINES` when `None`. Posting never waits on a slow sink;
+    /// dropped lines are counted in `chat_log_lines_dropped_total`.
+    pub max_queued_log_lines: Option<usize>,
+    /// Template every chat message is wrapped in on delivery, e.g. to brand or compliance-tag
+    /// the room. Validated by `Decoration::parse` when the config is built.
+    pub decoration: Option<Decoration>,
+    /// Tell a room's users when its transcript couldn't be opened and messages aren't being saved.
+    pub notify_log_failure: bool,
+    /// Backlog of queued transcript lines past which the logging task catches up in bursts:
+    /// rather than one line per write, it takes everything already queued (up to
+    /// `MAX_BURST_LINES`) and hands it to the sink in a single `write_lines`. It goes back to
+    /// single lines once the queue has emptied. Always writes single lines when `None`.
+    pub log_burst_threshold: Option<usize>,
+    /// Tag each transcript line with the message's room sequence number.
+    pub log_sequence: bool,
+    /// Record which connection created each room, and by which route, for `/stats` and `/rooms`.
+    pub record_origins: bool,
+    /// Data classification recorded at the top of each room's transcript, which also decides its
+    /// `RetentionPolicy`.
+    pub classification: Option<Classification>,
+    /// How the room's transcript file is written.
+    pub transcript_format: TranscriptFormat,
+    /// Create a room whose transcript can't be opened anyway, degraded and without one, rather
+    /// than refusing to create it. Ignored for rooms whose transcript replaces its file by syslog.
+    pub allow_degraded: bool,
+    /// Directory room transcripts are written to, created if it is missing. Transcripts go in the
+    /// working directory when `None`.
+    pub log_dir: Option<PathBuf>,
+    /// Where a room's transcript is reopened if writes to it keep failing and it can't be
+    /// reopened where it was. Failing transcripts are only retried in place when `None`.
+    pub fallback_log_dir: Option<PathBuf>,
+    /// Start a new transcript segment once the current one would pass this many bytes, keeping
+    /// everything in one file when `None`.
+    pub rotate_after_bytes: Option<u64>,
+    /// How often lines written to the transcript are flushed without being asked.
+    pub flush_interval: FlushInterval,
+    /// Record users joining and leaving in the transcript.
+    pub log_presence: bool,
+    /// Merge consecutive joins (or leaves) logged within this long of the first into one summary
+    /// line. Each is logged on its own when `None`.
+    pub coalesce_presence: Option<Duration>,
+    /// Tell the rest of the room whenever a user joins or leaves.
+    pub announce_presence: bool,
+    /// Also send a joining user the announcement of their own join, which arrives after their
+    /// welcome.
+    pub announce_own_join: bool,
+    /// Keep a file listing each room's connected users, for post-mortems.
+    pub membership_snapshots: Option<SnapshotConfig>,
+    /// How long a room is kept after its last user leaves, so a quick reconnect can reuse it.
+    /// With `None` the room is closed as soon as it empties.
+    pub linger: Option<Duration>,
+    /// Name the room in every JSON envelope sent to its users.
+    pub room_in_envelope: bool,
+    /// Include each sender's color and avatar seed in JSON message envelopes.
+    pub user_colors: bool,
+    /// Require clients to send `/join` before they may chat, handling earlier messages with the
+    /// given policy. Clients are joined as soon as they connect when `None`.
+    pub explicit_join: Option<PreJoinPolicy>,
+    /// Take the first text frame a client sends as `/nick <name>`, showing their messages as from
+    /// `name` rather than `User#<id>`. A first frame that isn't `/nick` is handled as usual.
+    pub nick_handshake: bool,
+    /// How a second connection from the same account to a room is handled.
+    pub duplicate_connections: DuplicatePolicy,
+    /// Kinds of message users may send; anything else is refused with a notice.
+    pub message_policy: MessagePolicy,
+    /// Treat text frames starting with `data:base64,` as the binary frame the rest of the text
+    /// encodes, for clients that can only send text. The decoded frame is subject to the message
+    /// policy like any other binary frame. Otherwise such frames are ordinary chat text.
+    pub base64_binary: bool,
+    /// Send `/me` emotes back to their sender too, as well as to everyone else.
+    pub echo_emotes: bool,
+    /// Disconnect users who send nothing for this long, never when `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Mark users away, and tell the room, once they have sent nothing for this long; their next
+    /// frame marks them back. Only shows them away before `idle_timeout` disconnects them if
+    /// shorter. Nobody is marked away when `None`.
+    pub away_after: Option<Duration>,
+    /// Disconnect every connection this long after it was opened, however active, so clients
+    /// reconnect and re-authenticate. Connections are kept for as long as they stay open when
+    /// `None`.
+    pub max_connection_lifetime: Option<Duration>,
+    /// Close server-initiated disconnects with a distinct code per `DisconnectReason` from the
+    /// 4000-4999 application range, rather than the nearest standard code.
+    pub app_close_codes: bool,
+    /// Relay `read` frames from JSON clients to the senders of the messages read, in rooms with at
+    /// most this many users; larger rooms would have every message answered by every user. Read
+    /// positions are still tracked in larger rooms. Receipts are never relayed when `None`.
+    pub read_receipts_max_users: Option<usize>,
+    /// How long after sending a message its sender may still edit or delete it. Nobody may when
+    /// `None`.
+    pub edit_window: Option<Duration>,
+    /// Least time a user must leave between chat messages; faster ones are dropped with a notice.
+    pub message_cooldown: Option<Duration>,
+    /// Ping connections to find dead ones; only failed writes reveal them when `None`.
+    pub keepalive: Option<Keepalive>,
+    /// Most inbound frames of any kind a connection may send per second before it is
+    /// disconnected, unlimited when `None`.
+    pub max_frames_per_sec: Option<u32>,
+    /// Level of the tracing event recorded for each HTTP request the server rejects, with its
+    /// method, path and reason, not recorded when `None`.
+    pub log_rejections: Option<tracing::Level>,
+    /// Level of the tracing event recorded under the `chat::access` target for every HTTP request
+    /// the server answers, websocket handshakes included, with its method, path, status, latency
+    /// and client address. No access log when `None`.
+    pub access_log: Option<tracing::Level>,
+    /// Label the per-room gauges on `/metrics` for only this many of the busiest rooms, summing
+    /// the rest into one series, so a server with many rooms doesn't flood Prometheus with
+    /// series. Every room is labeled when `None`.
+    pub metrics_room_labels: Option<usize>,
+    /// Bearer token required by the admin routes, which refuse every request with 403 when `None`.
+    pub admin_token: Option<String>,
+    /// Most expensive admin operations (gc, replay, the connections view and room snapshots)
+    /// running at once, unlimited when `None`. Requests past it are refused with 429.
+    pub max_admin_operations: Option<usize>,
+    /// Notice sent to each user as they connect, before any room traffic.
+    pub motd: Option<String>,
+    /// How long users may stay in a drained room before they are disconnected, or
+    /// `DEFAULT_DRAIN_GRACE` when `None`.
+    pub drain_grace: Option<Duration>,
+    /// Raised when the server starts shutting down.
+    pub shutdown: ShutdownFlag,
+    /// Whether existing rooms can still be joined after `shutdown` is raised.
+    pub shutdown_policy: ShutdownPolicy,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::PathBuf};
+
+    use crate::config::{bind_addr, IncompleteTls, InvalidBindAddr, TlsConfig, DEFAULT_BIND_ADDR};
+
+    #[test]
+    fn bind_address_parts_fall_back_to_the_default() {
+        assert_eq!(
+            bind_addr(Some("0.0.0.0"), Some("8080")),
+            Ok("0.0.0.0:8080".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            bind_addr(Some("::1"), None),
+            Ok("[::1]:3030".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(bind_addr(None, None), Ok(DEFAULT_BIND_ADDR));
+        assert_eq!(
+            bind_addr(None, Some("70000")),
+            Err(InvalidBindAddr::Port("70000".to_owned()))
+        );
+        assert_eq!(
+            bind_addr(Some("localhost"), None),
+            Err(InvalidBindAddr::Ip("localhost".to_owned()))
+        );
+    }
+
+    #[test]
+    fn tls_is_served_only_with_cert_and_key() {
+        let (cert, key) = (PathBuf::from("cert.pem"), PathBuf::from("key.pem"));
+        assert_eq!(
+            TlsConfig::from_paths(Some(cert.clone()), Some(key.clone())),
+            Ok(Some(TlsConfig {
+                cert_path: cert.clone(),
+                key_path: key.clone(),
+            }))
+        );
+        assert_eq!(TlsConfig::from_paths(None, None), Ok(None));
+        assert_eq!(TlsConfig::from_paths(Some(cert), None), Err(IncompleteTls));
+        assert_eq!(TlsConfig::from_paths(None, Some(key)), Err(IncompleteTls));
+    }
+}
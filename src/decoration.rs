@@ -0,0 +1,130 @@
+use std::{error::Error, fmt, time::SystemTime};
+
+/// A template wrapped around every chat message when it is delivered, e.g.
+/// `[{room}] {message} (not financial advice)`.
+///
+/// Placeholders are `{message}`, which is required, `{room}`, `{topic}` (empty for messages
+/// without one) and `{timestamp}`, the RFC 3339 delivery time. `{{` and `}}` stand for literal
+/// braces. Only recipients see the decoration; the transcript keeps the message as sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoration {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Message,
+    Room,
+    Topic,
+    Timestamp,
+}
+
+impl Decoration {
+    /// Parses `template`, rejecting unknown placeholders, unbalanced braces and templates that
+    /// would drop the message.
+    pub fn parse(template: &str) -> Result<Decoration, InvalidTemplate> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(InvalidTemplate("unmatched {".to_owned())),
+                        }
+                    }
+                    let part = match name.as_str() {
+                        "message" => Part::Message,
+                        "room" => Part::Room,
+                        "topic" => Part::Topic,
+                        "timestamp" => Part::Timestamp,
+                        _ => {
+                            return Err(InvalidTemplate(format!("unknown placeholder {{{}", name)))
+                        }
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                '}' => return Err(InvalidTemplate("unmatched }".to_owned())),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        if !parts.contains(&Part::Message) {
+            return Err(InvalidTemplate(
+                "template must include {message}".to_owned(),
+            ));
+        }
+        Ok(Decoration { parts })
+    }
+
+    /// Decorates `message`, sent in `room` to `topic` if it has one.
+    pub fn render(&self, message: &str, room: &str, topic: Option<&str>) -> String {
+        let mut out = String::with_capacity(message.len() + 32);
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Message => out.push_str(message),
+                Part::Room => out.push_str(room),
+                Part::Topic => out.push_str(topic.unwrap_or("")),
+                Part::Timestamp => {
+                    out.push_str(&humantime::format_rfc3339_seconds(SystemTime::now()).to_string())
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Returned when a decoration template can't be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTemplate(pub String);
+
+impl fmt::Display for InvalidTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid decoration template: {}", self.0)
+    }
+}
+
+impl Error for InvalidTemplate {}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoration::{Decoration, InvalidTemplate};
+
+    #[test]
+    fn templates_are_validated() {
+        let decoration = Decoration::parse("{{{room}}} {message}").unwrap();
+        assert_eq!(decoration.render("hi", "lobby", None), "{lobby} hi");
+
+        for template in [
+            "no message",
+            "{message} {author}",
+            "{message} }",
+            "{message",
+        ] {
+            assert!(Decoration::parse(template).is_err(), "{}", template);
+        }
+        assert_eq!(
+            Decoration::parse("{room}").unwrap_err(),
+            InvalidTemplate("template must include {message}".to_owned())
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use serde::Serialize;
+
+/// Why a message couldn't be queued for a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The user's queue had reached `max_queued_per_user`.
+    QueueFull,
+    /// The shared send budget the user counts against was used up.
+    OverBudget,
+    /// The user's connection had already closed.
+    Closed,
+}
+
+/// A message that wasn't delivered to one of a room's users.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeliveryFailure {
+    pub uid: usize,
+    /// The undelivered message's sequence number, if it was a numbered message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// RFC 3339 time the delivery failed.
+    pub at: String,
+    pub reason: FailureReason,
+}
+
+/// A room's latest delivery failures, for answering "I didn't get the message" reports.
+///
+/// Holds at most `capacity` failures, dropping the oldest to make room.
+#[derive(Debug)]
+pub struct DeliveryFailures {
+    capacity: usize,
+    recent: Mutex<VecDeque<DeliveryFailure>>,
+}
+
+impl DeliveryFailures {
+    pub fn new(capacity: usize) -> DeliveryFailures {
+        DeliveryFailures {
+            capacity,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, uid: usize, seq: Option<u64>, reason: FailureReason) {
+        if self.capacity == 0 {
+            return;
+        }
+        let failure = DeliveryFailure {
+            uid,
+            seq,
+            at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            reason,
+        };
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(failure);
+    }
+
+    /// The failures kept, oldest first.
+    pub fn recent(&self) -> Vec<DeliveryFailure> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delivery::{DeliveryFailures, FailureReason};
+
+    #[test]
+    fn only_the_latest_failures_are_kept() {
+        let failures = DeliveryFailures::new(2);
+        for seq in 1..=3 {
+            failures.record(7, Some(seq), FailureReason::QueueFull);
+        }
+        let seqs: Vec<_> = failures.recent().iter().map(|f| f.seq).collect();
+        assert_eq!(seqs, [Some(2), Some(3)]);
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+/// Why the server closed a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// A moderator removed the user.
+    Kicked,
+    /// The user is banned from the room.
+    Banned,
+    /// The user couldn't keep up with the room's traffic.
+    TooSlow,
+    /// The user sent nothing for longer than the room's idle timeout.
+    Idle,
+    /// The room was closed.
+    RoomClosed,
+    /// The room was drained for maintenance.
+    Drained,
+    /// The connection sent frames faster than the room allows.
+    Flooding,
+    /// The same account connected to the room again.
+    Replaced,
+    /// The connection reached the room's maximum lifetime, and has to reconnect (and
+    /// re-authenticate) to carry on.
+    Expired,
+    /// The connection didn't answer a keepalive ping in time.
+    Unresponsive,
+    /// The server or room had no room for the connection, which may retry later.
+    Busy,
+    /// Reading from the connection failed, say on a malformed or oversized frame.
+    ProtocolError,
+}
+
+impl DisconnectReason {
+    /// The close code sent for this reason: a distinct one from the 4000-4999 application range
+    /// if `app_codes` is set, otherwise the nearest standard code.
+    pub fn code(&self, app_codes: bool) -> u16 {
+        match (self, app_codes) {
+            (DisconnectReason::Kicked, true) => 4001,
+            (DisconnectReason::Banned, true) => 4002,
+            (DisconnectReason::TooSlow, true) => 4003,
+            (DisconnectReason::Idle, true) => 4004,
+            (DisconnectReason::RoomClosed, true) => 4005,
+            (DisconnectReason::Drained, true) => 4006,
+            (DisconnectReason::Flooding, true) => 4007,
+            (DisconnectReason::Replaced, true) => 4008,
+            (DisconnectReason::Expired, true) => 4009,
+            (DisconnectReason::Unresponsive, true) => 4010,
+            (DisconnectReason::Busy, true) => 4011,
+            (DisconnectReason::ProtocolError, true) => 4012,
+            (DisconnectReason::ProtocolError, false) => 1002,
+            // Try again later.
+            (DisconnectReason::Busy, false) => 1013,
+            // Policy violation.
+            (DisconnectReason::Kicked | DisconnectReason::Banned, false)
+            | (DisconnectReason::TooSlow | DisconnectReason::Flooding, false) => 1008,
+            // Going away.
+            (DisconnectReason::Idle | DisconnectReason::RoomClosed, false)
+            | (DisconnectReason::Drained | DisconnectReason::Replaced, false)
+            | (DisconnectReason::Expired | DisconnectReason::Unresponsive, false) => 1001,
+        }
+    }
+
+    /// The human-readable reason sent with the close code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::TooSlow => "too slow",
+            DisconnectReason::Idle => "idle timeout",
+            DisconnectReason::RoomClosed => "room closed",
+            DisconnectReason::Drained => "room closed for maintenance",
+            DisconnectReason::Flooding => "too many frames",
+            DisconnectReason::Replaced => "replaced by a newer connection",
+            DisconnectReason::Expired => "reconnect required",
+            DisconnectReason::Unresponsive => "ping timeout",
+            DisconnectReason::Busy => "server busy, retry later",
+            DisconnectReason::ProtocolError => "protocol error",
+        }
+    }
+
+    pub fn close_frame(&self, app_codes: bool) -> Message {
+        Message::close_with(self.code(app_codes), self.reason())
+    }
+}
+
+/// Stops a connection's task from outside it, for disconnects such as a kick, where the client
+/// can't be trusted to act on the close frame. The task waits on `cancelled` alongside its socket.
+#[derive(Debug, Default)]
+pub struct Cancellation {
+    /// Why the first `cancel` was made, once it has been.
+    reason: Mutex<Option<DisconnectReason>>,
+    notify: Notify,
+}
+
+impl Cancellation {
+    /// Cancels the task for `reason`, unless it was already cancelled for another.
+    pub fn cancel(&self, reason: DisconnectReason) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.notify.notify_waiters();
+    }
+
+    pub fn reason(&self) -> Option<DisconnectReason> {
+        *self.reason.lock().unwrap()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// Resolves once `cancel` has been called, straight away if it already has been.
+    pub async fn cancelled(&self) -> DisconnectReason {
+        // Created before checking, so a cancel between the two still wakes it.
+        let notified = self.notify.notified();
+        if let Some(reason) = self.reason() {
+            return reason;
+        }
+        notified.await;
+        self.reason()
+            .expect("waiters are only notified once a reason is set")
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    transcript::{read_varint, write_varint},
+    RecentMessage,
+};
+
+/// Shortest repeat `compress` encodes as a back-reference.
+const MIN_MATCH: usize = 4;
+
+/// Bits of the hash `compress` uses to find earlier occurrences of 4-byte sequences.
+const HASH_BITS: u32 = 12;
+
+/// How a room keeps messages that have left its recent buffer: compressed in blocks, so a long
+/// history costs far less memory than the same messages held as strings.
+///
+/// The recent buffer itself stays uncompressed, so the common short replays never decompress
+/// anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Most messages kept in compressed blocks; the oldest block is dropped past this.
+    pub max_messages: usize,
+    /// Messages compressed together. Larger blocks compress better but take longer to read back.
+    pub block_messages: usize,
+}
+
+/// Messages older than a room's recent buffer, oldest first.
+#[derive(Debug)]
+pub(crate) struct CompressedHistory {
+    config: HistoryConfig,
+    /// Messages waiting for a full block.
+    pending: Vec<RecentMessage>,
+    blocks: VecDeque<Block>,
+    /// Messages in `blocks`.
+    compressed_messages: usize,
+}
+
+#[derive(Debug)]
+struct Block {
+    messages: usize,
+    bytes: Vec<u8>,
+}
+
+impl CompressedHistory {
+    pub(crate) fn new(config: HistoryConfig) -> CompressedHistory {
+        CompressedHistory {
+            config,
+            pending: Vec::new(),
+            blocks: VecDeque::new(),
+            compressed_messages: 0,
+        }
+    }
+
+    /// Adds `message`, newer than every message already held.
+    pub(crate) fn push(&mut self, message: RecentMessage) {
+        self.pending.push(message);
+        if self.pending.len() < self.config.block_messages.max(1) {
+            return;
+        }
+        let block = Block {
+            messages: self.pending.len(),
+            bytes: compress(&encode(&self.pending)),
+        };
+        self.pending.clear();
+        self.compressed_messages += block.messages;
+        self.blocks.push_back(block);
+        while self.compressed_messages > self.config.max_messages {
+            match self.blocks.pop_front() {
+                Some(block) => self.compressed_messages -= block.messages,
+                None => break,
+            }
+        }
+    }
+
+    /// The newest `limit` messages held, oldest first. Only the blocks needed are decompressed.
+    pub(crate) fn latest(&self, limit: usize) -> Vec<RecentMessage> {
+        let mut newest_first: Vec<RecentMessage> =
+            self.pending.iter().rev().take(limit).cloned().collect();
+        for block in self.blocks.iter().rev() {
+            if newest_first.len() >= limit {
+                break;
+            }
+            let messages = decompress(&block.bytes)
+                .and_then(|bytes| decode(&bytes))
+                .unwrap_or_default();
+            let wanted = limit - newest_first.len();
+            newest_first.extend(messages.into_iter().rev().take(wanted));
+        }
+        newest_first.reverse();
+        newest_first
+    }
+
+    /// Bytes of compressed blocks held.
+    pub(crate) fn compressed_bytes(&self) -> usize {
+        self.blocks.iter().map(|block| block.bytes.len()).sum()
+    }
+}
+
+/// Serializes each message as varints for its sequence number, sender, send time (nanoseconds
+/// since the epoch) and body length, then the body.
+fn encode(messages: &[RecentMessage]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for message in messages {
+        let nanos = message
+            .sent_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        write_varint(&mut buf, message.seq);
+        write_varint(&mut buf, message.from as u64);
+        write_varint(&mut buf, nanos);
+        write_varint(&mut buf, message.body.len() as u64);
+        buf.extend_from_slice(message.body.as_bytes());
+    }
+    buf
+}
+
+fn decode(mut buf: &[u8]) -> Option<Vec<RecentMessage>> {
+    let mut messages = Vec::new();
+    while !buf.is_empty() {
+        let seq = read_varint(&mut buf)?;
+        let from = usize::try_from(read_varint(&mut buf)?).ok()?;
+        let nanos = read_varint(&mut buf)?;
+        let len = usize::try_from(read_varint(&mut buf)?).ok()?;
+        if buf.len() < len {
+            return None;
+        }
+        let (body, rest) = buf.split_at(len);
+        buf = rest;
+        messages.push(RecentMessage {
+            seq,
+            from,
+            body: String::from_utf8(body.to_vec()).ok()?,
+            sent_at: UNIX_EPOCH + Duration::from_nanos(nanos),
+        });
+    }
+    Some(messages)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// A small LZ77 compressor. The output is a series of tokens, each a varint holding a length
+/// shifted left by one: an even token is followed by that many literal bytes, an odd one by a
+/// varint offset back into the output to copy that many bytes from.
+fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literals = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let slot = hash(&input[i..]);
+        let candidate = table[slot];
+        table[slot] = i;
+        if candidate == usize::MAX
+            || input[candidate..candidate + MIN_MATCH] != input[i..i + MIN_MATCH]
+        {
+            i += 1;
+            continue;
+        }
+        let mut len = MIN_MATCH;
+        while i + len < input.len() && input[candidate + len] == input[i + len] {
+            len += 1;
+        }
+        write_literals(&mut out, &input[literals..i]);
+        write_varint(&mut out, (len as u64) << 1 | 1);
+        write_varint(&mut out, (i - candidate) as u64);
+        i += len;
+        literals = i;
+    }
+    write_literals(&mut out, &input[literals..]);
+    out
+}
+
+fn write_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    if !literals.is_empty() {
+        write_varint(out, (literals.len() as u64) << 1);
+        out.extend_from_slice(literals);
+    }
+}
+
+/// Reverses `compress`, returning `None` if `buf` isn't something it produced.
+fn decompress(mut buf: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(buf.len() * 2);
+    while !buf.is_empty() {
+        let token = read_varint(&mut buf)?;
+        let len = usize::try_from(token >> 1).ok()?;
+        if token & 1 == 0 {
+            if buf.len() < len {
+                return None;
+            }
+            let (literals, rest) = buf.split_at(len);
+            out.extend_from_slice(literals);
+            buf = rest;
+        } else {
+            let offset = usize::try_from(read_varint(&mut buf)?).ok()?;
+            if offset == 0 || offset > out.len() {
+                return None;
+            }
+            // Copied a byte at a time, since a match may overlap the bytes it produces.
+            let start = out.len() - offset;
+            for k in 0..len {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::{compress, decompress};
+
+    #[test]
+    fn compression_round_trips() {
+        let inputs: [&[u8]; 4] = [
+            b"",
+            b"abc",
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            b"the cat sat on the mat, the cat sat on the hat",
+        ];
+        for input in inputs.iter() {
+            assert_eq!(decompress(&compress(input)).as_deref(), Some(*input));
+        }
+        assert!(compress(inputs[2]).len() < inputs[2].len());
+    }
+}
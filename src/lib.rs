@@ -1,218 +1,5676 @@
 pub mod api;
+pub mod appearance;
+pub mod budget;
+pub mod classification;
+pub mod commands;
+pub mod config;
+pub mod decoration;
+pub mod delivery;
+pub mod disconnect;
+pub mod history;
+pub mod locks;
+pub mod membership;
+pub mod metrics;
+mod mux;
+pub mod protocol;
+pub mod ratelimit;
+pub mod reload;
+pub mod replay;
+pub mod rooms;
+pub mod shutdown;
+pub mod sink;
+pub mod store;
+pub mod syslog;
+pub mod transcript;
+pub mod transform;
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt, io, mem,
+    net::SocketAddr,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Weak,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock as SyncRwLock, Weak,
     },
+    time::{Duration, SystemTime},
 };
 
-use futures::{SinkExt, StreamExt, TryFutureExt};
+use futures::{future, Future, FutureExt, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
 use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufWriter},
-    sync::{mpsc, RwLock},
+    sync::{mpsc, oneshot, Notify, RwLock},
+    time::Instant,
 };
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::Instrument;
 use warp::ws::{Message, WebSocket};
 
-/// Our global unique user id counter.
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
+use crate::{
+    appearance::Appearance,
+    budget::{buffered_bytes, MessageBuffer, SendBudget},
+    classification::RetentionPolicy,
+    commands::Command,
+    config::{
+        DuplicatePolicy, FlushInterval, InvalidLimits, Keepalive, MessageRate, PreJoinPolicy,
+        RoomConfig, RoomLimits, ShutdownPolicy, DEFAULT_DRAIN_GRACE, DEFAULT_MAX_QUEUED_LOG_LINES,
+        DEFAULT_MAX_QUEUED_PER_USER,
+    },
+    delivery::{DeliveryFailures, FailureReason},
+    disconnect::{Cancellation, DisconnectReason},
+    history::CompressedHistory,
+    locks::timed_read,
+    metrics::{DeliveryTimer, QueueDepth},
+    protocol::{ChatEvent, ClientFrame, EncodedEvent, MessageKind, Protocol},
+    ratelimit::{Cooldown, FrameLimiter, TokenBucket},
+    rooms::{reap_shard, RoomOrigin},
+    shutdown::{retry_secs, Unavailable},
+    sink::{
+        BinaryFileSink, DiscardSink, FileSink, JsonFileSink, LogSink, RecoveringSink, Reopen,
+        RotatingFileSink, TeeSink,
+    },
+    store::{FileStore, MessageStore, StoreSink},
+    syslog::SyslogSink,
+    transcript::{LogCommand, Record, SystemBatch, SystemEvent, TranscriptFormat},
+};
+
+/// How many of a room's latest messages it keeps in memory, for pinning and
+/// `ChatRoom::recent_messages`, unless its `join_history` needs more.
+const RECENT_MESSAGES: usize = 50;
+
+/// What a user may do in their room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Member,
+    /// Can moderate the room.
+    Admin,
+    /// Watches the room without taking part.
+    Spectator,
+}
+
+/// Whether a user is at their keyboard, as far as the room can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Active,
+    /// The user has sent nothing for the room's `away_after`.
+    Away,
+}
+
+/// Who a connected user is.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Identity {
+    pub id: usize,
+    pub role: Role,
+    /// The authenticated account behind the connection, if it has one. Several connections may
+    /// share an account, but never an id.
+    pub account: Option<String>,
+    /// The nickname the user chose, shown in place of `User#<id>`.
+    pub nick: Option<String>,
+    pub status: Status,
+}
+
+impl Identity {
+    /// A plain member with the given id.
+    pub fn new(id: usize) -> Identity {
+        Identity {
+            id,
+            role: Role::Member,
+            account: None,
+            nick: None,
+            status: Status::Active,
+        }
+    }
+
+    /// The name this user is shown as to others.
+    pub fn display_name(&self) -> String {
+        match &self.nick {
+            Some(nick) => nick.clone(),
+            None => format!("User#{}", self.id),
+        }
+    }
+
+    /// The name this user is logged as in transcripts, which keep their id alongside any
+    /// nickname, e.g. `alice (User#3)`.
+    pub fn logged_name(&self) -> String {
+        match &self.nick {
+            Some(nick) => format!("{} (User#{})", nick, self.id),
+            None => format!("User#{}", self.id),
+        }
+    }
+}
+
+/// Whether `nick` could be taken for a user id, as `7` or `User#7`, which would let its owner
+/// pass as another user. Any `User#` prefix counts, since a suffix added to keep the nickname
+/// unique could turn it into an id.
+fn looks_like_id(nick: &str) -> bool {
+    nick.starts_with("User#") || nick.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// A connected user as seen by the room they are in.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub identity: Identity,
+    /// The connection's outbound queue, which holds up to the `max_queued_per_user` of the room
+    /// it connected to. Sends to a full queue are refused rather than waited on.
+    pub tx: mpsc::Sender<Message>,
+    /// Wire format negotiated for this user's connection.
+    pub protocol: Protocol,
+    /// Shared cap on queued outbound messages this user's sends count against.
+    pub budget: Option<Arc<SendBudget>>,
+    /// Messages sent to `tx` that the connection's forwarding task hasn't picked up yet.
+    pub depth: QueueDepth,
+    /// What a fan-out does once `tx` is full.
+    pub slow_consumer: SlowConsumer,
+    /// Room named in this user's JSON envelopes, if the room tags them.
+    pub envelope_room: Option<Arc<str>>,
+    /// Topics this user receives tagged messages for, shared with their connection.
+    pub topics: Arc<SyncRwLock<HashSet<String>>>,
+    /// Where the user connected from and what they have sent, shared by every room a
+    /// multiplexed connection is in.
+    pub info: Arc<ConnectionInfo>,
+    /// Where fan-outs that fail to reach this user are recorded, if their room keeps them.
+    pub delivery_failures: Option<Arc<DeliveryFailures>>,
+    /// Stops the connection reading from the user, once they have been removed from the room.
+    /// Each room a multiplexed connection is in has its own.
+    pub cancellation: Arc<Cancellation>,
+    /// Stops the connection's forwarding task, which abandons whatever is still queued and
+    /// closes the socket. Shared by every room a multiplexed connection is in.
+    pub writer_cancellation: Arc<Cancellation>,
+}
+
+/// How a fan-out treats a user whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowConsumer {
+    /// Drop the messages that don't fit and keep the user.
+    #[default]
+    Shed,
+    /// Remove the user from the room and close their connection as `DisconnectReason::TooSlow`,
+    /// without waiting for their queue to drain.
+    Disconnect,
+}
+
+/// Facts about a user's connection, for the admin view.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    /// The client's address, if the transport knows it.
+    pub addr: Option<SocketAddr>,
+    pub connected_at: SystemTime,
+    messages: AtomicU64,
+}
+
+impl ConnectionInfo {
+    pub fn new(addr: Option<SocketAddr>) -> ConnectionInfo {
+        ConnectionInfo {
+            addr,
+            connected_at: SystemTime::now(),
+            messages: AtomicU64::new(0),
+        }
+    }
+
+    /// Chat messages accepted from this connection so far.
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+}
+
+impl User {
+    pub fn new(tx: mpsc::Sender<Message>, protocol: Protocol) -> User {
+        User {
+            identity: Identity::default(),
+            tx,
+            protocol,
+            budget: None,
+            depth: QueueDepth::default(),
+            slow_consumer: SlowConsumer::Shed,
+            envelope_room: None,
+            topics: Arc::default(),
+            info: Arc::new(ConnectionInfo::new(None)),
+            delivery_failures: None,
+            cancellation: Arc::default(),
+            writer_cancellation: Arc::default(),
+        }
+    }
+
+    pub fn subscribe<'a>(&self, topics: impl IntoIterator<Item = &'a str>) {
+        let mut subscribed = self.topics.write().unwrap();
+        subscribed.extend(topics.into_iter().map(str::to_owned));
+    }
+
+    pub fn unsubscribe<'a>(&self, topics: impl IntoIterator<Item = &'a str>) {
+        let mut subscribed = self.topics.write().unwrap();
+        for topic in topics {
+            subscribed.remove(topic);
+        }
+    }
+
+    /// Whether this user should be sent `event`, which depends on the topic of tagged messages.
+    pub fn receives(&self, event: &ChatEvent) -> bool {
+        match event {
+            ChatEvent::Message {
+                topic: Some(topic), ..
+            } => self.topics.read().unwrap().contains(topic),
+            _ => true,
+        }
+    }
+
+    /// Queues `message` for this user, returning `false` if it was shed because the send budget
+    /// is exhausted or the user's own queue is full.
+    ///
+    /// Never waits, so a consumer that has stopped reading can't hold up a fan-out to the rest of
+    /// the room. A closed channel is not an error here: the user's `user_disconnected` code should
+    /// be running in another task.
+    pub fn send(&self, message: Message) -> bool {
+        // A closed connection is on its way out of the room, so it isn't counted as refusing.
+        !matches!(
+            self.try_send(message),
+            Err(FailureReason::QueueFull | FailureReason::OverBudget)
+        )
+    }
+
+    /// Queues `message` for the user, or says why it couldn't be.
+    pub fn try_send(&self, message: Message) -> Result<(), FailureReason> {
+        if self.queue_full() {
+            return Err(FailureReason::QueueFull);
+        }
+        if let Some(budget) = &self.budget {
+            if !budget.try_acquire() {
+                return Err(FailureReason::OverBudget);
+            }
+        }
+        self.queue(message)
+    }
+
+    /// Whether the user's queue has no room left.
+    pub fn queue_full(&self) -> bool {
+        self.tx.capacity() == 0
+    }
+
+    /// Closes the user's connection for `reason`, with an application close code if
+    /// `app_codes` is set.
+    ///
+    /// The close frame goes out after everything already queued. If the queue is full, the
+    /// forwarding task is cancelled instead, abandoning the queue and sending the close frame
+    /// straight away.
+    pub fn disconnect(&self, reason: DisconnectReason, app_codes: bool) {
+        if !self.send_unshed(reason.close_frame(app_codes)) {
+            self.writer_cancellation.cancel(reason);
+        }
+    }
+
+    /// Like `disconnect`, for a user already removed from their room by someone else. Their
+    /// connection also stops reading, so nothing more they send reaches the room even if their
+    /// client ignores the close frame.
+    pub fn hang_up(&self, reason: DisconnectReason, app_codes: bool) {
+        self.disconnect(reason, app_codes);
+        self.cancellation.cancel(reason);
+    }
+
+    /// Disconnects a user whose queue has filled, already removed from their room by the
+    /// fan-out that found it full. Their connection stops reading and writing at once; the
+    /// forwarding task sends the close frame in place of the backlog.
+    fn cut_off(&self, reason: DisconnectReason) {
+        self.writer_cancellation.cancel(reason);
+        self.cancellation.cancel(reason);
+    }
+
+    /// Queues `message` past any send budget, returning `false` only if the user's queue is full
+    /// or closed. It still takes a budget slot, even past the limit, as the forwarding task gives
+    /// one back for every message it writes.
+    fn send_unshed(&self, message: Message) -> bool {
+        if let Some(budget) = &self.budget {
+            budget.acquire_past_limit();
+        }
+        self.queue(message).is_ok()
+    }
+
+    /// Puts `message`, whose budget slot is already taken, on the queue, giving the slot back if
+    /// it can't be.
+    fn queue(&self, message: Message) -> Result<(), FailureReason> {
+        // Counted before sending so the forwarding task can never pop a message not yet pushed.
+        self.depth.push();
+        let failed = match self.tx.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => FailureReason::QueueFull,
+            Err(mpsc::error::TrySendError::Closed(_)) => FailureReason::Closed,
+        };
+        self.depth.pop();
+        if let Some(budget) = &self.budget {
+            budget.release();
+        }
+        Err(failed)
+    }
+
+    /// Pings the user's connection past any send budget, like `disconnect`, returning `false` if
+    /// their queue has no room for it.
+    pub fn ping(&self) -> bool {
+        self.send_unshed(Message::ping(Vec::new()))
+    }
+
+    /// Encodes `event` in this user's protocol.
+    pub fn encode(&self, event: &ChatEvent) -> Message {
+        self.protocol
+            .encode_in(event, self.envelope_room.as_deref())
+    }
+
+    /// Sends this user a notice from the server.
+    pub fn notice(&self, body: String) -> bool {
+        self.send(self.encode(&ChatEvent::Notice { body }))
+    }
+}
+
+/// A chat message kept in a room's in-memory buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentMessage {
+    pub seq: u64,
+    pub from: usize,
+    pub body: String,
+    pub sent_at: SystemTime,
+}
+
+/// Why a user's edit or deletion of a message was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeRefused {
+    /// The message isn't in the room's buffer: it never existed, was deleted, was sent to a topic
+    /// or is too old to be kept.
+    NotFound,
+    /// Someone else sent the message.
+    NotOwner,
+    /// The room's edit window has passed, or the room doesn't allow changes at all.
+    TooLate,
+}
+
+impl fmt::Display for ChangeRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChangeRefused::NotFound => "no such recent message",
+            ChangeRefused::NotOwner => "you can only change your own messages",
+            ChangeRefused::TooLate => "too late to change that message",
+        })
+    }
+}
+
+impl Error for ChangeRefused {}
 
 /// Our state of currently connected users.
 ///
 /// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-pub type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
-pub type ChatRooms = Arc<RwLock<HashMap<String, Weak<ChatRoom>>>>;
+/// - Value is the user's outbound sender and connection details
+pub type Users = Arc<RwLock<HashMap<usize, User>>>;
+
+pub use rooms::ChatRooms;
 
 #[derive(Debug)]
 pub struct ChatRoom {
     pub name: String,
     pub users: Users,
-    logging_tx: mpsc::UnboundedSender<String>,
-    cancellation_tx: mpsc::UnboundedSender<()>,
+    pub config: RoomConfig,
+    /// Current limits, initially `config.limits`.
+    limits: SyncRwLock<RoomLimits>,
+    /// The file this room's transcript is written to, if it is written to one.
+    pub log_path: Option<PathBuf>,
+    /// Sequence number of the last message accepted by this room.
+    last_seq: Mutex<u64>,
+    /// The last few untopiced messages, oldest first.
+    recent: Arc<MessageBuffer>,
+    /// Messages that have left `recent`, if the room keeps them.
+    history: Option<Mutex<CompressedHistory>>,
+    /// The pinned message, a `ChatEvent::Pinned`.
+    pinned: Mutex<Option<ChatEvent>>,
+    /// How the room was created, if its config records origins.
+    origin: Option<RoomOrigin>,
+    /// Bumped whenever the room is joined, cancelling any linger reap scheduled before.
+    reap_generation: AtomicU64,
+    /// When the room was last joined, written to or broadcast to, for the idle reaper.
+    last_activity: SyncRwLock<Instant>,
+    /// Set once the room is drained; it takes no new users or messages from then on.
+    draining: AtomicBool,
+    /// The id the room gives the next user to join it. Ids count up from 1 and aren't reused after
+    /// a user leaves, so each id names one connection for the life of the room and its
+    /// transcript. A room created again under the same name starts over from 1.
+    next_user_id: AtomicUsize,
+    /// Set once the room is closed by `close_room`, which then wakes `closing`'s waiters.
+    closed: AtomicBool,
+    closing: Notify,
+    /// Resolves once the logging task has opened its sink, taken by the first message posted.
+    log_ready: Mutex<Option<oneshot::Receiver<io::Result<()>>>>,
+    /// Set when the transcript couldn't be opened, so nothing said in the room is being saved.
+    degraded: Arc<AtomicBool>,
+    /// Set while the logging task is catching up on a backlog past `log_burst_threshold`.
+    log_bursting: Arc<AtomicBool>,
+    /// Subscriber count of every topic someone in the room is subscribed to.
+    topics: Mutex<HashMap<String, usize>>,
+    /// Holds up to `max_queued_log_lines`.
+    logging_tx: mpsc::Sender<LogCommand>,
+    /// Lines sent to `logging_tx` that the logging task hasn't picked up yet.
+    log_depth: QueueDepth,
+    /// Signals the membership snapshot task, if the room keeps snapshots.
+    membership_tx: Option<mpsc::UnboundedSender<()>>,
+    /// Stops the logging task once everything queued before is written, acknowledging on the
+    /// sender if one is given.
+    cancellation_tx: mpsc::UnboundedSender<Option<oneshot::Sender<()>>>,
+    /// The latest failed deliveries to the room's users, if the room keeps them.
+    delivery_failures: Option<Arc<DeliveryFailures>>,
 }
 
 impl ChatRoom {
-    pub async fn new(name: String, users: Users) -> ChatRoom {
-        // set up communication channels
-        let (tx, rx) = mpsc::unbounded_channel::<String>();
-        let mut rx = UnboundedReceiverStream::new(rx);
-        let (cancellation_tx, mut cancellation_rx) = mpsc::unbounded_channel::<()>();
+    /// Creates a room with the default config, returning the error if its transcript couldn't be
+    /// opened.
+    pub async fn new(name: String, users: Users) -> io::Result<ChatRoom> {
+        ChatRoom::open(name, users, RoomConfig::default()).await
+    }
+
+    /// Creates a room with `config`. If its transcript can't be opened the room is still created,
+    /// but degraded and without one.
+    pub async fn with_config(name: String, users: Users, config: RoomConfig) -> ChatRoom {
+        let syslog = config.syslog.clone();
+        if let Some(syslog) = syslog.as_ref().filter(|syslog| syslog.replace_file) {
+            let sink = Box::new(SyslogSink::new(syslog.clone()));
+            return ChatRoom::with_sink(name, users, config, sink).await;
+        }
 
+        let format = config.transcript_format;
+        let rotate_after_bytes = config.rotate_after_bytes;
         let file_name = format!(
-            "{}_{}.log",
-            name,
-            humantime::format_rfc3339(std::time::SystemTime::now())
+            "{}_{}.{}",
+            file_safe(&name),
+            humantime::format_rfc3339(std::time::SystemTime::now()),
+            format.extension()
+        );
+        let log_dir = config.log_dir.clone();
+        let log_path = match &log_dir {
+            Some(dir) => dir.join(&file_name),
+            None => PathBuf::from(&file_name),
+        };
+        // Read before the new transcript is created, which may have the same name.
+        let recovered = match config.recover_history {
+            Some(limit) => {
+                let dir = log_dir.as_deref().unwrap_or_else(|| Path::new("."));
+                recover_messages(&name, dir, format, limit).await
+            }
+            None => Vec::new(),
+        };
+
+        let path = log_path.clone();
+        let reopen = reopen_transcript(
+            log_path.clone(),
+            config
+                .fallback_log_dir
+                .as_ref()
+                .map(|dir| dir.join(&file_name)),
+            format,
         );
+        let sink = async move {
+            if let Some(dir) = log_dir {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let file: Box<dyn LogSink> = match (format, rotate_after_bytes) {
+                (_, Some(max_bytes)) => {
+                    Box::new(RotatingFileSink::create(&path, format, Some(max_bytes)).await?)
+                }
+                (TranscriptFormat::Text, None) => Box::new(FileSink::create(&path).await?),
+                (TranscriptFormat::Binary, None) => Box::new(BinaryFileSink::create(&path).await?),
+                (TranscriptFormat::Json, None) => Box::new(JsonFileSink::create(&path).await?),
+            };
+            let file = Box::new(RecoveringSink::new(file, reopen));
+            let sink: Box<dyn LogSink> = match syslog {
+                Some(syslog) => Box::new(TeeSink(file, Box::new(SyslogSink::new(syslog)))),
+                None => file,
+            };
+            Ok(sink)
+        };
+        let room = ChatRoom::spawn(name, users, config, Some(log_path), sink);
+        room.seed(recovered);
+        room
+    }
+
+    /// Creates a room around an existing `users` map whose transcript is thrown away, so messaging
+    /// can be exercised against fake senders without any file I/O.
+    pub async fn unlogged(name: String, users: Users) -> ChatRoom {
+        ChatRoom::with_sink(name, users, RoomConfig::default(), Box::new(DiscardSink)).await
+    }
+
+    /// Like `with_config`, but waits for the room's transcript to open, returning the error if it
+    /// couldn't be.
+    pub async fn open(name: String, users: Users, config: RoomConfig) -> io::Result<ChatRoom> {
+        let room = ChatRoom::with_config(name, users, config).await;
+        let ready = room.log_ready.lock().unwrap().take();
+        if let Some(ready) = ready {
+            ready
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("logging task stopped")))?;
+        }
+        Ok(room)
+    }
 
-        // This task handles writing to the log using a BufWriter
+    /// Creates a room whose transcript goes to `sink` instead of a log file.
+    pub async fn with_sink(
+        name: String,
+        users: Users,
+        config: RoomConfig,
+        sink: Box<dyn LogSink>,
+    ) -> ChatRoom {
+        ChatRoom::spawn(name, users, config, None, future::ready(Ok(sink)))
+    }
+
+    fn spawn<F>(
+        name: String,
+        users: Users,
+        config: RoomConfig,
+        log_path: Option<PathBuf>,
+        sink: F,
+    ) -> ChatRoom
+    where
+        F: Future<Output = io::Result<Box<dyn LogSink>>> + Send + 'static,
+    {
+        // set up communication channels, bounded so a slow sink can't grow the queue without
+        // limit
+        let capacity = config
+            .max_queued_log_lines
+            .unwrap_or(DEFAULT_MAX_QUEUED_LOG_LINES)
+            .max(1);
+        let (tx, rx) = mpsc::channel::<LogCommand>(capacity);
+        let mut rx = ReceiverStream::new(rx);
+        let (cancellation_tx, mut cancellation_rx) = mpsc::unbounded_channel();
+        let log_depth = QueueDepth::default();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        if let Some(classification) = config.classification {
+            log_depth.push();
+            let _ = tx.try_send(LogCommand::Line(classification.header().to_line(&name)));
+        }
+
+        // This task handles writing to the log through the room's sink
+        let room_name = name.clone();
+        let task_depth = log_depth.clone();
+        let coalesce = config.coalesce_presence;
+        let degraded = Arc::new(AtomicBool::new(false));
+        let task_degraded = degraded.clone();
+        let task_users = users.clone();
+        let notify_log_failure = config.notify_log_failure;
+        let burst_threshold = config.log_burst_threshold;
+        let log_bursting = Arc::new(AtomicBool::new(false));
+        let task_bursting = log_bursting.clone();
+        let store = config.message_store.clone();
+        let flush_every = match config.flush_interval {
+            FlushInterval::Every(period) => Some(period),
+            FlushInterval::Never => None,
+        };
         tokio::task::spawn(async move {
-            let file = File::create(&file_name).await.unwrap(); // TODO error handle
-            let mut log_writer = BufWriter::new(file);
+            let mut sink = match sink.await {
+                Ok(sink) => {
+                    let _ = ready_tx.send(Ok(()));
+                    match store {
+                        Some(store) => Box::new(TeeSink(sink, Box::new(StoreSink(store)))),
+                        None => sink,
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(room = %room_name, error = %e, "failed to create log for channel");
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            // Consecutive system events are held here until the run ends or the window closes.
+            let mut batch: Option<(SystemBatch, tokio::time::Instant)> = None;
+            let mut healthy = true;
+            // A command taken off the queue while gathering a burst, which ended it.
+            let mut deferred = None;
+            // When lines written since the last flush are next flushed, `None` if there are none.
+            let mut flush_at: Option<tokio::time::Instant> = None;
+            let closed: Option<oneshot::Sender<()>>;
             loop {
-                tokio::select! {
-                    Some(message) = rx.next() => {
-                        if let Err(e) = log_writer.write_all(format!("{}\n", message).as_bytes()).await {
-                            eprintln!("Error writing message: {:?}", e);
+                let deadline = batch.as_ref().map(|(_, deadline)| *deadline);
+                let command = match deferred.take() {
+                    Some(command) => command,
+                    // Commands queued before the cancellation are always handled first.
+                    None => tokio::select! {
+                        biased;
+                        _ = sleep_until_some(deadline) => {
+                            write_batch(&mut *sink, &mut batch, &room_name).await;
+                            flush_at = flush_at.or_else(|| flush_deadline(flush_every));
+                            continue;
+                        }
+                        _ = sleep_until_some(flush_at) => {
+                            flush_at = None;
+                            if let Err(e) = sink.flush().await {
+                                tracing::error!(room = %room_name, error = %e, "error flushing log");
+                            }
+                            continue;
+                        }
+                        Some(command) = rx.next() => command,
+                        Some(done) = cancellation_rx.recv() => {
+                            closed = done;
+                            break;
                         }
                     },
-                    Some(_) = cancellation_rx.recv() => {
-                        break;
+                };
+                if matches!(command, LogCommand::Line(_) | LogCommand::System(..)) {
+                    flush_at = flush_at.or_else(|| flush_deadline(flush_every));
+                }
+                match command {
+                    LogCommand::System(event, name) => {
+                        task_depth.pop();
+                        match (&mut batch, coalesce) {
+                            (Some((pending, _)), _) if pending.event == event => pending.add(),
+                            (_, Some(window)) => {
+                                write_batch(&mut *sink, &mut batch, &room_name).await;
+                                let deadline = tokio::time::Instant::now() + window;
+                                batch = Some((SystemBatch::new(event, name), deadline));
+                            }
+                            (_, None) => {
+                                let line = SystemBatch::new(event, name).into_line(&room_name);
+                                if let Err(e) = sink.write_line(&line).await {
+                                    tracing::error!(room = %room_name, error = %e, "error writing message");
+                                }
+                            }
+                        }
+                    }
+                    LogCommand::Line(message) => {
+                        task_depth.pop();
+                        write_batch(&mut *sink, &mut batch, &room_name).await;
+                        let bursting = task_bursting.load(Ordering::Relaxed);
+                        if !bursting && burst_threshold.is_none_or(|t| task_depth.get() <= t) {
+                            if let Err(e) = sink.write_line(&message).await {
+                                tracing::error!(room = %room_name, error = %e, "error writing message");
+                            }
+                        } else {
+                            if !bursting {
+                                task_bursting.store(true, Ordering::Relaxed);
+                                tracing::warn!(room = %room_name, "transcript backlog, writing in bursts");
+                            }
+                            let mut lines = vec![message];
+                            while lines.len() < MAX_BURST_LINES {
+                                match rx.next().now_or_never() {
+                                    Some(Some(LogCommand::Line(line))) => {
+                                        task_depth.pop();
+                                        lines.push(line);
+                                    }
+                                    Some(Some(command)) => {
+                                        deferred = Some(command);
+                                        break;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            if let Err(e) = sink.write_lines(&lines).await {
+                                tracing::error!(room = %room_name, error = %e, "error writing messages");
+                            }
+                            if task_depth.get() == 0 {
+                                task_bursting.store(false, Ordering::Relaxed);
+                                tracing::info!(room = %room_name, "transcript caught up");
+                            }
+                        }
+                    }
+                    LogCommand::Flush(done) => {
+                        write_batch(&mut *sink, &mut batch, &room_name).await;
+                        flush_at = None;
+                        if let Err(e) = sink.flush().await {
+                            tracing::error!(room = %room_name, error = %e, "error flushing log");
+                        }
+                        let _ = done.send(());
+                    }
+                    LogCommand::Rotate(done) => {
+                        write_batch(&mut *sink, &mut batch, &room_name).await;
+                        if let Err(e) = sink.rotate().await {
+                            tracing::error!(room = %room_name, error = %e, "error rotating log");
+                        }
+                        let _ = done.send(());
                     }
                 }
+                if sink.is_healthy() != healthy {
+                    healthy = !healthy;
+                    task_degraded.store(!healthy, Ordering::Release);
+                    report_sink_health(healthy, &room_name, &task_users, notify_log_failure).await;
+                }
             }
-            if let Err(e) = log_writer.flush().await {
-                eprintln!(
-                    "Failed to write log for channel. Name: {}, Error: {}",
-                    file_name, e
-                );
+            write_batch(&mut *sink, &mut batch, &room_name).await;
+            if let Err(e) = sink.flush().await {
+                tracing::error!(room = %room_name, error = %e, "failed to write log for channel");
+            }
+            if let Some(done) = closed {
+                let _ = done.send(());
             }
         });
 
+        let membership_tx = config
+            .membership_snapshots
+            .clone()
+            .map(|snapshots| membership::spawn(snapshots, name.clone(), users.clone()));
+        let recent = Arc::default();
+        if let Some(budget) = &config.buffer_budget {
+            budget.register(&recent);
+        }
+        let history = config
+            .compressed_history
+            .map(|history| Mutex::new(CompressedHistory::new(history)));
+        let delivery_failures = config
+            .delivery_failure_log
+            .map(|capacity| Arc::new(DeliveryFailures::new(capacity)));
         ChatRoom {
             name,
             users,
+            limits: SyncRwLock::new(config.limits.clone()),
+            config,
+            log_path,
+            last_seq: Mutex::new(0),
+            recent,
+            history,
+            pinned: Mutex::default(),
+            origin: None,
+            reap_generation: AtomicU64::new(0),
+            last_activity: SyncRwLock::new(Instant::now()),
+            draining: AtomicBool::new(false),
+            next_user_id: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+            closing: Notify::new(),
+            log_ready: Mutex::new(Some(ready_rx)),
+            degraded,
+            log_bursting,
+            topics: Mutex::default(),
             logging_tx: tx,
+            log_depth,
+            membership_tx,
             cancellation_tx,
+            delivery_failures,
         }
     }
 
-    pub fn log_message(&self, msg: &str, user_id: usize) {
-        if self
-            .logging_tx
-            .send(format!("Channel {}, user {}: {}", &self.name, user_id, msg))
-            .is_err()
-        {
-            eprintln!(
-                "Failed to log message. Channel: {}, user: {}, message: {}",
-                self.name, user_id, msg
-            );
-        }
+    /// Assigns `msg` the room's next sequence number and queues it for the transcript, returning
+    /// the sequence number.
+    ///
+    /// The number is assigned and the record queued under one lock, so transcript lines are
+    /// always in sequence order even with concurrent senders.
+    pub fn log_message(&self, msg: &str, user_id: usize) -> u64 {
+        self.log_sequenced(Record::new(user_id, msg))
     }
 
-    pub fn broadcast(&self, msg: &str) {
-        
+    /// Like `log_message`, recording `from`'s nickname alongside their id.
+    pub fn log_message_as(&self, msg: &str, from: &Identity) -> u64 {
+        self.log_sequenced(logged_as(from, msg))
     }
-}
 
-impl Drop for ChatRoom {
-    fn drop(&mut self) {
-        if self.cancellation_tx.send(()).is_err() {
-            eprintln!("Failed to send cancel notice to logging task, log may be incomplete. Channel: {}", self.name);
+    /// Like `log_message_as`, for a private message to `to`, which is marked as such in the
+    /// transcript.
+    pub fn log_private_message(&self, msg: &str, from: &Identity, to: usize) -> u64 {
+        self.log_sequenced(Record {
+            to: Some(to),
+            ..logged_as(from, msg)
+        })
+    }
+
+    fn log_sequenced(&self, mut record: Record) -> u64 {
+        self.touch();
+        let mut last_seq = self.last_seq.lock().unwrap();
+        *last_seq += 1;
+        let seq = *last_seq;
+
+        if self.config.log_sequence {
+            record.seq = Some(seq);
         }
-        eprintln!("Channel destroyed: {}", self.name);
+        self.log_record(record);
+        seq
     }
-}
 
-async fn get_room(room_name: &str, rooms: ChatRooms) -> Arc<ChatRoom> {
-    rooms
-        .write()
-        .await
-        .retain(|_, room_ptr| room_ptr.strong_count() > 0); // lazily remove closed channels
+    /// Queues `record` for the transcript as it is, without a new sequence number.
+    fn log_record(&self, record: Record) {
+        if !self.queue_log(LogCommand::Line(record.to_line(&self.name))) {
+            tracing::error!(room = %self.name, user_id = record.user_id, bytes = record.message.len(), "failed to log message");
+        }
+    }
 
-    let maybe_room = if let Some(c) = rooms.read().await.get(room_name) {
-        c.upgrade()
-    } else {
-        None
-    };
-    match maybe_room {
-        Some(room) => {
-            eprintln!("channel reused: {}", room_name);
-            room
+    /// Queues `command` for the logging task, returning `false` if the task has gone or the
+    /// queue is full, in which case the drop is counted.
+    fn queue_log(&self, command: LogCommand) -> bool {
+        // Counted before sending so the logging task can never pop a line not yet pushed.
+        self.log_depth.push();
+        match self.logging_tx.try_send(command) {
+            Ok(()) => return true,
+            Err(mpsc::error::TrySendError::Full(_)) => metrics::count_log_line_dropped(),
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
         }
-        None => {
-            let room = Arc::new(ChatRoom::new(room_name.to_owned(), Users::default()).await);
-            rooms
-                .write()
-                .await
-                .insert(room_name.to_owned(), Arc::downgrade(&room));
-            eprintln!("channel created: {}", room_name);
-            room
+        self.log_depth.pop();
+        false
+    }
+
+    /// Records `identity` joining or leaving in the transcript, if the room logs presence, and
+    /// tells everyone else if it announces it. Also schedules a membership snapshot.
+    async fn presence(&self, event: SystemEvent, identity: &Identity) {
+        if let Some(membership_tx) = &self.membership_tx {
+            let _ = membership_tx.send(());
+        }
+        let logged = LogCommand::System(event, identity.logged_name());
+        if self.config.log_presence && !self.queue_log(logged) {
+            tracing::error!(room = %self.name, user_id = identity.id, "failed to log presence");
+        }
+        if self.config.announce_presence {
+            let notice = format!("{} {}", identity.display_name(), event.verb());
+            if event == SystemEvent::Joined && self.config.announce_own_join {
+                self.broadcast(&notice).await;
+            } else {
+                self.broadcast_except(&notice, identity.id).await;
+            }
         }
     }
-}
 
-async fn user_connected(ws: WebSocket, room: Arc<ChatRoom>) {
-    // Use a counter to assign a new unique ID for this user.
-    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+    /// Logs `msg` as sent by `user_id` and broadcasts it to everyone else in the room, or only to
+    /// subscribers of `topic` if it has one, returning its sequence number.
+    pub async fn post_message(
+        &self,
+        user_id: usize,
+        msg: &str,
+        appearance: Option<Appearance>,
+        topic: Option<String>,
+    ) -> u64 {
+        let timer = DeliveryTimer::start();
+        self.confirm_logging().await;
+        let name = timed_read(&self.users, "users")
+            .await
+            .get(&user_id)
+            .and_then(|user| user.identity.nick.clone());
+        let seq = self.log_sequenced(Record {
+            name: name.clone(),
+            ..Record::new(user_id, msg)
+        });
+        metrics::count_message();
+        if topic.is_none() {
+            self.buffer(RecentMessage {
+                seq,
+                from: user_id,
+                body: msg.to_owned(),
+                sent_at: SystemTime::now(),
+            });
+        }
+        let delivered = match &self.config.decoration {
+            Some(decoration) => Cow::Owned(decoration.render(msg, &self.name, topic.as_deref())),
+            None => Cow::Borrowed(msg),
+        };
+        let event = ChatEvent::Message {
+            seq,
+            from: user_id,
+            body: delivered.into_owned(),
+            appearance,
+            topic,
+            name,
+            ts: Some(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+        };
 
-    eprintln!("new chat user: {}", my_id);
+        // New message from this user, send it to everyone else (except same uid)...
+        fan_out_timed(&event, &self.users, Some(user_id), timer).await;
+        seq
+    }
 
-    // Split the socket into a sender and receive of messages.
-    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    /// Logs `action` as `from`'s emote and sends it to every other user, and back to `from` too
+    /// if the room echoes emotes, returning its sequence number.
+    ///
+    /// The transcript records it as it reads, `* User#5 waves`. Emotes aren't kept in the room's
+    /// recent messages, so users joining later don't see them.
+    pub async fn post_emote(&self, from: &Identity, action: &str) -> u64 {
+        let timer = DeliveryTimer::start();
+        self.confirm_logging().await;
+        let name = from.nick.as_deref();
+        let seq = self.log_message_as(&protocol::emote_text(from.id, name, action), from);
+        metrics::count_message();
+        let event = ChatEvent::Emote {
+            seq,
+            from: from.id,
+            action: action.to_owned(),
+            name: from.nick.clone(),
+            ts: Some(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+        };
+        let skip = Some(from.id).filter(|_| !self.config.echo_emotes);
+        fan_out_timed(&event, &self.users, skip, timer).await;
+        seq
+    }
 
-    // Use an unbounded channel to handle buffering and flushing of messages
-    // to the websocket...
-    let (tx, rx) = mpsc::unbounded_channel();
-    let mut rx = UnboundedReceiverStream::new(rx);
+    /// Adds `message` to the room's buffer, moving the oldest into the compressed history (or
+    /// dropping it) if it is full, and charges it to the buffer budget.
+    fn buffer(&self, message: RecentMessage) {
+        let added = buffered_bytes(&message);
+        let released = {
+            let mut recent = self.recent.lock().unwrap();
+            let capacity = RECENT_MESSAGES.max(self.config.join_history.unwrap_or(0));
+            let dropped = if recent.len() >= capacity {
+                recent.pop_front()
+            } else {
+                None
+            };
+            recent.push_back(message);
+            let released = dropped.as_ref().map_or(0, buffered_bytes);
+            // Moved while `recent` is still locked, so the history stays in order.
+            if let (Some(history), Some(dropped)) = (&self.history, dropped) {
+                history.lock().unwrap().push(dropped);
+            }
+            released
+        };
+        if let Some(budget) = &self.config.buffer_budget {
+            budget.release(released);
+            budget.charge(added);
+        }
+    }
 
-    tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            user_ws_tx
-                .send(message)
-                .unwrap_or_else(|e| {
-                    eprintln!("websocket send error: {}", e);
-                })
-                .await;
+    /// Buffers messages recovered from an earlier transcript, numbering any that weren't logged
+    /// with sequence numbers, and carries on numbering new messages after them.
+    fn seed(&self, records: Vec<Record>) {
+        let mut last_seq = self.last_seq.lock().unwrap();
+        for record in records {
+            // New users mustn't inherit the name of whoever had their id before.
+            self.next_user_id
+                .fetch_max(record.user_id + 1, Ordering::Relaxed);
+            let seq = record.seq.unwrap_or(*last_seq + 1);
+            *last_seq = (*last_seq).max(seq);
+            let sent_at =
+                humantime::parse_rfc3339(&record.timestamp).unwrap_or_else(|_| SystemTime::now());
+            self.buffer(RecentMessage {
+                seq,
+                from: record.user_id,
+                body: record.message,
+                sent_at,
+            });
         }
-    });
+    }
+
+    /// The latest `limit` messages still in the room's buffer or compressed history, oldest
+    /// first. Messages sent to a topic aren't buffered.
+    pub fn recent_messages(&self, limit: usize) -> Vec<RecentMessage> {
+        let recent = self.recent.lock().unwrap();
+        let mut messages = match &self.history {
+            Some(history) if limit > recent.len() => {
+                history.lock().unwrap().latest(limit - recent.len())
+            }
+            _ => Vec::new(),
+        };
+        messages.extend(
+            recent
+                .iter()
+                .skip(recent.len().saturating_sub(limit))
+                .cloned(),
+        );
+        messages
+    }
 
-    // Save the sender in our list of connected users.
-    room.users.write().await.insert(my_id, tx);
+    /// Bytes held by the room's compressed history, 0 if it keeps none.
+    pub fn compressed_history_bytes(&self) -> usize {
+        self.history
+            .as_ref()
+            .map_or(0, |history| history.lock().unwrap().compressed_bytes())
+    }
 
-    // Return a `Future` that is basically a state machine managing
-    // this specific user's connection.
+    /// Pins recent message `seq` and tells everyone, returning `false` if it is too old to pin,
+    /// was sent to a topic, or doesn't exist. Replaces any earlier pin.
+    pub async fn pin(&self, seq: u64) -> bool {
+        let pinned = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|recent| recent.seq == seq)
+            .map(|recent| (recent.from, recent.body.clone()));
+        let (from, body) = match pinned {
+            Some(pinned) => pinned,
+            None => return false,
+        };
+        let name = timed_read(&self.users, "users")
+            .await
+            .get(&from)
+            .and_then(|user| user.identity.nick.clone());
+        let pinned = ChatEvent::Pinned {
+            seq,
+            from,
+            body,
+            name,
+        };
+        *self.pinned.lock().unwrap() = Some(pinned.clone());
+        fan_out(&pinned, &self.users, None).await;
+        true
+    }
 
-    // Every time the user sends a message, broadcast it to
-    // all other users...
-    while let Some(result) = user_ws_rx.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("websocket error(uid={}): {}", my_id, e);
-                break;
+    /// Unpins the pinned message and tells everyone, returning `false` if nothing was pinned.
+    pub async fn unpin(&self) -> bool {
+        let seq = match self.pinned.lock().unwrap().take() {
+            Some(ChatEvent::Pinned { seq, .. }) => seq,
+            _ => return false,
+        };
+        fan_out(&ChatEvent::Unpinned { seq }, &self.users, None).await;
+        true
+    }
+
+    /// Removes user `user_id` from the room and closes their connection for `reason`, returning
+    /// `false` if they aren't in the room.
+    pub async fn disconnect(&self, user_id: usize, reason: DisconnectReason) -> bool {
+        let user = match self.users.write().await.remove(&user_id) {
+            Some(user) => user,
+            None => return false,
+        };
+        tracing::info!(room = %self.name, user_id, reason = %reason.reason(), "disconnecting user");
+        user.hang_up(reason, self.config.app_close_codes);
+        true
+    }
+
+    /// Replaces the text of message `seq` with `body`, if `from` sent it within the room's edit
+    /// window, and tells everyone else. The transcript gains an edit record; the original line is
+    /// kept.
+    pub async fn edit_message(
+        &self,
+        from: &Identity,
+        seq: u64,
+        body: &str,
+    ) -> Result<(), ChangeRefused> {
+        let replaced = {
+            let mut recent = self.recent.lock().unwrap();
+            let index = self.changeable(&recent, from.id, seq)?;
+            mem::replace(&mut recent[index].body, body.to_owned())
+        };
+        if let Some(budget) = &self.config.buffer_budget {
+            budget.release(replaced.len());
+            budget.charge(body.len());
+        }
+        if let Some(ChatEvent::Pinned {
+            seq: pinned,
+            body: pinned_body,
+            ..
+        }) = &mut *self.pinned.lock().unwrap()
+        {
+            if *pinned == seq {
+                *pinned_body = body.to_owned();
             }
+        }
+        self.log_record(logged_as(
+            from,
+            &format!("*** edited message {}: {} ***", seq, body),
+        ));
+        let delivered = match &self.config.decoration {
+            Some(decoration) => decoration.render(body, &self.name, None),
+            None => body.to_owned(),
         };
-        // Skip any non-Text messages, logging any errors
-        if msg.is_text() {
-            match msg.to_str() {
-                Ok(s) => {
-                    room.log_message(s, my_id);
-                    user_message(my_id, s, &room.users).await;
-                }
-                Err(_) => {
-                    room.log_message("!!!ATTEMPTED TO SEND NON-TEXT MESSAGE!!!", my_id);
-                }
+        let event = ChatEvent::Edit {
+            seq,
+            from: from.id,
+            body: delivered,
+            name: from.nick.clone(),
+        };
+        fan_out(&event, &self.users, Some(from.id)).await;
+        Ok(())
+    }
+
+    /// Removes message `seq`, if `from` sent it within the room's edit window, and tells everyone
+    /// else. The transcript gains a deletion record; the original line is kept.
+    pub async fn delete_message(&self, from: &Identity, seq: u64) -> Result<(), ChangeRefused> {
+        let removed = {
+            let mut recent = self.recent.lock().unwrap();
+            let index = self.changeable(&recent, from.id, seq)?;
+            recent.remove(index)
+        };
+        if let (Some(budget), Some(removed)) = (&self.config.buffer_budget, removed) {
+            budget.release(buffered_bytes(&removed));
+        }
+        {
+            let mut pinned = self.pinned.lock().unwrap();
+            if matches!(&*pinned, Some(ChatEvent::Pinned { seq: pinned, .. }) if *pinned == seq) {
+                *pinned = None;
             }
         }
+        self.log_record(logged_as(from, &format!("*** deleted message {} ***", seq)));
+        let event = ChatEvent::Delete {
+            seq,
+            from: from.id,
+            name: from.nick.clone(),
+        };
+        fan_out(&event, &self.users, Some(from.id)).await;
+        Ok(())
     }
 
-    // user_ws_rx stream will keep processing as long as the user stays
-    // connected. Once they disconnect, then...
-    user_disconnected(my_id, &room.users).await;
-}
+    /// Where message `seq` is in `recent`, if `user_id` may still change it.
+    fn changeable(
+        &self,
+        recent: &VecDeque<RecentMessage>,
+        user_id: usize,
+        seq: u64,
+    ) -> Result<usize, ChangeRefused> {
+        let index = recent
+            .iter()
+            .position(|message| message.seq == seq)
+            .ok_or(ChangeRefused::NotFound)?;
+        let message = &recent[index];
+        if message.from != user_id {
+            return Err(ChangeRefused::NotOwner);
+        }
+        let window = self.config.edit_window.ok_or(ChangeRefused::TooLate)?;
+        // A clock that went backwards counts as no time passing.
+        if message.sent_at.elapsed().is_ok_and(|age| age > window) {
+            return Err(ChangeRefused::TooLate);
+        }
+        Ok(index)
+    }
 
-async fn user_message(my_id: usize, msg: &str, users: &Users) {
-    let new_msg = format!("<User#{}>: {}", my_id, msg);
+    /// What the room's classification requires of its transcripts, if it has one.
+    pub fn retention(&self) -> Option<RetentionPolicy> {
+        self.config
+            .classification
+            .map(|classification| classification.retention())
+    }
 
-    // New message from this user, send it to everyone else (except same uid)...
-    for (&uid, tx) in users.read().await.iter() {
-        if my_id != uid {
-            if let Err(_disconnected) = tx.send(Message::text(new_msg.clone())) {
-                // The tx is disconnected, our `user_disconnected` code
-                // should be happening in another task, nothing more to
-                // do here.
-            }
+    /// The pinned message, if there is one.
+    pub fn pinned(&self) -> Option<ChatEvent> {
+        self.pinned.lock().unwrap().clone()
+    }
+
+    /// The latest failed deliveries to the room's users, if the room keeps them.
+    pub fn delivery_failures(&self) -> Option<&DeliveryFailures> {
+        self.delivery_failures.as_deref()
+    }
+
+    /// Waits for the logging task to open its sink the first time it is called, marking the room
+    /// degraded (and telling its users, if configured) if that failed.
+    async fn confirm_logging(&self) {
+        let ready = match self.log_ready.lock().unwrap().take() {
+            Some(ready) => ready,
+            None => return,
+        };
+        let error = match ready.await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "logging task stopped".to_owned(),
+        };
+        self.degraded.store(true, Ordering::Release);
+        tracing::warn!(room = %self.name, error = %error, "channel degraded, transcript unavailable");
+        if self.config.notify_log_failure {
+            self.broadcast("this room's transcript is unavailable, messages are not being saved")
+                .await;
         }
     }
-}
 
-async fn user_disconnected(my_id: usize, users: &Users) {
-    eprintln!("good bye user: {}", my_id);
+    /// Whether the room's transcript couldn't be opened, which is only known once a message is
+    /// posted, or its writes are failing.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Acquire)
+    }
 
-    // Stream closed up, so remove from the user list
-    users.write().await.remove(&my_id);
+    /// Where the room's logged messages can be read back from: the configured message store, or
+    /// else the room's transcript file. `None` if the room has neither.
+    pub fn message_store(&self) -> Option<Arc<dyn MessageStore>> {
+        if let Some(store) = &self.config.message_store {
+            return Some(store.clone());
+        }
+        let path = self.log_path.clone()?;
+        Some(Arc::new(FileStore::new(
+            path,
+            self.config.transcript_format,
+        )))
+    }
+
+    /// Waits until every message logged so far has been written out to the room's sink.
+    pub async fn flush_log(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self
+            .logging_tx
+            .send(LogCommand::Flush(done_tx))
+            .await
+            .is_err()
+            || done_rx.await.is_err()
+        {
+            tracing::error!(room = %self.name, "failed to flush log");
+        }
+    }
+
+    /// Writes out and flushes everything logged so far, then stops the logging task, for shutting
+    /// down without losing buffered transcript lines. Anything logged afterwards is dropped.
+    pub async fn close_log(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.cancellation_tx.send(Some(done_tx)).is_err() || done_rx.await.is_err() {
+            tracing::error!(room = %self.name, "failed to close log");
+        }
+    }
+
+    /// Starts a new transcript segment, once every line queued before the call is written. Lines
+    /// queued meanwhile wait for the new segment rather than being lost.
+    pub async fn rotate_log(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self
+            .logging_tx
+            .send(LogCommand::Rotate(done_tx))
+            .await
+            .is_err()
+            || done_rx.await.is_err()
+        {
+            tracing::error!(room = %self.name, "failed to rotate log");
+        }
+    }
+
+    /// Transcript lines waiting for the logging task.
+    pub fn log_queue_depth(&self) -> usize {
+        self.log_depth.get()
+    }
+
+    /// Whether the logging task is working through a backlog in bursts rather than line by line.
+    pub fn is_log_bursting(&self) -> bool {
+        self.log_bursting.load(Ordering::Relaxed)
+    }
+
+    pub fn limits(&self) -> RoomLimits {
+        self.limits.read().unwrap().clone()
+    }
+
+    /// Replaces the room's limits, taking effect for subsequent joins and messages.
+    pub fn set_limits(&self, limits: RoomLimits) -> Result<(), InvalidLimits> {
+        limits.validate()?;
+        *self.limits.write().unwrap() = limits;
+        Ok(())
+    }
+
+    /// Cancels any linger reap pending for this room. Called with the rooms lock held.
+    fn revive(&self) {
+        self.reap_generation.fetch_add(1, Ordering::AcqRel);
+        self.touch();
+    }
+
+    /// Records activity in the room, putting off its idle reap.
+    fn touch(&self) {
+        *self.last_activity.write().unwrap() = Instant::now();
+    }
+
+    /// How long since the room was last joined, written to or broadcast to.
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.read().unwrap().elapsed()
+    }
+
+    /// How the room was created, if its config records origins.
+    pub fn origin(&self) -> Option<&RoomOrigin> {
+        self.origin.as_ref()
+    }
+
+    /// Takes an id for a user joining the room, unique within it.
+    pub fn next_user_id(&self) -> usize {
+        self.next_user_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Whether the room has been closed, see `close_room`.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Whether the room is being drained, see `drain_room`.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Subscribes `user` to `topics`, returning the topics refused because the room already has
+    /// `max_topics` active ones.
+    pub fn subscribe<'a>(
+        &self,
+        user: &User,
+        topics: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<String> {
+        let mut active = self.topics.lock().unwrap();
+        let mut subscribed = user.topics.write().unwrap();
+        let mut refused = Vec::new();
+        for topic in topics {
+            if subscribed.contains(topic) {
+                continue;
+            }
+            let at_cap = self
+                .config
+                .max_topics
+                .is_some_and(|max| active.len() >= max);
+            if at_cap && !active.contains_key(topic) {
+                refused.push(topic.to_owned());
+                continue;
+            }
+            *active.entry(topic.to_owned()).or_insert(0) += 1;
+            subscribed.insert(topic.to_owned());
+        }
+        refused
+    }
+
+    /// Unsubscribes `user` from `topics`, forgetting topics left without subscribers.
+    pub fn unsubscribe<'a>(&self, user: &User, topics: impl IntoIterator<Item = &'a str>) {
+        let mut active = self.topics.lock().unwrap();
+        let mut subscribed = user.topics.write().unwrap();
+        for topic in topics {
+            if !subscribed.remove(topic) {
+                continue;
+            }
+            if let Some(count) = active.get_mut(topic) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(topic);
+                }
+            }
+        }
+    }
+
+    /// Number of topics with at least one subscriber.
+    pub fn active_topics(&self) -> usize {
+        self.topics.lock().unwrap().len()
+    }
+
+    /// Whether a message may be posted in `topic` without exceeding `max_topics`.
+    fn accepts_topic(&self, topic: &str) -> bool {
+        let active = self.topics.lock().unwrap();
+        active.contains_key(topic) || self.config.max_topics.is_none_or(|max| active.len() < max)
+    }
+
+    /// Sends every user in the room a notice.
+    ///
+    /// This is where the room's own messages to its users go through; chat messages are fanned
+    /// out as `ChatEvent::Message`s instead, since recipients render them differently. It is
+    /// `async` because it takes the users' read lock itself, so callers must not hold it.
+    pub async fn broadcast(&self, msg: &str) {
+        self.broadcast_where(|_| true, msg).await
+    }
+
+    /// Sends a notice to every user in the room but `skip_uid`, e.g. the user it is about.
+    pub async fn broadcast_except(&self, msg: &str, skip_uid: usize) {
+        let event = ChatEvent::Notice {
+            body: msg.to_owned(),
+        };
+        self.touch();
+        fan_out(&event, &self.users, Some(skip_uid)).await;
+    }
+
+    /// Sends a notice to the users whose identity matches `pred`, e.g. only the room's admins.
+    pub async fn broadcast_where<P>(&self, pred: P, msg: &str)
+    where
+        P: Fn(&Identity) -> bool,
+    {
+        let event = ChatEvent::Notice {
+            body: msg.to_owned(),
+        };
+        self.touch();
+        deliver(&event, &self.users, None, |_, user| pred(&user.identity)).await;
+    }
+}
+
+impl Drop for ChatRoom {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.config.buffer_budget {
+            let recent = mem::take(&mut *self.recent.lock().unwrap());
+            budget.release(recent.iter().map(buffered_bytes).sum());
+        }
+        // The logging task has already finished if the log was closed.
+        if !self.cancellation_tx.is_closed() && self.cancellation_tx.send(None).is_err() {
+            tracing::error!(room = %self.name, "failed to send cancel notice to logging task, log may be incomplete");
+        }
+        tracing::info!(room = %self.name, "channel destroyed");
+    }
+}
+
+/// A transcript record of `message` from `from`, carrying their nickname if they have one.
+fn logged_as(from: &Identity, message: &str) -> Record {
+    Record {
+        name: from.nick.clone(),
+        ..Record::new(from.id, message)
+    }
+}
+
+/// Looks up a room that is still open.
+pub async fn find_room(room_name: &str, rooms: &ChatRooms) -> Option<Arc<ChatRoom>> {
+    rooms.get(room_name).await.as_ref().and_then(Weak::upgrade)
+}
+
+/// Removes every room that nobody is in, along with the entries of rooms already dropped,
+/// returning how many were removed.
+///
+/// Empty rooms go whether or not their linger has run out, and even if something still holds
+/// them, so their transcripts are closed and flushed here rather than when they're dropped.
+pub async fn reap_rooms(rooms: &ChatRooms) -> usize {
+    let dropped = rooms.reap().await;
+    let idle = rooms.remove_idle(Duration::ZERO).await;
+    future::join_all(idle.iter().map(|room| room.close_log())).await;
+    dropped + idle.len()
+}
+
+/// Finds the room called `room_name`, creating it if it doesn't exist.
+///
+/// Once `config.shutdown` is raised no room is created, and live rooms are only handed out if
+/// `config.shutdown_policy` allows it. A room whose transcript can't be opened isn't created
+/// either, unless `config.allow_degraded` is set.
+async fn get_room(
+    room_name: &str,
+    rooms: ChatRooms,
+    config: &RoomConfig,
+    origin: Option<RoomOrigin>,
+) -> Result<Arc<ChatRoom>, Unavailable> {
+    // Look up and create under the room's shard lock, so concurrent joins can't create duplicate
+    // rooms and a join always revives a lingering room before its reaper can decide to drop it.
+    let mut rooms = rooms.write_shard(room_name).await;
+    reap_shard(&mut rooms); // lazily remove closed channels
+    let shutting_down = config.shutdown.has_begun();
+    match rooms.get(room_name).and_then(Weak::upgrade) {
+        Some(_) if shutting_down && config.shutdown_policy == ShutdownPolicy::RefuseAll => {
+            tracing::info!(room = %room_name, "shutting down, refused to join channel");
+            Err(Unavailable::ShuttingDown)
+        }
+        Some(room) if room.is_draining() => {
+            tracing::info!(room = %room_name, "draining, refused to join channel");
+            Err(Unavailable::Draining)
+        }
+        Some(room) => {
+            room.revive();
+            tracing::info!(room = %room_name, "channel reused");
+            Ok(room)
+        }
+        None if shutting_down => {
+            tracing::info!(room = %room_name, "shutting down, refused to create channel");
+            Err(Unavailable::ShuttingDown)
+        }
+        None => {
+            if let Some(Err(wait)) = config
+                .room_creation_rate
+                .as_ref()
+                .map(|rate| rate.try_take())
+            {
+                tracing::warn!(room = %room_name, "room creation throttled, refused to create channel");
+                return Err(Unavailable::Throttled(wait));
+            }
+            let users = Users::default();
+            let mut room = if config.allow_degraded {
+                ChatRoom::with_config(room_name.to_owned(), users, config.clone()).await
+            } else {
+                // Opening the transcript holds up the shard, but only until its file is created.
+                match ChatRoom::open(room_name.to_owned(), users, config.clone()).await {
+                    Ok(room) => room,
+                    Err(e) => {
+                        tracing::error!(room = %room_name, error = %e, "failed to create log for channel, refused to create it");
+                        return Err(Unavailable::NoTranscript);
+                    }
+                }
+            };
+            room.origin = origin.filter(|_| config.record_origins);
+            let room = Arc::new(room);
+            rooms.insert(room_name.to_owned(), Arc::downgrade(&room));
+            metrics::count_room_created();
+            tracing::info!(room = %room_name, "channel created");
+            Ok(room)
+        }
+    }
+}
+
+/// Takes a room offline for maintenance, returning `false` if it is already draining.
+///
+/// From now on the room refuses joins and messages, and its users are told it is closing. Once
+/// the room's drain grace has passed, it is removed from `rooms` and anyone still in it is sent a
+/// close frame.
+pub async fn drain_room(room: Arc<ChatRoom>, rooms: ChatRooms) -> bool {
+    if room.draining.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    let grace = room.config.drain_grace.unwrap_or(DEFAULT_DRAIN_GRACE);
+    tracing::info!(room = %room.name, grace = ?grace, "channel draining");
+    let notice = ChatEvent::Notice {
+        body: format!(
+            "room is closing for maintenance in {}",
+            humantime::format_duration(grace)
+        ),
+    };
+    fan_out(&notice, &room.users, None).await;
+
+    tokio::task::spawn(async move {
+        tokio::time::sleep(grace).await;
+        {
+            let mut rooms = rooms.write_shard(&room.name).await;
+            let room_ptr = Arc::downgrade(&room);
+            if rooms
+                .get(&room.name)
+                .is_some_and(|ptr| ptr.ptr_eq(&room_ptr))
+            {
+                rooms.remove(&room.name);
+            }
+        }
+        let remaining: Vec<User> = room
+            .users
+            .write()
+            .await
+            .drain()
+            .map(|(_, user)| user)
+            .collect();
+        for user in &remaining {
+            user.hang_up(DisconnectReason::Drained, room.config.app_close_codes);
+        }
+        tracing::info!(room = %room.name, disconnected = remaining.len(), "drained channel closed");
+    });
+    true
+}
+
+/// Closes a room at once, returning how many users were disconnected, or `None` if it was
+/// already closed.
+///
+/// The room is removed from `rooms`, so the next join creates a new one, and its users are told
+/// and sent a close frame. Their connections stop reading without waiting for the client to
+/// answer, so the room is dropped, flushing its transcript, as soon as they have all left.
+/// Multiplexed connections aren't closed, but let go of the room as soon as it closes and stay
+/// open for their other rooms.
+pub async fn close_room(room: Arc<ChatRoom>, rooms: ChatRooms) -> Option<usize> {
+    if room.closed.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    {
+        let mut rooms = rooms.write_shard(&room.name).await;
+        let room_ptr = Arc::downgrade(&room);
+        if rooms
+            .get(&room.name)
+            .is_some_and(|ptr| ptr.ptr_eq(&room_ptr))
+        {
+            rooms.remove(&room.name);
+        }
+    }
+    let notice = ChatEvent::Notice {
+        body: "this room has been closed".to_owned(),
+    };
+    fan_out(&notice, &room.users, None).await;
+    let users: Vec<User> = room
+        .users
+        .write()
+        .await
+        .drain()
+        .map(|(_, user)| user)
+        .collect();
+    for user in users.iter().filter(|user| user.protocol != Protocol::MuxV1) {
+        user.disconnect(DisconnectReason::RoomClosed, room.config.app_close_codes);
+    }
+    room.closing.notify_waiters();
+    tracing::info!(room = %room.name, disconnected = users.len(), "channel closed");
+    Some(users.len())
+}
+
+/// Keeps a room that has just emptied alive for its configured linger, so that a quick reconnect
+/// reuses it (and its transcript) instead of creating a new one.
+///
+/// The reap is cancelled if the room is revived by `get_room` in the meantime. Both sides decide
+/// under the room's shard lock, so a reconnect either gets the intact room or a brand new one.
+fn linger(room: Arc<ChatRoom>, rooms: ChatRooms) {
+    let linger = match room.config.linger {
+        Some(linger) => linger,
+        None => return,
+    };
+    let generation = room.reap_generation.load(Ordering::Acquire);
+    tokio::task::spawn(async move {
+        tokio::time::sleep(linger).await;
+
+        let mut rooms = rooms.write_shard(&room.name).await;
+        let revived = room.reap_generation.load(Ordering::Acquire) != generation;
+        // Anyone else holding the room (e.g. a join still mid-handshake) keeps it alive too.
+        if revived || Arc::strong_count(&room) > 1 {
+            return;
+        }
+        let room_ptr = Arc::downgrade(&room);
+        if rooms
+            .get(&room.name)
+            .is_some_and(|ptr| ptr.ptr_eq(&room_ptr))
+        {
+            rooms.remove(&room.name);
+        }
+        tracing::info!(room = %room.name, "lingering channel reaped");
+        // Drop the last reference while the lock is still held.
+        drop(room);
+    });
+}
+
+async fn user_connected(
+    ws: WebSocket,
+    room: Arc<ChatRoom>,
+    rooms: ChatRooms,
+    protocol: Protocol,
+    addr: Option<SocketAddr>,
+    account: Option<String>,
+) {
+    let _open = metrics::OpenConnection::new();
+    // Use the room's counter to assign this user an ID unique within it.
+    let my_id = room.next_user_id();
+    // Everything logged while serving the connection, its writer task included, is in its span.
+    let span = tracing::info_span!("connection", user_id = my_id, room = %room.name);
+    async move {
+        tracing::info!(user_id = my_id, room = %room.name, "new chat user");
+        let identity = Identity {
+            account,
+            ..Identity::new(my_id)
+        };
+
+        // Split the socket into a sender and receive of messages.
+        let (user_ws_tx, mut user_ws_rx) = ws.split();
+        let me = User {
+            info: Arc::new(ConnectionInfo::new(addr)),
+            ..forward_to_socket(user_ws_tx, protocol, &room.config)
+        };
+
+        if protocol == Protocol::MuxV1 {
+            mux::serve(identity, me, user_ws_rx, room, rooms).await;
+            return;
+        }
+
+        // Save the sender in our list of connected users, unless the room is full.
+        let mut conn = match Connection::join(room, me, identity).await {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        read_frames(&mut conn, &mut user_ws_rx).await;
+
+        // user_ws_rx stream will keep processing as long as the user stays
+        // connected. Once they disconnect, then...
+        conn.leave(rooms).await;
+    }
+    .instrument(span)
+    .await
+}
+
+/// Closes a just-upgraded connection the server is too busy to serve, first telling the client
+/// how long to wait before retrying.
+pub(crate) async fn refuse_busy(
+    mut ws: WebSocket,
+    protocol: Protocol,
+    retry: Duration,
+    app_codes: bool,
+) {
+    let notice = ChatEvent::Notice {
+        body: format!("server busy, {}", retry_hint(retry)),
+    };
+    let _ = ws.send(protocol.encode(&notice)).await;
+    let _ = ws.send(DisconnectReason::Busy.close_frame(app_codes)).await;
+    let _ = ws.close().await;
+}
+
+/// `retry in 5s`, rounded up to whole seconds.
+fn retry_hint(retry: Duration) -> String {
+    format!(
+        "retry in {}",
+        humantime::format_duration(Duration::from_secs(retry_secs(retry)))
+    )
+}
+
+/// Handles a connection's inbound frames until it closes, errors or is disconnected.
+async fn read_frames<S>(conn: &mut Connection, frames_rx: &mut S)
+where
+    S: Stream<Item = Result<Message, warp::Error>> + Unpin,
+{
+    let my_id = conn.identity.id;
+    let config = &conn.room.config;
+    let (idle_timeout, app_codes) = (config.idle_timeout, config.app_close_codes);
+    let expires = config
+        .max_connection_lifetime
+        .map(|lifetime| Instant::now() + lifetime);
+    let away_after = config.away_after;
+    let mut pinger = config
+        .keepalive
+        .map(|keepalive| Pinger::new(keepalive, conn.me.clone()));
+
+    // Every time the user sends a message, broadcast it to
+    // all other users...
+    let mut frames = config.max_frames_per_sec.map(FrameLimiter::new);
+    let mut last_frame = Instant::now();
+    loop {
+        // Both count from the last frame, so going away doesn't put off the idle timeout.
+        let idle_left = idle_timeout
+            .map(|timeout| (last_frame + timeout).saturating_duration_since(Instant::now()));
+        let away_at = away_after
+            .filter(|_| conn.identity.status == Status::Active)
+            .map(|after| last_frame + after);
+        // Created before checking, so a close between the two still wakes it.
+        let closing = conn.room.closing.notified();
+        if conn.room.is_closed() || conn.me.cancellation.is_cancelled() {
+            break;
+        }
+        let frame = tokio::select! {
+            frame = next_frame(frames_rx, idle_left, expires, pinger.as_mut()) => frame,
+            _ = sleep_until_some(away_at) => {
+                conn.set_status(Status::Away).await;
+                continue;
+            }
+            _ = closing => break,
+            _ = conn.me.cancellation.cancelled() => break,
+        };
+        let msg = match frame {
+            Frame::Received(Ok(msg)) => msg,
+            Frame::Received(Err(e)) => {
+                tracing::warn!(user_id = my_id, error = %e, "websocket error, disconnecting user");
+                conn.me
+                    .disconnect(DisconnectReason::ProtocolError, app_codes);
+                break;
+            }
+            Frame::Closed => break,
+            Frame::Idle => {
+                tracing::info!(user_id = my_id, "idle, disconnecting user");
+                conn.me.disconnect(DisconnectReason::Idle, app_codes);
+                break;
+            }
+            Frame::Expired => {
+                tracing::info!(
+                    user_id = my_id,
+                    "connection lifetime reached, disconnecting user"
+                );
+                conn.me.disconnect(DisconnectReason::Expired, app_codes);
+                break;
+            }
+            Frame::Unresponsive => {
+                tracing::info!(user_id = my_id, "no pong, disconnecting user");
+                conn.me
+                    .disconnect(DisconnectReason::Unresponsive, app_codes);
+                break;
+            }
+        };
+        last_frame = Instant::now();
+        if conn.identity.status == Status::Away {
+            conn.set_status(Status::Active).await;
+        }
+        if !allow_frame(&mut frames, &conn.me, my_id, app_codes) {
+            break;
+        }
+        // Control frames are handled by warp, anything else is checked against the room's policy.
+        if let Ok(s) = msg.to_str() {
+            conn.handle_frame(s).await;
+        } else if msg.is_binary() {
+            conn.handle_binary(msg.as_bytes()).await;
+        }
+    }
+}
+
+/// What waiting for a connection's next frame turned up.
+enum Frame {
+    Received(Result<Message, warp::Error>),
+    Closed,
+    /// Nothing arrived within the idle timeout.
+    Idle,
+    /// The connection's lifetime ran out first.
+    Expired,
+    /// A keepalive ping went unanswered.
+    Unresponsive,
+}
+
+/// Keepalive pings for one connection. Only one ping is outstanding at a time.
+struct Pinger {
+    keepalive: Keepalive,
+    me: User,
+    /// When the next ping is due, or when the outstanding one times out.
+    deadline: Instant,
+    awaiting_pong: bool,
+}
+
+impl Pinger {
+    fn new(keepalive: Keepalive, me: User) -> Pinger {
+        Pinger {
+            keepalive,
+            me,
+            deadline: Instant::now() + keepalive.interval,
+            awaiting_pong: false,
+        }
+    }
+
+    fn pong(&mut self) {
+        if self.awaiting_pong {
+            self.awaiting_pong = false;
+            self.deadline = Instant::now() + self.keepalive.interval;
+        }
+    }
+
+    /// Called at the deadline: sends the next ping, or returns `false` if the last one went
+    /// unanswered.
+    fn tick(&mut self) -> bool {
+        if self.awaiting_pong {
+            return false;
+        }
+        if !self.me.ping() {
+            // The queue is full, which the room's slow consumer policy deals with, so the ping
+            // is tried again later rather than counted as unanswered.
+            self.deadline = Instant::now() + self.keepalive.interval;
+            return true;
+        }
+        self.awaiting_pong = true;
+        self.deadline = Instant::now() + self.keepalive.timeout;
+        true
+    }
+}
+
+/// Waits for the connection's next frame, keeping it pinged meanwhile if `pinger` is given. Pongs
+/// are taken by the pinger rather than returned, so they don't count as activity towards the idle
+/// timeout or against the frame limit.
+async fn next_frame<S>(
+    frames_rx: &mut S,
+    idle_timeout: Option<Duration>,
+    expires: Option<Instant>,
+    mut pinger: Option<&mut Pinger>,
+) -> Frame
+where
+    S: Stream<Item = Result<Message, warp::Error>> + Unpin,
+{
+    let idle_at = idle_timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let ping_at = pinger.as_ref().map(|pinger| pinger.deadline);
+        tokio::select! {
+            next = frames_rx.next() => match (next, pinger.as_mut()) {
+                (Some(Ok(msg)), Some(pinger)) if msg.is_pong() => pinger.pong(),
+                (next, _) => return next.map_or(Frame::Closed, Frame::Received),
+            },
+            _ = sleep_until_some(idle_at) => return Frame::Idle,
+            _ = sleep_until_some(expires) => return Frame::Expired,
+            _ = sleep_until_some(ping_at) => {
+                if pinger.as_mut().is_some_and(|pinger| !pinger.tick()) {
+                    return Frame::Unresponsive;
+                }
+            }
+        }
+    }
+}
+
+/// When lines written now should be flushed by, if they are flushed on a timer at all.
+fn flush_deadline(every: Option<Duration>) -> Option<tokio::time::Instant> {
+    every.map(|every| tokio::time::Instant::now() + every)
+}
+
+/// Waits until `deadline`, or forever without one.
+async fn sleep_until_some(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+/// The user `target` names: the one whose nickname it is, or failing that the one with the id it
+/// gives, as `7` or `User#7`. Nicknames are unique within a room, so at most one user matches.
+fn find_user<'a>(users: &'a HashMap<usize, User>, target: &str) -> Option<&'a User> {
+    if let Some(user) = users
+        .values()
+        .find(|user| user.identity.nick.as_deref() == Some(target))
+    {
+        return Some(user);
+    }
+    let id = target
+        .strip_prefix("User#")
+        .unwrap_or(target)
+        .parse()
+        .ok()?;
+    users.get(&id)
+}
+
+/// `name` with every character that can't appear in a file name on common filesystems replaced by
+/// `_`, for naming a room's transcript.
+fn file_safe(name: &str) -> Cow<'_, str> {
+    let unsafe_char = |c: char| {
+        c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|')
+    };
+    if name.contains(unsafe_char) {
+        Cow::Owned(name.replace(unsafe_char, "_"))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// The latest `limit` messages to the whole room in the newest transcript in `dir` of a room
+/// named `name`, oldest first. Empty if there is no such transcript or it can't be read.
+async fn recover_messages(
+    name: &str,
+    dir: &Path,
+    format: TranscriptFormat,
+    limit: usize,
+) -> Vec<Record> {
+    let prefix = format!("{}_", file_safe(name));
+    let suffix = format!(".{}", format.extension());
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    // Matching on the timestamp keeps room `a` from picking up room `a_b`'s transcripts.
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let created = entry
+            .file_name()
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(&prefix)?.strip_suffix(&suffix))
+            .and_then(|stamp| humantime::parse_rfc3339(stamp).ok());
+        if let Some(created) = created {
+            if newest.as_ref().is_none_or(|(newest, _)| created > *newest) {
+                newest = Some((created, entry.path()));
+            }
+        }
+    }
+    let path = match newest {
+        Some((_, path)) => path,
+        None => return Vec::new(),
+    };
+    let records = match tokio::fs::File::open(&path).await {
+        Ok(file) => transcript::records_in(file, format).try_collect().await,
+        Err(e) => Err(e),
+    };
+    let mut records: Vec<Record> = match records {
+        Ok(records) => records,
+        Err(e) => {
+            tracing::error!(room = %name, transcript = ?path, error = %e, "failed to recover history");
+            return Vec::new();
+        }
+    };
+    records.retain(|record| !record.is_private() && !record.is_notice());
+    let skip = records.len().saturating_sub(limit);
+    records.drain(..skip);
+    records
+}
+
+/// Reopens a failing transcript for appending, at `path` if it can be and otherwise at
+/// `fallback`. Reopened transcripts aren't rotated.
+fn reopen_transcript(path: PathBuf, fallback: Option<PathBuf>, format: TranscriptFormat) -> Reopen {
+    Box::new(move || {
+        let (path, fallback) = (path.clone(), fallback.clone());
+        Box::pin(async move {
+            let e = match append_transcript(&path, format).await {
+                Ok(sink) => return Ok(sink),
+                Err(e) => e,
+            };
+            match fallback {
+                Some(fallback) => {
+                    tracing::warn!(transcript = ?path, fallback = ?fallback, error = %e, "failed to reopen transcript, falling back");
+                    append_transcript(&fallback, format).await
+                }
+                None => Err(e),
+            }
+        })
+    })
+}
+
+async fn append_transcript(path: &Path, format: TranscriptFormat) -> io::Result<Box<dyn LogSink>> {
+    Ok(match format {
+        TranscriptFormat::Text => Box::new(FileSink::append(path).await?),
+        TranscriptFormat::Binary => Box::new(BinaryFileSink::append(path).await?),
+        TranscriptFormat::Json => Box::new(JsonFileSink::append(path).await?),
+    })
+}
+
+/// Logs a room's transcript failing or recovering, telling its users too if `notify` is set.
+async fn report_sink_health(healthy: bool, room: &str, users: &Users, notify: bool) {
+    let body = if healthy {
+        tracing::info!(room, "channel transcript recovered");
+        "this room's transcript has recovered, messages are being saved again"
+    } else {
+        tracing::warn!(room, "channel degraded, transcript writes failing");
+        "this room's transcript is failing, messages may not be saved"
+    };
+    if notify {
+        let event = ChatEvent::Notice {
+            body: body.to_owned(),
+        };
+        fan_out(&event, users, None).await;
+    }
+}
+
+/// Writes out the pending run of system events, if there is one.
+async fn write_batch(
+    sink: &mut dyn LogSink,
+    batch: &mut Option<(SystemBatch, tokio::time::Instant)>,
+    room: &str,
+) {
+    if let Some((batch, _)) = batch.take() {
+        if let Err(e) = sink.write_line(&batch.into_line(room)).await {
+            tracing::error!(room = %room, error = %e, "error writing message");
+        }
+    }
+}
+
+/// Counts an inbound frame against the connection's frame limit, closing the connection and
+/// returning `false` if it is over.
+fn allow_frame(
+    frames: &mut Option<FrameLimiter>,
+    me: &User,
+    my_id: usize,
+    app_codes: bool,
+) -> bool {
+    if frames.as_mut().is_none_or(FrameLimiter::allow) {
+        return true;
+    }
+    tracing::warn!(user_id = my_id, "frame rate exceeded, disconnecting user");
+    me.disconnect(DisconnectReason::Flooding, app_codes);
+    false
+}
+
+/// How long a cancelled forwarding task tries to send its close frame, as the client may have
+/// stopped reading altogether.
+const CLOSE_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many messages a connection's outbound queue holds under `config`.
+fn queue_capacity(config: &RoomConfig) -> usize {
+    // A channel needs room for at least one message.
+    config
+        .max_queued_per_user
+        .unwrap_or(DEFAULT_MAX_QUEUED_PER_USER)
+        .max(1)
+}
+
+/// Spawns the task that writes a connection's outbound messages to its websocket, returning the
+/// user that queues them. The queue and send budget are those of `config`'s rooms.
+fn forward_to_socket<S>(mut user_ws_tx: S, protocol: Protocol, config: &RoomConfig) -> User
+where
+    S: Sink<Message> + Unpin + Send + 'static,
+    S::Error: fmt::Display,
+{
+    // A bounded channel, so a client that stops reading holds at most a queue's worth of
+    // messages, after which fan-outs shed them or disconnect the user.
+    let (tx, mut rx) = mpsc::channel(queue_capacity(config));
+    let depth = QueueDepth::default();
+    let budget = config.send_budget.clone();
+    let writer_cancellation = Arc::new(Cancellation::default());
+    let app_codes = config.app_close_codes;
+
+    let forward_budget = budget.clone();
+    let forward_depth = depth.clone();
+    let cancellation = writer_cancellation.clone();
+    tokio::task::spawn(
+        async move {
+            let reason = loop {
+                let message = tokio::select! {
+                    biased;
+                    reason = cancellation.cancelled() => break reason,
+                    message = rx.recv() => match message {
+                        Some(message) => message,
+                        None => return,
+                    },
+                };
+                forward_depth.pop();
+                // A client that has stopped reading can hold up a send indefinitely.
+                let sent = tokio::select! {
+                    biased;
+                    reason = cancellation.cancelled() => Err(reason),
+                    sent = user_ws_tx.send(message) => Ok(sent),
+                };
+                if let Some(budget) = &forward_budget {
+                    budget.release();
+                }
+                match sent {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::warn!(error = %e, "websocket send error"),
+                    Err(reason) => break reason,
+                }
+            };
+            // Whatever the client hasn't taken yet is abandoned.
+            rx.close();
+            while let Some(_abandoned) = rx.recv().await {
+                forward_depth.pop();
+                if let Some(budget) = &forward_budget {
+                    budget.release();
+                }
+            }
+            let close = async {
+                user_ws_tx.send(reason.close_frame(app_codes)).await?;
+                user_ws_tx.close().await
+            };
+            let _ = tokio::time::timeout(CLOSE_FRAME_TIMEOUT, close).await;
+        }
+        .in_current_span(),
+    );
+
+    User {
+        budget,
+        depth,
+        writer_cancellation,
+        ..User::new(tx, protocol)
+    }
+}
+
+/// Queues the greeting a newly connected user receives before anything else.
+fn welcome(room: &ChatRoom, me: &User, users: &HashMap<usize, User>) {
+    if let Some(motd) = &room.config.motd {
+        me.notice(motd.clone());
+    }
+    if let Some(pinned) = room.pinned() {
+        me.send(me.encode(&pinned));
+    }
+    let mut away: Vec<&Identity> = users
+        .values()
+        .map(|user| &user.identity)
+        .filter(|identity| identity.status == Status::Away)
+        .collect();
+    away.sort_unstable_by_key(|identity| identity.id);
+    for identity in away {
+        me.send(me.encode(&ChatEvent::Status {
+            from: identity.id,
+            status: Status::Away,
+            name: identity.nick.clone(),
+        }));
+    }
+    if let Some(limit) = room.config.join_history {
+        for message in room.recent_messages(limit) {
+            me.send(me.encode(&replayed(room, message, users)));
+        }
+    }
+}
+
+/// `message` as it was delivered when sent, naming its sender by their current nickname if they
+/// are still in the room.
+///
+/// A message being posted as the user joins can be both replayed and then delivered live; the
+/// two share a sequence number.
+fn replayed(room: &ChatRoom, message: RecentMessage, users: &HashMap<usize, User>) -> ChatEvent {
+    let name = users
+        .get(&message.from)
+        .and_then(|user| user.identity.nick.clone());
+    let appearance = if room.config.user_colors {
+        let display = match &name {
+            Some(name) => Cow::Borrowed(name.as_str()),
+            None => Cow::Owned(format!("User#{}", message.from)),
+        };
+        Some(Appearance::for_name(&display))
+    } else {
+        None
+    };
+    let body = match &room.config.decoration {
+        Some(decoration) => decoration.render(&message.body, &room.name, None),
+        None => message.body,
+    };
+    ChatEvent::Message {
+        seq: message.seq,
+        from: message.from,
+        body,
+        appearance,
+        topic: None,
+        name,
+        ts: Some(humantime::format_rfc3339_millis(message.sent_at).to_string()),
+    }
+}
+
+/// Most transcript lines the logging task hands its sink in one burst, so a long backlog doesn't
+/// hold the whole queue in a single batch.
+pub const MAX_BURST_LINES: usize = 512;
+
+/// Messages held for a client that has not joined yet, in `PreJoinPolicy::Buffer` mode.
+const MAX_PRE_JOIN_MESSAGES: usize = 32;
+
+/// Marks a text frame as base64-encoded binary data in rooms with `base64_binary` set.
+pub const BASE64_PREFIX: &str = "data:base64,";
+
+/// State for one websocket connection, owned by its `user_connected` task.
+struct Connection {
+    room: Arc<ChatRoom>,
+    me: User,
+    identity: Identity,
+    appearance: Option<Appearance>,
+    /// Whether the client may chat yet. Always true unless the room requires an explicit join.
+    joined: bool,
+    /// Whether the next text frame is the nickname handshake.
+    awaiting_nick: bool,
+    /// When this user last sent a chat message, in rooms with a message cooldown.
+    cooldown: Option<Cooldown>,
+    /// This connection's allowance of chat messages, in rooms with a message rate, and the rate
+    /// it was filled for.
+    message_rate: Option<(MessageRate, TokenBucket)>,
+    /// Messages dropped in a row for going over the message rate.
+    rate_strikes: u32,
+    pre_join: VecDeque<String>,
+    /// Sequence number of the latest message the user has said they read, 0 before any.
+    read_up_to: u64,
+}
+
+/// Applies the room's `DuplicatePolicy` to a user joining with an account that is already
+/// connected, returning whether they may join.
+fn admit_duplicate(
+    room: &ChatRoom,
+    users: &mut HashMap<usize, User>,
+    me: &User,
+    identity: &Identity,
+) -> bool {
+    let account = match &identity.account {
+        Some(account) => account,
+        None => return true,
+    };
+    let existing: Vec<usize> = users
+        .values()
+        .filter(|user| user.identity.account.as_ref() == Some(account))
+        .map(|user| user.identity.id)
+        .collect();
+    if existing.is_empty() {
+        return true;
+    }
+    match room.config.duplicate_connections {
+        DuplicatePolicy::Allow => true,
+        DuplicatePolicy::Reject => {
+            tracing::info!(room = %room.name, user_id = identity.id, "duplicate connection, rejected user");
+            me.notice("already connected to this room".to_owned());
+            false
+        }
+        DuplicatePolicy::Replace => {
+            for id in existing {
+                if let Some(old) = users.remove(&id) {
+                    tracing::info!(room = %room.name, user_id = id, replaced_by = identity.id, "user replaced");
+                    old.hang_up(DisconnectReason::Replaced, room.config.app_close_codes);
+                }
+            }
+            true
+        }
+    }
+}
+
+impl Connection {
+    /// Adds `me` to `room`, unless the room is full, and welcomes them.
+    async fn join(room: Arc<ChatRoom>, me: User, identity: Identity) -> Option<Connection> {
+        // Multiplexed connections need every envelope tagged to tell their rooms apart.
+        let envelope_room = if room.config.room_in_envelope || me.protocol == Protocol::MuxV1 {
+            Some(Arc::from(room.name.as_str()))
+        } else {
+            None
+        };
+        let me = User {
+            identity: identity.clone(),
+            slow_consumer: if room.config.disconnect_slow_consumers {
+                SlowConsumer::Disconnect
+            } else {
+                SlowConsumer::Shed
+            },
+            envelope_room,
+            topics: Arc::default(),
+            delivery_failures: room.delivery_failures.clone(),
+            cancellation: Arc::default(),
+            ..me
+        };
+        let appearance = if room.config.user_colors {
+            Some(Appearance::for_name(&identity.display_name()))
+        } else {
+            None
+        };
+        {
+            let mut users = room.users.write().await;
+            if !admit_duplicate(&room, &mut users, &me, &identity) {
+                return None;
+            }
+            if let Some(max_users) = room.limits().max_users {
+                if users.len() >= max_users {
+                    tracing::info!(room = %room.name, user_id = identity.id, "room full, rejected user");
+                    let full = format!("room is full (max {} users)", max_users);
+                    // A multiplexed connection stays open for its other rooms.
+                    match room.config.busy_retry_after {
+                        Some(retry) if me.protocol != Protocol::MuxV1 => {
+                            me.notice(format!("{}, {}", full, retry_hint(retry)));
+                            me.disconnect(DisconnectReason::Busy, room.config.app_close_codes);
+                        }
+                        _ => {
+                            me.notice(full);
+                        }
+                    }
+                    return None;
+                }
+            }
+            // Everything the user is welcomed with is queued before they can see (or add to) any
+            // room traffic: other users only reach them once inserted, and their own messages are
+            // only read once this returns.
+            welcome(&room, &me, &users);
+            users.insert(identity.id, me.clone());
+        }
+        room.presence(SystemEvent::Joined, &identity).await;
+
+        Some(Connection {
+            joined: room.config.explicit_join.is_none(),
+            awaiting_nick: room.config.nick_handshake,
+            cooldown: room.config.message_cooldown.map(Cooldown::new),
+            message_rate: room.limits().message_rate.map(|rate| (rate, rate.bucket())),
+            rate_strikes: 0,
+            room,
+            me,
+            identity,
+            appearance,
+            pre_join: VecDeque::new(),
+            read_up_to: 0,
+        })
+    }
+
+    /// Removes the user from the room, which lingers if they were the last to leave.
+    async fn leave(self, rooms: ChatRooms) {
+        let topics: Vec<String> = self.me.topics.read().unwrap().iter().cloned().collect();
+        self.room
+            .unsubscribe(&self.me, topics.iter().map(String::as_str));
+        let room = self.room;
+        user_disconnected(self.identity.id, &room.users).await;
+        room.presence(SystemEvent::Left, &self.identity).await;
+        // A closed room has already left `rooms`, and should be dropped as soon as it can be.
+        if room.users.read().await.is_empty() && !room.is_closed() {
+            linger(room, rooms);
+        }
+    }
+
+    /// Handles a text frame, which a `chat.v1.json` client must send as a `ClientFrame`.
+    async fn handle_frame(&mut self, s: &str) {
+        if self.me.protocol != Protocol::JsonV1 {
+            return self.handle_text(s).await;
+        }
+        match serde_json::from_str::<ClientFrame>(s) {
+            Ok(ClientFrame::Message { body }) => self.handle_text(&body).await,
+            Ok(ClientFrame::Edit { seq, text }) => {
+                if self.accepts_command_frame() {
+                    self.edit(seq, &text).await;
+                }
+            }
+            Ok(ClientFrame::Delete { seq }) => {
+                if self.accepts_command_frame() {
+                    self.delete(seq).await;
+                }
+            }
+            Ok(ClientFrame::Read { seq }) => {
+                if self.accepts_command_frame() {
+                    self.mark_read(seq).await;
+                }
+            }
+            Err(e) => {
+                self.me.notice(format!("invalid frame: {}", e));
+            }
+        }
+    }
+
+    /// Whether a structured frame standing in for a slash command can be handled now, telling
+    /// the user if not. It is checked as a command, and ends any nickname handshake.
+    fn accepts_command_frame(&mut self) -> bool {
+        self.awaiting_nick = false;
+        if !self.joined {
+            self.me
+                .notice("not joined yet, send /join first".to_owned());
+            return false;
+        }
+        !self.refuse_while_draining() && self.allows(MessageKind::Command)
+    }
+
+    async fn handle_text(&mut self, s: &str) {
+        if self.awaiting_nick {
+            self.awaiting_nick = false;
+            if let Command::Nick(name) = commands::parse(s) {
+                return self.set_nick(&name).await;
+            }
+        }
+        if self.room.config.base64_binary {
+            if let Some(encoded) = s.strip_prefix(BASE64_PREFIX) {
+                return self.handle_base64(encoded).await;
+            }
+        }
+        if self.joined {
+            self.dispatch(s).await;
+        } else {
+            self.handle_pre_join(s).await;
+        }
+    }
+
+    /// Handles text from a joined user according to its kind.
+    async fn dispatch(&mut self, s: &str) {
+        if self.refuse_while_draining() {
+            return;
+        }
+        let kind = MessageKind::of_text(s);
+        if !self.allows(kind) {
+            return;
+        }
+        match commands::parse(s) {
+            Command::Typing => {
+                let event = ChatEvent::Typing {
+                    from: self.identity.id,
+                    name: self.identity.nick.clone(),
+                };
+                fan_out(&event, &self.room.users, Some(self.identity.id)).await;
+            }
+            Command::React { seq, emoji } => self.react(seq, emoji).await,
+            Command::Who => self.who().await,
+            Command::Subscribe(topics) => self.subscribe(topics.iter().map(String::as_str)),
+            Command::Unsubscribe(topics) => {
+                self.room
+                    .unsubscribe(&self.me, topics.iter().map(String::as_str));
+                self.notice_topics();
+            }
+            Command::Pin(seq) => self.pin(Some(seq)).await,
+            Command::Unpin => self.pin(None).await,
+            Command::Kick(id) => self.kick(id).await,
+            Command::Edit { seq, body } => self.edit(seq, &body).await,
+            Command::Delete(seq) => self.delete(seq).await,
+            Command::DirectMessage { target, body } => self.direct_message(&target, &body).await,
+            Command::Topic { topic, .. } if !self.room.accepts_topic(&topic) => {
+                self.notice_topic_limit(&[topic]);
+            }
+            Command::Topic { topic, body } => self.accept_message(&body, Some(topic)).await,
+            Command::Emote(action) => self.emote(&action).await,
+            Command::Malformed(usage) => {
+                self.me.notice(usage.to_owned());
+            }
+            // `/nick` and `/join` are over once the user has joined.
+            Command::Say(_) | Command::Nick(_) | Command::Join(_) => {
+                self.accept_message(s, None).await
+            }
+        }
+    }
+
+    fn subscribe<'a>(&self, topics: impl IntoIterator<Item = &'a str>) {
+        let refused = self.room.subscribe(&self.me, topics);
+        if !refused.is_empty() {
+            self.notice_topic_limit(&refused);
+        }
+        self.notice_topics();
+    }
+
+    fn notice_topic_limit<T: AsRef<str>>(&self, topics: &[T]) {
+        let topics: Vec<&str> = topics.iter().map(AsRef::as_ref).collect();
+        self.me.notice(format!(
+            "room topic limit reached (max {}), refused: {}",
+            self.room.config.max_topics.unwrap_or_default(),
+            topics.join(" ")
+        ));
+    }
+
+    /// Tells the user who is in the room, by id, marking them among the rest.
+    async fn who(&self) {
+        let names: Vec<String> = membership::members(&self.room.users)
+            .await
+            .into_iter()
+            .map(|member| {
+                if member.id == self.identity.id {
+                    format!("{} (you)", member.name)
+                } else {
+                    member.name
+                }
+            })
+            .collect();
+        self.me
+            .notice(format!("in this room: {}", names.join(", ")));
+    }
+
+    /// Tells the user which topics they are subscribed to.
+    fn notice_topics(&self) {
+        let mut topics: Vec<_> = self.me.topics.read().unwrap().iter().cloned().collect();
+        topics.sort();
+        self.me
+            .notice(format!("subscribed topics: {}", topics.join(" ")));
+    }
+
+    /// Marks the user `status` and tells everyone else in the room.
+    async fn set_status(&mut self, status: Status) {
+        if let Some(user) = self.room.users.write().await.get_mut(&self.identity.id) {
+            user.identity.status = status;
+        }
+        self.identity.status = status;
+        self.me.identity.status = status;
+        if let Some(membership_tx) = &self.room.membership_tx {
+            let _ = membership_tx.send(());
+        }
+        let event = ChatEvent::Status {
+            from: self.identity.id,
+            status,
+            name: self.identity.nick.clone(),
+        };
+        fan_out(&event, &self.room.users, Some(self.identity.id)).await;
+    }
+
+    /// Names the user `name`, with a numeric suffix if someone else in the room already goes by
+    /// it. An empty name leaves them as `User#<id>`.
+    async fn set_nick(&mut self, name: &str) {
+        if name.is_empty() {
+            self.me.notice(format!(
+                "no nickname given, you are {}",
+                self.identity.display_name()
+            ));
+            return;
+        }
+        if looks_like_id(name) {
+            self.me.notice(format!(
+                "nicknames can't look like user ids, you are {}",
+                self.identity.display_name()
+            ));
+            return;
+        }
+        // It would end the nickname's tag in transcript lines.
+        if name.contains(']') {
+            self.me.notice(format!(
+                "nicknames can't contain ']', you are {}",
+                self.identity.display_name()
+            ));
+            return;
+        }
+        let nick = {
+            let mut users = self.room.users.write().await;
+            let taken: HashSet<String> = users
+                .iter()
+                .filter(|(&uid, _)| uid != self.identity.id)
+                .map(|(_, user)| user.identity.display_name())
+                .collect();
+            let mut nick = name.to_owned();
+            let mut suffix = 1;
+            while taken.contains(&nick) {
+                suffix += 1;
+                nick = format!("{}{}", name, suffix);
+            }
+            if let Some(user) = users.get_mut(&self.identity.id) {
+                user.identity.nick = Some(nick.clone());
+            }
+            nick
+        };
+        // Logged under the old name, so the transcript says who took the new one.
+        self.room.log_record(logged_as(
+            &self.identity,
+            &format!("*** User#{} is now known as {} ***", self.identity.id, nick),
+        ));
+        self.me.notice(format!("you are now known as {}", nick));
+        if self.room.config.user_colors {
+            // Matches the appearance `replayed` gives the user's buffered messages.
+            self.appearance = Some(Appearance::for_name(&nick));
+        }
+        self.identity.nick = Some(nick.clone());
+        self.me.identity.nick = Some(nick);
+    }
+
+    async fn handle_pre_join(&mut self, s: &str) {
+        if let Command::Join(topics) = commands::parse(s) {
+            // `/join <topic>...` subscribes to the topics as it joins.
+            let refused = self
+                .room
+                .subscribe(&self.me, topics.iter().map(String::as_str));
+            if !refused.is_empty() {
+                self.notice_topic_limit(&refused);
+            }
+            self.joined = true;
+            self.me.notice(format!("joined {}", self.room.name));
+            while let Some(buffered) = self.pre_join.pop_front() {
+                self.dispatch(&buffered).await;
+            }
+            return;
+        }
+        match self.room.config.explicit_join {
+            Some(PreJoinPolicy::Buffer) if self.pre_join.len() < MAX_PRE_JOIN_MESSAGES => {
+                self.pre_join.push_back(s.to_owned());
+            }
+            _ => {
+                self.me
+                    .notice("not joined yet, send /join first".to_owned());
+            }
+        }
+    }
+
+    /// Decodes a base64 text frame and handles it as the binary frame it stands for.
+    async fn handle_base64(&mut self, encoded: &str) {
+        let encoded = encoded.trim_end();
+        if let Some(max_bytes) = self.room.limits().max_message_bytes {
+            // Anything longer than the encoding of `max_bytes` is refused without decoding it.
+            if encoded.len() > max_bytes.div_ceil(3) * 4 {
+                self.me
+                    .notice(format!("message too long (max {} bytes)", max_bytes));
+                return;
+            }
+        }
+        match base64::decode(encoded) {
+            Ok(bytes) => self.handle_binary(&bytes).await,
+            Err(e) => {
+                self.me.notice(format!("invalid base64 data: {}", e));
+            }
+        }
+    }
+
+    /// Relays a binary frame to the rest of the room, counted against the same cooldown, message
+    /// rate and size limit as a text message. Multiplexed connections are left out, since a bare
+    /// binary frame can't say which of their rooms it came from.
+    async fn handle_binary(&mut self, bytes: &[u8]) {
+        if !self.joined {
+            self.me
+                .notice("not joined yet, send /join first".to_owned());
+            return;
+        }
+        if self.refuse_while_draining() {
+            return;
+        }
+        if !self.allows(MessageKind::Binary) {
+            self.room
+                .log_message_as("!!!ATTEMPTED TO SEND NON-TEXT MESSAGE!!!", &self.identity);
+            return;
+        }
+        if !self.within_send_limits().await {
+            return;
+        }
+        if let Some(max_bytes) = self.room.limits().max_message_bytes {
+            if bytes.len() > max_bytes {
+                self.me
+                    .notice(format!("message too long (max {} bytes)", max_bytes));
+                return;
+            }
+        }
+        self.room
+            .log_message_as(&format!("<binary, {} bytes>", bytes.len()), &self.identity);
+        self.me.info.messages.fetch_add(1, Ordering::Relaxed);
+        for (&uid, user) in timed_read(&self.room.users, "users").await.iter() {
+            if uid != self.identity.id && user.protocol != Protocol::MuxV1 {
+                user.send(Message::binary(bytes.to_vec()));
+            }
+        }
+    }
+
+    /// Tells the user their message was refused if the room is draining.
+    fn refuse_while_draining(&self) -> bool {
+        let draining = self.room.is_draining();
+        if draining {
+            self.me
+                .notice("room is closed for maintenance, message not sent".to_owned());
+        }
+        draining
+    }
+
+    /// Checks the room's message policy, telling the user if `kind` is refused.
+    fn allows(&self, kind: MessageKind) -> bool {
+        let allowed = self.room.config.message_policy.allows(kind);
+        if !allowed {
+            self.me
+                .notice(format!("{} messages are not allowed in this room", kind));
+        }
+        allowed
+    }
+
+    /// Handles `/pin <seq>` and `/unpin`, which only admins may use.
+    async fn pin(&self, seq: Option<u64>) {
+        if self.identity.role != Role::Admin {
+            self.me.notice("only admins can pin messages".to_owned());
+            return;
+        }
+        match seq {
+            Some(seq) => {
+                if !self.room.pin(seq).await {
+                    self.me.notice(format!("message {} can't be pinned", seq));
+                }
+            }
+            None => {
+                if !self.room.unpin().await {
+                    self.me.notice("no message is pinned".to_owned());
+                }
+            }
+        }
+    }
+
+    /// Handles `/kick <id>`, which only admins may use.
+    async fn kick(&self, id: usize) {
+        if self.identity.role != Role::Admin {
+            self.me.notice("only admins can kick users".to_owned());
+            return;
+        }
+        if !self.room.disconnect(id, DisconnectReason::Kicked).await {
+            self.me.notice(format!("User#{} is not in this room", id));
+        }
+    }
+
+    /// Handles `/edit <seq> <text>` or an edit frame, checking the new text as if it were a new
+    /// message.
+    async fn edit(&self, seq: u64, body: &str) {
+        if let Some(max_bytes) = self.room.limits().max_message_bytes {
+            if body.len() > max_bytes {
+                self.me
+                    .notice(format!("message too long (max {} bytes)", max_bytes));
+                return;
+            }
+        }
+        let body = match self
+            .room
+            .config
+            .transforms
+            .apply(&self.identity, body.to_owned())
+        {
+            Some(body) => body,
+            None => return,
+        };
+        if let Err(e) = self.room.edit_message(&self.identity, seq, &body).await {
+            self.me.notice(format!("can't edit message {}: {}", seq, e));
+        }
+    }
+
+    /// Handles `/delete <seq>` or a delete frame.
+    async fn delete(&self, seq: u64) {
+        if let Err(e) = self.room.delete_message(&self.identity, seq).await {
+            self.me
+                .notice(format!("can't delete message {}: {}", seq, e));
+        }
+    }
+
+    /// Relays `/react <seq> <emoji>` to the rest of the room.
+    async fn react(&self, target: u64, emoji: String) {
+        self.room
+            .log_message_as(&format!("/react {} {}", target, emoji), &self.identity);
+        let event = ChatEvent::Reaction {
+            from: self.identity.id,
+            target,
+            emoji,
+            name: self.identity.nick.clone(),
+        };
+        fan_out(&event, &self.room.users, Some(self.identity.id)).await;
+    }
+
+    /// Handles `/msg <nick_or_id> <text>`, sending the text to that one user (and back to the
+    /// sender) instead of the room. It is checked like any chat message, and logged marked with
+    /// its recipient.
+    async fn direct_message(&mut self, target: &str, body: &str) {
+        if !self.within_message_rate().await {
+            return;
+        }
+        if let Some(max_bytes) = self.room.limits().max_message_bytes {
+            if body.len() > max_bytes {
+                self.me
+                    .notice(format!("message too long (max {} bytes)", max_bytes));
+                return;
+            }
+        }
+        let body = match self
+            .room
+            .config
+            .transforms
+            .apply(&self.identity, body.to_owned())
+        {
+            Some(body) => body,
+            None => return,
+        };
+        self.room.confirm_logging().await;
+        let users = timed_read(&self.room.users, "users").await;
+        let recipient = match find_user(&users, target) {
+            Some(recipient) => recipient,
+            None => {
+                drop(users);
+                self.me.notice(format!("{} is not in this room", target));
+                return;
+            }
+        };
+        let seq = self
+            .room
+            .log_private_message(&body, &self.identity, recipient.identity.id);
+        metrics::count_message();
+        self.me.info.messages.fetch_add(1, Ordering::Relaxed);
+        let event = ChatEvent::Private {
+            seq,
+            from: self.identity.id,
+            to: recipient.identity.id,
+            body,
+            name: self.identity.nick.clone(),
+            to_name: recipient.identity.nick.clone(),
+            ts: Some(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+        };
+        recipient.send(recipient.encode(&event));
+        if recipient.identity.id != self.identity.id {
+            self.me.send(self.me.encode(&event));
+        }
+    }
+
+    /// Moves the user's read position up to message `seq` and, in rooms small enough for read
+    /// receipts, tells everyone whose buffered messages it passed the newest of theirs read.
+    async fn mark_read(&mut self, seq: u64) {
+        let seq = seq.min(*self.room.last_seq.lock().unwrap());
+        if seq <= self.read_up_to {
+            return;
+        }
+        let after = std::mem::replace(&mut self.read_up_to, seq);
+        let max_users = match self.room.config.read_receipts_max_users {
+            Some(max_users) => max_users,
+            None => return,
+        };
+        let users = timed_read(&self.room.users, "users").await;
+        if users.len() > max_users {
+            return;
+        }
+        // Buffered oldest first, so each sender ends up with their newest message read.
+        let mut newest: HashMap<usize, u64> = HashMap::new();
+        for message in self.room.recent.lock().unwrap().iter() {
+            if message.seq > after && message.seq <= seq && message.from != self.identity.id {
+                newest.insert(message.from, message.seq);
+            }
+        }
+        for (sender, seq) in newest {
+            if let Some(user) = users.get(&sender) {
+                let receipt = ChatEvent::Read {
+                    from: self.identity.id,
+                    seq,
+                    name: self.identity.nick.clone(),
+                };
+                user.send(user.encode(&receipt));
+            }
+        }
+    }
+
+    /// Counts a chat message against the connection's message rate. One over it is refused with a
+    /// notice, and enough refused in a row disconnect the user.
+    async fn within_message_rate(&mut self) -> bool {
+        let rate = self.room.limits().message_rate;
+        // The room's rate may have been changed since the bucket was filled.
+        if self
+            .message_rate
+            .as_ref()
+            .map(|(filled_for, _)| *filled_for)
+            != rate
+        {
+            self.message_rate = rate.map(|rate| (rate, rate.bucket()));
+        }
+        let wait = match self
+            .message_rate
+            .as_ref()
+            .map(|(_, bucket)| bucket.try_take())
+        {
+            Some(Err(wait)) => wait,
+            None | Some(Ok(())) => {
+                self.rate_strikes = 0;
+                return true;
+            }
+        };
+        self.rate_strikes += 1;
+        let disconnect_after = rate.and_then(|rate| rate.disconnect_after);
+        if disconnect_after.is_some_and(|max| self.rate_strikes >= max) {
+            tracing::warn!(
+                user_id = self.identity.id,
+                "message rate exceeded, disconnecting user"
+            );
+            self.room
+                .disconnect(self.identity.id, DisconnectReason::Flooding)
+                .await;
+        } else {
+            self.me.notice(format!(
+                "too many messages, try again in {}",
+                humantime::format_duration(Duration::from_secs_f64(wait.as_secs_f64().ceil()))
+            ));
+        }
+        false
+    }
+
+    /// Counts a message from this user against the room's cooldown and message rate, returning
+    /// whether it may be sent, with the user told why if not.
+    async fn within_send_limits(&mut self) -> bool {
+        if let Some(cooldown) = &mut self.cooldown {
+            if !cooldown.allow() {
+                self.me.notice(format!(
+                    "slow down, wait {} between messages",
+                    humantime::format_duration(cooldown.interval())
+                ));
+                return false;
+            }
+        }
+        self.within_message_rate().await
+    }
+
+    /// Checks, transforms, logs and broadcasts one chat message from this user.
+    async fn accept_message(&mut self, s: &str, topic: Option<String>) {
+        if let Some(s) = self.admit(s).await {
+            self.room
+                .post_message(self.identity.id, &s, self.appearance, topic)
+                .await;
+        }
+    }
+
+    /// Checks, transforms, logs and broadcasts a `/me` emote from this user.
+    async fn emote(&mut self, action: &str) {
+        if let Some(action) = self.admit(action).await {
+            self.room.post_emote(&self.identity, &action).await;
+        }
+    }
+
+    /// Checks a public message from this user against the room's limits and applies its
+    /// transforms, returning the text to post or `None`, with the user told why, if it is refused.
+    async fn admit(&mut self, s: &str) -> Option<String> {
+        if !self.within_send_limits().await {
+            return None;
+        }
+        if let Some(max_bytes) = self.room.limits().max_message_bytes {
+            if s.len() > max_bytes {
+                self.me
+                    .notice(format!("message too long (max {} bytes)", max_bytes));
+                return None;
+            }
+        }
+        let s = self
+            .room
+            .config
+            .transforms
+            .apply(&self.identity, s.to_owned())?;
+        self.me.info.messages.fetch_add(1, Ordering::Relaxed);
+        Some(s)
+    }
+}
+
+/// Sends `event` to every user other than `skip_uid` who `receives` it, each in their own
+/// connection's protocol.
+async fn fan_out(event: &ChatEvent, users: &Users, skip_uid: Option<usize>) {
+    fan_out_timed(event, users, skip_uid, None).await
+}
+
+/// Like `fan_out`, recording each recipient's delivery latency against `timer` if there is one.
+async fn fan_out_timed(
+    event: &ChatEvent,
+    users: &Users,
+    skip_uid: Option<usize>,
+    timer: Option<DeliveryTimer>,
+) {
+    deliver(event, users, timer, |uid, user| {
+        Some(uid) != skip_uid && user.receives(event)
+    })
+    .await
+}
+
+/// Sends `event` to every user `accept` picks, each in their own connection's protocol.
+async fn deliver<A>(event: &ChatEvent, users: &Users, timer: Option<DeliveryTimer>, accept: A)
+where
+    A: Fn(usize, &User) -> bool,
+{
+    let mut encoded = EncodedEvent::new(event);
+    let mut too_slow = Vec::new();
+    for (&uid, user) in timed_read(users, "users").await.iter() {
+        if !accept(uid, user) {
+            continue;
+        }
+        let reason = match user.try_send(encoded.get(user.protocol, user.envelope_room.as_deref()))
+        {
+            Ok(()) => {
+                if let Some(timer) = &timer {
+                    timer.observe();
+                }
+                continue;
+            }
+            Err(reason) => reason,
+        };
+        if let Some(failures) = &user.delivery_failures {
+            failures.record(uid, event.seq(), reason);
+        }
+        if reason == FailureReason::Closed {
+            continue;
+        }
+        if user.slow_consumer != SlowConsumer::Shed && reason == FailureReason::QueueFull {
+            too_slow.push(uid);
+        } else {
+            tracing::warn!(user_id = uid, "outbound queue full, dropped message");
+        }
+    }
+    if too_slow.is_empty() {
+        return;
+    }
+    // Removed once the read lock is released, as `user_disconnected` would. Their connection is
+    // cut off rather than sent a close frame behind the backlog it isn't reading: it stops
+    // reading their frames, and its forwarding task drops the backlog and closes the socket.
+    let mut users = users.write().await;
+    for uid in too_slow {
+        if let Some(user) = users.remove(&uid) {
+            tracing::warn!(user_id = uid, "outbound queue full, disconnecting user");
+            user.cut_off(DisconnectReason::TooSlow);
+        }
+    }
+}
+
+async fn user_disconnected(my_id: usize, users: &Users) {
+    tracing::info!(user_id = my_id, "good bye user");
+
+    // Stream closed up, so remove from the user list
+    users.write().await.remove(&my_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use futures::future::{self, BoxFuture};
+
+    use tokio::sync::mpsc;
+    use warp::{ws::Message, Filter};
+
+    use crate::{
+        appearance::Appearance,
+        budget::SendBudget,
+        classification::Classification,
+        config::{
+            DuplicatePolicy, FlushInterval, Keepalive, MessagePolicy, MessageRate, RoomConfig,
+            RoomLimits, ShutdownPolicy, DEFAULT_MAX_QUEUED_PER_USER,
+        },
+        decoration::Decoration,
+        disconnect::DisconnectReason,
+        drain_room, fan_out, forward_to_socket, get_room,
+        history::HistoryConfig,
+        linger,
+        membership::{self, SnapshotConfig},
+        metrics,
+        protocol::{ChatEvent, MessageKind, Protocol},
+        ratelimit::TokenBucket,
+        read_frames,
+        rooms::spawn_idle_reaper,
+        shutdown::Unavailable,
+        sink::{
+            LogSink, MemorySink, RecoveringSink, Reopen, RotatingFileSink, FAILURES_BEFORE_REOPEN,
+        },
+        transcript::{JsonRecord, Record, TranscriptFormat, SERVER_USER_ID},
+        ChatRoom, ChatRooms, Connection, Identity, Role, Status, User, Users,
+    };
+
+    /// The default config, but with transcripts written to a temp dir instead of the working
+    /// directory.
+    pub(crate) fn test_config() -> RoomConfig {
+        RoomConfig {
+            log_dir: Some(std::env::temp_dir().join(format!("chat_tests_{}", std::process::id()))),
+            ..RoomConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fan_out_encodes_per_protocol() {
+        let users = Users::default();
+        let (legacy_tx, mut legacy_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let (json_tx, mut json_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let (sender_tx, mut sender_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        {
+            let mut users = users.write().await;
+            users.insert(1, User::new(legacy_tx, Protocol::LegacyText));
+            users.insert(2, User::new(json_tx, Protocol::JsonV1));
+            users.insert(3, User::new(sender_tx, Protocol::JsonV1));
+        }
+
+        let event = ChatEvent::Message {
+            seq: 1,
+            from: 3,
+            body: "hello".to_owned(),
+            appearance: None,
+            topic: None,
+            name: None,
+            ts: None,
+        };
+        fan_out(&event, &users, Some(3)).await;
+
+        let legacy = legacy_rx.recv().await.unwrap();
+        assert_eq!(legacy.to_str(), Ok("<User#3>: hello"));
+
+        let json = json_rx.recv().await.unwrap();
+        assert_eq!(
+            json.to_str(),
+            Ok(r#"{"type":"message","seq":1,"from":3,"body":"hello"}"#)
+        );
+
+        assert!(sender_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fan_out_sheds_past_send_budget() {
+        let budget = Arc::new(SendBudget::new(2));
+        let users = Users::default();
+        let mut receivers = Vec::new();
+        {
+            let mut users = users.write().await;
+            for uid in 1..=3 {
+                let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+                let user = User {
+                    budget: Some(budget.clone()),
+                    ..User::new(tx, Protocol::LegacyText)
+                };
+                users.insert(uid, user);
+                receivers.push(rx);
+            }
+        }
+
+        let event = ChatEvent::Message {
+            seq: 1,
+            from: 4,
+            body: "hello".to_owned(),
+            appearance: None,
+            topic: None,
+            name: None,
+            ts: None,
+        };
+        fan_out(&event, &users, None).await;
+
+        let delivered = receivers
+            .iter_mut()
+            .map(|rx| rx.try_recv())
+            .filter(Result::is_ok)
+            .count();
+        assert_eq!(delivered, 2);
+        assert_eq!(budget.queued(), 2);
+        assert_eq!(budget.shed(), 1);
+    }
+
+    /// A socket that writes one message each time its gate is opened, and the messages it has
+    /// written. The written channel closes once the socket is dropped.
+    fn gated_socket() -> (
+        impl futures::Sink<Message, Error = io::Error> + Unpin + Send + 'static,
+        mpsc::UnboundedSender<()>,
+        mpsc::UnboundedReceiver<Message>,
+    ) {
+        let (gate_tx, gate_rx) = mpsc::unbounded_channel::<()>();
+        let (written_tx, written_rx) = mpsc::unbounded_channel();
+        let socket = futures::sink::unfold(gate_rx, move |mut gate, message: Message| {
+            let written_tx = written_tx.clone();
+            async move {
+                gate.recv().await;
+                let _ = written_tx.send(message);
+                Ok::<_, io::Error>(gate)
+            }
+        });
+        (Box::pin(socket), gate_tx, written_rx)
+    }
+
+    #[tokio::test]
+    async fn pings_and_close_frames_keep_the_send_budget_balanced() {
+        let budget = Arc::new(SendBudget::new(2));
+        let (socket, gate_tx, mut written_rx) = gated_socket();
+        let config = RoomConfig {
+            send_budget: Some(budget.clone()),
+            ..test_config()
+        };
+        let user = forward_to_socket(socket, Protocol::LegacyText, &config);
+
+        assert!(user.send(Message::text("a")));
+        assert!(user.send(Message::text("b")));
+        for _ in 0..3 {
+            user.ping();
+        }
+        user.disconnect(DisconnectReason::Kicked, false);
+        // Nothing has been written yet, so all six are still queued, past the limit.
+        assert_eq!(budget.queued(), 6);
+        assert!(!user.send(Message::text("shed")));
+
+        for _ in 0..6 {
+            gate_tx.send(()).unwrap();
+            written_rx.recv().await.unwrap();
+        }
+        for _ in 0..100 {
+            if budget.queued() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("budget still counts {} written messages", budget.queued());
+    }
+
+    #[tokio::test]
+    async fn stalled_recipient_does_not_block_others() {
+        let users = Users::default();
+        let mut receivers = Vec::new();
+        // Nobody reads from this receiver, so the user's queue fills after two messages.
+        let (tx, _stalled_rx) = mpsc::channel(2);
+        {
+            let mut users = users.write().await;
+            users.insert(1, User::new(tx, Protocol::LegacyText));
+            for uid in 2..=3 {
+                let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+                users.insert(uid, User::new(tx, Protocol::LegacyText));
+                receivers.push(rx);
+            }
+        }
+
+        for seq in 1..=5 {
+            let event = ChatEvent::Message {
+                seq,
+                from: 4,
+                body: format!("message {}", seq),
+                appearance: None,
+                topic: None,
+                name: None,
+                ts: None,
+            };
+            tokio::time::timeout(Duration::from_millis(100), fan_out(&event, &users, None))
+                .await
+                .expect("fan-out waited on the stalled user");
+        }
+
+        for rx in &mut receivers {
+            for seq in 1..=5 {
+                let expected = format!("<User#4>: message {}", seq);
+                assert_eq!(rx.try_recv().unwrap().to_str(), Ok(expected.as_str()));
+            }
+        }
+        assert_eq!(users.read().await[&1].depth.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn stalled_user_queue_is_bounded_by_default() {
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "default_queue_room".to_owned(),
+                Users::default(),
+                test_config(),
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        // The socket never accepts a write, as if the client had stopped reading.
+        let (socket, _gate_tx, _written_rx) = gated_socket();
+        let me = forward_to_socket(socket, Protocol::LegacyText, &room.config);
+        let _stalled = Connection::join(room.clone(), me, Identity::new(1)).await;
+
+        for i in 0..DEFAULT_MAX_QUEUED_PER_USER + 10 {
+            room.post_message(2, &format!("message {}", i), None, None)
+                .await;
+        }
+
+        let users = room.users.read().await;
+        assert!(users[&1].queue_full());
+        assert_eq!(users[&1].depth.get(), DEFAULT_MAX_QUEUED_PER_USER);
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_is_disconnected_when_its_queue_fills() {
+        let config = RoomConfig {
+            max_queued_per_user: Some(3),
+            disconnect_slow_consumers: true,
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "slow_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        // The socket accepts nothing until the gate opens, as if the client had stopped reading.
+        let (socket, gate_tx, mut written_rx) = gated_socket();
+        let me = forward_to_socket(socket, Protocol::LegacyText, &room.config);
+        let mut slow = Connection::join(room.clone(), me, Identity::new(1))
+            .await
+            .unwrap();
+        let (tx, mut fast_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(2, User::new(tx, Protocol::LegacyText));
+
+        for i in 0..1000 {
+            room.post_message(3, &format!("message {}", i), None, None)
+                .await;
+        }
+
+        assert!(!room.users.read().await.contains_key(&1));
+        let mut delivered = 0;
+        while fast_rx.try_recv().is_ok() {
+            delivered += 1;
+        }
+        assert_eq!(delivered, 1000);
+
+        // The slow user's connection stops reading, so they can't post any more either.
+        let mut late = futures::stream::iter(vec![Ok(Message::text("still here"))]);
+        read_frames(&mut slow, &mut late).await;
+        assert!(fast_rx.try_recv().is_err());
+        slow.leave(ChatRooms::default()).await;
+
+        // The backlog is dropped in favour of the close frame. At most the message the socket was
+        // already writing when the queue filled goes out first.
+        for _ in 0..1000 {
+            let _ = gate_tx.send(());
+        }
+        let mut written = Vec::new();
+        while let Some(message) = written_rx.recv().await {
+            written.push(message);
+        }
+        let close = written.pop().unwrap();
+        assert_eq!(close.close_frame(), Some((4003, "too slow")));
+        assert!(written.len() <= 1);
+    }
+
+    #[tokio::test]
+    async fn topic_messages_reach_only_subscribers() {
+        let users = Users::default();
+        let (rust_tx, mut rust_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let (go_tx, mut go_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        {
+            let mut users = users.write().await;
+            let rust = User::new(rust_tx, Protocol::LegacyText);
+            rust.subscribe(["rust"]);
+            let go = User::new(go_tx, Protocol::LegacyText);
+            go.subscribe(["go"]);
+            users.insert(1, rust);
+            users.insert(2, go);
+        }
+
+        let tagged = ChatEvent::Message {
+            seq: 1,
+            from: 3,
+            body: "borrowck".to_owned(),
+            appearance: None,
+            topic: Some("rust".to_owned()),
+            name: None,
+            ts: None,
+        };
+        fan_out(&tagged, &users, None).await;
+        let untagged = ChatEvent::Message {
+            seq: 2,
+            from: 3,
+            body: "hello all".to_owned(),
+            appearance: None,
+            topic: None,
+            name: None,
+            ts: None,
+        };
+        fan_out(&untagged, &users, None).await;
+
+        assert_eq!(
+            rust_rx.recv().await.unwrap().to_str(),
+            Ok("<User#3> #rust: borrowck")
+        );
+        assert_eq!(
+            rust_rx.recv().await.unwrap().to_str(),
+            Ok("<User#3>: hello all")
+        );
+        assert_eq!(
+            go_rx.recv().await.unwrap().to_str(),
+            Ok("<User#3>: hello all")
+        );
+        assert!(go_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn transcript_is_flushed_on_a_timer() {
+        let dir = std::env::temp_dir().join(format!("flush_interval_{}", std::process::id()));
+        let room_with = |name: &str, flush_interval| {
+            let config = RoomConfig {
+                log_dir: Some(dir.clone()),
+                flush_interval,
+                ..test_config()
+            };
+            ChatRoom::open(name.to_owned(), Users::default(), config)
+        };
+        let timed = room_with("timed", FlushInterval::Every(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        let never = room_with("never", FlushInterval::Never).await.unwrap();
+        timed.log_message("hello", 1);
+        never.log_message("hello", 1);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let timed_transcript = tokio::fs::read_to_string(timed.log_path.as_ref().unwrap()).await;
+        let never_transcript = tokio::fs::read_to_string(never.log_path.as_ref().unwrap()).await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        let timed_transcript = timed_transcript.unwrap();
+        assert_eq!(
+            Record::parse(timed_transcript.trim_end()).unwrap().message,
+            "hello"
+        );
+        // Without a timer the line is still sitting in the writer's buffer.
+        assert_eq!(never_transcript.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn transcript_goes_in_log_dir_under_a_safe_name() {
+        let base = std::env::temp_dir().join(format!("log_dir_{}", std::process::id()));
+        let dir = base.join("transcripts");
+        let config = RoomConfig {
+            log_dir: Some(dir.clone()),
+            ..test_config()
+        };
+        let room = ChatRoom::with_config("ops/team:1".to_owned(), Users::default(), config).await;
+        room.log_message("hello", 1);
+        room.flush_log().await;
+
+        let log_path = room.log_path.clone().unwrap();
+        let file_name = log_path.file_name().unwrap().to_str().unwrap().to_owned();
+        let transcript = tokio::fs::read_to_string(&log_path).await;
+        let _ = tokio::fs::remove_dir_all(&base).await;
+        assert_eq!(log_path.parent(), Some(dir.as_path()));
+        assert!(file_name.starts_with("ops_team_1_"), "{}", file_name);
+        assert!(file_name.ends_with(".log"));
+        assert!(!room.is_degraded());
+        assert_eq!(
+            Record::parse(transcript.unwrap().trim_end())
+                .unwrap()
+                .message,
+            "hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn closing_transcripts_writes_everything_logged() {
+        let dir = std::env::temp_dir().join(format!("close_transcripts_{}", std::process::id()));
+        let config = RoomConfig {
+            log_dir: Some(dir.clone()),
+            ..test_config()
+        };
+        let rooms = ChatRooms::default();
+        let room = get_room("closing", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        for i in 0..1000 {
+            room.log_message(&format!("message {}", i), 1);
+        }
+        rooms.close_transcripts().await;
+
+        let transcript = tokio::fs::read_to_string(room.log_path.as_ref().unwrap()).await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        let messages: Vec<String> = transcript
+            .unwrap()
+            .lines()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        assert_eq!(messages.len(), 1000);
+        assert_eq!(messages[999], "message 999");
+    }
+
+    #[tokio::test]
+    async fn json_transcript_has_one_object_per_line() {
+        let config = RoomConfig {
+            transcript_format: TranscriptFormat::Json,
+            ..test_config()
+        };
+        let room = ChatRoom::with_config("json_room".to_owned(), Users::default(), config).await;
+        room.log_message("hello, \"world\"", 7);
+        room.flush_log().await;
+
+        let log_path = room.log_path.as_ref().unwrap();
+        assert_eq!(log_path.extension().unwrap(), "jsonl");
+        let transcript = tokio::fs::read_to_string(log_path).await.unwrap();
+        let lines: Vec<&str> = transcript.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: JsonRecord = serde_json::from_str(lines[0]).unwrap();
+        assert!(humantime::parse_rfc3339(&record.timestamp).is_ok());
+        assert_eq!(
+            record,
+            JsonRecord {
+                timestamp: record.timestamp.clone(),
+                room: "json_room".to_owned(),
+                seq: None,
+                user_id: 7,
+                to: None,
+                name: None,
+                message: "hello, \"world\"".to_owned(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn transcript_carries_sequence_numbers() {
+        let config = RoomConfig {
+            log_sequence: true,
+            ..test_config()
+        };
+        let room =
+            ChatRoom::with_config("sequenced_room".to_owned(), Users::default(), config).await;
+        for i in 0..5 {
+            room.log_message(&format!("message {}", i), 1);
+        }
+        room.flush_log().await;
+
+        let log_path = room.log_path.as_ref().unwrap();
+        let transcript = tokio::fs::read_to_string(log_path).await.unwrap();
+        let seqs: Vec<Option<u64>> = transcript
+            .lines()
+            .map(|line| Record::parse(line).unwrap().seq)
+            .collect();
+        assert_eq!(seqs, (1..=5).map(Some).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_senders_get_increasing_sequence_numbers() {
+        let config = RoomConfig {
+            log_sequence: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_config("concurrent_room".to_owned(), Users::default(), config).await,
+        );
+        let senders: Vec<_> = (1..=4)
+            .map(|user_id| {
+                let room = room.clone();
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        room.log_message(&format!("message {}", i), user_id);
+                    }
+                })
+            })
+            .collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        room.flush_log().await;
+
+        // Numbers are handed out in the order lines reach the transcript, with none skipped.
+        let log_path = room.log_path.as_ref().unwrap();
+        let transcript = tokio::fs::read_to_string(log_path).await.unwrap();
+        let seqs: Vec<Option<u64>> = transcript
+            .lines()
+            .map(|line| Record::parse(line).unwrap().seq)
+            .collect();
+        assert_eq!(seqs, (1..=100).map(Some).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_revives_lingering_room() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            linger: Some(Duration::from_secs(10)),
+            ..test_config()
+        };
+
+        // The last user leaves and the room starts lingering.
+        let room = get_room("lingering_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        linger(room.clone(), rooms.clone());
+        let room_ptr = Arc::downgrade(&room);
+        drop(room);
+
+        // A reconnect lands right as the linger expires, before the reaper gets to run.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let revived = get_room("lingering_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert!(room_ptr.ptr_eq(&Arc::downgrade(&revived)));
+        let entry = rooms.get("lingering_room").await;
+        assert!(entry.is_some_and(|ptr| ptr.ptr_eq(&room_ptr)));
+
+        // Once the revived room empties again a fresh linger applies, and then it is reaped.
+        linger(revived.clone(), rooms.clone());
+        drop(revived);
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(rooms.get("lingering_room").await.is_none());
+        assert!(room_ptr.upgrade().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_rooms_are_reaped() {
+        let rooms = ChatRooms::default();
+        let config = test_config();
+
+        // Held open by something other than a connection, so it never drops on its own.
+        let held = get_room("idle_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        let occupied = get_room("occupied_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        // Started once the rooms exist, so waiting for their transcripts can't skip its clock ahead.
+        spawn_idle_reaper(rooms.clone(), Duration::from_secs(60));
+        let (tx, _rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        occupied
+            .users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+
+        tokio::time::advance(Duration::from_secs(45)).await;
+        held.log_message("still here", 1);
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        assert!(rooms.get("idle_room").await.is_some());
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(rooms.get("idle_room").await.is_none());
+        assert!(rooms.get("occupied_room").await.is_some());
+
+        let fresh = get_room("idle_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&held, &fresh));
+    }
+
+    #[tokio::test]
+    async fn sink_room_writes_no_files() {
+        let sink = MemorySink::new();
+        let room = ChatRoom::with_sink(
+            "memory_sink_room".to_owned(),
+            Users::default(),
+            test_config(),
+            Box::new(sink.clone()),
+        )
+        .await;
+        room.log_message("in memory", 1);
+        room.flush_log().await;
+
+        assert_eq!(sink.lines().len(), 1);
+        assert!(sink.lines()[0].ends_with("Channel memory_sink_room, user 1: in memory"));
+        assert!(room.log_path.is_none());
+        let stray_files = std::fs::read_dir(".")
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("memory_sink_room")
+            })
+            .count();
+        assert_eq!(stray_files, 0);
+    }
+
+    #[tokio::test]
+    async fn get_room_during_shutdown() {
+        let rooms = ChatRooms::default();
+        let config = test_config();
+        let open_room = get_room("open_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+
+        config.shutdown.begin();
+        assert!(get_room("late_room", rooms.clone(), &config, None)
+            .await
+            .is_err());
+        assert!(rooms.get("late_room").await.is_none());
+        let reused = get_room("open_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&open_room, &reused));
+
+        let refuse_all = RoomConfig {
+            shutdown_policy: ShutdownPolicy::RefuseAll,
+            ..config
+        };
+        assert!(get_room("open_room", rooms.clone(), &refuse_all, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn room_creation_is_throttled() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            room_creation_rate: Some(Arc::new(TokenBucket::new(1.0, 3))),
+            ..test_config()
+        };
+        let mut created = Vec::new();
+        let mut throttled = 0;
+        for i in 0..6 {
+            match get_room(&format!("burst_room_{}", i), rooms.clone(), &config, None).await {
+                Ok(room) => created.push(room),
+                Err(Unavailable::Throttled(wait)) => {
+                    assert!(wait <= Duration::from_secs(1));
+                    throttled += 1;
+                }
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!((created.len(), throttled), (3, 3));
+
+        // Existing rooms can still be joined.
+        assert!(get_room("burst_room_0", rooms.clone(), &config, None)
+            .await
+            .is_ok());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let late = get_room("burst_room_late", rooms.clone(), &config, None).await;
+        assert!(late.is_ok());
+        assert!(get_room("burst_room_later", rooms.clone(), &config, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drained_room_refuses_joins_then_disconnects() {
+        let rooms = ChatRooms::default();
+        let config = RoomConfig {
+            drain_grace: Some(Duration::from_secs(60)),
+            ..test_config()
+        };
+        let room = get_room("drain_room", rooms.clone(), &config, None)
+            .await
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+
+        assert!(drain_room(room.clone(), rooms.clone()).await);
+        assert!(!drain_room(room.clone(), rooms.clone()).await);
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("*** room is closing for maintenance in 1m")
+        );
+        assert!(get_room("drain_room", rooms.clone(), &config, None)
+            .await
+            .is_err());
+
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(room.users.read().await.len(), 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(rx.recv().await.unwrap().is_close());
+        assert!(room.users.read().await.is_empty());
+        assert!(rooms.get("drain_room").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn post_message_reaches_injected_senders() {
+        let users = Users::default();
+        let mut receivers = Vec::new();
+        {
+            let mut users = users.write().await;
+            for uid in 1..=3 {
+                let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+                users.insert(uid, User::new(tx, Protocol::LegacyText));
+                receivers.push(rx);
+            }
+        }
+        let room = ChatRoom::unlogged("injected_room".to_owned(), users).await;
+
+        let seq = room.post_message(1, "hello", None, None).await;
+        assert_eq!(seq, 1);
+        assert!(receivers[0].try_recv().is_err());
+        for rx in &mut receivers[1..] {
+            assert_eq!(rx.recv().await.unwrap().to_str(), Ok("<User#1>: hello"));
+        }
+        assert!(room.log_path.is_none());
+    }
+
+    #[tokio::test]
+    async fn broadcast_where_matches_roles() {
+        let users = Users::default();
+        let mut receivers = Vec::new();
+        {
+            let mut users = users.write().await;
+            let roles = [Role::Admin, Role::Member, Role::Spectator, Role::Admin];
+            for (uid, role) in (1..).zip(roles) {
+                let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+                let user = User {
+                    identity: Identity {
+                        id: uid,
+                        role,
+                        account: None,
+                        nick: None,
+                        status: Status::Active,
+                    },
+                    ..User::new(tx, Protocol::LegacyText)
+                };
+                users.insert(uid, user);
+                receivers.push((role, rx));
+            }
+        }
+        let room = ChatRoom::unlogged("roles_room".to_owned(), users).await;
+
+        room.broadcast_where(|identity| identity.role == Role::Admin, "admins only")
+            .await;
+        room.broadcast("everyone").await;
+
+        for (role, rx) in &mut receivers {
+            if *role == Role::Admin {
+                assert_eq!(rx.recv().await.unwrap().to_str(), Ok("*** admins only"));
+            }
+            assert_eq!(rx.recv().await.unwrap().to_str(), Ok("*** everyone"));
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_except_skips_one_user() {
+        let users = Users::default();
+        let mut receivers = Vec::new();
+        for uid in 1..=3 {
+            let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+            users
+                .write()
+                .await
+                .insert(uid, User::new(tx, Protocol::LegacyText));
+            receivers.push(rx);
+        }
+        let room = ChatRoom::unlogged("except_room".to_owned(), users).await;
+
+        room.broadcast("all hands").await;
+        room.broadcast_except("not you", 2).await;
+
+        for (uid, rx) in (1..).zip(&mut receivers) {
+            assert_eq!(rx.recv().await.unwrap().to_str(), Ok("*** all hands"));
+            if uid != 2 {
+                assert_eq!(rx.recv().await.unwrap().to_str(), Ok("*** not you"));
+            }
+            assert!(rx.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn members_are_told_of_joins_and_leaves() {
+        let config = RoomConfig {
+            announce_presence: true,
+            announce_own_join: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "presence_notice_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (tx, mut a_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let _a = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(a_rx.recv().await.unwrap().to_str(), Ok("*** User#1 joined"));
+
+        let (tx, mut b_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let b = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(2),
+        )
+        .await
+        .unwrap();
+        assert_eq!(a_rx.recv().await.unwrap().to_str(), Ok("*** User#2 joined"));
+        assert_eq!(b_rx.recv().await.unwrap().to_str(), Ok("*** User#2 joined"));
+
+        b.leave(ChatRooms::default()).await;
+        assert_eq!(a_rx.recv().await.unwrap().to_str(), Ok("*** User#2 left"));
+    }
+
+    #[tokio::test]
+    async fn topics_are_capped_per_room() {
+        let config = RoomConfig {
+            max_topics: Some(2),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "topic_cap_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let me = User::new(tx, Protocol::LegacyText);
+        let mut conn = Connection::join(room.clone(), me, Identity::new(1))
+            .await
+            .unwrap();
+
+        conn.handle_text("/subscribe a b").await;
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("*** subscribed topics: a b")
+        );
+        conn.handle_text("/subscribe c").await;
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("*** room topic limit reached (max 2), refused: c")
+        );
+        rx.recv().await.unwrap();
+        conn.handle_text("/topic c hello").await;
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("*** room topic limit reached (max 2), refused: c")
+        );
+        assert_eq!(room.active_topics(), 2);
+
+        // Topics nobody subscribes to any more are pruned, freeing up room under the cap.
+        conn.handle_text("/unsubscribe a").await;
+        rx.recv().await.unwrap();
+        assert_eq!(room.active_topics(), 1);
+        conn.handle_text("/subscribe c").await;
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("*** subscribed topics: b c")
+        );
+    }
+
+    #[tokio::test]
+    async fn joins_are_coalesced_in_transcript() {
+        let sink = MemorySink::new();
+        let config = RoomConfig {
+            log_presence: true,
+            coalesce_presence: Some(Duration::from_secs(60)),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "presence_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+
+        let mut connections = Vec::new();
+        for id in 1..=5 {
+            let (tx, _rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+            let me = User::new(tx, Protocol::LegacyText);
+            connections.push(
+                Connection::join(room.clone(), me, Identity::new(id))
+                    .await
+                    .unwrap(),
+            );
+        }
+        room.post_message(1, "hello", None, None).await;
+        connections.pop().unwrap().leave(ChatRooms::default()).await;
+        room.flush_log().await;
+
+        let messages: Vec<String> = sink
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        assert_eq!(
+            messages,
+            ["*** 5 users joined ***", "hello", "*** User#5 left ***"]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_sender_may_edit() {
+        let sink = MemorySink::new();
+        let config = RoomConfig {
+            edit_window: Some(Duration::from_secs(60)),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "edit_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (tx, mut author_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut author = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(1),
+        )
+        .await
+        .unwrap();
+        let (tx, mut other_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut other = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(2),
+        )
+        .await
+        .unwrap();
+
+        author.handle_text("helo").await;
+        let seq = room.recent_messages(1)[0].seq;
+        assert_eq!(
+            other_rx.recv().await.unwrap().to_str(),
+            Ok("<User#1>: helo")
+        );
+
+        author.handle_text(&format!("/edit {} hello", seq)).await;
+        assert_eq!(
+            other_rx.recv().await.unwrap().to_str(),
+            Ok(format!("*** User#1 edited message {}: hello", seq).as_str())
+        );
+
+        other.handle_text(&format!("/edit {} hijacked", seq)).await;
+        assert_eq!(
+            other_rx.recv().await.unwrap().to_str(),
+            Ok(format!(
+                "*** can't edit message {}: you can only change your own messages",
+                seq
+            )
+            .as_str())
+        );
+        assert!(author_rx.try_recv().is_err());
+        assert_eq!(room.recent_messages(1)[0].body, "hello");
+
+        room.flush_log().await;
+        let messages: Vec<String> = sink
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "helo".to_owned(),
+                format!("*** edited message {}: hello ***", seq)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn json_clients_edit_and_delete_with_frames() {
+        let sink = MemorySink::new();
+        let config = RoomConfig {
+            edit_window: Some(Duration::from_secs(60)),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "json_edit_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (tx, mut author_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut author = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::JsonV1),
+            Identity::new(1),
+        )
+        .await
+        .unwrap();
+        let (tx, mut other_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut other = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::JsonV1),
+            Identity::new(2),
+        )
+        .await
+        .unwrap();
+
+        author
+            .handle_frame(r#"{"type":"message","body":"helo"}"#)
+            .await;
+        assert!(other_rx.recv().await.is_some());
+        let seq = room.recent_messages(1)[0].seq;
+
+        author
+            .handle_frame(&format!(
+                r#"{{"type":"edit","seq":{},"text":"hello"}}"#,
+                seq
+            ))
+            .await;
+        assert_eq!(
+            other_rx.recv().await.unwrap().to_str(),
+            Ok(format!(r#"{{"type":"edit","seq":{},"from":1,"body":"hello"}}"#, seq).as_str())
+        );
+
+        other
+            .handle_frame(&format!(
+                r#"{{"type":"edit","seq":{},"text":"hijacked"}}"#,
+                seq
+            ))
+            .await;
+        assert_eq!(
+            other_rx.recv().await.unwrap().to_str(),
+            Ok(format!(
+                r#"{{"type":"notice","body":"can't edit message {}: you can only change your own messages"}}"#,
+                seq
+            )
+            .as_str())
+        );
+        assert!(author_rx.try_recv().is_err());
+        assert_eq!(room.recent_messages(1)[0].body, "hello");
+
+        author
+            .handle_frame(&format!(r#"{{"type":"delete","seq":{}}}"#, seq))
+            .await;
+        assert_eq!(
+            other_rx.recv().await.unwrap().to_str(),
+            Ok(format!(r#"{{"type":"delete","seq":{},"from":1}}"#, seq).as_str())
+        );
+        assert!(room.recent_messages(1).is_empty());
+
+        room.flush_log().await;
+        let messages: Vec<String> = sink
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "helo".to_owned(),
+                format!("*** edited message {}: hello ***", seq),
+                format!("*** deleted message {} ***", seq),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn membership_snapshot_lists_members() {
+        let dir = std::env::temp_dir().join(format!("membership_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let config = RoomConfig {
+            membership_snapshots: Some(SnapshotConfig {
+                dir: dir.clone(),
+                debounce: Duration::from_millis(10),
+            }),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "snapshot_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+
+        let mut connections = Vec::new();
+        for id in [3, 1, 2] {
+            let (tx, _rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+            let me = User::new(tx, Protocol::LegacyText);
+            connections.push(
+                Connection::join(room.clone(), me, Identity::new(id))
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let path = dir.join("snapshot_room.members.json");
+        let mut members = serde_json::Value::Null;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if let Ok(json) = tokio::fs::read(&path).await {
+                let snapshot: serde_json::Value = serde_json::from_slice(&json).unwrap();
+                members = snapshot["members"].clone();
+                if members.as_array().map(Vec::len) == Some(3) {
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            members,
+            serde_json::json!([
+                {"id": 1, "name": "User#1", "role": "member", "status": "active"},
+                {"id": 2, "name": "User#2", "role": "member", "status": "active"},
+                {"id": 3, "name": "User#3", "role": "member", "status": "active"},
+            ])
+        );
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_within_cooldown_are_dropped() {
+        let config = RoomConfig {
+            message_cooldown: Some(Duration::from_millis(500)),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "cooldown_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (tx, mut sender_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let me = User::new(tx, Protocol::LegacyText);
+        let mut sender = Connection::join(room.clone(), me, Identity::new(1))
+            .await
+            .unwrap();
+        let (tx, mut listener_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let me = User::new(tx, Protocol::LegacyText);
+        let _listener = Connection::join(room.clone(), me, Identity::new(2))
+            .await
+            .unwrap();
+
+        sender.handle_text("first").await;
+        tokio::time::advance(Duration::from_millis(100)).await;
+        sender.handle_text("too soon").await;
+        assert_eq!(
+            sender_rx.recv().await.unwrap().to_str(),
+            Ok("*** slow down, wait 500ms between messages")
+        );
+
+        tokio::time::advance(Duration::from_millis(400)).await;
+        sender.handle_text("second").await;
+        for expected in ["<User#1>: first", "<User#1>: second"] {
+            assert_eq!(listener_rx.recv().await.unwrap().to_str(), Ok(expected));
+        }
+        assert!(listener_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn binary_frames_count_against_the_message_rate() {
+        let config = RoomConfig {
+            message_policy: MessagePolicy::allowing(&[MessageKind::Text, MessageKind::Binary]),
+            limits: RoomLimits {
+                message_rate: Some(MessageRate {
+                    max_messages_per_window: 2,
+                    window: Duration::from_secs(10),
+                    disconnect_after: None,
+                }),
+                ..RoomLimits::default()
+            },
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "binary_rate_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (sender, mut sender_rx) = join_as(&room, 1, "sender").await;
+        let (_listener, mut listener_rx) = join_as(&room, 2, "listener").await;
+        let (mux_tx, mut mux_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let _mux = Connection::join(
+            room.clone(),
+            User::new(mux_tx, Protocol::MuxV1),
+            Identity::new(3),
+        )
+        .await
+        .unwrap();
+        while mux_rx.try_recv().is_ok() {}
+        let mut sender = sender.unwrap();
+
+        for i in 0..4u8 {
+            sender.handle_binary(&[i]).await;
+        }
+        for expected in [[0u8], [1]] {
+            assert_eq!(listener_rx.recv().await.unwrap().as_bytes(), expected);
+        }
+        assert!(listener_rx.try_recv().is_err());
+        assert!(mux_rx.try_recv().is_err());
+        for _ in 0..2 {
+            assert_eq!(
+                sender_rx.recv().await.unwrap().to_str(),
+                Ok("*** too many messages, try again in 5s")
+            );
+        }
+        assert_eq!(sender.me.info.messages.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_over_rate_are_not_broadcast() {
+        let config = RoomConfig {
+            limits: RoomLimits {
+                message_rate: Some(MessageRate {
+                    max_messages_per_window: 3,
+                    window: Duration::from_secs(10),
+                    disconnect_after: Some(3),
+                }),
+                ..RoomLimits::default()
+            },
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "rate_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (sender, mut sender_rx) = join_as(&room, 1, "sender").await;
+        let (_listener, mut listener_rx) = join_as(&room, 2, "listener").await;
+        let mut sender = sender.unwrap();
+
+        for i in 0..5 {
+            sender.handle_text(&format!("message {}", i)).await;
+        }
+        for expected in [
+            "<User#1>: message 0",
+            "<User#1>: message 1",
+            "<User#1>: message 2",
+        ]
+        .iter()
+        {
+            assert_eq!(listener_rx.recv().await.unwrap().to_str(), Ok(*expected));
+        }
+        assert!(listener_rx.try_recv().is_err());
+        for _ in 0..2 {
+            assert_eq!(
+                sender_rx.recv().await.unwrap().to_str(),
+                Ok("*** too many messages, try again in 4s")
+            );
+        }
+
+        // A third refusal in a row disconnects the sender.
+        sender.handle_text("message 5").await;
+        assert!(sender_rx.recv().await.unwrap().is_close());
+        assert!(!room.users.read().await.contains_key(&1));
+        assert!(listener_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_room_closes_as_busy() {
+        let config = RoomConfig {
+            limits: RoomLimits {
+                max_users: Some(1),
+                ..RoomLimits::default()
+            },
+            busy_retry_after: Some(Duration::from_secs(30)),
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "busy_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (_member, _member_rx) = join_as(&room, 1, "member").await;
+
+        let (refused, mut refused_rx) = join_as(&room, 2, "refused").await;
+        assert!(refused.is_none());
+        assert_eq!(
+            refused_rx.recv().await.unwrap().to_str(),
+            Ok("*** room is full (max 1 users), retry in 30s")
+        );
+        let close = refused_rx.recv().await.unwrap();
+        assert_eq!(
+            close.close_frame(),
+            Some((4011, "server busy, retry later"))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_codes_distinguish_kick_from_idle() {
+        let config = RoomConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "close_code_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+
+        let (tx, mut admin_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let admin = Identity {
+            role: Role::Admin,
+            ..Identity::new(1)
+        };
+        let mut admin = Connection::join(room.clone(), User::new(tx, Protocol::LegacyText), admin)
+            .await
+            .unwrap();
+        let (tx, mut kicked_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let me = User::new(tx, Protocol::LegacyText);
+        let mut kicked = Connection::join(room.clone(), me, Identity::new(2))
+            .await
+            .unwrap();
+
+        admin.handle_text("/kick 2").await;
+        let close = kicked_rx.recv().await.unwrap();
+        assert_eq!(close.close_frame(), Some((4001, "kicked")));
+        assert!(!room.users.read().await.contains_key(&2));
+
+        // A client that ignores the close frame can't talk in the room any more.
+        while admin_rx.try_recv().is_ok() {}
+        let mut ignoring = futures::stream::iter(vec![Ok(Message::text("still here"))]);
+        read_frames(&mut kicked, &mut ignoring).await;
+        assert!(admin_rx.try_recv().is_err());
+
+        // The admin never sends anything again, so their connection goes idle.
+        let mut silent = futures::stream::pending();
+        read_frames(&mut admin, &mut silent).await;
+        let close = admin_rx.recv().await.unwrap();
+        assert_eq!(close.close_frame(), Some((4004, "idle timeout")));
+
+        assert_eq!(DisconnectReason::Kicked.code(false), 1008);
+        assert_eq!(DisconnectReason::Idle.code(false), 1001);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_users_are_marked_away() {
+        let config = RoomConfig {
+            away_after: Some(Duration::from_secs(60)),
+            idle_timeout: Some(Duration::from_secs(300)),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "away_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (_watcher, mut watcher_rx) = join_as(&room, 1, "watcher").await;
+        let (quiet, _quiet_rx) = join_as(&room, 2, "quiet").await;
+        let mut quiet = quiet.unwrap();
+        let (frames_tx, mut frames_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            let mut frames = futures::stream::poll_fn(move |cx| frames_rx.poll_recv(cx));
+            read_frames(&mut quiet, &mut frames).await;
+        });
+
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(watcher_rx.try_recv().is_err());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            watcher_rx.recv().await.unwrap().to_str(),
+            Ok("*** User#2 is away")
+        );
+        let members = membership::members(&room.users).await;
+        assert_eq!(members[1].status, Status::Away);
+
+        // Users joining later are told who is away.
+        let (tx, mut late_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let _late = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::JsonV1),
+            Identity::new(3),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            late_rx.try_recv().unwrap().to_str(),
+            Ok(r#"{"type":"status","from":2,"status":"away"}"#)
+        );
+
+        frames_tx.send(Ok(Message::text("back now"))).unwrap();
+        let last_frame = tokio::time::Instant::now();
+        assert_eq!(
+            watcher_rx.recv().await.unwrap().to_str(),
+            Ok("*** User#2 is back")
+        );
+        assert_eq!(
+            watcher_rx.recv().await.unwrap().to_str(),
+            Ok("<User#2>: back now")
+        );
+        assert_eq!(
+            membership::members(&room.users).await[1].status,
+            Status::Active
+        );
+
+        // Going away didn't put off the idle timeout, counted from the last frame.
+        reader.await.unwrap();
+        assert_eq!(last_frame.elapsed(), Duration::from_secs(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_that_stop_ponging_are_reaped() {
+        let config = RoomConfig {
+            keepalive: Some(Keepalive {
+                interval: Duration::from_secs(30),
+                timeout: Duration::from_secs(10),
+            }),
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "keepalive_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut conn = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(1),
+        )
+        .await
+        .unwrap();
+
+        // The client answers two pings and then goes silent, as if the connection half-closed.
+        let (frames_tx, mut frames) = mpsc::unbounded_channel();
+        let client = tokio::spawn(async move {
+            let mut answered = 0;
+            loop {
+                let message = rx.recv().await.unwrap();
+                if message.is_close() {
+                    return message;
+                }
+                if message.is_ping() && answered < 2 {
+                    answered += 1;
+                    frames_tx.send(Ok(Message::pong(Vec::new()))).unwrap();
+                }
+            }
+        });
+        let mut frames = futures::stream::poll_fn(move |cx| frames.poll_recv(cx));
+        let started = tokio::time::Instant::now();
+        read_frames(&mut conn, &mut frames).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(100));
+        let close = client.await.unwrap();
+        assert_eq!(close.close_frame(), Some((4010, "ping timeout")));
+
+        conn.leave(ChatRooms::default()).await;
+        assert!(room.users.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_told_why_they_were_closed() {
+        let config = RoomConfig {
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "broken_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut conn = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(1),
+        )
+        .await
+        .unwrap();
+
+        // warp errors can't be built directly, but binding a port that is already taken gives one.
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let error = warp::serve(warp::any().map(warp::reply))
+            .try_bind_ephemeral(taken.local_addr().unwrap())
+            .err()
+            .unwrap();
+        let mut frames = futures::stream::iter(vec![Ok(Message::text("hi")), Err(error)]);
+        read_frames(&mut conn, &mut frames).await;
+        let close = loop {
+            let message = rx.recv().await.unwrap();
+            if message.is_close() {
+                break message;
+            }
+        };
+        assert_eq!(close.close_frame(), Some((4012, "protocol error")));
+        assert_eq!(DisconnectReason::ProtocolError.code(false), 1002);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_are_closed_after_their_lifetime() {
+        let config = RoomConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            max_connection_lifetime: Some(Duration::from_secs(300)),
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "lifetime_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut conn = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::LegacyText),
+            Identity::new(1),
+        )
+        .await
+        .unwrap();
+
+        // A frame every 30 seconds keeps the connection from going idle, but not past its lifetime.
+        let (frames_tx, mut frames) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                if frames_tx.send(Ok(Message::text("still here"))).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut frames = futures::stream::poll_fn(move |cx| frames.poll_recv(cx));
+        let started = tokio::time::Instant::now();
+        read_frames(&mut conn, &mut frames).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(300));
+        let close = loop {
+            let message = rx.recv().await.unwrap();
+            if message.is_close() {
+                break message;
+            }
+        };
+        assert_eq!(close.close_frame(), Some((4009, "reconnect required")));
+        assert_eq!(DisconnectReason::Expired.code(false), 1001);
+    }
+
+    #[tokio::test]
+    async fn nickname_handshake_names_messages() {
+        let config = RoomConfig {
+            nick_handshake: true,
+            ..test_config()
+        };
+        let sink = MemorySink::new();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "nick_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (first, mut first_rx) = join_as(&room, 1, "alice").await;
+        let mut first = first.unwrap();
+        let (second, mut second_rx) = join_as(&room, 2, "bob").await;
+        let mut second = second.unwrap();
+        let (third, mut third_rx) = join_as(&room, 3, "carol").await;
+        let mut third = third.unwrap();
+
+        first.handle_text("/nick ada").await;
+        assert_eq!(
+            first_rx.recv().await.unwrap().to_str(),
+            Ok("*** you are now known as ada")
+        );
+        second.handle_text("/nick ada").await;
+        assert_eq!(
+            second_rx.recv().await.unwrap().to_str(),
+            Ok("*** you are now known as ada2")
+        );
+        third.handle_text("/nick").await;
+        assert_eq!(
+            third_rx.recv().await.unwrap().to_str(),
+            Ok("*** no nickname given, you are User#3")
+        );
+
+        first.handle_text("hello").await;
+        assert_eq!(second_rx.recv().await.unwrap().to_str(), Ok("<ada>: hello"));
+        second.handle_text("hi ada").await;
+        assert_eq!(
+            first_rx.recv().await.unwrap().to_str(),
+            Ok("<ada2>: hi ada")
+        );
+        // The handshake is over, so a later /nick is an ordinary message.
+        third.handle_text("/nick zed").await;
+        assert_eq!(
+            first_rx.recv().await.unwrap().to_str(),
+            Ok("<User#3>: /nick zed")
+        );
+
+        room.flush_log().await;
+        let messages: Vec<String> = sink
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        assert_eq!(messages[0], "*** User#1 is now known as ada ***");
+        assert_eq!(messages[1], "*** User#2 is now known as ada2 ***");
+        assert_eq!(messages[2], "hello");
+        let hello = Record::parse(&sink.lines()[2]).unwrap();
+        assert_eq!(hello.name.as_deref(), Some("ada"));
+        assert!(sink.lines()[2].contains("[nick=ada] Channel nick_room, user 1: hello"));
+    }
+
+    #[tokio::test]
+    async fn nicknames_name_presence_and_cannot_pass_as_ids() {
+        let config = RoomConfig {
+            nick_handshake: true,
+            announce_presence: true,
+            log_presence: true,
+            user_colors: true,
+            ..test_config()
+        };
+        let sink = MemorySink::new();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "nick_presence_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (first, mut first_rx) = join_as(&room, 1, "alice").await;
+        let mut first = first.unwrap();
+        let (second, mut second_rx) = join_as(&room, 2, "bob").await;
+        let mut second = second.unwrap();
+        let (third, mut third_rx) = join_as(&room, 3, "carol").await;
+        let mut third = third.unwrap();
+        for rx in [&mut first_rx, &mut second_rx, &mut third_rx] {
+            while rx.try_recv().is_ok() {}
+        }
+
+        first.handle_text("/nick User#2").await;
+        assert_eq!(
+            first_rx.recv().await.unwrap().to_str(),
+            Ok("*** nicknames can't look like user ids, you are User#1")
+        );
+        second.handle_text("/nick 7").await;
+        assert_eq!(
+            second_rx.recv().await.unwrap().to_str(),
+            Ok("*** nicknames can't look like user ids, you are User#2")
+        );
+        third.handle_text("/nick ada").await;
+        assert_eq!(
+            third_rx.recv().await.unwrap().to_str(),
+            Ok("*** you are now known as ada")
+        );
+        assert_eq!(third.appearance, Some(Appearance::for_name("ada")));
+
+        third.leave(ChatRooms::default()).await;
+        assert_eq!(first_rx.recv().await.unwrap().to_str(), Ok("*** ada left"));
+
+        room.flush_log().await;
+        let last = sink.lines().last().cloned().unwrap();
+        assert_eq!(
+            Record::parse(&last).unwrap().message,
+            "*** ada (User#3) left ***"
+        );
+    }
+
+    #[tokio::test]
+    async fn nicknames_name_edits_and_reactions() {
+        let config = RoomConfig {
+            nick_handshake: true,
+            edit_window: Some(Duration::from_secs(60)),
+            message_policy: MessagePolicy::allowing(&[
+                MessageKind::Text,
+                MessageKind::Command,
+                MessageKind::React,
+                MessageKind::Typing,
+            ]),
+            ..test_config()
+        };
+        let sink = MemorySink::new();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "nick_edit_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (author, mut author_rx) = join_as(&room, 1, "alice").await;
+        let mut author = author.unwrap();
+        let (reader, mut reader_rx) = join_as(&room, 2, "bob").await;
+        let mut reader = reader.unwrap();
+
+        author.handle_text("/nick ada").await;
+        reader.handle_text("/nick b]ob").await;
+        assert_eq!(
+            reader_rx.recv().await.unwrap().to_str(),
+            Ok("*** nicknames can't contain ']', you are User#2")
+        );
+        while author_rx.try_recv().is_ok() {}
+
+        author.handle_text("helo").await;
+        let seq = room.recent_messages(1)[0].seq;
+        assert_eq!(reader_rx.recv().await.unwrap().to_str(), Ok("<ada>: helo"));
+        author.handle_text(&format!("/edit {} hello", seq)).await;
+        assert_eq!(
+            reader_rx.recv().await.unwrap().to_str(),
+            Ok(format!("*** ada edited message {}: hello", seq).as_str())
+        );
+        author.handle_text(&format!("/react {} 👋", seq)).await;
+        assert_eq!(
+            reader_rx.recv().await.unwrap().to_str(),
+            Ok(format!("*** ada reacted 👋 to message {}", seq).as_str())
+        );
+        author.handle_text("/typing").await;
+        assert_eq!(
+            reader_rx.recv().await.unwrap().to_str(),
+            Ok("*** ada is typing...")
+        );
+
+        room.flush_log().await;
+        let logged: Vec<(Option<String>, String)> = sink
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap())
+            .map(|record| (record.name, record.message))
+            .collect();
+        let ada = Some("ada".to_owned());
+        assert_eq!(
+            logged,
+            [
+                (None, "*** User#1 is now known as ada ***".to_owned()),
+                (ada.clone(), "helo".to_owned()),
+                (
+                    ada.clone(),
+                    format!("*** edited message {}: hello ***", seq)
+                ),
+                (ada, format!("/react {} 👋", seq)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn base64_text_is_relayed_as_binary() {
+        let config = RoomConfig {
+            base64_binary: true,
+            message_policy: MessagePolicy::allowing(&[MessageKind::Text, MessageKind::Binary]),
+            limits: RoomLimits {
+                max_message_bytes: Some(8),
+                ..RoomLimits::default()
+            },
+            ..test_config()
+        };
+        let sink = MemorySink::new();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "base64_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (sender, mut sender_rx) = join_as(&room, 1, "alice").await;
+        let mut sender = sender.unwrap();
+        let (_receiver, mut receiver_rx) = join_as(&room, 2, "bob").await;
+
+        sender.handle_text("data:base64,AAFoaf8=").await;
+        let frame = receiver_rx.recv().await.unwrap();
+        assert!(frame.is_binary());
+        assert_eq!(frame.as_bytes(), [0, 1, b'h', b'i', 0xff]);
+
+        sender.handle_text("data:base64,not base64!").await;
+        assert!(sender_rx
+            .recv()
+            .await
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("*** invalid base64 data"));
+        sender.handle_text("data:base64,AAAAAAAAAAAAAAAA").await;
+        assert_eq!(
+            sender_rx.recv().await.unwrap().to_str(),
+            Ok("*** message too long (max 8 bytes)")
+        );
+        sender.handle_text("data").await;
+        assert_eq!(
+            receiver_rx.recv().await.unwrap().to_str(),
+            Ok("<User#1>: data")
+        );
+
+        room.flush_log().await;
+        assert_eq!(
+            Record::parse(&sink.lines()[0]).unwrap().message,
+            "<binary, 5 bytes>"
+        );
+    }
+
+    /// Joins `room` as a new user signed in to `account`.
+    async fn join_as(
+        room: &Arc<ChatRoom>,
+        id: usize,
+        account: &str,
+    ) -> (Option<Connection>, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let identity = Identity {
+            account: Some(account.to_owned()),
+            ..Identity::new(id)
+        };
+        let conn = Connection::join(room.clone(), User::new(tx, Protocol::LegacyText), identity);
+        (conn.await, rx)
+    }
+
+    #[tokio::test]
+    async fn duplicate_connection_policies() {
+        let config = RoomConfig {
+            duplicate_connections: DuplicatePolicy::Reject,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "reject_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (first, _first_rx) = join_as(&room, 1, "alice").await;
+        assert!(first.is_some());
+        let (second, mut second_rx) = join_as(&room, 2, "alice").await;
+        assert!(second.is_none());
+        assert_eq!(
+            second_rx.recv().await.unwrap().to_str(),
+            Ok("*** already connected to this room")
+        );
+        let (other, _other_rx) = join_as(&room, 3, "bob").await;
+        assert!(other.is_some());
+
+        let config = RoomConfig {
+            duplicate_connections: DuplicatePolicy::Replace,
+            app_close_codes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "replace_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (first, mut first_rx) = join_as(&room, 4, "alice").await;
+        let mut first = first.unwrap();
+        let (second, mut second_rx) = join_as(&room, 5, "alice").await;
+        assert!(second.is_some());
+        let close = first_rx.recv().await.unwrap();
+        assert_eq!(
+            close.close_frame(),
+            Some((4008, "replaced by a newer connection"))
+        );
+        {
+            let users = room.users.read().await;
+            assert!(!users.contains_key(&4));
+            assert!(users.contains_key(&5));
+        }
+
+        // The replaced connection stops reading, so what its client still sends goes nowhere.
+        while second_rx.try_recv().is_ok() {}
+        let mut ghost = futures::stream::iter(vec![Ok(Message::text("still here"))]);
+        read_frames(&mut first, &mut ghost).await;
+        assert!(second_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn recipients_see_decorated_messages() {
+        let sink = MemorySink::new();
+        let config = RoomConfig {
+            decoration: Some(Decoration::parse("[{room}/{topic}] {message} (unverified)").unwrap()),
+            ..test_config()
+        };
+        let room = ChatRoom::with_sink(
+            "branded_room".to_owned(),
+            Users::default(),
+            config,
+            Box::new(sink.clone()),
+        )
+        .await;
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let user = User::new(tx, Protocol::LegacyText);
+        user.subscribe(["rust"]);
+        room.users.write().await.insert(2, user);
+
+        room.post_message(1, "hello", None, None).await;
+        room.post_message(1, "borrowck", None, Some("rust".to_owned()))
+            .await;
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("<User#1>: [branded_room/] hello (unverified)")
+        );
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("<User#1> #rust: [branded_room/rust] borrowck (unverified)")
+        );
+
+        room.flush_log().await;
+        assert_eq!(Record::parse(&sink.lines()[0]).unwrap().message, "hello");
+    }
+
+    #[tokio::test]
+    async fn classification_heads_transcript_and_sets_retention() {
+        let sink = MemorySink::new();
+        let config = RoomConfig {
+            classification: Some(Classification::Confidential),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "hr_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        room.post_message(1, "salary bands", None, None).await;
+        room.flush_log().await;
+
+        let records: Vec<Record> = sink
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap())
+            .collect();
+        assert_eq!(records[0].user_id, SERVER_USER_ID);
+        assert_eq!(records[0].message, "*** classification: confidential ***");
+        assert_eq!(records[1].message, "salary bands");
+
+        let retention = room.retention().unwrap();
+        assert_eq!(
+            retention.max_age,
+            Some(Duration::from_secs(90 * 24 * 60 * 60))
+        );
+        assert!(retention.encrypt);
+
+        let rooms = ChatRooms::default();
+        rooms
+            .insert("hr_room".to_owned(), Arc::downgrade(&room))
+            .await;
+        let listed = metrics::room_users(&rooms).await;
+        assert_eq!(listed[0].classification, Some(Classification::Confidential));
+    }
+
+    #[tokio::test]
+    async fn recent_messages_are_newest_last() {
+        let room = ChatRoom::unlogged("recent_room".to_owned(), Users::default()).await;
+        for i in 1..=5 {
+            room.post_message(i, &format!("message {}", i), None, None)
+                .await;
+        }
+        room.post_message(6, "topical", None, Some("rust".to_owned()))
+            .await;
+
+        let recent: Vec<(u64, usize, String)> = room
+            .recent_messages(3)
+            .into_iter()
+            .map(|message| (message.seq, message.from, message.body))
+            .collect();
+        assert_eq!(
+            recent,
+            [
+                (3, 3, "message 3".to_owned()),
+                (4, 4, "message 4".to_owned()),
+                (5, 5, "message 5".to_owned()),
+            ]
+        );
+        assert_eq!(room.recent_messages(100).len(), 5);
+        assert!(room.recent_messages(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn joining_user_is_sent_recent_history() {
+        let config = RoomConfig {
+            join_history: Some(50),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "history_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (_early, mut early_rx) = join_as(&room, 1, "early").await;
+        for body in ["one", "two", "three"].iter() {
+            room.post_message(1, body, None, None).await;
+        }
+
+        let (_late, mut late_rx) = join_as(&room, 2, "late").await;
+        for expected in ["<User#1>: one", "<User#1>: two", "<User#1>: three"].iter() {
+            assert_eq!(late_rx.try_recv().unwrap().to_str(), Ok(*expected));
+        }
+        assert!(late_rx.try_recv().is_err());
+        // The replay went to the joining user alone.
+        assert!(early_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn recreated_room_replays_its_last_transcript() {
+        let dir = std::env::temp_dir().join(format!("recover_history_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let line = |record: Record| record.to_line("lobby") + "\n";
+        let stale = line(Record::new(1, "from an older transcript"));
+        let latest = [
+            line(Record::new(SERVER_USER_ID, "*** User#1 joined ***")),
+            line(Record::new(1, "first")),
+            line(Record {
+                to: Some(2),
+                ..Record::new(1, "just between us")
+            }),
+            "not a transcript line\n".to_owned(),
+            line(Record::new(2, "second")),
+        ]
+        .concat();
+        for (stamp, contents) in [
+            ("2021-10-01T12:00:00Z", stale.as_str()),
+            ("2021-10-02T12:00:00Z", latest.as_str()),
+        ]
+        .iter()
+        {
+            let path = dir.join(format!("lobby_{}.log", stamp));
+            tokio::fs::write(path, contents).await.unwrap();
+        }
+        // Another room whose name starts the same isn't mistaken for this one.
+        let other = dir.join("lobby_b_2021-10-03T12:00:00Z.log");
+        tokio::fs::write(other, line(Record::new(3, "wrong room")))
+            .await
+            .unwrap();
+
+        let config = RoomConfig {
+            log_dir: Some(dir.clone()),
+            join_history: Some(10),
+            recover_history: Some(10),
+            ..test_config()
+        };
+        let room =
+            Arc::new(ChatRoom::with_config("lobby".to_owned(), Users::default(), config).await);
+        let (_user, mut rx) = join_as(&room, 3, "returning").await;
+        let seq = room.post_message(3, "third", None, None).await;
+        room.flush_log().await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        for expected in ["<User#1>: first", "<User#2>: second"].iter() {
+            assert_eq!(rx.try_recv().unwrap().to_str(), Ok(*expected));
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(seq, 3);
+    }
+
+    #[tokio::test]
+    async fn history_is_not_recovered_without_a_transcript() {
+        let dir = std::env::temp_dir().join(format!("recover_missing_{}", std::process::id()));
+        let config = RoomConfig {
+            log_dir: Some(dir.clone()),
+            recover_history: Some(10),
+            ..test_config()
+        };
+        let room = ChatRoom::with_config("fresh".to_owned(), Users::default(), config).await;
+        room.flush_log().await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        assert!(room.recent_messages(10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_reaches_into_compressed_history() {
+        let config = RoomConfig {
+            compressed_history: Some(HistoryConfig {
+                max_messages: 1000,
+                block_messages: 32,
+            }),
+            ..test_config()
+        };
+        let room = ChatRoom::with_sink(
+            "archive_room".to_owned(),
+            Users::default(),
+            config,
+            Box::new(MemorySink::new()),
+        )
+        .await;
+        for i in 1..=500 {
+            room.post_message(i % 7, &format!("status update number {}", i), None, None)
+                .await;
+        }
+
+        let replayed = room.recent_messages(500);
+        assert_eq!(replayed.len(), 500);
+        for (message, i) in replayed.iter().zip(1..) {
+            assert_eq!(message.seq, i);
+            assert_eq!(message.from, (i % 7) as usize);
+            assert_eq!(message.body, format!("status update number {}", i));
+        }
+        // The window reaching back into the history starts at the right message.
+        assert_eq!(room.recent_messages(120)[0].seq, 381);
+
+        let raw: usize = replayed[..450].iter().map(|m| m.body.len()).sum();
+        assert!(room.compressed_history_bytes() < raw);
+    }
+
+    #[tokio::test]
+    async fn late_joiner_sees_pinned_message() {
+        let room = Arc::new(ChatRoom::unlogged("pin_room".to_owned(), Users::default()).await);
+        let (tx, mut admin_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let me = User::new(tx, Protocol::LegacyText);
+        let admin = Identity {
+            role: Role::Admin,
+            ..Identity::new(1)
+        };
+        let mut admin = Connection::join(room.clone(), me, admin).await.unwrap();
+
+        let seq = room.post_message(2, "read the rules", None, None).await;
+        assert_eq!(
+            admin_rx.try_recv().unwrap().to_str(),
+            Ok("<User#2>: read the rules")
+        );
+        admin.handle_text(&format!("/pin {}", seq)).await;
+        assert_eq!(
+            admin_rx.try_recv().unwrap().to_str(),
+            Ok("*** pinned message 1 from User#2: read the rules")
+        );
+
+        let (tx, mut late_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let me = User::new(tx, Protocol::JsonV1);
+        let mut late = Connection::join(room.clone(), me, Identity::new(3))
+            .await
+            .unwrap();
+        assert_eq!(
+            late_rx.try_recv().unwrap().to_str(),
+            Ok(r#"{"type":"pinned","seq":1,"from":2,"body":"read the rules"}"#)
+        );
+
+        late.handle_text("/unpin").await;
+        assert_eq!(
+            late_rx.try_recv().unwrap().to_str(),
+            Ok(r#"{"type":"notice","body":"only admins can pin messages"}"#)
+        );
+        admin.handle_text("/unpin").await;
+        assert_eq!(
+            late_rx.try_recv().unwrap().to_str(),
+            Ok(r#"{"type":"unpinned","seq":1}"#)
+        );
+        assert_eq!(room.pinned(), None);
+    }
+
+    #[tokio::test]
+    async fn rotation_loses_no_messages() {
+        let dir = std::env::temp_dir().join(format!("rotation_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("rotating_room.log");
+        let sink = RotatingFileSink::create(&path, TranscriptFormat::Text, Some(4096))
+            .await
+            .unwrap();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "rotating_room".to_owned(),
+                Users::default(),
+                test_config(),
+                Box::new(sink),
+            )
+            .await,
+        );
+
+        let sender = room.clone();
+        let sending = tokio::spawn(async move {
+            for i in 0..500 {
+                sender.log_message(&format!("message {}", i), 1);
+                if i % 50 == 0 {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+        for _ in 0..5 {
+            room.rotate_log().await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        sending.await.unwrap();
+        room.flush_log().await;
+
+        let mut messages = Vec::new();
+        let mut segments = 0;
+        while let Ok(text) =
+            tokio::fs::read_to_string(RotatingFileSink::segment_path(&path, segments)).await
+        {
+            messages.extend(
+                text.lines()
+                    .map(|line| Record::parse(line).unwrap().message),
+            );
+            segments += 1;
+        }
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        // 500 lines of about 70 bytes need several 4KiB segments even without forced rotations.
+        assert!(segments > 5);
+        let expected: Vec<String> = (0..500).map(|i| format!("message {}", i)).collect();
+        assert_eq!(messages, expected);
+    }
+
+    #[tokio::test]
+    async fn configured_rooms_rotate_their_transcript_by_size() {
+        let dir = std::env::temp_dir().join(format!("size_rotation_{}", std::process::id()));
+        let config = RoomConfig {
+            log_dir: Some(dir.clone()),
+            rotate_after_bytes: Some(256),
+            ..test_config()
+        };
+        let room =
+            ChatRoom::with_config("size_rotated_room".to_owned(), Users::default(), config).await;
+        for i in 0..10 {
+            room.log_message(&format!("message {}", i), 1);
+        }
+        room.flush_log().await;
+
+        let log_path = room.log_path.clone().unwrap();
+        let first = tokio::fs::metadata(&log_path).await;
+        let second = tokio::fs::metadata(RotatingFileSink::segment_path(&log_path, 1)).await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        assert!(first.unwrap().len() <= 256);
+        assert!(second.is_ok());
+    }
+
+    /// Takes a millisecond per call however many lines it is given, like a disk with a fixed
+    /// cost per write.
+    #[derive(Clone, Default)]
+    struct SlowSink {
+        lines: Arc<Mutex<Vec<String>>>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl LogSink for SlowSink {
+        fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                self.lines.lock().unwrap().push(line.to_owned());
+                Ok(())
+            })
+        }
+
+        fn write_lines<'a>(&'a mut self, lines: &'a [String]) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                self.lines.lock().unwrap().extend_from_slice(lines);
+                Ok(())
+            })
+        }
+
+        fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn log_backlog_is_written_in_bursts() {
+        let sink = SlowSink::default();
+        let config = RoomConfig {
+            log_burst_threshold: Some(50),
+            ..test_config()
+        };
+        let room = ChatRoom::with_sink(
+            "backlogged_room".to_owned(),
+            Users::default(),
+            config,
+            Box::new(sink.clone()),
+        )
+        .await;
+
+        // Ten messages a millisecond against a sink that manages one write a millisecond: line by
+        // line the queue would grow by nine every millisecond, to well over a thousand.
+        let mut max_depth = 0;
+        let mut burst_seen = false;
+        for i in 0..2000 {
+            room.log_message(&format!("message {}", i), 1);
+            max_depth = max_depth.max(room.log_queue_depth());
+            burst_seen |= room.is_log_bursting();
+            if i % 10 == 9 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        room.flush_log().await;
+
+        assert!(burst_seen);
+        assert!(max_depth < 200, "log queue reached {} lines", max_depth);
+        assert!(!room.is_log_bursting());
+        assert_eq!(room.log_queue_depth(), 0);
+        let messages: Vec<String> = sink
+            .lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        let expected: Vec<String> = (0..2000).map(|i| format!("message {}", i)).collect();
+        assert_eq!(messages, expected);
+        assert!(sink.writes.load(Ordering::Relaxed) < 500);
+    }
+
+    /// Fails every write while `failing` is set.
+    struct FlakySink {
+        failing: Arc<AtomicBool>,
+    }
+
+    impl LogSink for FlakySink {
+        fn write_line<'a>(&'a mut self, _line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+            let result = if self.failing.load(Ordering::Relaxed) {
+                Err(io::Error::other("disk unplugged"))
+            } else {
+                Ok(())
+            };
+            Box::pin(future::ready(result))
+        }
+
+        fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_log_recovers_without_losing_lines() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let replacement = MemorySink::new();
+        let reopen_failing = failing.clone();
+        let reopened = replacement.clone();
+        let reopen: Reopen = Box::new(move || {
+            let result: io::Result<Box<dyn LogSink>> = if reopen_failing.load(Ordering::Relaxed) {
+                Err(io::Error::other("still unplugged"))
+            } else {
+                Ok(Box::new(reopened.clone()))
+            };
+            Box::pin(future::ready(result))
+        });
+        let sink = RecoveringSink::new(
+            Box::new(FlakySink {
+                failing: failing.clone(),
+            }),
+            reopen,
+        );
+        let config = RoomConfig {
+            notify_log_failure: true,
+            ..test_config()
+        };
+        let room = ChatRoom::with_sink(
+            "flaky_room".to_owned(),
+            Users::default(),
+            config,
+            Box::new(sink),
+        )
+        .await;
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+
+        for n in 0..FAILURES_BEFORE_REOPEN {
+            room.post_message(2, &format!("lost {}", n), None, None)
+                .await;
+        }
+        room.flush_log().await;
+        assert!(room.is_degraded());
+
+        failing.store(false, Ordering::Relaxed);
+        room.post_message(2, "back", None, None).await;
+        room.flush_log().await;
+        assert!(!room.is_degraded());
+
+        let messages: Vec<String> = replacement
+            .lines()
+            .iter()
+            .map(|line| Record::parse(line).unwrap().message)
+            .collect();
+        assert_eq!(messages, ["lost 0", "lost 1", "lost 2", "back"]);
+
+        let notices: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|message| message.to_str().ok().map(str::to_owned))
+            .filter(|text| text.starts_with("***"))
+            .collect();
+        assert_eq!(
+            notices,
+            [
+                "*** this room's transcript is failing, messages may not be saved",
+                "*** this room's transcript has recovered, messages are being saved again"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn direct_message_reaches_only_its_recipient() {
+        let sink = MemorySink::new();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "dm_room".to_owned(),
+                Users::default(),
+                test_config(),
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (a, mut a_rx) = join_as(&room, 1, "a").await;
+        let (tx, mut b_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let bob = Identity {
+            nick: Some("bob".to_owned()),
+            ..Identity::new(2)
+        };
+        let _b = Connection::join(room.clone(), User::new(tx, Protocol::LegacyText), bob).await;
+        let (_c, mut c_rx) = join_as(&room, 3, "c").await;
+        let mut a = a.unwrap();
+
+        a.handle_text("/msg bob just between us").await;
+        let expected = "<User#1> (private to bob): just between us";
+        assert_eq!(b_rx.recv().await.unwrap().to_str(), Ok(expected));
+        assert_eq!(a_rx.recv().await.unwrap().to_str(), Ok(expected));
+        assert!(c_rx.try_recv().is_err());
+
+        a.handle_text("/msg User#9 anyone there?").await;
+        assert_eq!(
+            a_rx.recv().await.unwrap().to_str(),
+            Ok("*** User#9 is not in this room")
+        );
+
+        room.flush_log().await;
+        let logged: Vec<Record> = sink
+            .lines()
+            .iter()
+            .filter_map(|l| Record::parse(l))
+            .collect();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].to, Some(2));
+        assert_eq!(logged[0].message, "just between us");
+    }
+
+    #[tokio::test]
+    async fn emotes_are_shown_as_actions() {
+        let sink = MemorySink::new();
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "emote_room".to_owned(),
+                Users::default(),
+                test_config(),
+                Box::new(sink.clone()),
+            )
+            .await,
+        );
+        let (waver, mut waver_rx) = join_as(&room, 5, "waver").await;
+        let (_watcher, mut watcher_rx) = join_as(&room, 6, "watcher").await;
+        let mut waver = waver.unwrap();
+
+        waver.handle_text("/me waves").await;
+        assert_eq!(
+            watcher_rx.recv().await.unwrap().to_str(),
+            Ok("* User#5 waves")
+        );
+        assert!(waver_rx.try_recv().is_err());
+
+        room.flush_log().await;
+        let logged = Record::parse(&sink.lines()[0]).unwrap();
+        assert_eq!(
+            (logged.user_id, logged.message.as_str()),
+            (5, "* User#5 waves")
+        );
+
+        // Rooms that echo emotes show them to their sender too.
+        let config = RoomConfig {
+            echo_emotes: true,
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "echo_room".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (waver, mut waver_rx) = join_as(&room, 5, "waver").await;
+        waver.unwrap().handle_text("/me waves back").await;
+        assert_eq!(
+            waver_rx.recv().await.unwrap().to_str(),
+            Ok("* User#5 waves back")
+        );
+    }
+
+    #[tokio::test]
+    async fn who_lists_the_room_to_the_asker_alone() {
+        let room = Arc::new(ChatRoom::unlogged("who_room".to_owned(), Users::default()).await);
+        let (asker, mut asker_rx) = join_as(&room, 1, "asker").await;
+        let (tx, mut bob_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let bob = Identity {
+            nick: Some("bob".to_owned()),
+            ..Identity::new(2)
+        };
+        let _bob = Connection::join(room.clone(), User::new(tx, Protocol::LegacyText), bob).await;
+        while asker_rx.try_recv().is_ok() {}
+        while bob_rx.try_recv().is_ok() {}
+
+        asker.unwrap().handle_text("/who").await;
+        assert_eq!(
+            asker_rx.recv().await.unwrap().to_str(),
+            Ok("*** in this room: User#1 (you), bob")
+        );
+        assert!(bob_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn read_receipt_reaches_sender() {
+        let config = RoomConfig {
+            read_receipts_max_users: Some(10),
+            ..test_config()
+        };
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "receipts".to_owned(),
+                Users::default(),
+                config,
+                Box::new(MemorySink::new()),
+            )
+            .await,
+        );
+        let (sender, mut sender_rx) = join_as(&room, 1, "sender").await;
+        let mut sender = sender.unwrap();
+        let (tx, _reader_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        let mut reader = Connection::join(
+            room.clone(),
+            User::new(tx, Protocol::JsonV1),
+            Identity::new(2),
+        )
+        .await
+        .unwrap();
+
+        sender.handle_text("hello").await;
+        while sender_rx.try_recv().is_ok() {}
+        reader.handle_frame(r#"{"type":"read","seq":1}"#).await;
+        assert_eq!(
+            sender_rx.recv().await.unwrap().to_str(),
+            Ok("*** User#2 read your message 1")
+        );
+
+        // Reading the same message again isn't news.
+        reader.handle_frame(r#"{"type":"read","seq":1}"#).await;
+        assert!(sender_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unopenable_transcript_refuses_room() {
+        let config = RoomConfig {
+            log_dir: Some(std::path::PathBuf::from("Cargo.toml").join("transcripts")),
+            ..test_config()
+        };
+        let opened = ChatRoom::open(
+            "unwritable_room".to_owned(),
+            Users::default(),
+            config.clone(),
+        )
+        .await;
+        assert!(opened.is_err());
+
+        let rooms = ChatRooms::default();
+        let joined = get_room("unwritable_room", rooms.clone(), &config, None).await;
+        assert_eq!(joined.err(), Some(Unavailable::NoTranscript));
+        assert!(rooms.get("unwritable_room").await.is_none());
+
+        let degraded = RoomConfig {
+            allow_degraded: true,
+            ..config
+        };
+        let joined = get_room("unwritable_room", rooms.clone(), &degraded, None).await;
+        assert!(joined.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_log_marks_room_degraded() {
+        // The log directory can't be created inside a regular file.
+        let config = RoomConfig {
+            notify_log_failure: true,
+            log_dir: Some(std::path::PathBuf::from("Cargo.toml").join("transcripts")),
+            ..test_config()
+        };
+        let room =
+            ChatRoom::with_config("degraded_room".to_owned(), Users::default(), config).await;
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+
+        assert!(!room.is_degraded());
+        room.post_message(2, "anyone saving this?", None, None)
+            .await;
+        assert!(room.is_degraded());
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("*** this room's transcript is unavailable, messages are not being saved")
+        );
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("<User#2>: anyone saving this?")
+        );
+
+        // Users are only told once.
+        room.post_message(2, "again", None, None).await;
+        assert_eq!(rx.recv().await.unwrap().to_str(), Ok("<User#2>: again"));
+    }
 }
@@ -0,0 +1,119 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Hold time in microseconds above which a lock hold is reported, 0 when disabled.
+static SLOW_HOLD_THRESHOLD_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of lock holds that have exceeded the threshold since start.
+static SLOW_HOLDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Enables reporting of lock holds longer than `threshold`, or disables it with `None`.
+pub fn set_slow_hold_threshold(threshold: Option<Duration>) {
+    let micros = threshold.map_or(0, |t| (t.as_micros() as u64).max(1));
+    SLOW_HOLD_THRESHOLD_MICROS.store(micros, Ordering::Relaxed);
+}
+
+/// Total number of slow lock holds reported so far.
+pub fn slow_holds() -> usize {
+    SLOW_HOLDS.load(Ordering::Relaxed)
+}
+
+/// A lock guard that reports itself when it was held longer than the configured threshold.
+///
+/// When reporting is disabled no clock is read, so the only cost is a relaxed atomic load.
+#[derive(Debug)]
+pub struct TimedGuard<G> {
+    guard: G,
+    label: &'static str,
+    acquired: Option<Instant>,
+}
+
+impl<G> TimedGuard<G> {
+    fn new(guard: G, label: &'static str) -> TimedGuard<G> {
+        let acquired = if SLOW_HOLD_THRESHOLD_MICROS.load(Ordering::Relaxed) > 0 {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        TimedGuard {
+            guard,
+            label,
+            acquired,
+        }
+    }
+}
+
+impl<G> Deref for TimedGuard<G> {
+    type Target = G;
+
+    fn deref(&self) -> &G {
+        &self.guard
+    }
+}
+
+impl<G> DerefMut for TimedGuard<G> {
+    fn deref_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for TimedGuard<G> {
+    fn drop(&mut self) {
+        let acquired = match self.acquired {
+            Some(acquired) => acquired,
+            None => return,
+        };
+        let threshold = SLOW_HOLD_THRESHOLD_MICROS.load(Ordering::Relaxed);
+        let held = acquired.elapsed();
+        if threshold > 0 && held.as_micros() as u64 >= threshold {
+            SLOW_HOLDS.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(lock = self.label, ?held, "slow lock hold");
+        }
+    }
+}
+
+/// Acquires a read lock whose hold time is checked against the slow hold threshold.
+pub async fn timed_read<'a, T>(
+    lock: &'a RwLock<T>,
+    label: &'static str,
+) -> TimedGuard<RwLockReadGuard<'a, T>> {
+    TimedGuard::new(lock.read().await, label)
+}
+
+/// Acquires a write lock whose hold time is checked against the slow hold threshold.
+pub async fn timed_write<'a, T>(
+    lock: &'a RwLock<T>,
+    label: &'static str,
+) -> TimedGuard<RwLockWriteGuard<'a, T>> {
+    TimedGuard::new(lock.write().await, label)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::RwLock;
+
+    use crate::locks::{set_slow_hold_threshold, slow_holds, timed_write};
+
+    #[tokio::test]
+    async fn slow_hold_is_reported() {
+        let lock = RwLock::new(0);
+        set_slow_hold_threshold(Some(Duration::from_millis(5)));
+
+        let before = slow_holds();
+        {
+            let mut guard = timed_write(&lock, "test lock").await;
+            **guard += 1;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(slow_holds() > before);
+
+        set_slow_hold_threshold(None);
+    }
+}
@@ -12,17 +12,182 @@
 // Write at least 1 test.
 // Feel free to organize the code however you see fit
 
-use brightidea_test::{ChatRooms, api};
+use std::{env, path::PathBuf, process, time::Duration};
+
+use brightidea_test::{
+    api::{self, ServerOptions},
+    config::{
+        bind_addr, RoomConfig, TlsConfig, DEFAULT_JOIN_HISTORY, DEFAULT_LOG_BURST_THRESHOLD,
+        DEFAULT_METRICS_ROOM_LABELS,
+    },
+    reload::{ServerConfig, SharedConfig},
+    ChatRooms,
+};
+
+/// Names the JSON config file re-read on `SIGHUP`; defaults are used when unset.
+const CONFIG_ENV: &str = "CHAT_CONFIG";
+
+/// Names the directory room transcripts are written to; the working directory when unset.
+const LOG_DIR_ENV: &str = "CHAT_LOG_DIR";
+
+/// Name the PEM certificate and private key to serve HTTPS and WSS with. The server is plaintext
+/// when both are unset, and refuses to start when only one is set.
+const TLS_CERT_ENV: &str = "CHAT_TLS_CERT";
+const TLS_KEY_ENV: &str = "CHAT_TLS_KEY";
+
+/// Names the bearer token the admin routes require. They refuse every request when it is unset.
+const ADMIN_TOKEN_ENV: &str = "CHAT_ADMIN_TOKEN";
+
+/// Log filter used when `RUST_LOG` is unset: the server's own connection and room events, and
+/// the HTTP access log.
+const DEFAULT_LOG_FILTER: &str = "brightidea_test=info,chat::access=info";
+
+/// Name the IP address and port the server listens on, `127.0.0.1` and `3030` when unset.
+const BIND_IP_ENV: &str = "CHAT_BIND_IP";
+const PORT_ENV: &str = "CHAT_PORT";
+
+/// How long an empty room may sit unused before it is removed.
+const IDLE_ROOM_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    let filter = env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_owned());
+    pretty_env_logger::formatted_builder()
+        .parse_filters(&filter)
+        .init();
 
     // Keep track of all channels and their respective users
     let rooms = ChatRooms::default();
 
+    let admin_token = env::var(ADMIN_TOKEN_ENV).ok();
+    match admin_token.as_deref() {
+        Some("") => {
+            tracing::error!("{} is empty: set a token or unset it", ADMIN_TOKEN_ENV);
+            process::exit(1);
+        }
+        Some(_) => {}
+        None => tracing::warn!("{} is unset, admin routes are disabled", ADMIN_TOKEN_ENV),
+    }
+
+    let config = SharedConfig::new(RoomConfig {
+        log_rejections: Some(tracing::Level::DEBUG),
+        access_log: Some(tracing::Level::INFO),
+        disconnect_slow_consumers: true,
+        log_burst_threshold: Some(DEFAULT_LOG_BURST_THRESHOLD),
+        join_history: Some(DEFAULT_JOIN_HISTORY),
+        metrics_room_labels: Some(DEFAULT_METRICS_ROOM_LABELS),
+        log_dir: env::var_os(LOG_DIR_ENV).map(PathBuf::from),
+        admin_token,
+        reserved_room_names: api::ROUTE_NAMES
+            .iter()
+            .map(|&name| name.to_owned())
+            .collect(),
+        ..RoomConfig::default()
+    });
+    if let Some(path) = env::var_os(CONFIG_ENV).map(PathBuf::from) {
+        match ServerConfig::load(&path) {
+            Ok(file) => config.store(file.apply(&config.load())),
+            Err(e) => {
+                tracing::error!(path = %path.display(), error = %e, "invalid config file");
+                process::exit(1);
+            }
+        }
+        reload_on_hangup(path, config.clone());
+    }
+
+    if let Err(e) = api::check_reserved_names(&config.load()) {
+        tracing::error!("{}", e);
+        process::exit(1);
+    }
+
+    let tls = match TlsConfig::from_paths(
+        env::var_os(TLS_CERT_ENV).map(PathBuf::from),
+        env::var_os(TLS_KEY_ENV).map(PathBuf::from),
+    ) {
+        Ok(tls) => tls,
+        Err(e) => {
+            tracing::error!("{}: set both {} and {}", e, TLS_CERT_ENV, TLS_KEY_ENV);
+            process::exit(1);
+        }
+    };
+
+    let addr = match bind_addr(
+        env::var(BIND_IP_ENV).ok().as_deref(),
+        env::var(PORT_ENV).ok().as_deref(),
+    ) {
+        Ok(addr) => addr,
+        Err(e) => {
+            tracing::error!("{}: check {} and {}", e, BIND_IP_ENV, PORT_ENV);
+            process::exit(1);
+        }
+    };
+
+    let shutdown = config.load().shutdown.clone();
+
     // let index = warp::path::end().map(|| warp::reply::html(INDEX_HTML));
-    let routes = api::build_filters(rooms);
+    let options = ServerOptions {
+        rooms: config,
+        idle_room_timeout: Some(IDLE_ROOM_TIMEOUT),
+        addr,
+    };
+    let routes = api::build_filters(rooms.clone(), options);
+
+    let stop = async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down");
+        shutdown.begin();
+    };
+    match tls {
+        Some(tls) => {
+            let (_, server) = warp::serve(routes)
+                .tls()
+                .cert_path(&tls.cert_path)
+                .key_path(&tls.key_path)
+                .bind_with_graceful_shutdown(addr, stop);
+            server.await;
+        }
+        None => {
+            let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, stop);
+            server.await;
+        }
+    }
+    rooms.close_transcripts().await;
+}
+
+/// Resolves on the first `SIGINT` or `SIGTERM`.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    let mut terminate = signal(SignalKind::terminate()).expect("couldn't install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Re-reads the config file on each `SIGHUP`, keeping the running config if the file is invalid.
+#[cfg(unix)]
+fn reload_on_hangup(path: PathBuf, config: SharedConfig) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).expect("couldn't install SIGHUP handler");
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match ServerConfig::load(&path).and_then(|file| config.reload(&file)) {
+                Ok(()) => tracing::info!(path = %path.display(), "reloaded config"),
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "kept previous config")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_path: PathBuf, _config: SharedConfig) {}
@@ -0,0 +1,155 @@
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{ConnectionInfo, Identity, Role, Status, Users};
+
+/// Where and how often rooms record who is connected, so a post-mortem can tell who was in a
+/// room when the process died.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotConfig {
+    /// Directory holding one `<room>.members.json` file per room.
+    pub dir: PathBuf,
+    /// How long to wait after a membership change before writing, so a burst of joins or leaves
+    /// is written once.
+    pub debounce: Duration,
+}
+
+impl SnapshotConfig {
+    fn path(&self, room: &str) -> PathBuf {
+        self.dir.join(format!("{}.members.json", room))
+    }
+}
+
+/// The contents of a room's snapshot file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MembershipSnapshot {
+    pub room: String,
+    /// RFC 3339 time the snapshot was taken.
+    pub taken_at: String,
+    /// Connected users, by id.
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Member {
+    pub id: usize,
+    pub name: String,
+    pub role: Role,
+    pub status: Status,
+}
+
+/// Lists `users` by id. The map is only locked while identities are copied out; naming and
+/// sorting happen after it is released.
+pub async fn members(users: &Users) -> Vec<Member> {
+    let identities: Vec<Identity> = users
+        .read()
+        .await
+        .values()
+        .map(|user| user.identity.clone())
+        .collect();
+    let mut members: Vec<Member> = identities
+        .into_iter()
+        .map(|identity| Member {
+            id: identity.id,
+            name: identity.display_name(),
+            role: identity.role,
+            status: identity.status,
+        })
+        .collect();
+    members.sort_by_key(|member| member.id);
+    members
+}
+
+/// A user as shown on the admin connections view.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Connected {
+    pub id: usize,
+    pub name: String,
+    pub role: Role,
+    pub addr: Option<SocketAddr>,
+    /// RFC 3339 time the connection was opened.
+    pub connected_at: String,
+    pub messages: u64,
+}
+
+/// Lists `users` by id with their connection details, locking the map only while identities and
+/// connection handles are copied out.
+pub async fn connections(users: &Users) -> Vec<Connected> {
+    let users: Vec<(Identity, Arc<ConnectionInfo>)> = users
+        .read()
+        .await
+        .values()
+        .map(|user| (user.identity.clone(), user.info.clone()))
+        .collect();
+    let mut connected: Vec<Connected> = users
+        .into_iter()
+        .map(|(identity, info)| Connected::new(&identity, &info))
+        .collect();
+    connected.sort_by_key(|user| user.id);
+    connected
+}
+
+impl Connected {
+    pub(crate) fn new(identity: &Identity, info: &ConnectionInfo) -> Connected {
+        Connected {
+            id: identity.id,
+            name: identity.display_name(),
+            role: identity.role,
+            addr: info.addr,
+            connected_at: humantime::format_rfc3339_seconds(info.connected_at).to_string(),
+            messages: info.messages(),
+        }
+    }
+}
+
+impl MembershipSnapshot {
+    async fn take(room: &str, users: &Users) -> MembershipSnapshot {
+        MembershipSnapshot {
+            room: room.to_owned(),
+            taken_at: humantime::format_rfc3339(SystemTime::now()).to_string(),
+            members: members(users).await,
+        }
+    }
+}
+
+/// Spawns the task that keeps `room`'s snapshot file up to date, returning the sender its
+/// membership changes are signalled on.
+///
+/// Snapshots are written by the task rather than by the joining or leaving connection, and to a
+/// temporary file that is renamed into place so a crash never leaves a half-written one. The
+/// file is removed once the sender is dropped along with the room.
+pub(crate) fn spawn(
+    config: SnapshotConfig,
+    room: String,
+    users: Users,
+) -> mpsc::UnboundedSender<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+    tokio::task::spawn(async move {
+        let path = config.path(&room);
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(config.debounce).await;
+            while rx.try_recv().is_ok() {}
+            let snapshot = MembershipSnapshot::take(&room, &users).await;
+            if let Err(e) = write(&path, &snapshot).await {
+                tracing::error!(room = %room, error = %e, "failed to write membership snapshot");
+            }
+        }
+        let _ = tokio::fs::remove_file(&path).await;
+    });
+    tx
+}
+
+async fn write(path: &Path, snapshot: &MembershipSnapshot) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(snapshot).expect("snapshots always serialize");
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, json).await?;
+    tokio::fs::rename(&tmp, path).await
+}
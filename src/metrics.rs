@@ -0,0 +1,576 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{classification::Classification, rooms::RoomOrigin, ChatRooms};
+
+/// Counts the messages sitting in a channel, for channels that can't report their own length.
+///
+/// The sending side calls `push` after each successful send and the receiving side calls `pop`
+/// after each receive, so the count is only ever a relaxed atomic add away from the hot path.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub fn push(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pop(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                depth.checked_sub(1)
+            });
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds of the delivery latency histogram's buckets, in microseconds.
+const LATENCY_BUCKETS_MICROS: [u64; 9] = [10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000];
+
+/// Time one in this many fan-outs, 0 when latency sampling is disabled.
+static LATENCY_SAMPLE_EVERY: AtomicU64 = AtomicU64::new(0);
+static LATENCY_FAN_OUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Observations per bucket, the last one catching everything past the largest bound.
+static LATENCY_BUCKETS: [AtomicU64; LATENCY_BUCKETS_MICROS.len() + 1] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static LATENCY_SUM_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Enables delivery latency sampling for one in every `every` fan-outs, or disables it with
+/// `None`.
+pub fn set_latency_sampling(every: Option<u64>) {
+    LATENCY_SAMPLE_EVERY.store(every.map_or(0, |every| every.max(1)), Ordering::Relaxed);
+}
+
+/// Measures how long a message takes from being accepted to being queued for each recipient.
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryTimer(Instant);
+
+impl DeliveryTimer {
+    /// Starts timing a message if sampling is enabled and this fan-out is picked.
+    ///
+    /// When sampling is disabled this is one relaxed atomic load and no clock read.
+    pub fn start() -> Option<DeliveryTimer> {
+        let every = LATENCY_SAMPLE_EVERY.load(Ordering::Relaxed);
+        if every == 0
+            || !LATENCY_FAN_OUTS
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(every)
+        {
+            return None;
+        }
+        Some(DeliveryTimer(Instant::now()))
+    }
+
+    /// Records the time since the message was accepted for one recipient.
+    pub fn observe(&self) {
+        let micros = self.0.elapsed().as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MICROS.len());
+        LATENCY_BUCKETS[bucket].fetch_add(1, Ordering::Relaxed);
+        LATENCY_SUM_MICROS.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+/// Delivery latencies observed since start.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LatencyHistogram {
+    /// Cumulative observation counts, paired with each bucket's upper bound in microseconds.
+    pub buckets: Vec<(u64, u64)>,
+    pub count: u64,
+    pub sum_micros: u64,
+}
+
+pub fn delivery_latency() -> LatencyHistogram {
+    let mut cumulative = 0;
+    let buckets = LATENCY_BUCKETS_MICROS
+        .iter()
+        .zip(LATENCY_BUCKETS.iter())
+        .map(|(&bound, count)| {
+            cumulative += count.load(Ordering::Relaxed);
+            (bound, cumulative)
+        })
+        .collect();
+    LatencyHistogram {
+        buckets,
+        count: cumulative + LATENCY_BUCKETS[LATENCY_BUCKETS_MICROS.len()].load(Ordering::Relaxed),
+        sum_micros: LATENCY_SUM_MICROS.load(Ordering::Relaxed),
+    }
+}
+
+/// Chat messages posted in any room since start.
+static MESSAGES_POSTED: AtomicU64 = AtomicU64::new(0);
+
+/// When the server started serving, set by the first `mark_started` call.
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// How many rooms `ServerStats::busiest_rooms` lists.
+const BUSIEST_ROOMS: usize = 5;
+
+/// Failed transcript writes and flushes, in any room, since start.
+static LOG_WRITE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Transcripts reopened after their writes kept failing, since start.
+static LOG_RECOVERIES: AtomicU64 = AtomicU64::new(0);
+
+/// Transcript lines dropped because their room's log queue was full, since start.
+static LOG_LINES_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Websocket connections accepted since start.
+static CONNECTIONS_OPENED: AtomicU64 = AtomicU64::new(0);
+
+/// Websocket connections open right now.
+static CONNECTIONS_OPEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Rooms created since start, including ones that have since closed.
+static ROOMS_CREATED: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a websocket connection as open until it is dropped.
+#[derive(Debug)]
+pub(crate) struct OpenConnection(());
+
+impl OpenConnection {
+    pub(crate) fn new() -> OpenConnection {
+        CONNECTIONS_OPENED.fetch_add(1, Ordering::Relaxed);
+        CONNECTIONS_OPEN.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(())
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        CONNECTIONS_OPEN.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Websocket connections open right now.
+pub fn open_connections() -> usize {
+    CONNECTIONS_OPEN.load(Ordering::Relaxed)
+}
+
+pub(crate) fn count_room_created() {
+    ROOMS_CREATED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_message() {
+    MESSAGES_POSTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_log_failure() {
+    LOG_WRITE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_log_recovery() {
+    LOG_RECOVERIES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_log_line_dropped() {
+    LOG_LINES_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Starts the uptime clock. Later calls have no effect.
+pub fn mark_started() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Time since `mark_started` was first called, zero if it hasn't been.
+pub fn uptime() -> Duration {
+    STARTED.get().map_or(Duration::ZERO, Instant::elapsed)
+}
+
+/// A room and how many users it has.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomUsers {
+    pub room: String,
+    pub users: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub classification: Option<Classification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<RoomOrigin>,
+}
+
+/// A summary of the whole server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServerStats {
+    pub rooms: usize,
+    pub users: usize,
+    /// Chat messages posted since start, including in rooms that have since closed.
+    pub messages: u64,
+    pub uptime_secs: u64,
+    /// The rooms with the most users, busiest first.
+    pub busiest_rooms: Vec<RoomUsers>,
+}
+
+/// Counts the users in every live room, sorted by room name. Each room's user map is only locked
+/// long enough to read its length.
+pub async fn room_users(rooms: &ChatRooms) -> Vec<RoomUsers> {
+    let live = rooms.live_rooms().await;
+
+    let mut per_room = Vec::with_capacity(live.len());
+    for room in live {
+        let users = room.users.read().await.len();
+        per_room.push(RoomUsers {
+            room: room.name.clone(),
+            users,
+            classification: room.config.classification,
+            origin: room.origin().cloned(),
+        });
+    }
+    per_room.sort_by(|a, b| a.room.cmp(&b.room));
+    per_room
+}
+
+/// Summarizes every live room.
+pub async fn server_stats(rooms: &ChatRooms) -> ServerStats {
+    let mut per_room = room_users(rooms).await;
+    per_room.sort_by(|a, b| b.users.cmp(&a.users).then_with(|| a.room.cmp(&b.room)));
+
+    ServerStats {
+        rooms: per_room.len(),
+        users: per_room.iter().map(|room| room.users).sum(),
+        messages: MESSAGES_POSTED.load(Ordering::Relaxed),
+        uptime_secs: uptime().as_secs(),
+        busiest_rooms: per_room.into_iter().take(BUSIEST_ROOMS).collect(),
+    }
+}
+
+/// Gauges for one room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomMetrics {
+    pub room: String,
+    pub users: usize,
+    /// Messages queued for delivery, summed over every user in the room.
+    pub outbound_queue_depth: usize,
+    /// Transcript lines waiting for the room's logging task.
+    pub log_queue_depth: usize,
+    /// 1 while the room's transcript isn't being written, 0 otherwise.
+    pub log_degraded: usize,
+}
+
+/// Gathers gauges for every live room, sorted by room name.
+pub async fn snapshot(rooms: &ChatRooms) -> Vec<RoomMetrics> {
+    let live = rooms.live_rooms().await;
+
+    let mut metrics = Vec::with_capacity(live.len());
+    for room in live {
+        let users = room.users.read().await;
+        metrics.push(RoomMetrics {
+            room: room.name.clone(),
+            users: users.len(),
+            outbound_queue_depth: users.values().map(|user| user.depth.get()).sum(),
+            log_queue_depth: room.log_queue_depth(),
+            log_degraded: usize::from(room.is_degraded()),
+        });
+    }
+    metrics.sort_by(|a, b| a.room.cmp(&b.room));
+    metrics
+}
+
+/// A gauge's name, help text and how to read it from a room's metrics.
+type Gauge = (&'static str, &'static str, fn(&RoomMetrics) -> usize);
+
+/// Splits `metrics` into the `max_labeled` busiest rooms, by users and then queued messages, and
+/// the sum of the rest if there are any. The busiest rooms stay in name order.
+fn label_busiest(
+    metrics: &[RoomMetrics],
+    max_labeled: Option<usize>,
+) -> (Vec<&RoomMetrics>, Option<(usize, RoomMetrics)>) {
+    let mut ranked: Vec<&RoomMetrics> = metrics.iter().collect();
+    let limit = match max_labeled {
+        Some(limit) if limit < metrics.len() => limit,
+        _ => return (ranked, None),
+    };
+    ranked.sort_by(|a, b| {
+        b.users
+            .cmp(&a.users)
+            .then_with(|| b.outbound_queue_depth.cmp(&a.outbound_queue_depth))
+            .then_with(|| a.room.cmp(&b.room))
+    });
+    let rest = ranked.split_off(limit);
+    ranked.sort_by(|a, b| a.room.cmp(&b.room));
+    let mut other = RoomMetrics {
+        room: String::new(),
+        users: 0,
+        outbound_queue_depth: 0,
+        log_queue_depth: 0,
+        log_degraded: 0,
+    };
+    for room in &rest {
+        other.users += room.users;
+        other.outbound_queue_depth += room.outbound_queue_depth;
+        other.log_queue_depth += room.log_queue_depth;
+        other.log_degraded += room.log_degraded;
+    }
+    (ranked, Some((rest.len(), other)))
+}
+
+/// Renders a snapshot in the Prometheus text exposition format.
+///
+/// With `max_labeled` set, only that many of the busiest rooms get a `room` label; the rest are
+/// summed into one series labeled `other="true"`, so the number of series stays bounded however
+/// many rooms there are. `chat_unlabeled_rooms` counts the rooms summed.
+pub fn render_prometheus(metrics: &[RoomMetrics], max_labeled: Option<usize>) -> String {
+    let (labeled, other) = label_busiest(metrics, max_labeled);
+    let gauges: [Gauge; 4] = [
+        ("chat_room_users", "Users connected to the room.", |m| {
+            m.users
+        }),
+        (
+            "chat_outbound_queue_depth",
+            "Messages queued for delivery to the room's users.",
+            |m| m.outbound_queue_depth,
+        ),
+        (
+            "chat_log_queue_depth",
+            "Transcript lines waiting to be written by the room's logging task.",
+            |m| m.log_queue_depth,
+        ),
+        (
+            "chat_log_degraded",
+            "1 while the room's transcript can't be written.",
+            |m| m.log_degraded,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in gauges.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for room in &labeled {
+            let _ = writeln!(
+                out,
+                "{}{{room=\"{}\"}} {}",
+                name,
+                escape_label(&room.room),
+                value(room)
+            );
+        }
+        if let Some((_, other)) = &other {
+            let _ = writeln!(out, "{}{{other=\"true\"}} {}", name, value(other));
+        }
+    }
+    let name = "chat_unlabeled_rooms";
+    let _ = writeln!(
+        out,
+        "# HELP {} Rooms summed into the other=\"true\" series rather than labeled.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, other.map_or(0, |(rooms, _)| rooms));
+    let name = "chat_connections_open";
+    let _ = writeln!(out, "# HELP {} Websocket connections open.", name);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, open_connections());
+
+    let counters = [
+        (
+            "chat_messages_total",
+            "Chat messages posted in any room.",
+            &MESSAGES_POSTED,
+        ),
+        (
+            "chat_connections_total",
+            "Websocket connections accepted.",
+            &CONNECTIONS_OPENED,
+        ),
+        (
+            "chat_rooms_created_total",
+            "Rooms created, including ones that have since closed.",
+            &ROOMS_CREATED,
+        ),
+        (
+            "chat_log_write_failures_total",
+            "Failed transcript writes and flushes.",
+            &LOG_WRITE_FAILURES,
+        ),
+        (
+            "chat_log_recoveries_total",
+            "Transcripts reopened after their writes kept failing.",
+            &LOG_RECOVERIES,
+        ),
+        (
+            "chat_log_lines_dropped_total",
+            "Transcript lines dropped because the room's log queue was full.",
+            &LOG_LINES_DROPPED,
+        ),
+    ];
+    for (name, help, counter) in counters.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+
+    let latency = delivery_latency();
+    let name = "chat_delivery_latency_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time from accepting a message to queueing it for a recipient, sampled.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in &latency.buckets {
+        let le = Duration::from_micros(*bound).as_secs_f64();
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, latency.count);
+    let sum = Duration::from_micros(latency.sum_micros).as_secs_f64();
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, latency.count);
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        config::{RoomConfig, DEFAULT_MAX_QUEUED_PER_USER},
+        fan_out,
+        metrics::{delivery_latency, render_prometheus, set_latency_sampling, snapshot},
+        protocol::{ChatEvent, Protocol},
+        sink::DiscardSink,
+        ChatRoom, ChatRooms, User, Users,
+    };
+
+    #[tokio::test]
+    async fn stalled_consumer_shows_in_queue_depth() {
+        let room = Arc::new(
+            ChatRoom::with_sink(
+                "stalled".to_owned(),
+                Users::default(),
+                RoomConfig::default(),
+                Box::new(DiscardSink),
+            )
+            .await,
+        );
+        let rooms = ChatRooms::default();
+        rooms
+            .insert("stalled".to_owned(), Arc::downgrade(&room))
+            .await;
+
+        // Nobody reads from this receiver, so everything sent to the user piles up.
+        let (tx, _stalled_rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+
+        for seq in 1..=3 {
+            let event = ChatEvent::Message {
+                seq,
+                from: 2,
+                body: "backlog".to_owned(),
+                appearance: None,
+                topic: None,
+                name: None,
+                ts: None,
+            };
+            fan_out(&event, &room.users, None).await;
+        }
+
+        let metrics = snapshot(&rooms).await;
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].users, 1);
+        assert_eq!(metrics[0].outbound_queue_depth, 3);
+        assert!(render_prometheus(&metrics, None)
+            .contains("chat_outbound_queue_depth{room=\"stalled\"} 3\n"));
+    }
+
+    #[tokio::test]
+    async fn only_busiest_rooms_are_labeled() {
+        let rooms = ChatRooms::default();
+        let mut open = Vec::new();
+        let mut receivers = Vec::new();
+        for (name, users) in [("a", 1), ("b", 5), ("c", 2), ("d", 4), ("e", 3), ("f", 0)].iter() {
+            let room = Arc::new(ChatRoom::unlogged(name.to_string(), Users::default()).await);
+            for id in 0..*users {
+                let (tx, rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+                room.users
+                    .write()
+                    .await
+                    .insert(id, User::new(tx, Protocol::LegacyText));
+                receivers.push(rx);
+            }
+            rooms.insert(name.to_string(), Arc::downgrade(&room)).await;
+            open.push(room);
+        }
+
+        let text = render_prometheus(&snapshot(&rooms).await, Some(2));
+        let users: Vec<&str> = text
+            .lines()
+            .filter(|line| line.starts_with("chat_room_users{"))
+            .collect();
+        assert_eq!(
+            users,
+            [
+                "chat_room_users{room=\"b\"} 5",
+                "chat_room_users{room=\"d\"} 4",
+                "chat_room_users{other=\"true\"} 6",
+            ]
+        );
+        assert!(text.contains("chat_unlabeled_rooms 4\n"));
+
+        let text = render_prometheus(&snapshot(&rooms).await, None);
+        assert_eq!(text.matches("chat_room_users{room=").count(), 6);
+        assert!(!text.contains("{other=\"true\"}"));
+        assert!(text.contains("chat_unlabeled_rooms 0\n"));
+    }
+
+    #[tokio::test]
+    async fn sampled_delivery_latency_is_recorded() {
+        let room = ChatRoom::with_sink(
+            "latency".to_owned(),
+            Users::default(),
+            RoomConfig::default(),
+            Box::new(DiscardSink),
+        )
+        .await;
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(1, User::new(tx, Protocol::LegacyText));
+
+        set_latency_sampling(Some(1));
+        let before = delivery_latency().count;
+        room.post_message(2, "timed", None, None).await;
+        set_latency_sampling(None);
+
+        assert!(rx.recv().await.is_some());
+        assert!(delivery_latency().count > before);
+        assert!(render_prometheus(&[], None).contains("chat_delivery_latency_seconds_count "));
+    }
+}
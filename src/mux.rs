@@ -0,0 +1,206 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{future, stream::SplitStream};
+use tokio::time::Instant;
+use warp::ws::WebSocket;
+
+use crate::{
+    allow_frame,
+    disconnect::{Cancellation, DisconnectReason},
+    get_room, next_frame,
+    protocol::MuxCommand,
+    ratelimit::FrameLimiter,
+    rooms::{validate_room_name, CreationRoute, RoomOrigin},
+    ChatRoom, ChatRooms, Connection, Frame, Identity, Pinger, User,
+};
+
+/// Runs a multiplexed connection, which starts out in `first` and joins and leaves other rooms
+/// as the client asks.
+///
+/// Each membership is an ordinary `Connection` sharing the socket's outbound queue, so rooms
+/// treat a multiplexed user like any other. Rooms joined later are created with `first`'s config.
+pub(crate) async fn serve(
+    identity: Identity,
+    me: User,
+    mut user_ws_rx: SplitStream<WebSocket>,
+    first: Arc<ChatRoom>,
+    rooms: ChatRooms,
+) {
+    let config = first.config.clone();
+    let mut frames = config.max_frames_per_sec.map(FrameLimiter::new);
+    let expires = config
+        .max_connection_lifetime
+        .map(|lifetime| Instant::now() + lifetime);
+    let mut pinger = config
+        .keepalive
+        .map(|keepalive| Pinger::new(keepalive, me.clone()));
+    let mut joined: HashMap<String, Connection> = HashMap::new();
+    join(&mut joined, first, &me, &identity).await;
+
+    loop {
+        let open: Vec<Arc<ChatRoom>> = joined.values().map(|conn| conn.room.clone()).collect();
+        let cancellations: Vec<Arc<Cancellation>> = joined
+            .values()
+            .map(|conn| conn.me.cancellation.clone())
+            .collect();
+        let frame = tokio::select! {
+            frame = next_frame(&mut user_ws_rx, config.idle_timeout, expires, pinger.as_mut()) => {
+                Some(frame)
+            }
+            _ = any_closed(&open) => None,
+            _ = any_cancelled(&cancellations) => None,
+        };
+        drop(open);
+        if me.writer_cancellation.is_cancelled() {
+            // The socket has been closed under the connection, say for being too slow.
+            break;
+        }
+        // Closed rooms have already removed this connection, and are waiting on it to let go.
+        joined.retain(|_, conn| !conn.room.is_closed());
+        // So have rooms that disconnected the user, say with a kick, but they still see them out.
+        let cancelled: Vec<String> = joined
+            .iter()
+            .filter(|(_, conn)| conn.me.cancellation.is_cancelled())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in cancelled {
+            if let Some(conn) = joined.remove(&name) {
+                conn.leave(rooms.clone()).await;
+            }
+        }
+        let msg = match frame {
+            None => continue,
+            Some(Frame::Received(Ok(msg))) => msg,
+            Some(Frame::Received(Err(e))) => {
+                tracing::warn!(user_id = identity.id, error = %e, "websocket error");
+                break;
+            }
+            Some(Frame::Closed) => break,
+            Some(Frame::Idle) => {
+                tracing::info!(user_id = identity.id, "idle, disconnecting user");
+                me.disconnect(DisconnectReason::Idle, config.app_close_codes);
+                break;
+            }
+            Some(Frame::Expired) => {
+                tracing::info!(
+                    user_id = identity.id,
+                    "connection lifetime reached, disconnecting user"
+                );
+                me.disconnect(DisconnectReason::Expired, config.app_close_codes);
+                break;
+            }
+            Some(Frame::Unresponsive) => {
+                tracing::info!(user_id = identity.id, "no pong, disconnecting user");
+                me.disconnect(DisconnectReason::Unresponsive, config.app_close_codes);
+                break;
+            }
+        };
+        if !allow_frame(&mut frames, &me, identity.id, config.app_close_codes) {
+            break;
+        }
+        let text = match msg.to_str() {
+            Ok(text) => text,
+            Err(()) => {
+                if msg.is_binary() {
+                    me.notice(
+                        "binary frames are not supported on multiplexed connections".to_owned(),
+                    );
+                }
+                continue;
+            }
+        };
+        match serde_json::from_str::<MuxCommand>(text) {
+            Ok(MuxCommand::Join { room }) => {
+                if joined.contains_key(&room) {
+                    me.notice(format!("already in {}", room));
+                    continue;
+                }
+                if let Err(e) = validate_room_name(&room) {
+                    me.notice(format!("invalid room name: {}", e));
+                    continue;
+                }
+                let origin =
+                    RoomOrigin::new(CreationRoute::Mux, me.info.addr, identity.account.clone());
+                match get_room(&room, rooms.clone(), &config, Some(origin)).await {
+                    Ok(room) => {
+                        // Ids are per room, so the connection is numbered afresh in each one.
+                        let identity = Identity {
+                            id: room.next_user_id(),
+                            ..identity.clone()
+                        };
+                        join(&mut joined, room, &me, &identity).await
+                    }
+                    Err(e) => {
+                        me.notice(format!("can't join {}: {}", room, e));
+                    }
+                }
+            }
+            Ok(MuxCommand::Leave { room }) => match joined.remove(&room) {
+                Some(conn) => {
+                    conn.me.notice(format!("left {}", room));
+                    conn.leave(rooms.clone()).await;
+                }
+                None => {
+                    me.notice(format!("not in {}", room));
+                }
+            },
+            Ok(MuxCommand::Send { room, body }) => match joined.get_mut(&room) {
+                Some(conn) => conn.handle_text(&body).await,
+                None => {
+                    me.notice(format!("not in {}", room));
+                }
+            },
+            Err(e) => {
+                me.notice(format!("invalid multiplexed frame: {}", e));
+            }
+        }
+    }
+
+    for (_, conn) in joined {
+        conn.leave(rooms.clone()).await;
+    }
+}
+
+/// Resolves once any of `cancellations` is cancelled, so the connection stops reading for that
+/// room straight away.
+async fn any_cancelled(cancellations: &[Arc<Cancellation>]) {
+    if cancellations.is_empty() {
+        future::pending::<()>().await;
+    }
+    future::select_all(
+        cancellations
+            .iter()
+            .map(|cancellation| Box::pin(cancellation.cancelled())),
+    )
+    .await;
+}
+
+/// Resolves once any of `rooms` is closed, so the connection can let go of it straight away.
+async fn any_closed(rooms: &[Arc<ChatRoom>]) {
+    // Created before checking, so a close between the two still wakes them.
+    let closing: Vec<_> = rooms
+        .iter()
+        .map(|room| Box::pin(room.closing.notified()))
+        .collect();
+    if closing.is_empty() {
+        future::pending::<()>().await;
+    }
+    if !rooms.iter().any(|room| room.is_closed()) {
+        future::select_all(closing).await;
+    }
+}
+
+async fn join(
+    joined: &mut HashMap<String, Connection>,
+    room: Arc<ChatRoom>,
+    me: &User,
+    identity: &Identity,
+) {
+    let name = room.name.clone();
+    if let Some(mut conn) = Connection::join(room, me.clone(), identity.clone()).await {
+        // Joining over the multiplexed connection is the explicit join.
+        conn.joined = true;
+        conn.me.notice(format!("joined {}", name));
+        joined.insert(name, conn);
+    }
+}
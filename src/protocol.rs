@@ -0,0 +1,528 @@
+use std::{borrow::Cow, fmt};
+
+use serde::{Deserialize, Serialize};
+use warp::ws::Message;
+
+use crate::{appearance::Appearance, Status};
+
+/// Something that happened in a room, independent of how it is put on the wire.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatEvent {
+    /// A chat message sent by a user, numbered by its room's sequence.
+    Message {
+        seq: u64,
+        from: usize,
+        body: String,
+        /// How to render the sender, if the room hands out appearances.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        appearance: Option<Appearance>,
+        /// Only users subscribed to this topic receive the message; everyone does when `None`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        /// The sender's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// RFC 3339 time the room accepted the message.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ts: Option<String>,
+    },
+    /// A message from the server itself, e.g. explaining why a message was rejected.
+    Notice { body: String },
+    /// A user is typing a message.
+    Typing {
+        from: usize,
+        /// The user's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// A user went away or came back. Also sent to users as they join for everyone who is away.
+    Status {
+        from: usize,
+        status: Status,
+        /// The user's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// A user reacted to the message with sequence number `target`.
+    Reaction {
+        from: usize,
+        target: u64,
+        emoji: String,
+        /// The user's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// A moderator pinned message `seq`, originally sent by `from`. Also sent to users as they
+    /// join while it stays pinned.
+    Pinned {
+        seq: u64,
+        from: usize,
+        body: String,
+        /// The sender's nickname, if they were in the room with one when it was pinned.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// The pinned message `seq` was unpinned.
+    Unpinned { seq: u64 },
+    /// `from` replaced the text of their message `seq` with `body`.
+    Edit {
+        seq: u64,
+        from: usize,
+        body: String,
+        /// The user's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// `from` deleted their message `seq`.
+    Delete {
+        seq: u64,
+        from: usize,
+        /// The user's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// `from` has read every message up to `seq`, sent to whoever sent `seq`.
+    Read {
+        from: usize,
+        seq: u64,
+        /// The user's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// `/me <action>`, shown as `* User#<from> <action>`.
+    Emote {
+        seq: u64,
+        from: usize,
+        action: String,
+        /// The sender's nickname, shown in place of `User#<from>` if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// RFC 3339 time the room accepted the emote.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ts: Option<String>,
+    },
+    /// A message from `from` to `to` alone, sent to both of them.
+    Private {
+        seq: u64,
+        from: usize,
+        to: usize,
+        body: String,
+        /// The sender's nickname, if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// The recipient's nickname, if they have one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        to_name: Option<String>,
+        /// RFC 3339 time the room accepted the message.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ts: Option<String>,
+    },
+}
+
+impl ChatEvent {
+    /// The room sequence number of the message this event delivers, if it delivers one.
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            ChatEvent::Message { seq, .. }
+            | ChatEvent::Emote { seq, .. }
+            | ChatEvent::Private { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+}
+
+/// `* User#5 waves`, how an emote reads in text.
+pub fn emote_text(from: usize, name: Option<&str>, action: &str) -> String {
+    format!("* {} {}", display_name(from, name), action)
+}
+
+/// How user `from` is shown in text: their nickname if they have one, otherwise `User#<from>`.
+fn display_name(from: usize, name: Option<&str>) -> Cow<'_, str> {
+    match name {
+        Some(name) => Cow::Borrowed(name),
+        None => Cow::Owned(format!("User#{}", from)),
+    }
+}
+
+/// Kinds of inbound message a room can allow or refuse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// Plain chat text.
+    Text,
+    /// A slash command other than the ones below.
+    Command,
+    /// `/typing`, a typing indicator.
+    Typing,
+    /// `/react <seq> <emoji>`, a reaction to an earlier message.
+    React,
+    /// A binary frame.
+    Binary,
+}
+
+impl MessageKind {
+    /// Classifies the text of a text frame.
+    pub fn of_text(text: &str) -> MessageKind {
+        let text = text.trim_start();
+        if !text.starts_with('/') {
+            return MessageKind::Text;
+        }
+        match text.split_whitespace().next() {
+            Some("/typing") => MessageKind::Typing,
+            Some("/react") => MessageKind::React,
+            _ => MessageKind::Command,
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MessageKind::Text => "text",
+            MessageKind::Command => "command",
+            MessageKind::Typing => "typing",
+            MessageKind::React => "react",
+            MessageKind::Binary => "binary",
+        })
+    }
+}
+
+/// Wire formats a connection can speak, chosen during the websocket handshake.
+///
+/// Adding a protocol version means adding a variant here, teaching `negotiate` its subprotocol
+/// name and `encode` its format; nothing else needs to know about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Bare text frames such as `<User#3>: hi`, used when no subprotocol is requested.
+    LegacyText,
+    /// One JSON object per text frame, negotiated with the `chat.v1.json` subprotocol. The
+    /// client sends `ClientFrame`s.
+    JsonV1,
+    /// `JsonV1` envelopes, always tagged with their room, for a connection that is in several
+    /// rooms at once. Negotiated with the `chat.v1.mux` subprotocol; the client sends
+    /// `MuxCommand`s.
+    MuxV1,
+}
+
+impl Protocol {
+    pub const JSON_V1: &'static str = "chat.v1.json";
+    pub const MUX_V1: &'static str = "chat.v1.mux";
+
+    /// Picks a protocol from the client's `Sec-WebSocket-Protocol` header, falling back to
+    /// `LegacyText` if none of the requested subprotocols are supported.
+    pub fn negotiate(requested: Option<&str>) -> Protocol {
+        requested
+            .into_iter()
+            .flat_map(|header| header.split(','))
+            .find_map(|name| match name.trim() {
+                Protocol::JSON_V1 => Some(Protocol::JsonV1),
+                Protocol::MUX_V1 => Some(Protocol::MuxV1),
+                _ => None,
+            })
+            .unwrap_or(Protocol::LegacyText)
+    }
+
+    /// The subprotocol name to echo back in the handshake response, if any.
+    pub fn subprotocol(&self) -> Option<&'static str> {
+        match self {
+            Protocol::LegacyText => None,
+            Protocol::JsonV1 => Some(Protocol::JSON_V1),
+            Protocol::MuxV1 => Some(Protocol::MUX_V1),
+        }
+    }
+
+    pub fn encode(&self, event: &ChatEvent) -> Message {
+        self.encode_in(event, None)
+    }
+
+    /// Encodes `event`, naming the room it happened in if `room` is given and the protocol can
+    /// carry it. The legacy text protocol never does.
+    pub fn encode_in(&self, event: &ChatEvent, room: Option<&str>) -> Message {
+        match self {
+            Protocol::LegacyText => match event {
+                ChatEvent::Message {
+                    from,
+                    body,
+                    topic,
+                    name,
+                    ..
+                } => {
+                    let sender = display_name(*from, name.as_deref());
+                    match topic {
+                        Some(topic) => Message::text(format!("<{}> #{}: {}", sender, topic, body)),
+                        None => Message::text(format!("<{}>: {}", sender, body)),
+                    }
+                }
+                ChatEvent::Notice { body } => Message::text(format!("*** {}", body)),
+                ChatEvent::Typing { from, name } => Message::text(format!(
+                    "*** {} is typing...",
+                    display_name(*from, name.as_deref())
+                )),
+                ChatEvent::Status { from, status, name } => {
+                    let user = display_name(*from, name.as_deref());
+                    Message::text(match status {
+                        Status::Active => format!("*** {} is back", user),
+                        Status::Away => format!("*** {} is away", user),
+                    })
+                }
+                ChatEvent::Reaction {
+                    from,
+                    target,
+                    emoji,
+                    name,
+                } => Message::text(format!(
+                    "*** {} reacted {} to message {}",
+                    display_name(*from, name.as_deref()),
+                    emoji,
+                    target
+                )),
+                ChatEvent::Pinned {
+                    seq,
+                    from,
+                    body,
+                    name,
+                } => Message::text(format!(
+                    "*** pinned message {} from {}: {}",
+                    seq,
+                    display_name(*from, name.as_deref()),
+                    body
+                )),
+                ChatEvent::Unpinned { seq } => {
+                    Message::text(format!("*** message {} unpinned", seq))
+                }
+                ChatEvent::Edit {
+                    seq,
+                    from,
+                    body,
+                    name,
+                } => Message::text(format!(
+                    "*** {} edited message {}: {}",
+                    display_name(*from, name.as_deref()),
+                    seq,
+                    body
+                )),
+                ChatEvent::Delete { seq, from, name } => Message::text(format!(
+                    "*** {} deleted message {}",
+                    display_name(*from, name.as_deref()),
+                    seq
+                )),
+                ChatEvent::Read { from, seq, name } => Message::text(format!(
+                    "*** {} read your message {}",
+                    display_name(*from, name.as_deref()),
+                    seq
+                )),
+                ChatEvent::Emote {
+                    from, action, name, ..
+                } => Message::text(emote_text(*from, name.as_deref(), action)),
+                ChatEvent::Private {
+                    from,
+                    to,
+                    body,
+                    name,
+                    to_name,
+                    ..
+                } => {
+                    let sender = display_name(*from, name.as_deref());
+                    let recipient = display_name(*to, to_name.as_deref());
+                    Message::text(format!("<{}> (private to {}): {}", sender, recipient, body))
+                }
+            },
+            Protocol::JsonV1 | Protocol::MuxV1 => Message::text(
+                serde_json::to_string(&Envelope { room, event })
+                    .expect("chat events always serialize"),
+            ),
+        }
+    }
+}
+
+/// A frame sent by a client on a `chat.v1.json` connection. Fields the server fills in itself,
+/// such as `from` and `ts`, are ignored if the client sends them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Chat text, read just as a legacy text frame would be, slash commands included.
+    Message { body: String },
+    /// Replace the text of the client's own message `seq`, like `/edit`.
+    Edit { seq: u64, text: String },
+    /// Delete the client's own message `seq`, like `/delete`.
+    Delete { seq: u64 },
+    /// The client has shown every message up to `seq`, for read receipts.
+    Read { seq: u64 },
+}
+
+/// A frame sent by a client on a multiplexed connection.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum MuxCommand {
+    /// Start receiving the room's traffic.
+    Join {
+        room: String,
+    },
+    Leave {
+        room: String,
+    },
+    /// Send `body` to a joined room, as if it were a text frame on a connection to that room.
+    Send {
+        room: String,
+        body: String,
+    },
+}
+
+/// A JSON event, optionally tagged with its room.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a ChatEvent,
+}
+
+/// Renders one event lazily for each protocol it is requested in, so a fan-out encodes it at most
+/// once per protocol (and room tag) rather than once per recipient.
+pub struct EncodedEvent<'a> {
+    event: &'a ChatEvent,
+    encoded: Vec<(Protocol, Option<String>, Message)>,
+}
+
+impl<'a> EncodedEvent<'a> {
+    pub fn new(event: &'a ChatEvent) -> EncodedEvent<'a> {
+        EncodedEvent {
+            event,
+            encoded: Vec::new(),
+        }
+    }
+
+    pub fn get(&mut self, protocol: Protocol, room: Option<&str>) -> Message {
+        let room = match protocol {
+            Protocol::LegacyText => None,
+            Protocol::JsonV1 | Protocol::MuxV1 => room,
+        };
+        if let Some((_, _, message)) = self
+            .encoded
+            .iter()
+            .find(|(p, r, _)| *p == protocol && r.as_deref() == room)
+        {
+            return message.clone();
+        }
+        let message = protocol.encode_in(self.event, room);
+        self.encoded
+            .push((protocol, room.map(str::to_owned), message.clone()));
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        appearance::Appearance,
+        protocol::{ChatEvent, MessageKind, Protocol},
+    };
+
+    #[test]
+    fn negotiate_subprotocol() {
+        assert_eq!(Protocol::negotiate(None), Protocol::LegacyText);
+        assert_eq!(
+            Protocol::negotiate(Some("chat.v2.xml")),
+            Protocol::LegacyText
+        );
+        assert_eq!(Protocol::negotiate(Some("chat.v1.json")), Protocol::JsonV1);
+        assert_eq!(
+            Protocol::negotiate(Some("chat.v2.xml, chat.v1.json")),
+            Protocol::JsonV1
+        );
+        assert_eq!(Protocol::negotiate(Some("chat.v1.mux")), Protocol::MuxV1);
+    }
+
+    #[test]
+    fn appearance_only_in_json() {
+        let event = ChatEvent::Message {
+            seq: 1,
+            from: 3,
+            body: "hi".to_owned(),
+            appearance: Some(Appearance {
+                color: "#e6194b",
+                avatar_seed: 7,
+            }),
+            topic: None,
+            name: None,
+            ts: None,
+        };
+        assert_eq!(
+            Protocol::JsonV1.encode(&event).to_str(),
+            Ok(
+                r##"{"type":"message","seq":1,"from":3,"body":"hi","appearance":{"color":"#e6194b","avatar_seed":7}}"##
+            )
+        );
+        assert_eq!(
+            Protocol::LegacyText.encode(&event).to_str(),
+            Ok("<User#3>: hi")
+        );
+    }
+
+    #[test]
+    fn topic_in_both_protocols() {
+        let event = ChatEvent::Message {
+            seq: 2,
+            from: 3,
+            body: "hi".to_owned(),
+            appearance: None,
+            topic: Some("rust".to_owned()),
+            name: None,
+            ts: None,
+        };
+        assert_eq!(
+            Protocol::JsonV1.encode(&event).to_str(),
+            Ok(r#"{"type":"message","seq":2,"from":3,"body":"hi","topic":"rust"}"#)
+        );
+        assert_eq!(
+            Protocol::LegacyText.encode(&event).to_str(),
+            Ok("<User#3> #rust: hi")
+        );
+    }
+
+    #[test]
+    fn emote_in_both_protocols() {
+        let event = ChatEvent::Emote {
+            seq: 4,
+            from: 5,
+            action: "waves".to_owned(),
+            name: None,
+            ts: None,
+        };
+        assert_eq!(
+            Protocol::JsonV1.encode(&event).to_str(),
+            Ok(r#"{"type":"emote","seq":4,"from":5,"action":"waves"}"#)
+        );
+        assert_eq!(
+            Protocol::LegacyText.encode(&event).to_str(),
+            Ok("* User#5 waves")
+        );
+    }
+
+    #[test]
+    fn room_only_in_json_envelope() {
+        let event = ChatEvent::Notice {
+            body: "hello".to_owned(),
+        };
+        assert_eq!(
+            Protocol::JsonV1.encode_in(&event, Some("lobby")).to_str(),
+            Ok(r#"{"room":"lobby","type":"notice","body":"hello"}"#)
+        );
+        assert_eq!(
+            Protocol::LegacyText
+                .encode_in(&event, Some("lobby"))
+                .to_str(),
+            Ok("*** hello")
+        );
+    }
+
+    #[test]
+    fn classify_text() {
+        assert_eq!(MessageKind::of_text("hello"), MessageKind::Text);
+        assert_eq!(MessageKind::of_text("/join"), MessageKind::Command);
+        assert_eq!(MessageKind::of_text(" /typing"), MessageKind::Typing);
+        assert_eq!(MessageKind::of_text("/react 4 +1"), MessageKind::React);
+        assert_eq!(MessageKind::of_text("/reactor"), MessageKind::Command);
+    }
+}
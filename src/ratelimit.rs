@@ -0,0 +1,162 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Duration, Instant},
+};
+
+/// Counts a connection's inbound frames in one-second windows.
+///
+/// Every frame counts, control frames included, so this catches floods that never reach message
+/// classification.
+#[derive(Debug)]
+pub struct FrameLimiter {
+    max_per_sec: u32,
+    window_start: Instant,
+    frames: u32,
+}
+
+impl FrameLimiter {
+    pub fn new(max_per_sec: u32) -> FrameLimiter {
+        FrameLimiter {
+            max_per_sec,
+            window_start: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    /// Counts one frame, returning `false` once the current second's allowance is exceeded.
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.frames = 0;
+        }
+        self.frames += 1;
+        self.frames <= self.max_per_sec
+    }
+}
+
+/// Enforces a minimum interval between a user's messages. Unlike a rate, this allows no bursts.
+#[derive(Debug)]
+pub struct Cooldown {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Cooldown {
+    pub fn new(interval: Duration) -> Cooldown {
+        Cooldown {
+            interval,
+            last: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Whether a message may be sent now, which restarts the cooldown if so. Refused messages
+    /// don't count, so the wait is always measured from the last accepted message.
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
+/// A token bucket holding up to `burst` tokens and refilled at `per_sec` tokens a second.
+///
+/// Owned by a connection to limit it alone, or shared through an `Arc` to limit something across
+/// the whole server.
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_sec: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// A full bucket. `per_sec` must be positive.
+    pub fn new(per_sec: f64, burst: u32) -> TokenBucket {
+        assert!(per_sec > 0.0, "token bucket rate must be positive");
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            per_sec,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    pub fn try_take(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.per_sec).min(self.burst);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *tokens) / self.per_sec))
+        }
+    }
+}
+
+/// Caps how many operations run at once, with no cap when built from `None`.
+///
+/// Clones share the same cap.
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyLimit(Option<Arc<Semaphore>>);
+
+impl ConcurrencyLimit {
+    pub fn new(max: Option<usize>) -> ConcurrencyLimit {
+        ConcurrencyLimit(max.map(|max| Arc::new(Semaphore::new(max))))
+    }
+
+    /// Starts an operation that counts against the cap until the returned guard is dropped, or
+    /// returns `None` if the cap is already reached.
+    pub fn try_start(&self) -> Option<Running> {
+        match &self.0 {
+            Some(semaphore) => semaphore
+                .clone()
+                .try_acquire_owned()
+                .ok()
+                .map(|permit| Running {
+                    _permit: Some(permit),
+                }),
+            None => Some(Running { _permit: None }),
+        }
+    }
+}
+
+/// An operation counted against a `ConcurrencyLimit`.
+#[derive(Debug)]
+pub struct Running {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+
+    use crate::ratelimit::FrameLimiter;
+
+    #[tokio::test(start_paused = true)]
+    async fn frame_allowance_resets_each_second() {
+        let mut limiter = FrameLimiter::new(3);
+        assert!((0..3).all(|_| limiter.allow()));
+        assert!(!limiter.allow());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.allow());
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use serde::Deserialize;
+
+use crate::config::{InvalidLimits, RoomConfig, RoomLimits};
+
+/// The settings an operator can change from the config file, re-read on `SIGHUP`.
+///
+/// Anything not named here (transforms, sinks, shared budgets) is fixed when the server starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub limits: RoomLimits,
+    pub motd: Option<String>,
+    pub max_frames_per_sec: Option<u32>,
+    pub max_topics: Option<usize>,
+    pub max_queued_per_user: Option<usize>,
+}
+
+impl ServerConfig {
+    /// Reads and validates a JSON config file.
+    pub fn load(path: &Path) -> Result<ServerConfig, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: ServerConfig =
+            serde_json::from_str(&text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.limits.validate().map_err(ConfigError::Invalid)?;
+        if self.max_frames_per_sec == Some(0) {
+            return Err(ConfigError::Invalid(InvalidLimits(
+                "max_frames_per_sec must be at least 1",
+            )));
+        }
+        Ok(())
+    }
+
+    /// `base` with this file's settings in place of its own.
+    pub fn apply(&self, base: &RoomConfig) -> RoomConfig {
+        RoomConfig {
+            limits: self.limits.clone(),
+            motd: self.motd.clone(),
+            max_frames_per_sec: self.max_frames_per_sec,
+            max_topics: self.max_topics,
+            max_queued_per_user: self.max_queued_per_user,
+            ..base.clone()
+        }
+    }
+}
+
+/// Why a config file couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(InvalidLimits),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "couldn't read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "couldn't parse config: {}", e),
+            ConfigError::Invalid(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The server's current `RoomConfig`, which a reload swaps out as a whole.
+///
+/// Each new connection reads the config once, so it sees either the old config or the new one,
+/// never a mix. Rooms keep the config they were created with, as do their connections.
+///
+/// To check a reload by hand: start the server with `CHAT_CONFIG=chat.json`, connect to a room,
+/// change `motd` in `chat.json` and send `kill -HUP <pid>`. A connection to a new room gets the
+/// new motd while the first stays connected. Setting `"max_users": 0` and sending `SIGHUP` again
+/// logs the error and keeps the previous config.
+#[derive(Debug, Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<RoomConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: RoomConfig) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn load(&self) -> Arc<RoomConfig> {
+        Arc::clone(&self.0.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn store(&self, config: RoomConfig) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+
+    /// Validates `file` and, if it is usable, swaps in the current config with its settings
+    /// applied. The current config is left alone otherwise.
+    pub fn reload(&self, file: &ServerConfig) -> Result<(), ConfigError> {
+        file.validate()?;
+        let mut current = self.0.write().unwrap_or_else(|e| e.into_inner());
+        *current = Arc::new(file.apply(&current));
+        Ok(())
+    }
+}
+
+impl From<RoomConfig> for SharedConfig {
+    fn from(config: RoomConfig) -> SharedConfig {
+        SharedConfig::new(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{RoomConfig, RoomLimits},
+        reload::{ServerConfig, SharedConfig},
+    };
+
+    #[test]
+    fn reload_swaps_only_valid_configs() {
+        let shared = SharedConfig::new(RoomConfig::default());
+        let before = shared.load();
+
+        let file = ServerConfig {
+            motd: Some("welcome back".to_owned()),
+            ..ServerConfig::default()
+        };
+        shared.reload(&file).unwrap();
+        assert_eq!(shared.load().motd.as_deref(), Some("welcome back"));
+        // Readers holding the old config keep it.
+        assert_eq!(before.motd, None);
+
+        let invalid = ServerConfig {
+            limits: RoomLimits {
+                max_users: Some(0),
+                ..RoomLimits::default()
+            },
+            ..ServerConfig::default()
+        };
+        assert!(shared.reload(&invalid).is_err());
+        assert_eq!(shared.load().motd.as_deref(), Some("welcome back"));
+    }
+}
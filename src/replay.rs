@@ -0,0 +1,158 @@
+use std::{io, time::SystemTime};
+
+use futures::{pin_mut, TryStreamExt};
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+
+use crate::{
+    transcript::{self, TranscriptFormat},
+    ChatRoom,
+};
+
+/// How quickly replayed messages are posted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplaySpeed {
+    /// Keep the gaps between the original messages, going by their transcript timestamps.
+    RealTime,
+    /// Post every message immediately.
+    #[default]
+    AsFastAsPossible,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct ReplayOptions {
+    #[serde(default)]
+    pub speed: ReplaySpeed,
+    /// Only count the messages that would be replayed.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// How the transcript being replayed is stored.
+    #[serde(default)]
+    pub format: TranscriptFormat,
+}
+
+/// Re-posts every message of a transcript into `room` as if its original senders had just sent
+/// it, returning how many messages were replayed. Private messages and notices, such as joins,
+/// edits and nick changes, are skipped.
+///
+/// The transcript is read one line at a time, so replaying a large file doesn't load it into
+/// memory.
+pub async fn replay<R>(transcript: R, room: &ChatRoom, options: ReplayOptions) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let records = transcript::records_in(transcript, options.format);
+    pin_mut!(records);
+
+    let mut replayed = 0;
+    let mut previous: Option<SystemTime> = None;
+    while let Some(record) = records.try_next().await? {
+        if record.is_private() || record.is_notice() {
+            continue;
+        }
+        if options.dry_run {
+            replayed += 1;
+            continue;
+        }
+        if options.speed == ReplaySpeed::RealTime {
+            let logged_at = humantime::parse_rfc3339(&record.timestamp).ok();
+            if let (Some(previous), Some(logged_at)) = (previous, logged_at) {
+                if let Ok(gap) = logged_at.duration_since(previous) {
+                    tokio::time::sleep(gap).await;
+                }
+            }
+            previous = logged_at.or(previous);
+        }
+        room.post_message(record.user_id, &record.message, None, None)
+            .await;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        config::DEFAULT_MAX_QUEUED_PER_USER,
+        protocol::Protocol,
+        replay::{replay, ReplayOptions, ReplaySpeed},
+        ChatRoom, User, Users,
+    };
+
+    const TRANSCRIPT: &str = "\
+[2021-10-01T12:00:00Z] Channel source, user 1: first
+[2021-10-01T12:00:02Z] Channel source, user 2: second
+not a transcript line
+[2021-10-01T12:00:05Z] Channel source, user 1: third
+";
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_posts_in_order() {
+        let room = ChatRoom::unlogged("replay_room".to_owned(), Users::default()).await;
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(99, User::new(tx, Protocol::LegacyText));
+
+        let dry_run = ReplayOptions {
+            dry_run: true,
+            ..ReplayOptions::default()
+        };
+        assert_eq!(
+            replay(TRANSCRIPT.as_bytes(), &room, dry_run).await.unwrap(),
+            3
+        );
+        assert!(rx.try_recv().is_err());
+
+        let real_time = ReplayOptions {
+            speed: ReplaySpeed::RealTime,
+            ..ReplayOptions::default()
+        };
+        let started = tokio::time::Instant::now();
+        assert_eq!(
+            replay(TRANSCRIPT.as_bytes(), &room, real_time)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+        for expected in ["<User#1>: first", "<User#2>: second", "<User#1>: third"] {
+            assert_eq!(rx.recv().await.unwrap().to_str(), Ok(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_skips_private_messages_and_notices() {
+        let room = ChatRoom::unlogged("quiet_room".to_owned(), Users::default()).await;
+        let (tx, mut rx) = mpsc::channel(DEFAULT_MAX_QUEUED_PER_USER);
+        room.users
+            .write()
+            .await
+            .insert(99, User::new(tx, Protocol::LegacyText));
+        let transcript = "\
+[2021-10-01T12:00:00Z] Channel source, user 0: *** User#1 joined ***
+[2021-10-01T12:00:01Z] [to=2] Channel source, user 1: just between us
+[2021-10-01T12:00:02Z] Channel source, user 1: *** User#1 is now known as alice ***
+[2021-10-01T12:00:03Z] Channel source, user 1: *** edited message 4: hi ***
+[2021-10-01T12:00:04Z] Channel source, user 1: hello everyone
+";
+
+        let options = ReplayOptions::default();
+        assert_eq!(
+            replay(transcript.as_bytes(), &room, options).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            rx.recv().await.unwrap().to_str(),
+            Ok("<User#1>: hello everyone")
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    error::Error,
+    fmt,
+    hash::BuildHasher,
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+
+use futures::future;
+use serde::Serialize;
+
+use tokio::{
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    task::JoinHandle,
+};
+
+use crate::{
+    locks::{timed_read, timed_write, TimedGuard},
+    ChatRoom,
+};
+
+/// One shard's rooms, keyed by name.
+pub type RoomMap = HashMap<String, Weak<ChatRoom>>;
+
+/// Who created a room and how, kept by rooms whose config sets `record_origins`, to help track
+/// down where unexpected rooms come from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomOrigin {
+    pub route: CreationRoute,
+    /// Address of the connection that created the room, if the transport knows it.
+    pub addr: Option<SocketAddr>,
+    /// Authenticated account of the connection that created the room, if it has one.
+    pub account: Option<String>,
+    /// RFC 3339 time at which the room was created.
+    pub created_at: String,
+}
+
+impl RoomOrigin {
+    /// An origin timestamped now.
+    pub fn new(route: CreationRoute, addr: Option<SocketAddr>, account: Option<String>) -> Self {
+        RoomOrigin {
+            route,
+            addr,
+            account,
+            created_at: humantime::format_rfc3339(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+/// How the connection that created a room asked for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreationRoute {
+    /// A websocket upgrade of `/chat/<room>`.
+    Websocket,
+    /// A `join` on a multiplexed connection.
+    Mux,
+}
+
+/// Longest room name allowed, in characters.
+pub const MAX_ROOM_NAME_CHARS: usize = 64;
+
+/// Why a room name was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomNameError {
+    Empty,
+    TooLong,
+    /// The name contains a character other than a letter, digit, `-` or `_`.
+    InvalidChar(char),
+}
+
+impl fmt::Display for RoomNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomNameError::Empty => f.write_str("room name is empty"),
+            RoomNameError::TooLong => write!(
+                f,
+                "room name is longer than {} characters",
+                MAX_ROOM_NAME_CHARS
+            ),
+            RoomNameError::InvalidChar(c) => write!(f, "room name contains {:?}", c),
+        }
+    }
+}
+
+impl Error for RoomNameError {}
+
+/// Checks that `name` can name a room: up to `MAX_ROOM_NAME_CHARS` letters, digits, `-` and `_`.
+/// Room names end up in transcript file names, so anything else is refused before a room is made.
+pub fn validate_room_name(name: &str) -> Result<(), RoomNameError> {
+    if name.is_empty() {
+        return Err(RoomNameError::Empty);
+    }
+    if name.chars().count() > MAX_ROOM_NAME_CHARS {
+        return Err(RoomNameError::TooLong);
+    }
+    match name
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || c == '-' || c == '_'))
+    {
+        Some(c) => Err(RoomNameError::InvalidChar(c)),
+        None => Ok(()),
+    }
+}
+
+/// Shards used by `ChatRooms::default()`.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Every open room, split across independently locked shards by a hash of the room's name.
+///
+/// Creating a room only write-locks its own shard, so a flood of connects to distinct rooms
+/// doesn't serialise behind one global lock. Anything that must be atomic for a single room
+/// (find-or-create, linger reaps) holds that room's shard, which is always the same one. With one
+/// shard this behaves like a single `RwLock`ed map.
+///
+/// Clones share the same rooms.
+#[derive(Debug, Clone)]
+pub struct ChatRooms {
+    shards: Arc<[RwLock<RoomMap>]>,
+    hasher: RandomState,
+}
+
+impl ChatRooms {
+    pub fn with_shards(shards: usize) -> ChatRooms {
+        ChatRooms {
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, room_name: &str) -> &RwLock<RoomMap> {
+        let hash = self.hasher.hash_one(room_name);
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    /// Read-locks the shard holding `room_name`.
+    pub async fn read_shard(&self, room_name: &str) -> TimedGuard<RwLockReadGuard<'_, RoomMap>> {
+        timed_read(self.shard(room_name), "rooms").await
+    }
+
+    /// Write-locks the shard holding `room_name`.
+    pub async fn write_shard(&self, room_name: &str) -> TimedGuard<RwLockWriteGuard<'_, RoomMap>> {
+        timed_write(self.shard(room_name), "rooms").await
+    }
+
+    pub async fn get(&self, room_name: &str) -> Option<Weak<ChatRoom>> {
+        self.read_shard(room_name).await.get(room_name).cloned()
+    }
+
+    pub async fn insert(&self, room_name: String, room: Weak<ChatRoom>) {
+        self.write_shard(&room_name).await.insert(room_name, room);
+    }
+
+    /// Number of entries, including rooms that have closed but not been reaped yet.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += timed_read(shard, "rooms").await.len();
+        }
+        len
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Every room that is still open, in no particular order.
+    pub async fn live_rooms(&self) -> Vec<Arc<ChatRoom>> {
+        let mut live = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = timed_read(shard, "rooms").await;
+            live.extend(shard.values().filter_map(Weak::upgrade));
+        }
+        live
+    }
+
+    /// Closes the transcript of every open room, returning once all of them are written out and
+    /// flushed. Meant for shutdown, after the server has stopped taking connections.
+    pub async fn close_transcripts(&self) {
+        let rooms = self.live_rooms().await;
+        future::join_all(rooms.iter().map(|room| room.close_log())).await;
+    }
+
+    /// Removes the entries of rooms that have been dropped, one shard at a time, returning how
+    /// many were removed.
+    pub async fn reap(&self) -> usize {
+        let mut reaped = 0;
+        for shard in self.shards.iter() {
+            let mut shard = timed_write(shard, "rooms").await;
+            reaped += reap_shard(&mut shard);
+        }
+        reaped
+    }
+
+    /// Removes every room that nobody is in and that has been idle for at least `timeout`,
+    /// returning how many were removed.
+    ///
+    /// Rooms are normally closed once their last reference is dropped, but something other than
+    /// a connection (a task, say) can hold an empty room open indefinitely. Once removed it can't
+    /// be joined, so the next join creates a fresh room, and it closes when its last holder lets
+    /// go. Draining rooms are left to `drain_room`.
+    pub async fn reap_idle(&self, timeout: Duration) -> usize {
+        self.remove_idle(timeout).await.len()
+    }
+
+    /// Like `reap_idle`, but hands back the rooms it removed.
+    pub async fn remove_idle(&self, timeout: Duration) -> Vec<Arc<ChatRoom>> {
+        let mut reaped = Vec::new();
+        for room in self.live_rooms().await {
+            // Checked under the shard lock, which a join holds until it has revived the room.
+            let mut shard = self.write_shard(&room.name).await;
+            if room.idle_for() < timeout
+                || room.is_draining()
+                || !room.users.read().await.is_empty()
+            {
+                continue;
+            }
+            let room_ptr = Arc::downgrade(&room);
+            if shard
+                .get(&room.name)
+                .is_some_and(|ptr| ptr.ptr_eq(&room_ptr))
+            {
+                shard.remove(&room.name);
+                tracing::info!(room = %room.name, "idle channel reaped");
+                reaped.push(room);
+            }
+        }
+        reaped
+    }
+}
+
+impl Default for ChatRooms {
+    fn default() -> ChatRooms {
+        ChatRooms::with_shards(DEFAULT_SHARDS)
+    }
+}
+
+/// Removes the entries in `shard` whose room has been dropped, returning how many were removed.
+pub(crate) fn reap_shard(shard: &mut RoomMap) -> usize {
+    let before = shard.len();
+    shard.retain(|_, room_ptr| room_ptr.strong_count() > 0);
+    before - shard.len()
+}
+
+/// Runs `ChatRooms::reap_idle` on `rooms` every half `timeout`, so an idle room is removed at
+/// most one and a half `timeout`s after its last activity.
+pub fn spawn_idle_reaper(rooms: ChatRooms, timeout: Duration) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut sweeps = tokio::time::interval((timeout / 2).max(Duration::from_millis(1)));
+        loop {
+            sweeps.tick().await;
+            rooms.reap_idle(timeout).await;
+        }
+    })
+}
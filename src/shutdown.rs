@@ -0,0 +1,62 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Set once the server starts draining, after which no new rooms are created.
+///
+/// Clones share the flag, so one held by the server's `RoomConfig` is seen by every room.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownFlag(Arc<AtomicBool>);
+
+impl ShutdownFlag {
+    pub fn begin(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn has_begun(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Why a room can't be joined right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    /// The server has begun shutting down.
+    ShuttingDown,
+    /// The room is being drained for maintenance.
+    Draining,
+    /// The room doesn't exist and new rooms are being created too quickly; one may be created
+    /// after the given wait.
+    Throttled(Duration),
+    /// The room doesn't exist and couldn't be created, because its transcript couldn't be opened
+    /// and the config requires one.
+    NoTranscript,
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unavailable::ShuttingDown => f.write_str("server is shutting down"),
+            Unavailable::Draining => f.write_str("room is closed for maintenance"),
+            Unavailable::Throttled(wait) => write!(
+                f,
+                "too many new rooms, retry in {}",
+                humantime::format_duration(Duration::from_secs(retry_secs(*wait)))
+            ),
+            Unavailable::NoTranscript => f.write_str("room transcript couldn't be opened"),
+        }
+    }
+}
+
+/// A wait rounded up to whole seconds, as sent in `Retry-After`.
+pub fn retry_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+impl Error for Unavailable {}
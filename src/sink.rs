@@ -0,0 +1,473 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{self, BoxFuture};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::{
+    metrics,
+    transcript::{Record, TranscriptFormat},
+};
+
+/// Where a room's logging task writes its transcript.
+///
+/// Methods return boxed futures so sinks can be used as trait objects.
+pub trait LogSink: Send {
+    /// Writes one transcript line, which doesn't include a trailing newline.
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Writes several lines in order, as the logging task does when it falls behind. Sinks with a
+    /// per-call cost should write them in one go; by default each goes through `write_line`,
+    /// stopping at the first error.
+    fn write_lines<'a>(&'a mut self, lines: &'a [String]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            for line in lines {
+                self.write_line(line).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Makes everything written so far durable.
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Starts writing to a new segment, for sinks that split the transcript into several. Lines
+    /// written before this call land in the old segment and lines written after it in the new
+    /// one. Does nothing by default.
+    fn rotate(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    /// Whether the sink is keeping up with what it is given. Only sinks that hold on to lines
+    /// they couldn't write can tell; others always say yes.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
+
+/// Opens a file for appending, creating it if needed, so reopening a transcript keeps what was
+/// already written.
+async fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Writes the transcript to a file through a `BufWriter`. The default sink.
+#[derive(Debug)]
+pub struct FileSink {
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    pub async fn create(path: &Path) -> io::Result<FileSink> {
+        let file = File::create(path).await?;
+        Ok(FileSink {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Opens `path` to add to the end of it, creating it if needed.
+    pub async fn append(path: &Path) -> io::Result<FileSink> {
+        Ok(FileSink {
+            writer: BufWriter::new(open_append(path).await?),
+        })
+    }
+}
+
+impl LogSink for FileSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.writer.write_all(line.as_bytes()).await?;
+            self.writer.write_all(b"\n").await
+        })
+    }
+
+    fn write_lines<'a>(&'a mut self, lines: &'a [String]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let len = lines.iter().map(|line| line.len() + 1).sum();
+            let mut buf = Vec::with_capacity(len);
+            for line in lines {
+                buf.extend_from_slice(line.as_bytes());
+                buf.push(b'\n');
+            }
+            self.writer.write_all(&buf).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.writer.flush())
+    }
+}
+
+/// Writes the transcript to a file in the compact binary format described at
+/// `Record::to_binary`.
+///
+/// Lines are parsed back into records before encoding; a line that doesn't parse is an
+/// `InvalidData` error.
+#[derive(Debug)]
+pub struct BinaryFileSink {
+    writer: BufWriter<File>,
+}
+
+impl BinaryFileSink {
+    pub async fn create(path: &Path) -> io::Result<BinaryFileSink> {
+        let file = File::create(path).await?;
+        Ok(BinaryFileSink {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Opens `path` to add to the end of it, creating it if needed.
+    pub async fn append(path: &Path) -> io::Result<BinaryFileSink> {
+        Ok(BinaryFileSink {
+            writer: BufWriter::new(open_append(path).await?),
+        })
+    }
+}
+
+impl LogSink for BinaryFileSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let record = Record::parse(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unparseable transcript line")
+            })?;
+            self.writer.write_all(&record.to_binary()?).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.writer.flush())
+    }
+}
+
+/// Writes the transcript as JSON lines to a file.
+///
+/// Lines are parsed back into records before encoding, like `BinaryFileSink` does.
+#[derive(Debug)]
+pub struct JsonFileSink {
+    writer: BufWriter<File>,
+}
+
+impl JsonFileSink {
+    pub async fn create(path: &Path) -> io::Result<JsonFileSink> {
+        let file = File::create(path).await?;
+        Ok(JsonFileSink {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Opens `path` to add to the end of it, creating it if needed.
+    pub async fn append(path: &Path) -> io::Result<JsonFileSink> {
+        Ok(JsonFileSink {
+            writer: BufWriter::new(open_append(path).await?),
+        })
+    }
+}
+
+impl LogSink for JsonFileSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (room, record) = Record::parse_in(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unparseable transcript line")
+            })?;
+            let mut json = record.to_json(room);
+            json.push('\n');
+            self.writer.write_all(json.as_bytes()).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.writer.flush())
+    }
+}
+
+/// Writes the transcript to numbered segment files, starting a new one whenever the current one
+/// would grow past `max_bytes`.
+///
+/// The first segment is `path` itself and later ones have `.1`, `.2`, ... appended. The logging
+/// task only hands the sink one line at a time, so lines arriving while a new segment is being
+/// opened wait in the room's log queue and go to the new segment once it is ready. If it can't be
+/// opened, writing carries on in the current segment and rotation is retried on the next line, so
+/// no line is dropped or written twice.
+#[derive(Debug)]
+pub struct RotatingFileSink {
+    path: PathBuf,
+    format: TranscriptFormat,
+    max_bytes: Option<u64>,
+    segment: usize,
+    current: SegmentSink,
+    written: u64,
+}
+
+#[derive(Debug)]
+enum SegmentSink {
+    Text(FileSink),
+    Binary(BinaryFileSink),
+    Json(JsonFileSink),
+}
+
+impl SegmentSink {
+    async fn create(path: &Path, format: TranscriptFormat) -> io::Result<SegmentSink> {
+        Ok(match format {
+            TranscriptFormat::Text => SegmentSink::Text(FileSink::create(path).await?),
+            TranscriptFormat::Binary => SegmentSink::Binary(BinaryFileSink::create(path).await?),
+            TranscriptFormat::Json => SegmentSink::Json(JsonFileSink::create(path).await?),
+        })
+    }
+
+    fn sink(&mut self) -> &mut dyn LogSink {
+        match self {
+            SegmentSink::Text(sink) => sink,
+            SegmentSink::Binary(sink) => sink,
+            SegmentSink::Json(sink) => sink,
+        }
+    }
+}
+
+impl RotatingFileSink {
+    pub async fn create(
+        path: &Path,
+        format: TranscriptFormat,
+        max_bytes: Option<u64>,
+    ) -> io::Result<RotatingFileSink> {
+        Ok(RotatingFileSink {
+            path: path.to_owned(),
+            format,
+            max_bytes,
+            segment: 0,
+            current: SegmentSink::create(path, format).await?,
+            written: 0,
+        })
+    }
+
+    /// The file segment `n` of the transcript at `path` is written to.
+    pub fn segment_path(path: &Path, n: usize) -> PathBuf {
+        if n == 0 {
+            return path.to_owned();
+        }
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Opens the next segment, then flushes the current one and switches over.
+    async fn next_segment(&mut self) -> io::Result<()> {
+        let path = RotatingFileSink::segment_path(&self.path, self.segment + 1);
+        let next = SegmentSink::create(&path, self.format).await?;
+        self.current.sink().flush().await?;
+        self.current = next;
+        self.segment += 1;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl LogSink for RotatingFileSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let len = line.len() as u64 + 1;
+            let full = self
+                .max_bytes
+                .is_some_and(|max| self.written > 0 && self.written + len > max);
+            if full {
+                if let Err(e) = self.next_segment().await {
+                    tracing::error!(path = %self.path.display(), error = %e, "failed to rotate transcript");
+                }
+            }
+            self.current.sink().write_line(line).await?;
+            self.written += len;
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.current.sink().flush()
+    }
+
+    fn rotate(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.next_segment())
+    }
+}
+
+/// Opens a replacement for a sink that keeps failing.
+pub type Reopen = Box<dyn FnMut() -> BoxFuture<'static, io::Result<Box<dyn LogSink>>> + Send>;
+
+/// Consecutive failures after which a `RecoveringSink` replaces its sink.
+pub const FAILURES_BEFORE_REOPEN: u32 = 3;
+
+/// Most lines a `RecoveringSink` holds while it can't write them; older ones are dropped.
+pub const MAX_UNWRITTEN_LINES: usize = 1024;
+
+/// Keeps a transcript going when its sink starts failing mid-operation, e.g. because the disk
+/// filled up or the directory was unmounted.
+///
+/// Lines that couldn't be written are held, up to `MAX_UNWRITTEN_LINES`, and retried with the
+/// next line. After `FAILURES_BEFORE_REOPEN` failures in a row the sink reports itself unhealthy
+/// and calls `reopen` for a replacement before each later write, until one opens; held lines go
+/// to the replacement first. Lines already accepted into a buffer whose flush failed can't be
+/// recovered.
+pub struct RecoveringSink {
+    current: Box<dyn LogSink>,
+    reopen: Reopen,
+    unwritten: VecDeque<String>,
+    failures: u32,
+}
+
+impl RecoveringSink {
+    pub fn new(sink: Box<dyn LogSink>, reopen: Reopen) -> RecoveringSink {
+        RecoveringSink {
+            current: sink,
+            reopen,
+            unwritten: VecDeque::new(),
+            failures: 0,
+        }
+    }
+
+    fn failed(&mut self, e: &io::Error) {
+        self.failures += 1;
+        metrics::count_log_failure();
+        tracing::error!(failures = self.failures, error = %e, "transcript write failed");
+    }
+
+    /// Replaces the sink if it has failed too often, then writes every held line.
+    async fn write_unwritten(&mut self) -> io::Result<()> {
+        if self.failures >= FAILURES_BEFORE_REOPEN {
+            self.current = (self.reopen)().await?;
+            self.failures = 0;
+            metrics::count_log_recovery();
+        }
+        while let Some(line) = self.unwritten.front() {
+            if let Err(e) = self.current.write_line(line).await {
+                self.failed(&e);
+                return Err(e);
+            }
+            self.unwritten.pop_front();
+        }
+        self.failures = 0;
+        Ok(())
+    }
+}
+
+impl LogSink for RecoveringSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if self.unwritten.len() == MAX_UNWRITTEN_LINES {
+                self.unwritten.pop_front();
+            }
+            self.unwritten.push_back(line.to_owned());
+            self.write_unwritten().await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.write_unwritten().await?;
+            let flushed = self.current.flush().await;
+            if let Err(e) = &flushed {
+                self.failed(e);
+            }
+            flushed
+        })
+    }
+
+    fn rotate(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.current.rotate()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.failures < FAILURES_BEFORE_REOPEN
+    }
+}
+
+/// Keeps transcript lines in memory, where clones of the sink can read them back. Mostly useful
+/// in tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+}
+
+impl LogSink for MemorySink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.lines.lock().unwrap().push(line.to_owned());
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Throws the transcript away, for benchmarks and rooms that shouldn't be recorded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiscardSink;
+
+impl LogSink for DiscardSink {
+    fn write_line<'a>(&'a mut self, _line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Writes every line to both of its sinks, reporting the first error from either.
+pub struct TeeSink(pub Box<dyn LogSink>, pub Box<dyn LogSink>);
+
+impl LogSink for TeeSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let first = self.0.write_line(line).await;
+            let second = self.1.write_line(line).await;
+            first.and(second)
+        })
+    }
+
+    fn write_lines<'a>(&'a mut self, lines: &'a [String]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let first = self.0.write_lines(lines).await;
+            let second = self.1.write_lines(lines).await;
+            first.and(second)
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let first = self.0.flush().await;
+            let second = self.1.flush().await;
+            first.and(second)
+        })
+    }
+
+    fn rotate(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let first = self.0.rotate().await;
+            let second = self.1.rotate().await;
+            first.and(second)
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.0.is_healthy() && self.1.is_healthy()
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    future::{self, BoxFuture},
+    TryStreamExt,
+};
+
+use crate::{
+    sink::{BinaryFileSink, FileSink, JsonFileSink, LogSink},
+    transcript::{self, Record, TranscriptFormat},
+};
+
+/// Somewhere a room's messages are kept once logged, which can be read back and searched.
+///
+/// Methods return boxed futures so stores can be used as trait objects. A store configured in
+/// `RoomConfig::message_store` is shared by every room, so each call names the room it is about.
+pub trait MessageStore: Send + Sync + fmt::Debug {
+    /// Adds `record` to the end of `room`'s messages.
+    fn append<'a>(&'a self, room: &'a str, record: Record) -> BoxFuture<'a, io::Result<()>>;
+
+    /// The latest `limit` messages in `room`, oldest first, leaving out private messages.
+    fn query_recent<'a>(
+        &'a self,
+        room: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, io::Result<Vec<Record>>>;
+
+    /// The latest `limit` messages in `room` containing `query`, ignoring case, oldest first,
+    /// leaving out private messages.
+    fn search<'a>(
+        &'a self,
+        room: &'a str,
+        query: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, io::Result<Vec<Record>>>;
+}
+
+/// The last `limit` of `records` that aren't private and contain `query`, ignoring case.
+fn latest_matching(
+    records: impl Iterator<Item = Record>,
+    query: Option<&str>,
+    limit: usize,
+) -> Vec<Record> {
+    let query = query.map(str::to_lowercase);
+    let mut matching: Vec<_> = records
+        .filter(|record| {
+            !record.is_private()
+                && query
+                    .as_ref()
+                    .is_none_or(|query| record.message.to_lowercase().contains(query))
+        })
+        .collect();
+    let skip = matching.len().saturating_sub(limit);
+    matching.drain(..skip);
+    matching
+}
+
+/// A single room's transcript file, the store every room falls back to.
+///
+/// Only `path` itself is read, not the later segments of a rotated transcript. The room name
+/// given to each call is ignored, since the file only holds one room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStore {
+    path: PathBuf,
+    format: TranscriptFormat,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf, format: TranscriptFormat) -> FileStore {
+        FileStore { path, format }
+    }
+
+    async fn read(&self) -> io::Result<Vec<Record>> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        transcript::records_in(file, self.format)
+            .try_collect()
+            .await
+    }
+}
+
+impl MessageStore for FileStore {
+    fn append<'a>(&'a self, room: &'a str, record: Record) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut sink: Box<dyn LogSink> = match self.format {
+                TranscriptFormat::Text => Box::new(FileSink::append(&self.path).await?),
+                TranscriptFormat::Binary => Box::new(BinaryFileSink::append(&self.path).await?),
+                TranscriptFormat::Json => Box::new(JsonFileSink::append(&self.path).await?),
+            };
+            sink.write_line(&record.to_line(room)).await?;
+            sink.flush().await
+        })
+    }
+
+    fn query_recent<'a>(
+        &'a self,
+        _room: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, io::Result<Vec<Record>>> {
+        Box::pin(async move { Ok(latest_matching(self.read().await?.into_iter(), None, limit)) })
+    }
+
+    fn search<'a>(
+        &'a self,
+        _room: &'a str,
+        query: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, io::Result<Vec<Record>>> {
+        Box::pin(async move {
+            let records = self.read().await?.into_iter();
+            Ok(latest_matching(records, Some(query), limit))
+        })
+    }
+}
+
+/// Keeps every room's messages in memory, for tests and rooms that don't need them to last.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<String, Vec<Record>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn latest(&self, room: &str, query: Option<&str>, limit: usize) -> Vec<Record> {
+        let rooms = self.rooms.lock().unwrap();
+        let records = rooms.get(room).into_iter().flatten().cloned();
+        latest_matching(records, query, limit)
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn append<'a>(&'a self, room: &'a str, record: Record) -> BoxFuture<'a, io::Result<()>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.to_owned()).or_default().push(record);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn query_recent<'a>(
+        &'a self,
+        room: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, io::Result<Vec<Record>>> {
+        Box::pin(future::ready(Ok(self.latest(room, None, limit))))
+    }
+
+    fn search<'a>(
+        &'a self,
+        room: &'a str,
+        query: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, io::Result<Vec<Record>>> {
+        Box::pin(future::ready(Ok(self.latest(room, Some(query), limit))))
+    }
+}
+
+/// Appends every transcript line a room logs to a message store.
+///
+/// Lines are parsed back into records first; a line that doesn't parse is an `InvalidData`
+/// error, like `BinaryFileSink` gives.
+#[derive(Debug, Clone)]
+pub struct StoreSink(pub Arc<dyn MessageStore>);
+
+impl LogSink for StoreSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let (room, record) = Record::parse_in(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unparseable transcript line")
+            })?;
+            self.0.append(room, record).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        store::{MemoryStore, MessageStore},
+        transcript::Record,
+    };
+
+    #[tokio::test]
+    async fn memory_store_keeps_each_rooms_messages() {
+        let store = MemoryStore::new();
+        for message in ["Hello there", "general kenobi", "HELLO again", "bye"].iter() {
+            store
+                .append("lobby", Record::new(1, message))
+                .await
+                .unwrap();
+        }
+        store
+            .append("other", Record::new(2, "hello"))
+            .await
+            .unwrap();
+
+        let messages = |records: Vec<Record>| -> Vec<String> {
+            records.into_iter().map(|record| record.message).collect()
+        };
+        assert_eq!(
+            messages(store.query_recent("lobby", 2).await.unwrap()),
+            ["HELLO again", "bye"]
+        );
+        assert_eq!(
+            messages(store.search("lobby", "hello", 10).await.unwrap()),
+            ["Hello there", "HELLO again"]
+        );
+        assert_eq!(
+            messages(store.search("lobby", "hello", 1).await.unwrap()),
+            ["HELLO again"]
+        );
+        assert!(store.query_recent("empty", 5).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,214 @@
+use std::{io, net::SocketAddr, path::PathBuf, time::SystemTime};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::sink::LogSink;
+
+/// Syslog facilities a transcript can be filed under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(&self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+/// Where syslog messages are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTarget {
+    Udp(SocketAddr),
+    /// TCP with octet-counted framing (RFC 6587).
+    Tcp(SocketAddr),
+    /// A local datagram socket such as `/dev/log`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// How transcripts are sent to syslog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogConfig {
+    pub target: SyslogTarget,
+    pub facility: Facility,
+    /// The APP-NAME every message is tagged with.
+    pub tag: String,
+    /// Send transcripts only to syslog rather than alongside the room's log file.
+    pub replace_file: bool,
+}
+
+/// Severity of every transcript message: informational.
+const SEVERITY_INFO: u8 = 6;
+
+#[derive(Debug)]
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixDatagram),
+}
+
+/// Sends each transcript line to a syslog daemon as an RFC 5424 message.
+///
+/// The connection is made on the first write and remade after a failure. Lines that can't be sent
+/// are dropped, with the error reported to the logging task, so an unreachable daemon never stops
+/// the room.
+#[derive(Debug)]
+pub struct SyslogSink {
+    config: SyslogConfig,
+    connection: Option<Connection>,
+}
+
+impl SyslogSink {
+    pub fn new(config: SyslogConfig) -> SyslogSink {
+        SyslogSink {
+            config,
+            connection: None,
+        }
+    }
+
+    /// Formats a transcript line, taking the timestamp from the line itself where it has one.
+    pub fn format(&self, line: &str) -> String {
+        let parsed = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+            .and_then(|(timestamp, rest)| {
+                humantime::parse_rfc3339(timestamp)
+                    .ok()
+                    .map(|time| (time, rest))
+            });
+        let (time, message) = parsed.unwrap_or((SystemTime::now(), line));
+        format!(
+            "<{}>1 {} - {} {} - - {}",
+            self.config.facility.code() * 8 + SEVERITY_INFO,
+            humantime::format_rfc3339_micros(time),
+            self.config.tag,
+            std::process::id(),
+            message
+        )
+    }
+
+    async fn connect(target: &SyslogTarget) -> io::Result<Connection> {
+        Ok(match target {
+            SyslogTarget::Udp(addr) => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Connection::Udp(socket)
+            }
+            SyslogTarget::Tcp(addr) => Connection::Tcp(TcpStream::connect(addr).await?),
+            #[cfg(unix)]
+            SyslogTarget::Unix(path) => {
+                let socket = tokio::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Connection::Unix(socket)
+            }
+        })
+    }
+
+    async fn send(&mut self, message: &str) -> io::Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(SyslogSink::connect(&self.config.target).await?);
+        }
+        let sent = match self.connection.as_mut() {
+            Some(Connection::Udp(socket)) => socket.send(message.as_bytes()).await.map(drop),
+            Some(Connection::Tcp(stream)) => {
+                let framed = format!("{} {}", message.len(), message);
+                stream.write_all(framed.as_bytes()).await
+            }
+            #[cfg(unix)]
+            Some(Connection::Unix(socket)) => socket.send(message.as_bytes()).await.map(drop),
+            None => unreachable!("connected above"),
+        };
+        if sent.is_err() {
+            self.connection = None;
+        }
+        sent
+    }
+}
+
+impl LogSink for SyslogSink {
+    fn write_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let message = self.format(line);
+            self.send(&message).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            match self.connection.as_mut() {
+                Some(Connection::Tcp(stream)) => stream.flush().await,
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        sink::LogSink,
+        syslog::{Facility, SyslogConfig, SyslogSink, SyslogTarget},
+        transcript::Record,
+    };
+
+    #[tokio::test]
+    async fn sends_record_over_udp() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = SyslogSink::new(SyslogConfig {
+            target: SyslogTarget::Udp(daemon.local_addr().unwrap()),
+            facility: Facility::Local0,
+            tag: "chat".to_owned(),
+            replace_file: true,
+        });
+
+        let record = Record {
+            timestamp: "2021-10-01T12:00:00.123456789Z".to_owned(),
+            ..Record::new(3, "hi")
+        };
+        sink.write_line(&record.to_line("lobby")).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let len = daemon.recv(&mut buf).await.unwrap();
+        let received = std::str::from_utf8(&buf[..len]).unwrap();
+        assert_eq!(
+            received,
+            format!(
+                "<134>1 2021-10-01T12:00:00.123456Z - chat {} - - Channel lobby, user 3: hi",
+                std::process::id()
+            )
+        );
+    }
+}
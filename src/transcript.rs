@@ -0,0 +1,633 @@
+use std::{
+    convert::TryFrom,
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{
+    future::{self, Either},
+    stream, Stream, StreamExt, TryStreamExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    sync::oneshot,
+};
+
+/// Work sent from a room to its logging task.
+#[derive(Debug)]
+pub(crate) enum LogCommand {
+    /// A formatted transcript line, without its trailing newline.
+    Line(String),
+    /// A user joined or left, logged under the given name as a server line that may be merged
+    /// with its neighbours.
+    System(SystemEvent, String),
+    /// Flush everything written so far to disk, then acknowledge.
+    Flush(oneshot::Sender<()>),
+    /// Start a new transcript segment now, if the sink is segmented, then acknowledge.
+    Rotate(oneshot::Sender<()>),
+}
+
+/// Presence changes recorded in a room's transcript.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemEvent {
+    Joined,
+    Left,
+}
+
+impl SystemEvent {
+    pub fn verb(&self) -> &'static str {
+        match self {
+            SystemEvent::Joined => "joined",
+            SystemEvent::Left => "left",
+        }
+    }
+}
+
+/// User id that server-written transcript lines are attributed to. Real users are numbered
+/// from 1.
+pub const SERVER_USER_ID: usize = 0;
+
+/// A run of consecutive system events of one kind, waiting to be written as a single line.
+#[derive(Debug)]
+pub(crate) struct SystemBatch {
+    pub(crate) event: SystemEvent,
+    first_name: String,
+    count: usize,
+    /// Taken when the first event arrived, so the summary is timestamped with the start of the
+    /// run.
+    record: Record,
+}
+
+impl SystemBatch {
+    pub(crate) fn new(event: SystemEvent, name: String) -> SystemBatch {
+        SystemBatch {
+            event,
+            first_name: name,
+            count: 1,
+            record: Record::new(SERVER_USER_ID, ""),
+        }
+    }
+
+    pub(crate) fn add(&mut self) {
+        self.count += 1;
+    }
+
+    /// The transcript line for the run, e.g. `*** alice (User#3) joined ***` or
+    /// `*** 5 users joined ***`.
+    pub(crate) fn into_line(self, room: &str) -> String {
+        let message = match self.count {
+            1 => format!("*** {} {} ***", self.first_name, self.event.verb()),
+            n => format!("*** {} users {} ***", n, self.event.verb()),
+        };
+        Record {
+            message,
+            ..self.record
+        }
+        .to_line(room)
+    }
+}
+
+/// One line of a room's transcript.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Record {
+    /// RFC 3339 time at which the message was logged.
+    pub timestamp: String,
+    /// The room's sequence number for the message, if the transcript records them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub user_id: usize,
+    /// The one user a private message was sent to, `None` for messages to the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<usize>,
+    /// The sender's nickname at the time, if they had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub message: String,
+}
+
+impl Record {
+    pub fn new(user_id: usize, message: &str) -> Record {
+        Record {
+            timestamp: humantime::format_rfc3339(SystemTime::now()).to_string(),
+            seq: None,
+            user_id,
+            to: None,
+            name: None,
+            message: message.to_owned(),
+        }
+    }
+
+    /// Whether the record is a private message, which only its sender and recipient saw.
+    pub fn is_private(&self) -> bool {
+        self.to.is_some()
+    }
+
+    /// Whether the record notes something that happened in the room, such as a join, an edit or
+    /// a nick change, rather than being a message someone sent.
+    pub fn is_notice(&self) -> bool {
+        self.user_id == SERVER_USER_ID
+            || (self.message.starts_with("*** ") && self.message.ends_with(" ***"))
+    }
+
+    /// Formats the record as a transcript line for `room`, e.g.
+    /// `[2021-10-01T12:00:00.000000000Z] [seq=42] [to=5] [nick=alice] Channel lobby, user 3: hi`,
+    /// where the sequence, recipient and nickname tags are only present if the record has them.
+    pub fn to_line(&self, room: &str) -> String {
+        let mut line = format!("[{}] ", self.timestamp);
+        if let Some(seq) = self.seq {
+            line.push_str(&format!("[seq={}] ", seq));
+        }
+        if let Some(to) = self.to {
+            line.push_str(&format!("[to={}] ", to));
+        }
+        if let Some(name) = &self.name {
+            line.push_str(&format!("[nick={}] ", name));
+        }
+        line.push_str(&format!(
+            "Channel {}, user {}: {}",
+            room, self.user_id, self.message
+        ));
+        line
+    }
+
+    /// Parses a line produced by `to_line`, returning `None` if it is malformed.
+    pub fn parse(line: &str) -> Option<Record> {
+        Record::parse_in(line).map(|(_, record)| record)
+    }
+
+    /// Like `parse`, also returning the name of the room the line was logged in.
+    pub fn parse_in(line: &str) -> Option<(&str, Record)> {
+        let (timestamp, rest) = line.strip_prefix('[')?.split_once("] ")?;
+        let (seq, rest) = match rest.strip_prefix("[seq=") {
+            Some(rest) => {
+                let (seq, rest) = rest.split_once("] ")?;
+                (Some(seq.parse().ok()?), rest)
+            }
+            None => (None, rest),
+        };
+        let (to, rest) = match rest.strip_prefix("[to=") {
+            Some(rest) => {
+                let (to, rest) = rest.split_once("] ")?;
+                (Some(to.parse().ok()?), rest)
+            }
+            None => (None, rest),
+        };
+        let (name, rest) = match rest.strip_prefix("[nick=") {
+            Some(rest) => {
+                let (name, rest) = rest.split_once("] ")?;
+                (Some(name.to_owned()), rest)
+            }
+            None => (None, rest),
+        };
+        let (room, rest) = rest.strip_prefix("Channel ")?.split_once(", user ")?;
+        let (user_id, message) = rest.split_once(": ")?;
+        let record = Record {
+            timestamp: timestamp.to_owned(),
+            seq,
+            user_id: user_id.parse().ok()?,
+            to,
+            name,
+            message: message.to_owned(),
+        };
+        Some((room, record))
+    }
+
+    /// Formats the record as a JSON transcript line for `room`.
+    pub fn to_json(&self, room: &str) -> String {
+        let record = JsonRecord {
+            timestamp: self.timestamp.clone(),
+            room: room.to_owned(),
+            seq: self.seq,
+            user_id: self.user_id,
+            to: self.to,
+            name: self.name.clone(),
+            message: self.message.clone(),
+        };
+        serde_json::to_string(&record).expect("transcript records always serialize")
+    }
+}
+
+/// One line of a JSON transcript.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonRecord {
+    /// RFC 3339 time at which the message was logged, not written.
+    pub timestamp: String,
+    pub room: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub user_id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub message: String,
+}
+
+impl From<JsonRecord> for Record {
+    fn from(record: JsonRecord) -> Record {
+        Record {
+            timestamp: record.timestamp,
+            seq: record.seq,
+            user_id: record.user_id,
+            to: record.to,
+            name: record.name,
+            message: record.message,
+        }
+    }
+}
+
+/// How a transcript is stored on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscriptFormat {
+    /// One `Record::to_line` line per message.
+    #[default]
+    Text,
+    /// Length-prefixed `Record::to_binary` records, for rooms busy enough that the text format's
+    /// size and formatting cost matter more than being able to read the file by eye.
+    Binary,
+    /// One `JsonRecord` object per line, for tools that would rather not parse the text format.
+    Json,
+}
+
+impl TranscriptFormat {
+    /// File extension for transcripts in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TranscriptFormat::Text => "log",
+            TranscriptFormat::Binary => "bin",
+            TranscriptFormat::Json => "jsonl",
+        }
+    }
+}
+
+/// Largest binary record a reader will accept, so a corrupt length can't exhaust memory.
+const MAX_BINARY_RECORD: u64 = 16 * 1024 * 1024;
+
+impl Record {
+    /// Encodes the record in the binary transcript format, including its length prefix.
+    ///
+    /// Every record is an unsigned LEB128 varint giving the length of its payload, followed by
+    /// the payload itself:
+    ///
+    /// | field     | encoding                                                      |
+    /// |-----------|---------------------------------------------------------------|
+    /// | timestamp | varint nanoseconds since the Unix epoch                       |
+    /// | seq       | varint, `0` for no sequence number, otherwise `seq + 1`       |
+    /// | user_id   | varint                                                        |
+    /// | message   | varint byte length, then the UTF-8 bytes                      |
+    /// | to        | varint `to + 1`, or `0` for a named message to the room;      |
+    /// |           | only present for private or named messages                    |
+    /// | name      | varint byte length, then the UTF-8 bytes; only present for    |
+    /// |           | named messages                                                |
+    ///
+    /// The room name isn't stored, since each transcript belongs to a single room. Fails if the
+    /// timestamp isn't RFC 3339.
+    pub fn to_binary(&self) -> io::Result<Vec<u8>> {
+        let logged_at = humantime::parse_rfc3339(&self.timestamp)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let nanos = logged_at
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .as_nanos() as u64;
+
+        let mut payload = Vec::with_capacity(self.message.len() + 24);
+        write_varint(&mut payload, nanos);
+        write_varint(&mut payload, self.seq.map_or(0, |seq| seq + 1));
+        write_varint(&mut payload, self.user_id as u64);
+        write_varint(&mut payload, self.message.len() as u64);
+        payload.extend_from_slice(self.message.as_bytes());
+        if self.to.is_some() || self.name.is_some() {
+            write_varint(&mut payload, self.to.map_or(0, |to| to as u64 + 1));
+        }
+        if let Some(name) = &self.name {
+            write_varint(&mut payload, name.len() as u64);
+            payload.extend_from_slice(name.as_bytes());
+        }
+
+        let mut record = Vec::with_capacity(payload.len() + 4);
+        write_varint(&mut record, payload.len() as u64);
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    /// Decodes a payload written by `to_binary`, without its length prefix, returning `None` if
+    /// it is malformed.
+    pub fn from_binary(mut payload: &[u8]) -> Option<Record> {
+        let nanos = read_varint(&mut payload)?;
+        let seq = read_varint(&mut payload)?;
+        let user_id = read_varint(&mut payload)?;
+        let len = usize::try_from(read_varint(&mut payload)?).ok()?;
+        if payload.len() < len {
+            return None;
+        }
+        let (message, mut rest) = payload.split_at(len);
+        let to = if rest.is_empty() {
+            None
+        } else {
+            read_varint(&mut rest)?
+                .checked_sub(1)
+                .map(usize::try_from)
+                .transpose()
+                .ok()?
+        };
+        let name = if rest.is_empty() {
+            None
+        } else {
+            let len = usize::try_from(read_varint(&mut rest)?).ok()?;
+            if rest.len() != len {
+                return None;
+            }
+            Some(String::from_utf8(rest.to_vec()).ok()?)
+        };
+        Some(Record {
+            timestamp: humantime::format_rfc3339(UNIX_EPOCH + Duration::from_nanos(nanos))
+                .to_string(),
+            seq: seq.checked_sub(1),
+            user_id: usize::try_from(user_id).ok()?,
+            to,
+            name,
+            message: String::from_utf8(message.to_vec()).ok()?,
+        })
+    }
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Reads records from a binary transcript.
+///
+/// Unlike the text reader this stops at the first malformed record with an `InvalidData` error,
+/// since a bad length prefix leaves no way to find where the next record starts.
+pub fn binary_records<R>(reader: R) -> impl Stream<Item = io::Result<Record>>
+where
+    R: AsyncRead + Unpin,
+{
+    stream::unfold(Some(BufReader::new(reader)), |reader| async move {
+        let mut reader = reader?;
+        match read_binary_record(&mut reader).await {
+            Ok(Some(record)) => Some((Ok(record), Some(reader))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+/// Reads one binary record, or `None` at a clean end of file.
+async fn read_binary_record<R>(reader: &mut BufReader<R>) -> io::Result<Option<Record>>
+where
+    R: AsyncRead + Unpin,
+{
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+    let mut len = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        len |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            if len > MAX_BINARY_RECORD {
+                return Err(invalid("binary transcript record too long"));
+            }
+            let mut payload = vec![0; len as usize];
+            reader.read_exact(&mut payload).await?;
+            return Record::from_binary(&payload)
+                .map(Some)
+                .ok_or_else(|| invalid("malformed binary transcript record"));
+        }
+    }
+    Err(invalid("malformed binary transcript record length"))
+}
+
+/// Reads records from a transcript stored in `format`.
+pub fn records_in<R>(reader: R, format: TranscriptFormat) -> impl Stream<Item = io::Result<Record>>
+where
+    R: AsyncRead + Unpin,
+{
+    match format {
+        TranscriptFormat::Text => Either::Left(records(reader)),
+        TranscriptFormat::Binary => Either::Right(Either::Left(binary_records(reader))),
+        TranscriptFormat::Json => Either::Right(Either::Right(json_records(reader))),
+    }
+}
+
+/// Reads records from a JSON transcript one line at a time, skipping lines that don't parse.
+pub fn json_records<R>(reader: R) -> impl Stream<Item = io::Result<Record>>
+where
+    R: AsyncRead + Unpin,
+{
+    stream::unfold(Some(BufReader::new(reader).lines()), |lines| async move {
+        let mut lines = lines?;
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Ok(record) = serde_json::from_str::<JsonRecord>(&line) {
+                        return Some((Ok(record.into()), Some(lines)));
+                    }
+                }
+                Ok(None) => return None,
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+    })
+}
+
+/// Reads records from a transcript one line at a time, skipping lines that don't parse.
+pub fn records<R>(reader: R) -> impl Stream<Item = io::Result<Record>>
+where
+    R: AsyncRead + Unpin,
+{
+    stream::unfold(Some(BufReader::new(reader).lines()), |lines| async move {
+        let mut lines = lines?;
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Some(record) = Record::parse(&line) {
+                        return Some((Ok(record), Some(lines)));
+                    }
+                }
+                Ok(None) => return None,
+                // Stop after the first read error rather than retrying a broken reader forever.
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+    })
+}
+
+/// Structured formats a transcript can be exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A header row followed by `timestamp,user_id,message` rows.
+    Csv,
+    /// A single JSON array of records.
+    Json,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    fn render(&self, record: &Record, first: bool) -> String {
+        match self {
+            ExportFormat::Csv => format!(
+                "{},{},{}\n",
+                csv_field(&record.timestamp),
+                record.user_id,
+                csv_field(&record.message)
+            ),
+            ExportFormat::Json => {
+                let json = serde_json::to_string(record).expect("records always serialize");
+                if first {
+                    json
+                } else {
+                    format!(",{}", json)
+                }
+            }
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Converts a transcript stored in `stored` to `format` chunk by chunk, without reading the whole
+/// file into memory. Private messages are left out.
+pub fn export<R>(
+    reader: R,
+    stored: TranscriptFormat,
+    format: ExportFormat,
+) -> impl Stream<Item = io::Result<String>>
+where
+    R: AsyncRead + Unpin,
+{
+    let (header, footer) = match format {
+        ExportFormat::Csv => ("timestamp,user_id,message\n", ""),
+        ExportFormat::Json => ("[", "]"),
+    };
+    let body = records_in(reader, stored)
+        .try_filter(|record| future::ready(!record.is_private()))
+        .enumerate()
+        .map(move |(i, record)| record.map(|record| format.render(&record, i == 0)));
+    stream::once(future::ready(Ok(header.to_owned())))
+        .chain(body)
+        .chain(stream::once(future::ready(Ok(footer.to_owned()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use crate::{
+        sink::{BinaryFileSink, LogSink},
+        transcript::{binary_records, Record},
+    };
+
+    #[test]
+    fn record_round_trip() {
+        let record = Record::new(3, "hi: there, user 4: no");
+        assert_eq!(
+            Record::parse(&record.to_line("lobby")),
+            Some(record.clone())
+        );
+
+        let sequenced = Record {
+            seq: Some(42),
+            ..record
+        };
+        assert_eq!(
+            Record::parse(&sequenced.to_line("lobby")),
+            Some(sequenced.clone())
+        );
+
+        let private = Record {
+            to: Some(5),
+            ..sequenced
+        };
+        assert_eq!(
+            Record::parse(&private.to_line("lobby")),
+            Some(private.clone())
+        );
+
+        let named = Record {
+            name: Some("ada lovelace".to_owned()),
+            ..private
+        };
+        assert_eq!(Record::parse(&named.to_line("lobby")), Some(named));
+        assert_eq!(Record::parse("Channel lobby, user 3: hi"), None);
+    }
+
+    #[tokio::test]
+    async fn binary_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("binary_round_trip_{}.bin", std::process::id()));
+        let records = vec![
+            Record::new(3, "hi: there, user 4: no"),
+            Record {
+                seq: Some(0),
+                ..Record::new(usize::MAX, "")
+            },
+            Record {
+                seq: Some(u64::MAX - 1),
+                ..Record::new(7, &"ünïcödé\n".repeat(100))
+            },
+            Record {
+                to: Some(0),
+                ..Record::new(8, "private")
+            },
+            Record {
+                name: Some("ada".to_owned()),
+                ..Record::new(9, "named")
+            },
+            Record {
+                to: Some(3),
+                name: Some("ünï".to_owned()),
+                ..Record::new(9, "named and private")
+            },
+        ];
+
+        let mut sink = BinaryFileSink::create(&path).await.unwrap();
+        for record in &records {
+            sink.write_line(&record.to_line("busy_room")).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let read: Vec<Record> = binary_records(file).try_collect().await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(read, records);
+
+        let truncated = &records[0].to_binary().unwrap()[..5];
+        let read: Result<Vec<Record>, _> = binary_records(truncated).try_collect().await;
+        assert!(read.is_err());
+    }
+}
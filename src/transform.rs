@@ -0,0 +1,66 @@
+use std::{fmt, sync::Arc};
+
+use crate::Identity;
+
+/// A single step of a [`Pipeline`]. Returning `None` drops the message.
+pub type Transform = Arc<dyn Fn(&Identity, String) -> Option<String> + Send + Sync>;
+
+/// An ordered list of transforms applied to every inbound message before it is logged and
+/// broadcast.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    steps: Vec<Transform>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Appends a step to the end of the pipeline.
+    pub fn with<F>(mut self, step: F) -> Pipeline
+    where
+        F: Fn(&Identity, String) -> Option<String> + Send + Sync + 'static,
+    {
+        self.steps.push(Arc::new(step));
+        self
+    }
+
+    /// Runs `msg` through every step in order, stopping as soon as one drops it.
+    pub fn apply(&self, identity: &Identity, msg: String) -> Option<String> {
+        self.steps
+            .iter()
+            .try_fold(msg, |msg, step| step(identity, msg))
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("steps", &self.steps.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{transform::Pipeline, Identity};
+
+    #[test]
+    fn steps_apply_in_order() {
+        let pipeline = Pipeline::new()
+            .with(|_, msg| Some(msg.trim().to_uppercase()))
+            .with(|_, msg| if msg.is_empty() { None } else { Some(msg) });
+        let identity = Identity::new(1);
+
+        assert_eq!(
+            pipeline.apply(&identity, " hello ".to_owned()),
+            Some("HELLO".to_owned())
+        );
+        assert_eq!(pipeline.apply(&identity, "   ".to_owned()), None);
+        assert_eq!(
+            Pipeline::new().apply(&identity, "untouched".to_owned()),
+            Some("untouched".to_owned())
+        );
+    }
+}
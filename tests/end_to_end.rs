@@ -0,0 +1,61 @@
+//! Clients talking to each other through the full set of routes, over test websockets.
+
+use std::time::Duration;
+
+use brightidea_test::{api, config::RoomConfig, find_room, ChatRooms};
+
+/// Waits until `room` has `users` connected, failing the test if it takes too long.
+async fn wait_for_users(rooms: &ChatRooms, room: &str, users: usize) {
+    for _ in 0..100 {
+        let connected = match find_room(room, rooms).await {
+            Some(room) => room.users.read().await.len(),
+            None => 0,
+        };
+        if connected == users {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} never had {} users", room, users);
+}
+
+#[tokio::test]
+async fn message_reaches_other_user_but_not_sender() {
+    let rooms = ChatRooms::default();
+    let config = RoomConfig {
+        log_dir: Some(std::env::temp_dir().join(format!("e2e_{}", std::process::id()))),
+        ..RoomConfig::default()
+    };
+    let filters = api::build_filters(rooms.clone(), config);
+
+    let mut alice = warp::test::ws()
+        .path("/chat/e2e_room")
+        .handshake(filters.clone())
+        .await
+        .unwrap();
+    let mut bob = warp::test::ws()
+        .path("/chat/e2e_room")
+        .handshake(filters.clone())
+        .await
+        .unwrap();
+    wait_for_users(&rooms, "e2e_room", 2).await;
+
+    alice.send_text("hello bob").await;
+    let received = bob.recv().await.unwrap();
+    let text = received.to_str().unwrap();
+    assert!(text.starts_with("<User#"), "unexpected message {:?}", text);
+    assert!(
+        text.ends_with(">: hello bob"),
+        "unexpected message {:?}",
+        text
+    );
+
+    // Nothing comes back to the sender.
+    let echoed = tokio::time::timeout(Duration::from_millis(100), alice.recv()).await;
+    assert!(echoed.is_err());
+
+    drop(bob);
+    wait_for_users(&rooms, "e2e_room", 1).await;
+    drop(alice);
+    wait_for_users(&rooms, "e2e_room", 0).await;
+}